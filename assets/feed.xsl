@@ -0,0 +1,65 @@
+<?xml version="1.0" encoding="utf-8"?>
+<!-- Renders a podcast feed as a page, when it's opened in a browser -->
+<xsl:stylesheet version="1.0"
+    xmlns:xsl="http://www.w3.org/1999/XSL/Transform"
+    xmlns:atom="http://www.w3.org/2005/Atom"
+    xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <xsl:output method="html" encoding="utf-8" doctype-system="about:legacy-compat"/>
+
+  <xsl:variable name="feed" select="/rss/channel/atom:link[@rel='self']/@href"/>
+  <xsl:variable name="feed-host-path" select="substring-after($feed, '://')"/>
+
+  <xsl:template match="/rss/channel">
+    <html>
+      <head>
+        <meta charset="utf-8"/>
+        <meta name="viewport" content="width=device-width, initial-scale=1"/>
+        <title><xsl:value-of select="title"/></title>
+        <style>
+          body { font-family: sans-serif; max-width: 48em; margin: 0 auto; padding: 1em; line-height: 1.4; }
+          header { display: flex; gap: 1em; align-items: flex-start; }
+          header img { width: 10em; height: 10em; }
+          .subscribe a, .subscribe input { display: inline-block; margin: 0.25em 0.25em 0.25em 0; }
+          .subscribe input { width: 100%; box-sizing: border-box; }
+          article { border-top: 1px solid #ccc; padding: 0.5em 0; }
+          article h2 { font-size: 1.1em; margin-bottom: 0.25em; }
+          time { color: #666; font-size: 0.9em; }
+          audio { width: 100%; }
+        </style>
+      </head>
+      <body>
+        <header>
+          <xsl:if test="image/url">
+            <img src="{image/url}" alt=""/>
+          </xsl:if>
+          <div>
+            <h1><xsl:value-of select="title"/></h1>
+            <p><xsl:value-of select="itunes:subtitle"/></p>
+            <p><a href="{link}">Listen on BBC Sounds</a></p>
+          </div>
+        </header>
+        <section class="subscribe">
+          <p>This is a podcast feed. Subscribe by copying its address into your podcast app, or:</p>
+          <a href="podcast://{$feed-host-path}">Apple Podcasts</a>
+          <a href="overcast://x-callback-url/add?url={$feed}">Overcast</a>
+          <a href="pktc://subscribe/{$feed-host-path}">Pocket Casts</a>
+          <a href="{$feed}" type="application/rss+xml">RSS</a>
+          <input type="text" readonly="readonly" value="{$feed}" onclick="this.select()"/>
+        </section>
+        <xsl:for-each select="atom:link[@rel='next']">
+          <p><a href="{@href}">Older episodes</a></p>
+        </xsl:for-each>
+        <xsl:apply-templates select="item"/>
+      </body>
+    </html>
+  </xsl:template>
+
+  <xsl:template match="item">
+    <article>
+      <h2><xsl:value-of select="title"/></h2>
+      <time><xsl:value-of select="pubDate"/></time>
+      <p><xsl:value-of select="description"/></p>
+      <audio controls="controls" preload="none" src="{enclosure/@url}"/>
+    </article>
+  </xsl:template>
+</xsl:stylesheet>
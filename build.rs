@@ -0,0 +1,40 @@
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    // Only rerun for a new commit or proto, so the build time is when those last changed
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=proto/sounds_proxy.proto");
+    // outside a checkout (e.g. building an image) a missing path would rerun every time
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(head_ref) = head.strip_prefix("ref: ").map(str::trim) {
+            if Path::new(".git").join(head_ref).exists() {
+                println!("cargo:rerun-if-changed=.git/{}", head_ref);
+            }
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/sounds_proxy.proto").unwrap();
+
+    // Reported by /api/version
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=SOUNDS_PROXY_GIT_SHA={}", git_sha);
+
+    let build_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    println!("cargo:rustc-env=SOUNDS_PROXY_BUILD_TIME={}", build_time);
+}
@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{bbc::Pid, failures::Failure, upload_status::UploadState};
+
+const USAGE: &str = "usage: admin [--server <url>] [--token <token>] \
+    (subscriptions | queue | purge <episode-id> | errors [--follow])";
+
+const DEFAULT_SERVER: &str = "http://localhost:8080";
+
+// Failures printed by `errors`, most recent last
+const ERRORS_SHOWN: usize = 20;
+// How often `errors --follow` checks for new failures
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Subscriptions,
+    Queue,
+    Purge(Pid),
+    Errors { follow: bool },
+}
+
+/// A command for a proxy's admin API, e.g. `--server https://proxy.example.com queue`
+#[derive(Debug, PartialEq, Eq)]
+struct Invocation {
+    server: String,
+    /// Defaults to the admin token this host is configured with
+    token: Option<String>,
+    command: Command,
+}
+
+#[derive(Deserialize)]
+struct QueuedUpload {
+    key: String,
+    state: UploadState,
+}
+
+#[derive(Deserialize)]
+struct PurgeResponse {
+    deleted: Vec<String>,
+}
+
+fn usage() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, USAGE)
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> std::io::Result<Invocation> {
+    let mut server = DEFAULT_SERVER.to_string();
+    let mut token = None;
+    let mut command = None;
+    let mut follow = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = args.next().ok_or_else(usage)?,
+            "--token" => token = Some(args.next().ok_or_else(usage)?),
+            "--follow" | "-f" => follow = true,
+            "subscriptions" => command = Some(Command::Subscriptions),
+            "queue" => command = Some(Command::Queue),
+            "purge" => {
+                let pid = args.next().ok_or_else(usage)?;
+                command = Some(Command::Purge(pid.parse().map_err(|_| usage())?));
+            }
+            "errors" => command = Some(Command::Errors { follow: false }),
+            _ => return Err(usage()),
+        }
+    }
+    let command = match command.ok_or_else(usage)? {
+        Command::Errors { .. } => Command::Errors { follow },
+        _ if follow => return Err(usage()),
+        command => command,
+    };
+    Ok(Invocation {
+        server: server.trim_end_matches('/').to_string(),
+        token,
+        command,
+    })
+}
+
+fn request_error(e: reqwest::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+struct Client {
+    http: reqwest::Client,
+    server: String,
+    token: String,
+}
+
+impl Client {
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> std::io::Result<T> {
+        let resp = request
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .map_err(request_error)?;
+        if resp.status() == reqwest::StatusCode::FORBIDDEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "the server didn't accept the admin token",
+            ));
+        }
+        resp.error_for_status()
+            .map_err(request_error)?
+            .json()
+            .await
+            .map_err(request_error)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> std::io::Result<T> {
+        self.send(self.http.get(format!("{}{}", self.server, path)))
+            .await
+    }
+
+    async fn delete<T: DeserializeOwned>(&self, path: &str) -> std::io::Result<T> {
+        self.send(self.http.delete(format!("{}{}", self.server, path)))
+            .await
+    }
+}
+
+fn print_failure(failure: &Failure) {
+    let next = match failure.retry_at {
+        Some(retry_at) => format!("retrying at {}", retry_at.to_rfc3339()),
+        None => "quarantined".to_string(),
+    };
+    println!(
+        "{} {} ({} in a row, {}): {}",
+        failure.last_failure.to_rfc3339(),
+        failure.pid,
+        failure.failures,
+        next,
+        failure.last_error
+    );
+}
+
+/// Prints the most recent failures, then with `follow`, new ones as they happen
+async fn tail_errors(client: &Client, follow: bool) -> std::io::Result<()> {
+    // by the server's clock, which the failures are timed by
+    let mut printed_until: Option<DateTime<Utc>> = None;
+    loop {
+        let mut failures: Vec<Failure> = client.get("/api/admin/failures").await?;
+        failures.sort_by_key(|f| f.last_failure);
+        let new = failures
+            .iter()
+            .filter(|f| printed_until.map_or(true, |until| f.last_failure > until))
+            .collect::<Vec<_>>();
+        // only the most recent to begin with
+        let skip = match printed_until {
+            None => new.len().saturating_sub(ERRORS_SHOWN),
+            Some(_) => 0,
+        };
+        for failure in &new[skip..] {
+            print_failure(failure);
+        }
+        if let Some(last) = new.last() {
+            printed_until = Some(last.last_failure);
+        }
+        if !follow {
+            return Ok(());
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+/// `sounds-proxy admin ...`, which manages a proxy (this one by default) through its
+/// admin API
+pub async fn run(
+    args: impl Iterator<Item = String>,
+    default_token: Option<String>,
+) -> std::io::Result<()> {
+    let invocation = parse(args)?;
+    let token = invocation.token.or(default_token).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "an admin token is needed, from --token or SOUNDS_PROXY_ADMIN_TOKEN",
+        )
+    })?;
+    let client = Client {
+        http: reqwest::Client::new(),
+        server: invocation.server,
+        token,
+    };
+
+    match invocation.command {
+        Command::Subscriptions => {
+            let subscriptions: Vec<Pid> = client.get("/api/admin/subscriptions").await?;
+            for pid in subscriptions {
+                println!("{}", pid);
+            }
+        }
+        Command::Queue => {
+            let queue: Vec<QueuedUpload> = client.get("/api/admin/queue").await?;
+            if queue.is_empty() {
+                println!("Nothing queued");
+            }
+            for upload in queue {
+                let state = match upload.state {
+                    UploadState::InProgress => "uploading",
+                    _ => "queued",
+                };
+                println!("{:<10} {}", state, upload.key);
+            }
+        }
+        Command::Purge(pid) => {
+            let purged: PurgeResponse = client
+                .delete(&format!("/api/admin/episodes/{}", pid))
+                .await?;
+            println!("Purged {}", pid);
+            for key in purged.deleted {
+                println!("  deleted {}", key);
+            }
+        }
+        Command::Errors { follow } => tail_errors(&client, follow).await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn args(args: &str) -> impl Iterator<Item = String> + '_ {
+        args.split_whitespace().map(str::to_string)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(args(
+                "--server https://proxy.example.com/ --token secret errors -f"
+            ))
+            .unwrap(),
+            Invocation {
+                server: "https://proxy.example.com".to_string(),
+                token: Some("secret".to_string()),
+                command: Command::Errors { follow: true },
+            }
+        );
+        assert_eq!(
+            parse(args("purge p0bzn8f1")).unwrap(),
+            Invocation {
+                server: DEFAULT_SERVER.to_string(),
+                token: None,
+                command: Command::Purge("p0bzn8f1".parse().unwrap()),
+            }
+        );
+        assert!(parse(args("")).is_err());
+        assert!(parse(args("purge")).is_err());
+        assert!(parse(args("queue --follow")).is_err());
+        assert!(parse(args("restart")).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::bbc::Pid;
+
+/// How long a request counts towards admitting an episode, by default
+pub const DEFAULT_WINDOW_DAYS: u64 = 7;
+
+// Most episodes requests are remembered for, so one-off requests can't use up memory
+const MAX_TRACKED: usize = 65536;
+
+/// Which private episodes are kept in S3. Those which aren't are streamed to each
+/// listener, which costs more bandwidth but nothing in storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Admission {
+    /// Every episode requested
+    Always,
+    /// Episodes requested again within the admission window
+    Repeat,
+    /// Episodes of subscribed shows
+    Subscribed,
+}
+
+/// Remembers when episodes were last requested, so ones requested more than once
+/// can be told apart from one-offs
+#[derive(Default)]
+pub struct AdmissionTracker {
+    requests: Mutex<HashMap<Pid, DateTime<Utc>>>,
+}
+
+impl AdmissionTracker {
+    /// Records a request for the episode. Returns true if it was also requested within
+    /// the window before this.
+    pub fn repeat_request(&self, episode_id: &Pid, window: Duration, now: DateTime<Utc>) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() >= MAX_TRACKED {
+            requests.retain(|_, requested| now - *requested < window);
+            if requests.len() >= MAX_TRACKED {
+                requests.clear();
+            }
+        }
+        match requests.insert(episode_id.clone(), now) {
+            Some(previous) => now - previous < window,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_repeat_request() {
+        let tracker = AdmissionTracker::default();
+        let episode: Pid = "p0bzn8f1".parse().unwrap();
+        let other: Pid = "m001kqyh".parse().unwrap();
+        let window = Duration::days(7);
+        let now = Utc::now();
+
+        assert!(!tracker.repeat_request(&episode, window, now));
+        assert!(!tracker.repeat_request(&other, window, now));
+        assert!(tracker.repeat_request(&episode, window, now + Duration::days(6)));
+
+        // too long after the last request, so it starts again
+        let later = now + Duration::days(14);
+        assert!(!tracker.repeat_request(&other, window, later));
+        assert!(tracker.repeat_request(&other, window, later + Duration::hours(1)));
+    }
+}
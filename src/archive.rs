@@ -0,0 +1,226 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Datelike;
+use futures::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::{bbc, parents, s3};
+
+type Result<T, E = bbc::BbcResponseError> = core::result::Result<T, E>;
+
+const UNKNOWN_YEAR: &str = "Unknown";
+
+// Archived episodes looked up on the BBC at once
+const LOOKUP_CONCURRENCY: usize = 8;
+
+// Sizes listed from a bucket are reused for this long, as every feed request wants them
+const SIZES_LIFETIME: Duration = Duration::from_secs(60);
+
+struct SizesEntry {
+    sizes: HashMap<String, u64>,
+    expires: Instant,
+}
+
+/// By bucket. Locked while listing, so requests arriving together share one listing.
+static SIZES: Lazy<tokio::sync::Mutex<HashMap<String, SizesEntry>>> = Lazy::new(Default::default);
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn sanitize_filename(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '[' | ']' => '-',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+/// An episode kept in S3, as the BBC describes it
+struct ArchivedEpisode {
+    id: bbc::Pid,
+    programme: Arc<bbc::ProgrammeInfo>,
+}
+
+/// Whether a year in a path could be one of the folders episodes are listed in
+pub fn is_year_folder(year: &str) -> bool {
+    year == UNKNOWN_YEAR || (year.len() == 4 && year.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn year_folder(episode: &ArchivedEpisode) -> String {
+    episode
+        .programme
+        .first_broadcast
+        .map_or_else(|| UNKNOWN_YEAR.to_string(), |d| d.year().to_string())
+}
+
+/// Media servers name files after the listing, so include the date and title,
+/// but keep the pid in brackets so it can be recovered when the file is requested.
+fn episode_filename(episode: &ArchivedEpisode) -> String {
+    let date = episode
+        .programme
+        .first_broadcast
+        .map(|d| d.format("%Y-%m-%d ").to_string())
+        .unwrap_or_default();
+    let title = episode
+        .programme
+        .title
+        .as_deref()
+        .unwrap_or_else(|| episode.id.as_str());
+    format!("{}{} [{}].aac", date, sanitize_filename(title), episode.id)
+}
+
+/// Sizes of the episodes saved to S3, by episode id, as listed in the last minute
+pub async fn get_archived_sizes(
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+) -> Result<HashMap<String, u64>> {
+    let mut cache = SIZES.lock().await;
+    if let Some(entry) = cache.get(bucket).filter(|e| e.expires > Instant::now()) {
+        return Ok(entry.sizes.clone());
+    }
+    let sizes = s3::list_objects(s3_client, bucket)
+        .await?
+        .into_iter()
+        .filter_map(|(key, size)| Some((key.strip_suffix(".aac")?.to_string(), size)))
+        .collect::<HashMap<_, _>>();
+    cache.insert(
+        bucket.to_string(),
+        SizesEntry {
+            sizes: sizes.clone(),
+            expires: Instant::now() + SIZES_LIFETIME,
+        },
+    );
+    Ok(sizes)
+}
+
+/// Extracts the episode pid from a filename produced by `episode_filename`
+pub fn parse_episode_filename(filename: &str) -> Option<bbc::Pid> {
+    let name = filename.strip_suffix(".aac")?;
+    let start = name.rfind('[')?;
+    name[start + 1..].strip_suffix(']')?.parse().ok()
+}
+
+fn render_index(title: &str, entries: &[String]) -> String {
+    let title = html_escape(title);
+    let links = entries
+        .iter()
+        .map(|e| {
+            format!(
+                "<li><a href=\"{}\">{}</a></li>",
+                utf8_percent_encode(e.trim_end_matches('/'), NON_ALPHANUMERIC).to_string()
+                    + if e.ends_with('/') { "/" } else { "" },
+                html_escape(e)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n<li><a href=\"../\">../</a></li>\n{1}\n</ul>\n</body>\n</html>\n",
+        title, links
+    )
+}
+
+/// The show's title, and its episodes in S3 by year. They're found from the bucket's
+/// listing, as the BBC may only list a show's newest few dozen episodes.
+/// `rendition` tells apart the show's uploads when it has its own audio settings.
+async fn get_archived_episodes(
+    programme_id: &bbc::Pid,
+    rendition: Option<&str>,
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+) -> Result<(String, BTreeMap<String, Vec<ArchivedEpisode>>)> {
+    let show = parents::get_programme(programme_id).await?;
+    let show_title = show
+        .title
+        .clone()
+        .unwrap_or_else(|| programme_id.to_string());
+
+    // other renditions and parts don't parse as pids once the suffix is stripped
+    let suffix = format!(
+        "{}.aac",
+        rendition.map(|r| format!("-{}", r)).unwrap_or_default()
+    );
+    let archived = s3::list_objects(s3_client, bucket)
+        .await?
+        .into_keys()
+        .filter_map(|key| key.strip_suffix(&suffix)?.parse::<bbc::Pid>().ok());
+    let episodes = stream::iter(archived)
+        .map(|id| async move {
+            let programme = parents::get_programme(&id).await;
+            (id, programme)
+        })
+        .buffer_unordered(LOOKUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut years = BTreeMap::new();
+    for (id, programme) in episodes {
+        match programme {
+            Ok(programme) if programme.ancestors.contains(programme_id) => {
+                let episode = ArchivedEpisode { id, programme };
+                years
+                    .entry(year_folder(&episode))
+                    .or_insert_with(Vec::new)
+                    .push(episode);
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Couldn't look up archived episode {}: {}", id, e),
+        }
+    }
+    for episodes in years.values_mut() {
+        episodes.sort_by_key(|e| Reverse(e.programme.first_broadcast));
+    }
+
+    Ok((show_title, years))
+}
+
+/// Lists the years for which a show has archived episodes
+pub async fn get_show_index(
+    programme_id: &bbc::Pid,
+    rendition: Option<&str>,
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+) -> Result<String> {
+    let (show, years) = get_archived_episodes(programme_id, rendition, s3_client, bucket).await?;
+
+    let entries = years.keys().map(|y| format!("{}/", y)).collect::<Vec<_>>();
+
+    Ok(render_index(&sanitize_filename(&show), &entries))
+}
+
+/// Lists a show's archived episodes for a year
+pub async fn get_year_index(
+    programme_id: &bbc::Pid,
+    rendition: Option<&str>,
+    year: &str,
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+) -> Result<String> {
+    let (show, mut years) =
+        get_archived_episodes(programme_id, rendition, s3_client, bucket).await?;
+
+    let entries = years
+        .remove(year)
+        .ok_or(bbc::BbcResponseError::NotFound)?
+        .iter()
+        .map(episode_filename)
+        .collect::<Vec<_>>();
+
+    Ok(render_index(
+        &format!("{}/{}", sanitize_filename(&show), year),
+        &entries,
+    ))
+}
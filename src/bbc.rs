@@ -1,7 +1,9 @@
 use crate::hls::HlsError;
-use crate::s3_upload::S3Error;
+use crate::s3::S3Error;
 
 use super::fetch::{get, head, FetchError};
+use chrono::{DateTime, FixedOffset};
+use futures::{Stream, StreamExt, TryStreamExt};
 use hyper::header::ToStrError;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
@@ -12,15 +14,35 @@ pub enum BbcResponseError {
     #[error("Bad request")]
     BadRequest,
 
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Too busy")]
+    Overloaded,
+
     #[error("Not found")]
     NotFound,
 
+    /// The show is on the deny list, or not on the allow list
+    #[error("Show not permitted")]
+    NotPermitted,
+
+    #[error("Not implemented")]
+    NotImplemented,
+
+    #[error("Not acceptable")]
+    NotAcceptable,
+
     #[error("Server response code: {0}")]
     ServerResponseError(u16),
 
     #[error("BBC response not understood")]
     FormatError,
 
+    /// The media selector's reason, e.g. `geolocation`
+    #[error("Media unavailable: {0}")]
+    Unavailable(String),
+
     #[error("Unsupported media: pid {0}, message {1}")]
     UnsupportedMedia(String, String),
 
@@ -33,8 +55,8 @@ pub enum BbcResponseError {
     #[error("HLS download error: {0}")]
     HlsDownloadError(#[from] HlsError),
 
-    #[error("S3 upload error: {0}")]
-    S3UploadError(#[from] S3Error),
+    #[error("S3 error: {0}")]
+    S3Error(#[from] S3Error),
 }
 
 impl From<FetchError> for BbcResponseError {
@@ -51,6 +73,54 @@ impl From<FetchError> for BbcResponseError {
     }
 }
 
+/// A BBC programme identifier (pid), e.g. `p02pc9pj`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pid(String);
+
+impl Pid {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for Pid {
+    type Err = BbcResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = s.len() == 8
+            && matches!(s.as_bytes()[0], b'b' | b'm' | b'p' | b'w')
+            && s.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+
+        if valid {
+            Ok(Pid(s.to_string()))
+        } else {
+            Err(BbcResponseError::BadRequest)
+        }
+    }
+}
+
+impl TryFrom<String> for Pid {
+    type Error = BbcResponseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Pid> for String {
+    fn from(pid: Pid) -> Self {
+        pid.0
+    }
+}
+
+impl std::fmt::Display for Pid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 impl From<BbcResponseError> for std::io::Error {
     fn from(err: BbcResponseError) -> Self {
         match err {
@@ -64,6 +134,20 @@ impl From<BbcResponseError> for std::io::Error {
     }
 }
 
+impl BbcResponseError {
+    /// True if audio was found to be missing, even once passed through an upload
+    pub fn is_truncated(&self) -> bool {
+        match self {
+            BbcResponseError::HlsDownloadError(HlsError::Truncated(_)) => true,
+            BbcResponseError::S3Error(S3Error::Io(e)) => e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<BbcResponseError>())
+                .map_or(false, |e| e.is_truncated()),
+            _ => false,
+        }
+    }
+}
+
 impl From<ToStrError> for BbcResponseError {
     fn from(_: ToStrError) -> Self {
         BbcResponseError::BadRequest
@@ -83,6 +167,44 @@ pub struct Titles {
     pub secondary: Option<String>,
 }
 
+/// Titles and synopses in another language, which the BBC gives for some Radio Cymru
+/// and Radio nan Gàidheal programmes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Translation {
+    /// e.g. `cy` or `gd`
+    pub language: String,
+    pub titles: Option<Titles>,
+    pub synopses: Option<Synopses>,
+}
+
+/// The titles and synopses translated into `language`, or else the BBC's own. Tags match
+/// on their primary language, so `cy-GB` matches `cy`.
+fn localised<'a>(
+    titles: &'a Titles,
+    synopses: &'a Synopses,
+    translations: &'a [Translation],
+    language: Option<&str>,
+) -> (&'a Titles, &'a Synopses) {
+    let primary = |tag: &str| {
+        tag.split('-')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    };
+    let translation = language.and_then(|language| {
+        translations
+            .iter()
+            .find(|t| primary(&t.language) == primary(language))
+    });
+    match translation {
+        Some(t) => (
+            t.titles.as_ref().unwrap_or(titles),
+            t.synopses.as_ref().unwrap_or(synopses),
+        ),
+        None => (titles, synopses),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Duration {
     pub value: u64,
@@ -93,8 +215,17 @@ pub struct Release {
     pub date: String,
 }
 
+/// When an episode can be played. Upcoming episodes are listed before they're available.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Availability {
+    #[serde(alias = "start")]
+    pub from: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct QualityVariant {
+    /// kbps
+    pub bitrate: Option<u64>,
     pub file_url: Option<String>,
     pub file_size: Option<u64>,
 }
@@ -117,13 +248,41 @@ pub struct Network {
     pub short_title: String,
 }
 
+/// A genre a show is listed under, e.g. `comedy` or `factual/history`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Category {
+    pub id: String,
+    pub title: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ContainerItemData {
     pub id: String,
+    /// e.g. `urn:bbc:radio:series:b006qgvj`
+    pub urn: Option<String>,
     pub titles: Titles,
     pub synopses: Synopses,
-    pub network: Network,
+    /// Absent for some podcast-only brands
+    pub network: Option<Network>,
     pub image_url: Option<String>,
+    /// Only given for some shows in Welsh or Gaelic
+    #[serde(default)]
+    pub translations: Vec<Translation>,
+}
+
+impl ContainerItemData {
+    /// Titles and synopses in `language`, where they're translated into it
+    pub fn localised(&self, language: Option<&str>) -> (&Titles, &Synopses) {
+        localised(&self.titles, &self.synopses, &self.translations, language)
+    }
+}
+
+/// The series an episode is in
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EpisodeContainer {
+    pub id: String,
+    /// Of the series in its brand, where the BBC numbers them
+    pub position: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -132,14 +291,107 @@ pub struct ContainerListData {
     pub titles: Titles,
     pub synopses: Synopses,
     pub duration: Duration,
+    /// Of the episode in its series, where the BBC numbers them
+    pub position: Option<u32>,
+    pub container: Option<EpisodeContainer>,
     pub release: Release,
+    pub availability: Option<Availability>,
     pub download: Download,
+    pub network: Option<Network>,
     pub image_url: Option<String>,
+    /// Only given for some episodes in Welsh or Gaelic
+    #[serde(default)]
+    pub translations: Vec<Translation>,
+}
+
+impl ContainerListData {
+    /// Titles and synopses in `language`, where they're translated into it
+    pub fn localised(&self, language: Option<&str>) -> (&Titles, &Synopses) {
+        localised(&self.titles, &self.synopses, &self.translations, language)
+    }
+}
+
+/// An entry in a container's list: an episode, or a container nested in it, such as
+/// one of a brand's series
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ListEntry {
+    PlayableItem(Box<ContainerListData>),
+    ContainerItem(Box<ContainerItemData>),
+    #[serde(other)]
+    Other,
+}
+
+/// Where the rest of a list is, as the BBC only gives the first page of long ones
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pagination {
+    /// Path of a page, with `{offset}` and `{limit}` to fill in
+    pub uri: String,
+    /// Size of the BBC's pages
+    pub limit: usize,
+    /// Entries in the whole list
+    pub total: usize,
+}
+
+#[derive(Deserialize)]
+struct ListUris {
+    pagination: Option<Pagination>,
+}
+
+#[derive(Deserialize)]
+struct RawContainerList {
+    data: Vec<ListEntry>,
+    #[serde(default)]
+    uris: Option<ListUris>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(from = "RawContainerList")]
 pub struct ContainerList {
     pub data: Vec<ContainerListData>,
+    /// Containers listed alongside (or instead of) episodes, e.g. a brand's series
+    pub containers: Vec<ContainerItemData>,
+    pub pagination: Option<Pagination>,
+}
+
+impl ContainerList {
+    /// True if the BBC left some of the list out
+    pub fn is_partial(&self) -> bool {
+        self.pagination
+            .as_ref()
+            .map_or(false, |p| p.total > self.data.len())
+    }
+
+    /// The episodes listed, and with `all_pages` the rest of them if the BBC left some out,
+    /// read a slice at a time as feed pages are
+    pub async fn episodes(&self, all_pages: bool) -> Result<Vec<ContainerListData>> {
+        let mut data = self.data.clone();
+        if let Some(pagination) = self.pagination.as_ref().filter(|_| all_pages) {
+            if self.is_partial() {
+                let listed = data.len();
+                data.extend(get_list_slice(pagination, listed, pagination.total - listed).await?);
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl From<RawContainerList> for ContainerList {
+    fn from(raw: RawContainerList) -> Self {
+        let mut list = ContainerList {
+            data: Vec::new(),
+            containers: Vec::new(),
+            pagination: raw.uris.and_then(|u| u.pagination),
+        };
+        for entry in raw.data {
+            match entry {
+                ListEntry::PlayableItem(item) => list.data.push(*item),
+                ListEntry::ContainerItem(item) => list.containers.push(*item),
+                ListEntry::Other => {}
+            }
+        }
+        list
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -164,7 +416,7 @@ impl Container {
         }
     }
 
-    pub fn list(&self) -> Option<&ContainerList> {
+    pub fn into_list(self) -> Option<ContainerList> {
         match self {
             Container::ContainerList(list) => Some(list),
             _ => None,
@@ -183,11 +435,50 @@ pub struct Connection {
     pub href: String,
     #[serde(alias = "transferFormat")]
     pub transfer_format: String,
+    /// CDN serving the connection, e.g. `akamai_hls_open_https`
+    pub supplier: Option<String>,
+    /// The BBC's ranking of its CDNs, lowest first
+    pub priority: Option<String>,
+    /// Share of traffic the BBC sends to the CDN
+    pub dpw: Option<String>,
+}
+
+impl Connection {
+    pub fn priority(&self) -> Option<u32> {
+        self.priority.as_deref().and_then(|p| p.parse().ok())
+    }
+
+    fn weight(&self) -> u32 {
+        self.dpw
+            .as_deref()
+            .and_then(|w| w.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Order in which connections are tried. https first, then CDNs whose supplier
+    /// contains one of `preferred_suppliers`, in that order, then by the BBC's priority
+    /// and share of traffic.
+    pub fn rank(&self, preferred_suppliers: &[String]) -> impl Ord {
+        let supplier = self.supplier.as_deref().unwrap_or_default();
+        let preference = preferred_suppliers
+            .iter()
+            .position(|s| supplier.contains(s.as_str()))
+            .unwrap_or(preferred_suppliers.len());
+        (
+            self.protocol != "https",
+            preference,
+            self.priority().unwrap_or(u32::MAX),
+            std::cmp::Reverse(self.weight()),
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Media {
     pub kind: String,
+    /// Which version of the audio this is, e.g. `audio-syndication-dash`
+    #[serde(default)]
+    pub service: Option<String>,
     pub r#type: String,
     pub bitrate: String,
     pub encoding: String,
@@ -199,42 +490,329 @@ pub struct MediaList {
     pub media: Vec<Media>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SegmentOffset {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+/// Someone credited on a segment, e.g. a piece of music's composer or a presenter
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Contribution {
+    pub name: String,
+    pub role: Option<String>,
+    /// The contributor's pid, for their page on bbc.co.uk, if they have one
+    #[serde(default, alias = "pid")]
+    pub id: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SegmentItem {
+    pub id: String,
+    pub segment_type: Option<String>,
+    pub titles: Titles,
+    pub offset: Option<SegmentOffset>,
+    /// Only listed for some segments, mostly music
+    #[serde(default)]
+    pub contributions: Vec<Contribution>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SegmentList {
+    pub data: Vec<SegmentItem>,
+}
+
+/// A programme listed in a category
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CategoryProgramme {
+    pub id: String,
+    pub urn: String,
+    pub titles: Titles,
+    pub synopses: Option<Synopses>,
+    pub image_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CategoryModule {
+    /// A list of items, or a single item for headers
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct CategoryResponse {
+    data: Vec<CategoryModule>,
+}
+
+/// A programme or version on bbc.co.uk/programmes, with what it's part of
+#[derive(Deserialize, Debug)]
+struct Programme {
+    pid: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    first_broadcast_date: Option<String>,
+    #[serde(default)]
+    parent: Option<Box<ProgrammeParent>>,
+    /// Genres, and formats such as `discussionandtalk`
+    #[serde(default)]
+    categories: Vec<ProgrammeCategory>,
+}
+
+/// A genre or format on bbc.co.uk/programmes, within any broader one
+#[derive(Deserialize, Debug)]
+struct ProgrammeCategory {
+    #[serde(rename = "type")]
+    kind: String,
+    key: String,
+    title: Option<String>,
+    #[serde(default)]
+    broader: Option<BroaderCategory>,
+}
+
+/// Empty for a category which isn't within another
+#[derive(Deserialize, Debug)]
+struct BroaderCategory {
+    #[serde(default)]
+    category: Option<Box<ProgrammeCategory>>,
+}
+
+impl ProgrammeCategory {
+    /// The genre as a show's category, named by its broader genres then its own key,
+    /// e.g. `factual/history`
+    fn genre(&self) -> Option<Category> {
+        if self.kind != "genre" {
+            return None;
+        }
+        let mut keys = vec![self.key.as_str()];
+        let mut broader = self.broader.as_ref().and_then(|b| b.category.as_ref());
+        while let Some(category) = broader {
+            keys.insert(0, category.key.as_str());
+            broader = category.broader.as_ref().and_then(|b| b.category.as_ref());
+        }
+        Some(Category {
+            id: keys.join("/"),
+            title: self.title.clone(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ProgrammeParent {
+    programme: Programme,
+}
+
+/// Versions (which episodes are played by) and programmes are described alike
+#[derive(Deserialize, Debug)]
+struct ProgrammeResponse {
+    programme: Option<Programme>,
+    version: Option<Programme>,
+}
+
+/// A programme, or the episode a version is of, and what it's part of
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgrammeInfo {
+    pub title: Option<String>,
+    pub first_broadcast: Option<DateTime<FixedOffset>>,
+    /// Pids of everything it's part of, nearest first, e.g. a version's episode, then its
+    /// series and brand
+    pub ancestors: Vec<Pid>,
+    /// Genres it's listed under
+    pub genres: Vec<Category>,
+}
+
+impl ProgrammeResponse {
+    fn info(&self) -> ProgrammeInfo {
+        // versions have no title or date of their own, so they're their episode's
+        let titled = match (&self.version, &self.programme) {
+            (Some(version), _) => version.parent.as_ref().map(|p| &p.programme),
+            (None, programme) => programme.as_ref(),
+        };
+        ProgrammeInfo {
+            title: titled.and_then(|p| p.title.clone()),
+            first_broadcast: titled
+                .and_then(|p| p.first_broadcast_date.as_deref())
+                .and_then(|d| DateTime::parse_from_rfc3339(d).ok()),
+            ancestors: self.ancestors(),
+            genres: titled
+                .map(|p| p.categories.iter().filter_map(|c| c.genre()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn ancestors(&self) -> Vec<Pid> {
+        let mut ancestors = Vec::new();
+        let mut parent = self
+            .version
+            .as_ref()
+            .or(self.programme.as_ref())
+            .and_then(|p| p.parent.as_ref());
+        while let Some(p) = parent {
+            if let Ok(pid) = p.programme.pid.parse() {
+                ancestors.push(pid);
+            }
+            parent = p.programme.parent.as_ref();
+        }
+        ancestors
+    }
+}
+
 type Result<T, E = BbcResponseError> = std::result::Result<T, E>;
 
-pub async fn get_container(urn: &str) -> Result<ContainerResponse> {
+pub fn container_url(urn: &str) -> String {
     let encoded_urn = utf8_percent_encode(urn, NON_ALPHANUMERIC).to_string();
-    let uri = format!(
+    format!(
         "https://rms.api.bbc.co.uk/v2/experience/inline/container/{}",
         encoded_urn
+    )
+}
+
+/// A container, with the first page of its list
+pub async fn get_container(urn: &str) -> Result<ContainerResponse> {
+    let resp_text = get(container_url(urn)).await?.text()?;
+
+    serde_json::from_str(&resp_text).map_err(|_| BbcResponseError::FormatError)
+}
+
+// Pages of a list fetched at once
+const LIST_PAGE_CONCURRENCY: usize = 4;
+
+fn list_page_url(pagination: &Pagination, offset: usize, limit: usize) -> String {
+    format!(
+        "https://rms.api.bbc.co.uk{}",
+        pagination
+            .uri
+            .replace("{offset}", &offset.to_string())
+            .replace("{limit}", &limit.to_string())
+    )
+}
+
+/// Episodes `offset` to `offset + limit` of a paginated list, fetched a page (of the BBC's
+/// size) at a time
+pub async fn get_list_slice(
+    pagination: &Pagination,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<ContainerListData>> {
+    let end = (offset + limit).min(pagination.total);
+    let page_size = pagination.limit.max(1);
+    let pages = (offset..end).step_by(page_size).map(|start| async move {
+        let url = list_page_url(pagination, start, page_size.min(end - start));
+        let resp_text = get(url).await?.text()?;
+        let page: ContainerList =
+            serde_json::from_str(&resp_text).map_err(|_| BbcResponseError::FormatError)?;
+        Ok::<_, BbcResponseError>(page.data)
+    });
+    let pages: Vec<_> = futures::stream::iter(pages)
+        .buffered(LIST_PAGE_CONCURRENCY)
+        .try_collect()
+        .await?;
+    Ok(pages.into_iter().flatten().collect())
+}
+
+/// The rest of a paginated list from `offset`, a page (of the BBC's size) at a time, in order
+pub fn list_pages(
+    pagination: Pagination,
+    offset: usize,
+) -> impl Stream<Item = Result<Vec<ContainerListData>>> {
+    let page_size = pagination.limit.max(1);
+    let starts = (offset..pagination.total).step_by(page_size);
+    futures::stream::iter(starts)
+        .map(move |start| {
+            let pagination = pagination.clone();
+            async move { get_list_slice(&pagination, start, page_size).await }
+        })
+        .buffered(LIST_PAGE_CONCURRENCY)
+}
+
+/// Lists the programmes in a BBC Sounds category, e.g. `drama`
+pub async fn get_category(category: &str) -> Result<Vec<CategoryProgramme>> {
+    let urn = format!("urn:bbc:radio:category:{}", category);
+
+    let resp_text = get(container_url(&urn)).await?.text()?;
+
+    let resp: CategoryResponse =
+        serde_json::from_str(&resp_text).map_err(|_| BbcResponseError::FormatError)?;
+
+    // Modules list episodes as well as programmes, so keep only the programmes
+    Ok(resp
+        .data
+        .into_iter()
+        .filter_map(|m| match m.data {
+            serde_json::Value::Array(items) => Some(items),
+            _ => None,
+        })
+        .flatten()
+        .filter(|item| item["type"] == "container_item")
+        .filter_map(|item| serde_json::from_value(item).ok())
+        .collect())
+}
+
+pub fn media_selector_url(pid: &Pid) -> String {
+    let encoded_pid = utf8_percent_encode(pid.as_str(), NON_ALPHANUMERIC).to_string();
+    format!("https://open.live.bbc.co.uk/mediaselector/6/select/version/2.0/format/json/mediaset/mobile-phone-main/vpid/{}/transferformat/hls/", 
+        encoded_pid)
+}
+
+/// What the media selector says instead of listing media, when there's none to give
+#[derive(Deserialize)]
+struct MediaSelectorError {
+    result: String,
+}
+
+pub async fn get_media(pid: &Pid) -> Result<MediaList> {
+    let resp_text = get(media_selector_url(pid)).await?.text()?;
+
+    serde_json::from_str::<MediaList>(&resp_text).map_err(|_| {
+        match serde_json::from_str::<MediaSelectorError>(&resp_text) {
+            Ok(e) => BbcResponseError::Unavailable(e.result),
+            Err(_) => BbcResponseError::FormatError,
+        }
+    })
+}
+
+pub async fn get_segments(pid: &Pid) -> Result<SegmentList> {
+    let encoded_pid = utf8_percent_encode(pid.as_str(), NON_ALPHANUMERIC).to_string();
+    let uri = format!(
+        "https://rms.api.bbc.co.uk/v2/versions/{}/segments",
+        encoded_pid
     );
 
     let resp_text = get(uri).await?.text()?;
 
-    let resp: ContainerResponse =
+    let resp: SegmentList =
         serde_json::from_str(&resp_text).map_err(|_| BbcResponseError::FormatError)?;
 
     Ok(resp)
 }
 
-pub async fn get_media(pid: &str) -> Result<MediaList> {
-    let encoded_pid = utf8_percent_encode(pid, NON_ALPHANUMERIC).to_string();
-    let uri = format!("https://open.live.bbc.co.uk/mediaselector/6/select/version/2.0/format/json/mediaset/mobile-phone-main/vpid/{}/transferformat/hls/", 
-        encoded_pid);
+/// An episode (or its version) and what it's part of, from bbc.co.uk/programmes
+pub async fn get_programme(pid: &Pid) -> Result<ProgrammeInfo> {
+    let encoded_pid = utf8_percent_encode(pid.as_str(), NON_ALPHANUMERIC).to_string();
+    let uri = format!("https://www.bbc.co.uk/programmes/{}.json", encoded_pid);
 
     let resp_text = get(uri).await?.text()?;
 
-    let resp: MediaList =
+    let resp: ProgrammeResponse =
         serde_json::from_str(&resp_text).map_err(|_| BbcResponseError::FormatError)?;
 
-    Ok(resp)
+    Ok(resp.info())
 }
 
-pub async fn get_media_url(pid: &str) -> Result<Option<String>> {
-    let media_url = format!("https://open.live.bbc.co.uk/mediaselector/6/redir/version/2.0/mediaset/audio-nondrm-download/proto/https/vpid/{}.mp3", pid);
-    let resp = head(media_url.clone()).await?;
+fn media_url(pid: &Pid) -> String {
+    format!("https://open.live.bbc.co.uk/mediaselector/6/redir/version/2.0/mediaset/audio-nondrm-download/proto/https/vpid/{}.mp3", pid)
+}
+
+/// Returns the status of a HEAD request to the public mp3 redirector
+pub async fn get_media_url_status(pid: &Pid) -> Result<u16> {
+    Ok(head(media_url(pid)).await?)
+}
+
+pub async fn get_media_url(pid: &Pid) -> Result<Option<String>> {
+    let resp = get_media_url_status(pid).await?;
 
     if resp == 200 {
-        Ok(Some(media_url))
+        Ok(Some(media_url(pid)))
     } else {
         Ok(None)
     }
@@ -263,6 +841,104 @@ mod tests {
         println!("{:#?}", _example);
     }
 
+    #[tokio::test]
+    async fn test_deserialise_example_without_network() {
+        // not every programme has a network, so they're removed from a recorded response
+        let example_path = "./payload_examples/container.json";
+        let example_text = std::fs::read_to_string(example_path).unwrap();
+        let mut example: serde_json::Value = serde_json::from_str(&example_text).unwrap();
+        example["data"][0]["data"]
+            .as_object_mut()
+            .unwrap()
+            .remove("network");
+        for episode in example["data"][1]["data"].as_array_mut().unwrap() {
+            episode.as_object_mut().unwrap().remove("network");
+        }
+        let example: ContainerResponse = serde_json::from_value(example).unwrap();
+
+        let item = example.data.iter().find_map(|d| d.item()).unwrap();
+        assert!(item.data.network.is_none());
+    }
+
+    #[test]
+    fn test_pagination() {
+        let example_path = "./payload_examples/container.json";
+        let example_text = std::fs::read_to_string(example_path).unwrap();
+        let example: ContainerResponse = serde_json::from_str(&example_text).unwrap();
+
+        let list = example
+            .data
+            .into_iter()
+            .find_map(|d| d.into_list())
+            .unwrap();
+        assert!(list.is_partial());
+        let pagination = list.pagination.as_ref().unwrap();
+        assert_eq!((pagination.limit, pagination.total), (30, 109));
+        assert_eq!(
+            list_page_url(pagination, 90, 19),
+            "https://rms.api.bbc.co.uk/v2/programmes/playable?container=p02pc9pj\
+             &sort=sequential&type=episode&experience=domestic&offset=90&limit=19"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deserialise_nested_example() {
+        // a list holding a container as well as episodes, as a brand's lists its series,
+        // made from the container item at the top of a recorded response
+        let example_path = "./payload_examples/container.json";
+        let example_text = std::fs::read_to_string(example_path).unwrap();
+        let mut example: serde_json::Value = serde_json::from_str(&example_text).unwrap();
+        let container = example["data"][0]["data"].clone();
+        example["data"][1]["data"]
+            .as_array_mut()
+            .unwrap()
+            .insert(0, container);
+        let example: ContainerResponse = serde_json::from_value(example).unwrap();
+
+        let list = example
+            .data
+            .into_iter()
+            .find_map(|d| d.into_list())
+            .unwrap();
+        assert_eq!(
+            list.containers
+                .iter()
+                .map(|c| (c.id.as_str(), c.urn.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("p02pc9pj", Some("urn:bbc:radio:series:p02pc9pj"))]
+        );
+        assert_eq!(list.data.len(), 30);
+    }
+
+    #[test]
+    fn test_localised() {
+        // a Welsh translation added to an episode of a recorded response
+        let example_text = std::fs::read_to_string("./payload_examples/container.json").unwrap();
+        let mut example: serde_json::Value = serde_json::from_str(&example_text).unwrap();
+        example["data"][1]["data"][0]["translations"] = serde_json::json!([{
+            "language": "cy",
+            "titles": {"primary": "Rhaglen", "secondary": "Pennod"},
+            "synopses": {"short": "Byr", "medium": null, "long": null}
+        }]);
+        let example: ContainerResponse = serde_json::from_value(example).unwrap();
+        let list = example
+            .data
+            .into_iter()
+            .find_map(|d| d.into_list())
+            .unwrap();
+
+        let episode = &list.data[0];
+        let (titles, synopses) = episode.localised(Some("cy-GB"));
+        assert_eq!(titles.secondary.as_deref(), Some("Pennod"));
+        assert_eq!(synopses.short.as_deref(), Some("Byr"));
+        let (titles, _) = episode.localised(Some("en"));
+        assert_eq!(titles.secondary, episode.titles.secondary);
+        let (titles, _) = episode.localised(None);
+        assert_eq!(titles.secondary, episode.titles.secondary);
+        // episodes without translations keep the BBC's own
+        assert!(list.data[1].translations.is_empty());
+    }
+
     #[tokio::test]
     async fn test_deserialise_media() {
         let example_path = "./payload_examples/media.json";
@@ -274,10 +950,70 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_media() {
-        let id = "p0btf00q";
+        let id = "p0btf00q".parse().unwrap();
 
-        let _media = get_media(id).await.unwrap();
+        let _media = get_media(&id).await.unwrap();
 
         println!("{:#?}", _media);
     }
+
+    #[test]
+    fn test_ancestors() {
+        let version: ProgrammeResponse = serde_json::from_str(
+            r#"{"version": {"pid": "p0bzn8f1", "duration": 1675, "parent": {"programme": {
+                "type": "episode", "pid": "p0bzn7xm", "title": "Episode 1",
+                "first_broadcast_date": "2022-04-25T11:00:00+01:00", "parent": {"programme": {
+                    "type": "series", "pid": "p02pc9pj", "parent": null}}}}}}"#,
+        )
+        .unwrap();
+        let info = version.info();
+        assert_eq!(
+            info.ancestors,
+            vec!["p0bzn7xm".parse().unwrap(), "p02pc9pj".parse().unwrap()]
+        );
+        assert_eq!(info.title.as_deref(), Some("Episode 1"));
+        assert_eq!(
+            info.first_broadcast.map(|d| d.to_rfc3339()),
+            Some("2022-04-25T11:00:00+01:00".to_string())
+        );
+
+        let brand: ProgrammeResponse =
+            serde_json::from_str(r#"{"programme": {"type": "brand", "pid": "b006qykl"}}"#).unwrap();
+        assert!(brand.ancestors().is_empty());
+    }
+
+    #[test]
+    fn test_programme_genres() {
+        // as bbc.co.uk/programmes lists a show's categories
+        let series: ProgrammeResponse = serde_json::from_str(
+            r#"{"programme": {"type": "series", "pid": "p02pc9pj", "categories": [
+                {"type": "genre", "id": "C00045", "key": "history", "title": "History",
+                    "narrower": [], "broader": {"category": {"type": "genre", "id": "C00035",
+                    "key": "factual", "title": "Factual", "broader": {}}}},
+                {"type": "format", "id": "PT003", "key": "discussionandtalk",
+                    "title": "Discussion & Talk", "narrower": [], "broader": {}},
+                {"type": "genre", "id": "C00079", "key": "drama", "title": "Drama",
+                    "narrower": [], "broader": {}}]}}"#,
+        )
+        .unwrap();
+        let genres = series
+            .info()
+            .genres
+            .into_iter()
+            .map(|g| g.id)
+            .collect::<Vec<_>>();
+        assert_eq!(genres, vec!["factual/history", "drama"]);
+    }
+
+    #[test]
+    fn test_parse_pid() {
+        assert!("p02pc9pj".parse::<Pid>().is_ok());
+        assert!("m001abcd".parse::<Pid>().is_ok());
+
+        assert!("x02pc9pj".parse::<Pid>().is_err());
+        assert!("P02PC9PJ".parse::<Pid>().is_err());
+        assert!("p02pc9p".parse::<Pid>().is_err());
+        assert!("p02pc9pj0".parse::<Pid>().is_err());
+        assert!("p02pc/pj".parse::<Pid>().is_err());
+    }
 }
@@ -0,0 +1,71 @@
+use serde::Serialize;
+
+use crate::{archive::html_escape, bbc, sounds_proxy};
+
+type Result<T, E = bbc::BbcResponseError> = core::result::Result<T, E>;
+
+/// A show in a category, with its feed through the proxy
+#[derive(Serialize)]
+pub struct BrowseShow {
+    pub pid: String,
+    pub title: String,
+    pub synopsis: Option<String>,
+    pub image: Option<String>,
+    pub feed_url: String,
+}
+
+fn valid_category(category: &str) -> bool {
+    !category.is_empty()
+        && category
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Lists the shows in a BBC Sounds category, e.g. `drama` or `news`
+pub async fn get_category(base_url: &str, category: &str) -> Result<Vec<BrowseShow>> {
+    if !valid_category(category) {
+        return Err(bbc::BbcResponseError::BadRequest);
+    }
+
+    let programmes = bbc::get_category(category).await?;
+
+    Ok(programmes
+        .into_iter()
+        // feeds are only available for series
+        .filter(|p| p.urn.starts_with("urn:bbc:radio:series:"))
+        .filter_map(|p| {
+            let pid: bbc::Pid = p.id.parse().ok()?;
+            Some(BrowseShow {
+                feed_url: format!("{}/show/{}", base_url, pid),
+                pid: pid.into(),
+                title: p.titles.primary,
+                synopsis: p.synopses.and_then(|s| s.short.or(s.medium)),
+                image: p.image_url.and_then(sounds_proxy::template_url),
+            })
+        })
+        .collect())
+}
+
+pub fn render_category(category: &str, shows: &[BrowseShow]) -> String {
+    let items = shows
+        .iter()
+        .map(|s| {
+            format!(
+                "<li><a href=\"{}\">{}</a>{}</li>",
+                html_escape(&s.feed_url),
+                html_escape(&s.title),
+                s.synopsis
+                    .as_ref()
+                    .map(|d| format!(" - {}", html_escape(d)))
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<p>Subscribe to a show by adding its link to your podcast app.</p>\n<ul>\n{1}\n</ul>\n</body>\n</html>\n",
+        html_escape(category),
+        items
+    )
+}
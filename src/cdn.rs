@@ -0,0 +1,151 @@
+use actix_web::{
+    body::{BodySize, MessageBody},
+    http::header::{HeaderName, HeaderValue},
+    HttpResponse,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::bbc::Pid;
+
+#[derive(Error, Debug)]
+pub enum CdnError {
+    #[error("Reqwest error: {0}")]
+    ReqwestError(#[from] reqwest::Error),
+}
+
+/// CDN whose cache is purged by tag when content changes
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CdnPurge {
+    /// API token needs the Cache Purge permission for the zone
+    Cloudflare { zone_id: String, api_token: String },
+    /// API token needs the purge_select scope for the service
+    Fastly {
+        service_id: String,
+        api_token: String,
+    },
+}
+
+/// Tag of a show's feed and playlist responses
+pub fn show_tag(programme_id: &Pid) -> String {
+    format!("show-{}", programme_id)
+}
+
+/// Tag of an episode's responses
+pub fn episode_tag(episode_id: &Pid) -> String {
+    format!("episode-{}", episode_id)
+}
+
+/// Tags of an episode's responses, including its show's tag if it's known
+pub fn episode_tags(episode_id: &Pid, show: Option<&Pid>) -> Vec<String> {
+    std::iter::once(episode_tag(episode_id))
+        .chain(show.map(show_tag))
+        .collect()
+}
+
+/// Lets a CDN cache a successful response for `max_age` seconds (Fastly reads
+/// `Surrogate-Control`, most others `CDN-Cache-Control`), tagged so it can be
+/// purged. Does nothing unless a max age is configured. Streamed bodies are left alone,
+/// as a stream cut short would be cached as if it were the whole episode.
+pub fn with_headers(
+    max_age: Option<u64>,
+    tags: &[String],
+    mut response: HttpResponse,
+) -> HttpResponse {
+    let max_age = match max_age {
+        Some(max_age) => max_age,
+        None => return response,
+    };
+    let status = response.status();
+    let streamed = matches!(response.body().size(), BodySize::Stream);
+    if !(status.is_success() || status.is_redirection()) || streamed {
+        return response;
+    }
+
+    let control = format!("max-age={}", max_age);
+    let headers = [
+        ("surrogate-control", control.clone()),
+        ("cdn-cache-control", control),
+        // Fastly separates tags with spaces, Cloudflare with commas
+        ("surrogate-key", tags.join(" ")),
+        ("cache-tag", tags.join(",")),
+    ];
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+    response
+}
+
+/// Purges everything cached with any of the tags
+pub async fn purge(cdn: &CdnPurge, tags: &[String]) -> Result<(), CdnError> {
+    let client = reqwest::Client::new();
+    match cdn {
+        CdnPurge::Cloudflare { zone_id, api_token } => {
+            client
+                .post(format!(
+                    "https://api.cloudflare.com/client/v4/zones/{}/purge_cache",
+                    zone_id
+                ))
+                .bearer_auth(api_token)
+                .json(&serde_json::json!({ "tags": tags }))
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        CdnPurge::Fastly {
+            service_id,
+            api_token,
+        } => {
+            client
+                .post(format!(
+                    "https://api.fastly.com/service/{}/purge",
+                    service_id
+                ))
+                .header("Fastly-Key", api_token)
+                .header("Surrogate-Key", tags.join(" "))
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+    }
+    log::info!("Purged {} from the CDN", tags.join(", "));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_with_headers() {
+        let tags = episode_tags(
+            &"m0015ryz".parse().unwrap(),
+            Some(&"p02pc9pj".parse().unwrap()),
+        );
+
+        let response = with_headers(Some(60), &tags, HttpResponse::Ok().finish());
+        let header = |name| response.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("surrogate-control"), "max-age=60");
+        assert_eq!(header("cdn-cache-control"), "max-age=60");
+        assert_eq!(header("surrogate-key"), "episode-m0015ryz show-p02pc9pj");
+        assert_eq!(header("cache-tag"), "episode-m0015ryz,show-p02pc9pj");
+
+        let response = with_headers(Some(60), &tags, HttpResponse::NotFound().finish());
+        assert!(response.headers().get("cdn-cache-control").is_none());
+
+        let response = with_headers(None, &tags, HttpResponse::Ok().finish());
+        assert!(response.headers().get("cdn-cache-control").is_none());
+
+        let stream = futures::stream::iter(vec![Ok::<_, std::io::Error>(
+            actix_web::web::Bytes::from_static(b"audio"),
+        )]);
+        let response = with_headers(Some(60), &tags, HttpResponse::Ok().streaming(stream));
+        assert!(response.headers().get("cdn-cache-control").is_none());
+    }
+}
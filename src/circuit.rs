@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Failures in a row from a host before requests to it are stopped
+const TRIP_AFTER: u32 = 5;
+
+// How long requests are stopped for before one is let through to try again
+const COOL_DOWN: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct HostState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops requests to a host that keeps failing, so an upstream outage gets a
+/// quick error rather than every request retrying against it.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl CircuitBreaker {
+    /// Returns how long until the host may be tried again, if it's tripped.
+    /// Once the cool down ends, one request is let through to test the host.
+    pub fn check(&self, host: &str, now: Instant) -> Result<(), Duration> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = match hosts.get_mut(host) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        match state.open_until {
+            Some(open_until) if open_until > now => Err(open_until - now),
+            Some(_) => {
+                state.open_until = Some(now + COOL_DOWN);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(state) = hosts.remove(host) {
+            if state.open_until.is_some() {
+                log::info!("{} is responding again", host);
+            }
+        }
+    }
+
+    /// Hosts with failures since their last success, and whether requests to them are stopped
+    pub fn failing(&self, now: Instant) -> Vec<(String, bool)> {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .iter()
+            .map(|(host, state)| {
+                let open = state
+                    .open_until
+                    .map_or(false, |open_until| open_until > now);
+                (host.clone(), open)
+            })
+            .collect()
+    }
+
+    pub fn record_failure(&self, host: &str, now: Instant) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.failures += 1;
+        if state.failures >= TRIP_AFTER {
+            if state.open_until.is_none() {
+                log::warn!(
+                    "{} failed {} times in a row, pausing requests to it",
+                    host,
+                    state.failures
+                );
+            }
+            state.open_until = Some(now + COOL_DOWN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_trip_and_recover() {
+        let breaker = CircuitBreaker::default();
+        let now = Instant::now();
+
+        for _ in 0..TRIP_AFTER - 1 {
+            breaker.record_failure("a", now);
+        }
+        assert!(breaker.check("a", now).is_ok());
+
+        breaker.record_failure("a", now);
+        assert_eq!(breaker.check("a", now), Err(COOL_DOWN));
+        assert!(breaker.check("b", now).is_ok());
+
+        // one request is let through after the cool down
+        let later = now + COOL_DOWN;
+        assert!(breaker.check("a", later).is_ok());
+        assert!(breaker.check("a", later).is_err());
+
+        // and trips it again straight away if it fails
+        breaker.record_failure("a", later);
+        assert_eq!(breaker.check("a", later), Err(COOL_DOWN));
+
+        breaker.record_success("a");
+        assert!(breaker.check("a", later).is_ok());
+    }
+}
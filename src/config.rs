@@ -0,0 +1,638 @@
+use std::collections::{HashMap, HashSet};
+
+use figment::{providers::Env, Figment};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission::{self, Admission},
+    bbc::Pid,
+    cdn::CdnPurge,
+    health::Subsystem,
+    notify::NotifierConfig,
+    s3,
+    sounds_proxy::{self, EpisodeOrder, Funding, MediaVariant, ResolutionStrategy},
+    state::token_hash,
+    tee,
+    throttle::UploadWindow,
+    transcode::{AudioFormat, FfmpegOptions},
+    tuning::RESOURCES,
+};
+
+/// How clients are redirected to public episodes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublicRedirect {
+    /// 302, clients come back to the proxy each time
+    Temporary,
+    /// 308, clients may cache the episode's URL indefinitely
+    Permanent,
+}
+
+/// Settings which can be overridden for an individual show
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ShowConfig {
+    pub resolution: Option<Vec<ResolutionStrategy>>,
+    pub audio_format: Option<AudioFormat>,
+    /// Versions of the audio which may be served, most preferred first
+    pub media_variants: Option<Vec<MediaVariant>>,
+    /// Bandwidth of the HLS variant to stream, in bits per second, rather than the highest
+    pub hls_bandwidth: Option<u64>,
+    pub enclosure_prefix: Option<String>,
+    pub language: Option<String>,
+    pub delay_hours: Option<u64>,
+    pub max_age_days: Option<u64>,
+    pub max_items: Option<usize>,
+    pub order: Option<EpisodeOrder>,
+    pub funding: Option<Vec<Funding>>,
+    pub split_parts: Option<bool>,
+    pub upcoming: Option<bool>,
+    pub nested_depth: Option<usize>,
+    pub tracklist: Option<bool>,
+    pub credits: Option<bool>,
+    pub backfill: Option<bool>,
+    pub chapters: Option<bool>,
+    pub s3_admission: Option<Admission>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Config {
+    pub base_url: Option<String>,
+    pub listen_port: Option<u16>,
+    pub s3_bucket: Option<String>,
+    pub s3_base_url: Option<String>,
+    pub s3_endpoint_url: Option<String>,
+    pub s3_async_upload: Option<bool>,
+    pub s3_sse: Option<s3::Sse>,
+    /// Only used with `aws:kms`
+    pub s3_sse_kms_key_id: Option<String>,
+    /// Also keep a low quality rendition of each episode in S3, in this format
+    pub s3_low_quality: Option<AudioFormat>,
+    /// Which private episodes are kept in S3, rather than streamed to each listener
+    pub s3_admission: Option<Admission>,
+    /// How many days apart requests may be for `repeat` admission
+    pub s3_admission_days: Option<u64>,
+    /// Most kilobits per second each upload to S3 may use
+    pub upload_bandwidth_kbps: Option<u64>,
+    /// Time of day queued uploads run in, e.g. `02:00-06:00`
+    pub upload_window: Option<UploadWindow>,
+    pub chapters: Option<bool>,
+    pub subscriptions: Option<Vec<Pid>>,
+    /// Shows listed at `/opml`, for importing them all into a podcast app
+    pub opml_shows: Option<Vec<Pid>>,
+    pub export_interval_hours: Option<u64>,
+    pub resolution: Option<Vec<ResolutionStrategy>>,
+    /// Check public file URLs exist before using them, falling back to lower qualities
+    pub validate_file_urls: Option<bool>,
+    pub shows: Option<HashMap<String, ShowConfig>>,
+    /// The only shows which may be proxied, if set
+    pub allowed_shows: Option<Vec<Pid>>,
+    /// Shows which may not be proxied
+    pub blocked_shows: Option<Vec<Pid>>,
+    pub admin_token: Option<String>,
+    /// Keys listeners' players sync playback positions with, sent as bearer tokens
+    pub api_keys: Option<Vec<String>>,
+    /// File to keep playback positions in, so they outlast restarts
+    pub progress_file: Option<String>,
+    /// File to keep resolution counts and bytes served in, so they outlast restarts
+    pub stats_file: Option<String>,
+    pub grpc_port: Option<u16>,
+    pub default_author: Option<String>,
+    /// Email address feeds are locked to, so they can't be imported to directories by anyone else
+    pub owner_email: Option<String>,
+    /// Links for listeners to support the shows or the proxy, as `podcast:funding`
+    pub funding: Option<Vec<Funding>>,
+    /// List each part of omnibus episodes as its own episode, cut at their chapters
+    pub split_parts: Option<bool>,
+    /// List episodes which aren't available yet, dated when they will be, rather than leaving them out
+    pub upcoming: Option<bool>,
+    /// How many levels of nested containers (e.g. a brand's series) are merged into a show's feed
+    pub nested_depth: Option<usize>,
+    /// Append the music played in each episode (composer, work and performers) to its description
+    pub tracklist: Option<bool>,
+    /// Credit each episode's presenters and guests in its description and as `podcast:person`
+    pub credits: Option<bool>,
+    /// Fetch every page of each show's BBC listing, so feeds have its whole back catalogue
+    pub backfill: Option<bool>,
+    /// Base64 Ed25519 key (a 32 byte seed) to sign feeds with, e.g. from `openssl rand -base64 32`
+    pub signing_key: Option<String>,
+    /// Token proving ownership of the feeds to directories, in the feed and at `/.well-known/podcast-verification`
+    pub verification_token: Option<String>,
+    pub audio_format: Option<AudioFormat>,
+    pub media_variants: Option<Vec<MediaVariant>>,
+    /// Bandwidth of the HLS variant to stream, in bits per second, rather than the highest
+    pub hls_bandwidth: Option<u64>,
+    /// CDNs to stream from before others, matched against each connection's supplier
+    pub preferred_suppliers: Option<Vec<String>>,
+    pub enclosure_prefix: Option<String>,
+    /// Language of feeds, e.g. `cy` for Welsh language shows
+    pub language: Option<String>,
+    /// Hide episodes from feeds until this many hours after publication
+    pub delay_hours: Option<u64>,
+    /// Leave episodes older than this out of feeds
+    pub max_age_days: Option<u64>,
+    /// Most episodes in a feed
+    pub max_items: Option<usize>,
+    /// Order of feed items, `desc` (newest first) or `asc` (oldest first)
+    pub order: Option<EpisodeOrder>,
+    /// Most episodes on each page of a feed
+    pub feed_page_size: Option<usize>,
+    /// Read each page of a feed from its slice of the BBC's listing
+    pub feed_page_slices: Option<bool>,
+    /// Use defaults suited to small (e.g. 256 MB) containers
+    pub low_memory: Option<bool>,
+    pub workers: Option<usize>,
+    /// How long running streams may take to finish when stopping or reloading
+    pub shutdown_timeout_secs: Option<u64>,
+    pub max_streams: Option<usize>,
+    /// Parts of each S3 upload sent at once
+    pub s3_part_concurrency: Option<usize>,
+    pub memory_limit_mb: Option<u64>,
+    /// Most memory used by stream buffers across all streams and uploads
+    pub buffer_limit_mb: Option<u64>,
+    /// Memory used to cache recently fetched HLS segments, 0 to not cache them
+    pub segment_cache_mb: Option<u64>,
+    /// Threads each ffmpeg decoder and encoder may use
+    pub ffmpeg_threads: Option<usize>,
+    /// Only ever remux audio, never decode it
+    pub ffmpeg_copy_only: Option<bool>,
+    /// Niceness of the threads running ffmpeg, from -20 to 19
+    pub ffmpeg_nice: Option<i32>,
+    /// Best effort IO priority of the threads running ffmpeg, from 0 to 7
+    pub ffmpeg_ionice: Option<u8>,
+    /// Fingerprint transcoded episodes, and leave out ones with the same audio from feeds
+    pub dedupe_audio: Option<bool>,
+    /// File to keep audio fingerprints in, so they outlast restarts
+    pub fingerprint_file: Option<String>,
+    /// `Alt-Svc` header value advertising HTTP/3 on a proxy in front, e.g. `h3=":443"; ma=86400`
+    pub alt_svc: Option<String>,
+    /// Origins allowed to embed the HTML pages in frames, e.g. `https://example.com`
+    pub frame_ancestors: Option<Vec<String>>,
+    pub public_redirect: Option<PublicRedirect>,
+    /// Link feeds to episode URLs which are never cached
+    pub revalidate_episode_links: Option<bool>,
+    /// Add a hash of how episodes are resolved to proxied episode URLs
+    pub bust_redirect_caches: Option<bool>,
+    /// Format of feed item GUIDs, e.g. `urn:bbc:pid:{pid}`
+    pub guid_format: Option<String>,
+    pub notifiers: Option<Vec<NotifierConfig>>,
+    /// How often subscribed shows are checked for new episodes to notify of
+    pub notify_interval_minutes: Option<u64>,
+    /// Seconds a CDN in front may cache feeds and episode redirects for
+    pub cdn_max_age: Option<u64>,
+    /// CDN to purge when content changes
+    pub cdn_purge: Option<CdnPurge>,
+    /// Subsystems which make `/healthz` report the proxy as down when they're down
+    pub health_critical: Option<Vec<Subsystem>>,
+}
+
+// Defaults for the low memory profile
+const LOW_MEMORY_WORKERS: usize = 1;
+const LOW_MEMORY_MAX_STREAMS: usize = 4;
+const LOW_MEMORY_LIMIT_MB: u64 = 192;
+const LOW_MEMORY_SEGMENT_CACHE_MB: u64 = 8;
+const LOW_MEMORY_S3_PART_CONCURRENCY: usize = 1;
+
+const DEFAULT_SEGMENT_CACHE_MB: u64 = 32;
+
+/// Most each stream buffers: what listeners following an upload haven't read yet, and
+/// the S3 part being filled
+const STREAM_BUFFER_WINDOW: usize = tee::MAX_LAG_BYTES + s3::BUFFER_SIZE;
+
+/// Compares secrets in time which depends only on their lengths, so a guess can't be
+/// refined by timing how long it takes to be refused
+fn secret_eq(secret: &str, guess: &str) -> bool {
+    secret.len() == guess.len()
+        && secret
+            .bytes()
+            .zip(guess.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl Config {
+    /// Reads config from `SOUNDS_PROXY_` prefixed environment variables.
+    /// Nested keys (e.g. per-show settings) are separated by `__`.
+    pub fn from_env() -> Self {
+        let config: Self = Figment::new()
+            .merge(Env::prefixed("SOUNDS_PROXY_").split("__"))
+            .extract()
+            .map_err(|e| {
+                println!("{}", e);
+                println!(
+                    "Set config fields by prefixing environment variables with 'SOUNDS_PROXY_'"
+                );
+                e
+            })
+            .unwrap();
+        if let Err(e) = config.check() {
+            println!("{}", e);
+            panic!("Invalid config");
+        }
+        config
+    }
+
+    /// Checks settings which only make sense together
+    fn check(&self) -> Result<(), String> {
+        if self.s3_sse_kms_key_id.is_some() && self.s3_sse != Some(s3::Sse::AwsKms) {
+            return Err("S3_SSE_KMS_KEY_ID needs S3_SSE set to aws:kms".to_string());
+        }
+        Ok(())
+    }
+
+    /// Shows named anywhere in the config: subscribed to, allowed or given settings
+    pub fn configured_shows(&self) -> HashSet<String> {
+        let listed = self
+            .subscriptions
+            .iter()
+            .chain(&self.allowed_shows)
+            .flatten()
+            .map(|pid| pid.to_string());
+        let with_settings = self.shows.iter().flat_map(|shows| shows.keys().cloned());
+        listed.chain(with_settings).collect()
+    }
+
+    pub fn show(&self, programme_id: &Pid) -> Option<&ShowConfig> {
+        self.shows.as_ref()?.get(programme_id.as_str())
+    }
+
+    pub fn resolution(&self, programme_id: Option<&Pid>) -> Vec<ResolutionStrategy> {
+        programme_id
+            .and_then(|id| self.show(id))
+            .and_then(|s| s.resolution.clone())
+            .or_else(|| self.resolution.clone())
+            .unwrap_or_else(|| sounds_proxy::DEFAULT_RESOLUTION.to_vec())
+    }
+
+    /// The value, if the low memory profile is enabled
+    fn low_memory_default<T>(&self, value: T) -> Option<T> {
+        if self.low_memory.unwrap_or(false) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// HTTP worker threads, by default one per CPU there's memory for
+    pub fn workers(&self) -> usize {
+        self.workers
+            .or_else(|| self.low_memory_default(LOW_MEMORY_WORKERS))
+            .unwrap_or_else(|| RESOURCES.workers())
+    }
+
+    /// Most episodes streamed or uploaded at once, by default as many as the CPUs and
+    /// memory can run
+    pub fn max_streams(&self) -> usize {
+        self.max_streams
+            .or_else(|| self.low_memory_default(LOW_MEMORY_MAX_STREAMS))
+            .unwrap_or_else(|| RESOURCES.max_streams())
+    }
+
+    /// Parts of each S3 upload sent at once
+    pub fn s3_part_concurrency(&self) -> usize {
+        self.s3_part_concurrency
+            .or_else(|| self.low_memory_default(LOW_MEMORY_S3_PART_CONCURRENCY))
+            .unwrap_or_else(|| RESOURCES.s3_part_concurrency())
+            .max(1)
+    }
+
+    /// Memory use above which new streams are refused, or None for no limit
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.memory_limit_mb
+            .or_else(|| self.low_memory_default(LOW_MEMORY_LIMIT_MB))
+            .map(|mb| mb * 1024 * 1024)
+    }
+
+    /// Bytes stream buffers and the segment cache may hold in total, or None for no
+    /// limit. The low memory profile allows each stream its window as well as the
+    /// cache, so streams aren't refused while it's within its limits.
+    pub fn buffer_limit(&self) -> Option<usize> {
+        self.buffer_limit_mb
+            .map(|mb| mb as usize * 1024 * 1024)
+            .or_else(|| {
+                self.low_memory_default(
+                    self.max_streams() * STREAM_BUFFER_WINDOW + self.segment_cache_size(),
+                )
+            })
+    }
+
+    /// Bytes recently fetched HLS segments may hold, at most a quarter of the memory
+    /// limit so the cache can't be what makes streams get refused
+    pub fn segment_cache_size(&self) -> usize {
+        let mb = self
+            .segment_cache_mb
+            .or_else(|| self.low_memory_default(LOW_MEMORY_SEGMENT_CACHE_MB))
+            .unwrap_or(DEFAULT_SEGMENT_CACHE_MB);
+        let size = mb as usize * 1024 * 1024;
+        self.memory_limit()
+            .map_or(size, |limit| size.min(limit as usize / 4))
+    }
+
+    pub fn ffmpeg_options(&self) -> FfmpegOptions {
+        FfmpegOptions {
+            threads: self.ffmpeg_threads,
+            copy_only: self.ffmpeg_copy_only.unwrap_or(false),
+            nice: self.ffmpeg_nice,
+            io_priority: self.ffmpeg_ionice,
+            fingerprint: self.dedupe_audio.unwrap_or(false),
+        }
+    }
+
+    /// Bytes per second each upload to S3 may use, or None for no limit
+    pub fn upload_bytes_per_sec(&self) -> Option<u64> {
+        self.upload_bandwidth_kbps.map(|kbps| kbps * 1000 / 8)
+    }
+
+    pub fn public_redirect(&self) -> PublicRedirect {
+        self.public_redirect.unwrap_or(PublicRedirect::Temporary)
+    }
+
+    /// Format to re-encode episodes to, if any
+    pub fn audio_format(&self, programme_id: Option<&Pid>) -> Option<AudioFormat> {
+        programme_id
+            .and_then(|id| self.show(id))
+            .and_then(|s| s.audio_format)
+            .or(self.audio_format)
+    }
+
+    /// Versions of an episode's audio which may be served, most preferred first
+    pub fn media_variants(&self, programme_id: Option<&Pid>) -> Vec<MediaVariant> {
+        programme_id
+            .and_then(|id| self.show(id))
+            .and_then(|s| s.media_variants.clone())
+            .or_else(|| self.media_variants.clone())
+            .unwrap_or_else(|| sounds_proxy::DEFAULT_MEDIA_VARIANTS.to_vec())
+    }
+
+    /// Tells apart uploads of a show's episodes when the show's audio settings differ from
+    /// the defaults, so each is kept under its own S3 key. None for the default settings.
+    pub fn rendition(&self, programme_id: Option<&Pid>) -> Option<String> {
+        let settings = |id| {
+            (
+                self.audio_format(id),
+                self.media_variants(id),
+                self.hls_bandwidth(id),
+            )
+        };
+        let show_settings = settings(programme_id);
+        if show_settings == settings(None) {
+            return None;
+        }
+        let settings = serde_json::to_string(&show_settings).ok()?;
+        Some(token_hash(&settings)[..8].to_string())
+    }
+
+    /// Pinned HLS variant bandwidth, if the highest isn't wanted
+    pub fn hls_bandwidth(&self, programme_id: Option<&Pid>) -> Option<u64> {
+        programme_id
+            .and_then(|id| self.show(id))
+            .and_then(|s| s.hls_bandwidth)
+            .or(self.hls_bandwidth)
+    }
+
+    /// Which private episodes of the show are kept in S3
+    pub fn s3_admission(&self, programme_id: Option<&Pid>) -> Admission {
+        programme_id
+            .and_then(|id| self.show(id))
+            .and_then(|s| s.s3_admission)
+            .or(self.s3_admission)
+            .unwrap_or(Admission::Always)
+    }
+
+    /// How far apart requests for an episode may be to admit it to S3
+    pub fn s3_admission_window(&self) -> chrono::Duration {
+        let days = self
+            .s3_admission_days
+            .unwrap_or(admission::DEFAULT_WINDOW_DAYS);
+        chrono::Duration::days(days as i64)
+    }
+
+    pub fn feed_options(&self, programme_id: &Pid) -> sounds_proxy::FeedOptions {
+        sounds_proxy::FeedOptions {
+            chapters: self
+                .show(programme_id)
+                .and_then(|s| s.chapters)
+                .or(self.chapters)
+                .unwrap_or(false),
+            resolution: self.resolution(Some(programme_id)),
+            // so episodes can be checked against the allow and deny lists
+            link_show: self.show(programme_id).is_some()
+                || self.allowed_shows.is_some()
+                || self.blocked_shows.is_some(),
+            default_author: self
+                .default_author
+                .clone()
+                .unwrap_or_else(|| sounds_proxy::DEFAULT_AUTHOR.to_string()),
+            revalidate_links: self.revalidate_episode_links.unwrap_or(false),
+            guid_format: self
+                .guid_format
+                .clone()
+                .unwrap_or_else(|| sounds_proxy::DEFAULT_GUID_FORMAT.to_string()),
+            delay_hours: self
+                .show(programme_id)
+                .and_then(|s| s.delay_hours)
+                .or(self.delay_hours),
+            max_age_days: self
+                .show(programme_id)
+                .and_then(|s| s.max_age_days)
+                .or(self.max_age_days),
+            max_items: self
+                .show(programme_id)
+                .and_then(|s| s.max_items)
+                .or(self.max_items),
+            order: self
+                .show(programme_id)
+                .and_then(|s| s.order)
+                .or(self.order)
+                .unwrap_or(EpisodeOrder::Desc),
+            page_size: self
+                .feed_page_size
+                .unwrap_or(sounds_proxy::DEFAULT_PAGE_SIZE),
+            page_slices: self.feed_page_slices.unwrap_or(false),
+            enclosure_prefix: self
+                .show(programme_id)
+                .and_then(|s| s.enclosure_prefix.clone())
+                .or_else(|| self.enclosure_prefix.clone()),
+            language: self
+                .show(programme_id)
+                .and_then(|s| s.language.clone())
+                .or_else(|| self.language.clone()),
+            validate_file_urls: self.validate_file_urls.unwrap_or(false),
+            owner_email: self.owner_email.clone(),
+            verification_token: self.verification_token.clone(),
+            funding: self
+                .show(programme_id)
+                .and_then(|s| s.funding.clone())
+                .or_else(|| self.funding.clone())
+                .unwrap_or_default(),
+            split_parts: self
+                .show(programme_id)
+                .and_then(|s| s.split_parts)
+                .or(self.split_parts)
+                .unwrap_or(false),
+            dedupe: self.dedupe_audio.unwrap_or(false),
+            upcoming: self
+                .show(programme_id)
+                .and_then(|s| s.upcoming)
+                .or(self.upcoming)
+                .unwrap_or(false),
+            nested_depth: self
+                .show(programme_id)
+                .and_then(|s| s.nested_depth)
+                .or(self.nested_depth)
+                .unwrap_or(sounds_proxy::DEFAULT_NESTED_DEPTH),
+            tracklist: self
+                .show(programme_id)
+                .and_then(|s| s.tracklist)
+                .or(self.tracklist)
+                .unwrap_or(false),
+            credits: self
+                .show(programme_id)
+                .and_then(|s| s.credits)
+                .or(self.credits)
+                .unwrap_or(false),
+            backfill: self
+                .show(programme_id)
+                .and_then(|s| s.backfill)
+                .or(self.backfill)
+                .unwrap_or(false),
+            cache_buster: if self.bust_redirect_caches.unwrap_or(false) {
+                Some(self.resolution_hash(programme_id))
+            } else {
+                None
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Short hash of how a show's episodes are resolved and redirected to, which changes
+    /// when that does
+    fn resolution_hash(&self, programme_id: &Pid) -> String {
+        // spelled out rather than left to Debug, which may change, as links carry the hash.
+        // The names are those first hashed, so links made then keep theirs.
+        let strategies = self
+            .resolution(Some(programme_id))
+            .iter()
+            .map(|strategy| match strategy {
+                ResolutionStrategy::FileUrl => "FileUrl",
+                ResolutionStrategy::Redirector => "Redirector",
+                ResolutionStrategy::Proxy => "Proxy",
+            })
+            .collect::<Vec<_>>();
+        let redirect = match self.public_redirect() {
+            PublicRedirect::Temporary => "Temporary",
+            PublicRedirect::Permanent => "Permanent",
+        };
+        let resolution = format!(
+            "[{}] {} {}",
+            strategies.join(", "),
+            redirect,
+            self.revalidate_episode_links.unwrap_or(false)
+        );
+        token_hash(&resolution)[..8].to_string()
+    }
+
+    /// True if shows are allowed or blocked, so episodes need their shows looked up
+    pub fn has_show_lists(&self) -> bool {
+        self.allowed_shows.is_some() || self.blocked_shows.is_some()
+    }
+
+    /// Whether a show, or an episode which is part of the given shows (e.g. its series and
+    /// brand), may be proxied. Any of them may be listed.
+    pub fn is_permitted(&self, pid: &Pid, parents: &[Pid]) -> bool {
+        let listed =
+            |list: &Vec<Pid>| list.contains(pid) || parents.iter().any(|p| list.contains(p));
+        let blocked = self.blocked_shows.as_ref().map_or(false, listed);
+        let allowed = self.allowed_shows.as_ref().map_or(true, listed);
+        allowed && !blocked
+    }
+
+    /// Checks a bearer token against the configured admin token.
+    /// Admin actions are disabled if no token is configured.
+    pub fn is_admin(&self, authorization: Option<&str>) -> bool {
+        match (&self.admin_token, authorization) {
+            (Some(token), Some(auth)) => auth
+                .strip_prefix("Bearer ")
+                .map_or(false, |guess| secret_eq(token, guess)),
+            _ => false,
+        }
+    }
+
+    /// The API key in a bearer token, if it's one of the configured keys
+    pub fn api_key<'a>(&self, authorization: Option<&'a str>) -> Option<&'a str> {
+        let key = authorization?.strip_prefix("Bearer ")?;
+        self.api_keys
+            .as_ref()
+            .filter(|keys| {
+                keys.iter()
+                    .fold(false, |found, k| found | secret_eq(k, key))
+            })
+            .map(|_| key)
+    }
+
+    pub fn s3_encryption(&self) -> s3::Encryption {
+        s3::Encryption {
+            algorithm: self.s3_sse,
+            kms_key_id: self.s3_sse_kms_key_id.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_resolution_hash() {
+        let config: Config = Figment::new().extract().unwrap();
+        let pid = "p02pc9pj".parse().unwrap();
+        // links already made with the default resolution carry this
+        assert_eq!(config.resolution_hash(&pid), "94f29684");
+
+        let config = Config {
+            resolution: Some(vec![ResolutionStrategy::Proxy]),
+            ..config
+        };
+        assert_ne!(config.resolution_hash(&pid), "94f29684");
+    }
+
+    #[test]
+    fn test_s3_sse() {
+        let config = |sse: &str, key: Option<&str>| {
+            let figment = Figment::new().merge(("s3_sse", sse));
+            let figment = match key {
+                Some(key) => figment.merge(("s3_sse_kms_key_id", key)),
+                None => figment,
+            };
+            figment.extract::<Config>().ok()
+        };
+        let aes = config("AES256", None).unwrap();
+        assert_eq!(aes.s3_sse, Some(s3::Sse::Aes256));
+        assert!(aes.check().is_ok());
+        assert!(config("aws:kms", Some("alias/episodes"))
+            .unwrap()
+            .check()
+            .is_ok());
+        assert!(config("AES256", Some("alias/episodes"))
+            .unwrap()
+            .check()
+            .is_err());
+        assert!(config("aes256", None).is_none());
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let config: Config = Figment::new().extract().unwrap();
+        assert!(!config.is_admin(Some("Bearer ")));
+
+        let config = Config {
+            admin_token: Some("s3cret".to_string()),
+            ..config
+        };
+        assert!(config.is_admin(Some("Bearer s3cret")));
+        assert!(!config.is_admin(Some("Bearer s3cre")));
+        assert!(!config.is_admin(Some("Bearer s3creT")));
+        assert!(!config.is_admin(Some("s3cret")));
+        assert!(!config.is_admin(None));
+    }
+}
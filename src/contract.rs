@@ -0,0 +1,249 @@
+use serde::Deserialize;
+
+use crate::{bbc, fetch};
+
+/// Which BBC payloads are recorded in `payload_examples`, and the programmes they came from
+pub const CONTRACT: &str = include_str!("../payload_examples/contracts.json");
+
+// Where payloads are recorded, from the repository's root
+const EXAMPLES_DIR: &str = "payload_examples";
+
+// Shape of programmes which aren't in the contract
+const UNRECORDED: &str = "unrecorded";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadKind {
+    /// RMS experience container, for a show and its episodes
+    Container,
+    /// Media selector list, for an episode's streams
+    Media,
+}
+
+/// Which kind of programme a container's URN names
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UrnKind {
+    Series,
+    Brand,
+}
+
+impl UrnKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            UrnKind::Series => "series",
+            UrnKind::Brand => "brand",
+        }
+    }
+}
+
+/// A programme to check or record, given as `[brand:|series:]pid`, a series if not said
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Programme {
+    pub urn: UrnKind,
+    pub pid: bbc::Pid,
+}
+
+impl std::str::FromStr for Programme {
+    type Err = bbc::BbcResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (urn, pid) = match s.split_once(':') {
+            Some(("series", pid)) => (UrnKind::Series, pid),
+            Some(("brand", pid)) => (UrnKind::Brand, pid),
+            Some(_) => return Err(bbc::BbcResponseError::BadRequest),
+            None => (UrnKind::Series, s),
+        };
+        Ok(Programme {
+            urn,
+            pid: pid.parse()?,
+        })
+    }
+}
+
+/// A recorded payload example
+#[derive(Clone, Debug, Deserialize)]
+pub struct Payload {
+    /// File in `payload_examples`
+    pub file: String,
+    pub kind: PayloadKind,
+    pub pid: bbc::Pid,
+    /// Kind of the programme a container was fetched for, by its URN
+    #[serde(default)]
+    pub urn: Option<UrnKind>,
+    /// What's particular about this payload, e.g. `brand`
+    pub shape: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Contract {
+    /// Bumped whenever the examples are re-recorded because the BBC's format changed
+    pub version: u32,
+    pub payloads: Vec<Payload>,
+}
+
+pub fn contract() -> Contract {
+    serde_json::from_str(CONTRACT).expect("payload_examples/contracts.json is invalid")
+}
+
+/// Checks a payload can be read the way the proxy reads it, describing where it can't
+pub fn validate(kind: PayloadKind, text: &str) -> Result<(), String> {
+    let result = match kind {
+        PayloadKind::Container => serde_json::from_str::<bbc::ContainerResponse>(text).map(|_| ()),
+        PayloadKind::Media => serde_json::from_str::<bbc::MediaList>(text).map(|_| ()),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+async fn fetch_live(payload: &Payload) -> Result<String, fetch::FetchError> {
+    let url = match payload.kind {
+        PayloadKind::Container => bbc::container_url(&format!(
+            "urn:bbc:radio:{}:{}",
+            payload.urn.unwrap_or(UrnKind::Series).as_str(),
+            payload.pid
+        )),
+        PayloadKind::Media => bbc::media_selector_url(&payload.pid),
+    };
+    fetch::get(url).await?.text()
+}
+
+/// The contract's payloads (or just those of `programmes`), and programmes that aren't in
+/// the contract as shows
+fn selected(contract: Contract, programmes: &[Programme]) -> Vec<Payload> {
+    let mut payloads = contract
+        .payloads
+        .into_iter()
+        .filter(|p| programmes.is_empty() || programmes.iter().any(|g| g.pid == p.pid))
+        .collect::<Vec<_>>();
+    for Programme { urn, pid } in programmes {
+        if !payloads.iter().any(|p| &p.pid == pid) {
+            payloads.push(Payload {
+                file: format!("container_{}.json", pid),
+                kind: PayloadKind::Container,
+                pid: pid.clone(),
+                urn: Some(*urn),
+                shape: UNRECORDED.to_string(),
+            });
+        }
+    }
+    payloads
+}
+
+/// Fetches the live payload for each programme in the contract (or just `programmes`, where
+/// those that aren't in the contract are checked as shows) and reports any which no
+/// longer match what the proxy expects. Fails if any don't.
+pub async fn check_live(programmes: &[Programme]) -> std::io::Result<()> {
+    let contract = contract();
+    println!(
+        "Checking live payloads against contract version {}",
+        contract.version
+    );
+    let mut drifted = 0;
+    for payload in selected(contract, programmes) {
+        let result = match fetch_live(&payload).await {
+            Ok(text) => validate(payload.kind, &text),
+            Err(e) => Err(format!("fetch failed: {}", e)),
+        };
+        let Payload {
+            file,
+            kind,
+            pid,
+            shape,
+            ..
+        } = payload;
+        match result {
+            Ok(()) => println!("ok     {:?} {} ({}, {})", kind, pid, shape, file),
+            Err(e) => {
+                drifted += 1;
+                println!("DRIFT  {:?} {} ({}, {}): {}", kind, pid, shape, file, e);
+            }
+        }
+    }
+
+    if drifted == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} payloads no longer match the contract", drifted),
+        ))
+    }
+}
+
+/// Records the live payload of each programme in the contract (or just `programmes`) over
+/// its example, and those of programmes that aren't in the contract as new examples, which
+/// then need adding to `contracts.json` with their URN kind and shape. Run from the
+/// repository's root.
+pub async fn record_live(programmes: &[Programme]) -> std::io::Result<()> {
+    for payload in selected(contract(), programmes) {
+        let text = fetch_live(&payload)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        // recorded anyway, so tests show what changed
+        if let Err(e) = validate(payload.kind, &text) {
+            println!("DRIFT  {:?} {}: {}", payload.kind, payload.pid, e);
+        }
+        std::fs::write(std::path::Path::new(EXAMPLES_DIR).join(&payload.file), text)?;
+        println!(
+            "recorded {:?} {} ({}) in {}",
+            payload.kind, payload.pid, payload.shape, payload.file
+        );
+        if payload.shape == UNRECORDED {
+            println!(
+                "  add {} to contracts.json with its URN kind and shape",
+                payload.file
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_payload_examples() {
+        let contract = contract();
+        assert!(!contract.payloads.is_empty());
+
+        for payload in contract.payloads {
+            let path = format!("./payload_examples/{}", payload.file);
+            let text = std::fs::read_to_string(&path).unwrap();
+            if let Err(e) = validate(payload.kind, &text) {
+                panic!("{} ({}) doesn't match: {}", payload.file, payload.shape, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_programme_urn() {
+        let pid: bbc::Pid = "b006qykl".parse().unwrap();
+        let programme = |urn| Programme {
+            urn,
+            pid: pid.clone(),
+        };
+        assert_eq!(
+            "b006qykl".parse::<Programme>().unwrap(),
+            programme(UrnKind::Series)
+        );
+        assert_eq!(
+            "series:b006qykl".parse::<Programme>().unwrap(),
+            programme(UrnKind::Series)
+        );
+        assert_eq!(
+            "brand:b006qykl".parse::<Programme>().unwrap(),
+            programme(UrnKind::Brand)
+        );
+        assert!("clip:b006qykl".parse::<Programme>().is_err());
+        assert!("brand:nope".parse::<Programme>().is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_drift() {
+        let text = r#"{"media": [{"kind": "audio", "type": "audio/mp4", "encoding": "aac", "connection": []}]}"#;
+        let error = validate(PayloadKind::Media, text).unwrap_err();
+        assert!(error.contains("missing field `bitrate`"), "{}", error);
+    }
+}
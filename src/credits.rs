@@ -0,0 +1,168 @@
+use itertools::Itertools;
+
+use crate::{
+    bbc::{BbcResponseError, Contribution, Pid, SegmentItem},
+    tracklist,
+};
+
+/// Roles of people leading an episode, in lower case
+const PRESENTER_ROLES: &[&str] = &["presenter", "co-presenter", "host", "chair"];
+/// Roles of people appearing on an episode, in lower case
+const GUEST_ROLES: &[&str] = &[
+    "guest",
+    "contributor",
+    "interviewee",
+    "panellist",
+    "panelist",
+];
+
+/// Someone credited on an episode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Person {
+    pub name: String,
+    /// Their pid, if the BBC has a page for them
+    pub id: Option<Pid>,
+}
+
+impl Person {
+    fn from_contribution(contribution: &Contribution) -> Self {
+        Person {
+            name: contribution.name.clone(),
+            id: contribution.id.as_ref().and_then(|id| id.parse().ok()),
+        }
+    }
+
+    /// Their page on bbc.co.uk
+    pub fn page(&self) -> Option<String> {
+        self.id
+            .as_ref()
+            .map(|id| format!("https://www.bbc.co.uk/programmes/{}", id))
+    }
+}
+
+/// Who presents an episode and who appears on it, in the order they're first credited
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credits {
+    pub presenters: Vec<Person>,
+    pub guests: Vec<Person>,
+}
+
+impl Credits {
+    /// The credits as lines of text, e.g. `Presented by Andy Zaltzman` then
+    /// `With Anand Menon, Ola Labib`
+    pub fn describe(&self) -> Option<String> {
+        let lines = [("Presented by", &self.presenters), ("With", &self.guests)]
+            .into_iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(label, people)| {
+                format!("{} {}", label, people.iter().map(|p| &p.name).join(", "))
+            })
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+fn has_role(roles: &[&str], role: Option<&str>) -> bool {
+    role.map_or(false, |role| {
+        roles.iter().any(|r| r.eq_ignore_ascii_case(role.trim()))
+    })
+}
+
+/// The people credited on an episode's segments. Music is left out, as whoever's credited
+/// on it performed or wrote it, and is in the tracklist.
+pub fn credits(segments: &[SegmentItem]) -> Credits {
+    let contributions = segments
+        .iter()
+        .filter(|s| s.segment_type.as_deref() != Some(tracklist::MUSIC_SEGMENT_TYPE))
+        .sorted_by_key(|s| s.offset.as_ref().map(|o| o.start))
+        .flat_map(|s| &s.contributions)
+        .collect::<Vec<_>>();
+    let presenters = contributions
+        .iter()
+        .filter(|c| has_role(PRESENTER_ROLES, c.role.as_deref()))
+        .unique_by(|c| &c.name)
+        .map(|c| Person::from_contribution(c))
+        .collect::<Vec<_>>();
+    let guests = contributions
+        .iter()
+        .filter(|c| has_role(GUEST_ROLES, c.role.as_deref()))
+        // a presenter may also be credited as contributing
+        .filter(|c| !presenters.iter().any(|p| p.name == c.name))
+        .unique_by(|c| &c.name)
+        .map(|c| Person::from_contribution(c))
+        .collect();
+    Credits { presenters, guests }
+}
+
+/// The episode's credits, from its segments
+pub async fn get_credits(episode_id: &Pid) -> Result<Credits, BbcResponseError> {
+    Ok(credits(&tracklist::get_segments(episode_id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::bbc::{Contribution, SegmentOffset, Titles};
+
+    use super::*;
+
+    fn segment(segment_type: &str, start: u64, contributions: &[(&str, &str)]) -> SegmentItem {
+        SegmentItem {
+            id: format!("p{:07}", start),
+            segment_type: Some(segment_type.to_string()),
+            titles: Titles {
+                primary: "Segment".to_string(),
+                secondary: None,
+            },
+            offset: Some(SegmentOffset { start, end: None }),
+            contributions: contributions
+                .iter()
+                .map(|(name, role)| Contribution {
+                    name: name.to_string(),
+                    role: Some(role.to_string()),
+                    id: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_credits() {
+        let segments = vec![
+            segment(
+                "speech",
+                600,
+                &[("Ola Labib", "Guest"), ("Andy Zaltzman", "Contributor")],
+            ),
+            segment(
+                "speech",
+                0,
+                &[("Andy Zaltzman", "Presenter"), ("Anand Menon", "Guest")],
+            ),
+            segment("music", 300, &[("Yo-Yo Ma", "Performer")]),
+            segment("speech", 900, &[("Anand Menon", "guest")]),
+        ];
+        let mut segments = segments;
+        segments[1].contributions[0].id = Some("p00b9f4b".to_string());
+        let credits = credits(&segments);
+        let names = |people: &[Person]| people.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&credits.presenters), ["Andy Zaltzman"]);
+        assert_eq!(names(&credits.guests), ["Anand Menon", "Ola Labib"]);
+        assert_eq!(
+            credits.presenters[0].page().unwrap(),
+            "https://www.bbc.co.uk/programmes/p00b9f4b"
+        );
+        assert_eq!(credits.guests[0].page(), None);
+        assert_eq!(
+            credits.describe().unwrap(),
+            "Presented by Andy Zaltzman\nWith Anand Menon, Ola Labib"
+        );
+
+        assert_eq!(super::credits(&segments[2..3]), Credits::default());
+        assert_eq!(Credits::default().describe(), None);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+
+use crate::{archive, bbc, config::Config, fetch, readiness::Readiness, s3, sounds_proxy};
+
+type Result<T, E = bbc::BbcResponseError> = core::result::Result<T, E>;
+
+/// Periodically renders subscribed feeds and uploads them to S3,
+/// so they can be served statically if the proxy is unavailable.
+pub struct FeedExporter {
+    pub s3_client: aws_sdk_s3::Client,
+    pub bucket: String,
+    pub base_url: String,
+    pub config: Config,
+    pub interval: Duration,
+    pub readiness: Arc<Readiness>,
+}
+
+impl FeedExporter {
+    async fn export_show(&self, programme_id: &bbc::Pid) -> Result<()> {
+        let mut options = self.config.feed_options(programme_id);
+        options.known_sizes = archive::get_archived_sizes(&self.s3_client, &self.bucket).await?;
+        let encryption = self.config.s3_encryption();
+
+        let feed =
+            sounds_proxy::get_podcast_feed(&self.base_url, programme_id, &options, 1).await?;
+
+        let feed_path = format!("feeds/{}.xml", programme_id);
+        log::debug!("Exporting feed to s3://{}/{}", self.bucket, feed_path);
+
+        s3::put_bytes(
+            &self.s3_client,
+            &self.bucket,
+            Bytes::from(feed),
+            &feed_path,
+            Some("application/rss+xml"),
+            "public, max-age=900",
+            &encryption,
+        )
+        .await?;
+
+        let (show, _) = sounds_proxy::get_show(&self.base_url, programme_id, &options).await?;
+
+        if let Some(image_url) = show.image {
+            let image = fetch::get(image_url).await?.into_bytes()?;
+            let image_path = format!("feeds/{}.jpg", programme_id);
+
+            s3::put_bytes(
+                &self.s3_client,
+                &self.bucket,
+                Bytes::from(image),
+                &image_path,
+                Some("image/jpeg"),
+                "public, max-age=604800",
+                &encryption,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn run(self) {
+        loop {
+            for programme_id in self.config.subscriptions.iter().flatten() {
+                if let Err(e) = self.export_show(programme_id).await {
+                    log::error!("Export of feed {} failed: {}", programme_id, e);
+                }
+            }
+            self.readiness.set_subscriptions_ready();
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::bbc::Pid;
+
+// Wait before retrying a failed episode, doubling after each further failure
+const RETRY_BASE_DELAY_MINS: i64 = 5;
+
+// Stop retrying automatically after this many failures in a row
+const QUARANTINE_AFTER: u32 = 5;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Failure {
+    pub pid: Pid,
+    /// Failures in a row
+    pub failures: u32,
+    pub last_error: String,
+    pub last_failure: DateTime<Utc>,
+    /// When the episode may be tried again, if it isn't quarantined
+    pub retry_at: Option<DateTime<Utc>>,
+    pub quarantined: bool,
+}
+
+/// Why an episode can't be tried now
+#[derive(Debug, PartialEq, Eq)]
+pub enum Blocked {
+    RetryAt(DateTime<Utc>),
+    Quarantined,
+}
+
+fn retry_at(failures: u32, last_failure: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if failures >= QUARANTINE_AFTER {
+        None
+    } else {
+        Some(last_failure + Duration::minutes(RETRY_BASE_DELAY_MINS << (failures - 1)))
+    }
+}
+
+/// Tracks episodes which failed to upload, so they're retried with backoff
+/// and eventually quarantined rather than retried on every request.
+#[derive(Default)]
+pub struct FailureTracker {
+    failures: Mutex<HashMap<Pid, Failure>>,
+}
+
+impl FailureTracker {
+    /// Returns why the episode shouldn't be tried yet, if it failed recently
+    pub fn check(&self, episode_id: &Pid, now: DateTime<Utc>) -> Option<Blocked> {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(episode_id)?.retry_at {
+            None => Some(Blocked::Quarantined),
+            Some(retry_at) if retry_at > now => Some(Blocked::RetryAt(retry_at)),
+            Some(_) => None,
+        }
+    }
+
+    /// Returns true if this failure quarantined the episode
+    pub fn record_failure(&self, episode_id: &Pid, error: String, now: DateTime<Utc>) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.get(episode_id).map_or(0, |f| f.failures) + 1;
+        let retry_at = retry_at(count, now);
+        failures.insert(
+            episode_id.clone(),
+            Failure {
+                pid: episode_id.clone(),
+                failures: count,
+                last_error: error,
+                last_failure: now,
+                retry_at,
+                quarantined: retry_at.is_none(),
+            },
+        );
+        count == QUARANTINE_AFTER
+    }
+
+    pub fn record_success(&self, episode_id: &Pid) {
+        self.failures.lock().unwrap().remove(episode_id);
+    }
+
+    pub fn list(&self) -> Vec<Failure> {
+        let mut failures = self
+            .failures
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        failures.sort_by_key(|f| f.last_failure);
+        failures
+    }
+
+    /// Adds failures carried over from elsewhere, keeping any more recent ones here
+    pub fn restore(&self, restored: Vec<Failure>) -> usize {
+        let mut failures = self.failures.lock().unwrap();
+        let mut count = 0;
+        for failure in restored {
+            let newer_here = failures
+                .get(&failure.pid)
+                .map_or(false, |f| f.last_failure >= failure.last_failure);
+            if !newer_here {
+                failures.insert(failure.pid.clone(), failure);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Forgets an episode's failures so it's tried on the next request.
+    /// Returns false if it had none.
+    pub fn clear(&self, episode_id: &Pid) -> bool {
+        self.failures.lock().unwrap().remove(episode_id).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_quarantine() {
+        let tracker = FailureTracker::default();
+        let pid: Pid = "p0btf00q".parse().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(tracker.check(&pid, now), None);
+
+        tracker.record_failure(&pid, "error".to_string(), now);
+        assert_eq!(
+            tracker.check(&pid, now),
+            Some(Blocked::RetryAt(now + Duration::minutes(5)))
+        );
+        assert_eq!(tracker.check(&pid, now + Duration::minutes(5)), None);
+
+        tracker.record_failure(&pid, "error".to_string(), now);
+        assert_eq!(
+            tracker.check(&pid, now),
+            Some(Blocked::RetryAt(now + Duration::minutes(10)))
+        );
+
+        for _ in 2..QUARANTINE_AFTER {
+            tracker.record_failure(&pid, "error".to_string(), now);
+        }
+        assert_eq!(
+            tracker.check(&pid, now + Duration::days(365)),
+            Some(Blocked::Quarantined)
+        );
+        assert!(tracker.list()[0].quarantined);
+
+        assert!(tracker.clear(&pid));
+        assert_eq!(tracker.check(&pid, now), None);
+        assert!(!tracker.clear(&pid));
+    }
+}
@@ -1,10 +1,70 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::{Lazy, OnceCell};
 use thiserror::Error;
 
+use crate::{circuit::CircuitBreaker, resolver::CachingResolver};
+
+static CIRCUIT_BREAKER: Lazy<CircuitBreaker> = Lazy::new(CircuitBreaker::default);
+
+/// Hosts which have asked for requests to stop (429), and until when.
+/// Shared by all requests, so one rate limited response pauses every caller.
+static RATE_LIMITS: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Mutex::default);
+
+// Back off for this long if a 429 doesn't say
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// Larger responses aren't cached. Media segments never are, as the segment cache has them.
+const MAX_CACHED_BYTES: usize = 1024 * 1024;
+const MAX_CACHE_ENTRIES: usize = 512;
+// Most the cached responses hold in total
+const MAX_CACHE_BYTES: usize = 16 * 1024 * 1024;
+
+struct CacheEntry {
+    bytes: Vec<u8>,
+    expires: Instant,
+}
+
+/// Responses to GET requests which the server allows to be cached, by URL.
+/// Expired entries are kept until space is needed, to be served while the host is failing.
+static CACHE: Lazy<Mutex<HashMap<String, CacheEntry>>> = Lazy::new(Mutex::default);
+
+static RESOLVER: Lazy<Arc<CachingResolver>> = Lazy::new(Arc::default);
+
+/// Where requests to the BBC go instead, for the load test's mock upstream
+static UPSTREAM: OnceCell<String> = OnceCell::new();
+
+thread_local! {
+    /// Reused so connections, DNS lookups and TLS sessions carry over between
+    /// requests, rather than being set up again for each HLS segment.
+    /// One per thread, as connections belong to the runtime which opened them.
+    static CLIENT: reqwest::Client = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .dns_resolver(RESOLVER.clone())
+        // rustls resumes TLS sessions with hosts it has connected to before
+        .use_rustls_tls()
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+        .unwrap();
+}
+
 #[derive(Error, Debug)]
 pub enum FetchError {
     #[error("server response code: {0}")]
     ResponseCode(u16),
 
+    #[error("{0} is failing, not retrying for {1} seconds")]
+    CircuitOpen(String, u64),
+
+    #[error("{0} is rate limiting requests, not retrying for {1} seconds")]
+    RateLimited(String, u64),
+
     #[error("Reqwest error: {0}")]
     ReqwestError(#[from] reqwest::Error),
 }
@@ -27,34 +87,297 @@ impl Response {
         self.status_error()?;
         Ok(String::from_utf8(self.bytes.clone()).unwrap())
     }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>, FetchError> {
+        self.status_error()?;
+        Ok(self.bytes)
+    }
 }
 
 const USER_AGENT: &str =
     "BBCSounds/2.6.0.14059 (iPhone13,3; iOS 15.3.1) MediaSelectorClient/7.0.4 BBCHTTPClient/9.0.0";
 
+/// Sends requests for BBC hosts to `base_url` instead, as `{base_url}/{host}/{path}`
+pub fn set_upstream(base_url: String) {
+    let _ = UPSTREAM.set(base_url);
+}
+
+fn upstream(uri: String) -> String {
+    let base_url = match UPSTREAM.get() {
+        Some(base_url) => base_url,
+        None => return uri,
+    };
+    match uri
+        .strip_prefix("https://")
+        .and_then(|rest| rest.split_once('/'))
+    {
+        Some((host, path)) if host.ends_with(".bbc.co.uk") => {
+            format!("{}/{}/{}", base_url, host, path)
+        }
+        _ => uri,
+    }
+}
+
+fn host(uri: &str) -> String {
+    url::Url::parse(uri)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// How long a 429's `Retry-After` header (seconds, or an HTTP date) asks to wait
+fn retry_after(value: Option<&str>, now: DateTime<Utc>) -> Duration {
+    let value = match value {
+        Some(value) => value.trim(),
+        None => return DEFAULT_RETRY_AFTER,
+    };
+    if let Ok(secs) = value.parse() {
+        return Duration::from_secs(secs);
+    }
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .and_then(|date| (date.with_timezone(&Utc) - now).to_std().ok())
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+fn check_rate_limit(host: &str, now: Instant) -> Result<(), FetchError> {
+    let mut limits = RATE_LIMITS.lock().unwrap();
+    match limits.get(host) {
+        Some(until) if *until > now => Err(FetchError::RateLimited(
+            host.to_string(),
+            (*until - now).as_secs().max(1),
+        )),
+        Some(_) => {
+            limits.remove(host);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Responses held in the cache, including expired ones kept for when a host is failing
+pub fn cache_entries() -> usize {
+    CACHE.lock().unwrap().len()
+}
+
+/// Hosts which are failing or rate limiting, and whether requests to them are stopped
+pub fn failing_hosts() -> Vec<(String, bool)> {
+    let now = Instant::now();
+    let mut hosts = CIRCUIT_BREAKER.failing(now);
+    let limits = RATE_LIMITS.lock().unwrap();
+    for (host, _) in limits.iter().filter(|(_, until)| **until > now) {
+        match hosts.iter_mut().find(|(h, _)| h == host) {
+            Some((_, stopped)) => *stopped = true,
+            None => hosts.push((host.clone(), true)),
+        }
+    }
+    hosts
+}
+
+/// Sends the request unless the host is rate limiting or its circuit breaker has
+/// tripped, counting connection errors and server errors as failures
+async fn send(
+    request: reqwest::RequestBuilder,
+    host: &str,
+) -> Result<reqwest::Response, FetchError> {
+    check_rate_limit(host, Instant::now())?;
+    CIRCUIT_BREAKER
+        .check(host, Instant::now())
+        .map_err(|wait| FetchError::CircuitOpen(host.to_string(), wait.as_secs().max(1)))?;
+
+    let resp = request.send().await;
+
+    if let Ok(r) = &resp {
+        if r.status().as_u16() == 429 {
+            let wait = retry_after(
+                r.headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|h| h.to_str().ok()),
+                Utc::now(),
+            );
+            log::warn!(
+                "{} is rate limiting requests, pausing for {} seconds",
+                host,
+                wait.as_secs()
+            );
+            RATE_LIMITS
+                .lock()
+                .unwrap()
+                .insert(host.to_string(), Instant::now() + wait);
+            return Err(FetchError::RateLimited(
+                host.to_string(),
+                wait.as_secs().max(1),
+            ));
+        }
+    }
+
+    let failed = resp.as_ref().map_or(true, |r| r.status().is_server_error());
+    if failed {
+        CIRCUIT_BREAKER.record_failure(host, Instant::now());
+    } else {
+        CIRCUIT_BREAKER.record_success(host);
+    }
+
+    Ok(resp?)
+}
+
+/// How long a response may be cached for, from its `Cache-Control` and `Age` headers
+fn freshness(cache_control: Option<&str>, age: Option<&str>) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in cache_control?.split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            Some(("max-age", v)) => max_age = v.trim_matches('"').parse::<u64>().ok(),
+            Some(("s-maxage", v)) => s_maxage = v.trim_matches('"').parse::<u64>().ok(),
+            None if directive == "no-store" || directive == "no-cache" => return None,
+            _ => {}
+        }
+    }
+
+    // this is a shared cache, so s-maxage takes precedence
+    let lifetime = s_maxage.or(max_age)?;
+    let age = age.and_then(|a| a.trim().parse().ok()).unwrap_or(0);
+    lifetime
+        .checked_sub(age)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+fn cached(uri: &str, allow_stale: bool) -> Option<Vec<u8>> {
+    let cache = CACHE.lock().unwrap();
+    let entry = cache.get(uri)?;
+    if allow_stale || entry.expires > Instant::now() {
+        Some(entry.bytes.clone())
+    } else {
+        None
+    }
+}
+
+fn store(uri: String, bytes: &[u8], lifetime: Duration) {
+    if bytes.len() > MAX_CACHED_BYTES {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut cache = CACHE.lock().unwrap();
+    cache.remove(&uri);
+    let full = |cache: &HashMap<String, CacheEntry>| {
+        cache.len() >= MAX_CACHE_ENTRIES
+            || cache.values().map(|e| e.bytes.len()).sum::<usize>() + bytes.len() > MAX_CACHE_BYTES
+    };
+    if full(&cache) {
+        cache.retain(|_, e| e.expires > now);
+    }
+    while full(&cache) {
+        let soonest = cache
+            .iter()
+            .min_by_key(|(_, e)| e.expires)
+            .map(|(k, _)| k.clone());
+        match soonest {
+            Some(soonest) => cache.remove(&soonest),
+            None => break,
+        };
+    }
+    cache.insert(
+        uri,
+        CacheEntry {
+            bytes: bytes.to_vec(),
+            expires: now + lifetime,
+        },
+    );
+}
+
+/// GETs the URI, from the cache if the server allowed an earlier response to be cached
 pub async fn get(uri: String) -> Result<Response, FetchError> {
-    let client = reqwest::Client::new();
+    fetch(uri, true).await
+}
+
+/// GETs the URI without caching the response, for media segments, which the segment
+/// cache keeps (within its own limit) instead
+pub async fn get_uncached(uri: String) -> Result<Response, FetchError> {
+    fetch(uri, false).await
+}
+
+async fn fetch(uri: String, use_cache: bool) -> Result<Response, FetchError> {
+    let uri = upstream(uri);
+    if let Some(bytes) = cached(&uri, false).filter(|_| use_cache) {
+        return Ok(Response { status: 200, bytes });
+    }
+
+    let host = host(&uri);
+    let resp = match send(CLIENT.with(|c| c.get(&uri)), &host).await {
+        Ok(resp) => resp,
+        Err(e @ FetchError::CircuitOpen(_, _)) | Err(e @ FetchError::RateLimited(_, _)) => {
+            // better out of date than nothing while the host is failing or limiting us
+            return cached(&uri, true)
+                .filter(|_| use_cache)
+                .map(|bytes| Response { status: 200, bytes })
+                .ok_or(e);
+        }
+        Err(e) => return Err(e),
+    };
 
-    let resp = client
-        .get(uri)
-        .header("User-Agent", USER_AGENT)
-        .send()
-        .await?;
+    let status = resp.status().as_u16();
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|h: &reqwest::header::HeaderValue| h.to_str().ok())
+            .map(str::to_string)
+    };
+    let lifetime = freshness(
+        header(reqwest::header::CACHE_CONTROL).as_deref(),
+        header(reqwest::header::AGE).as_deref(),
+    );
 
-    Ok(Response {
-        status: resp.status().as_u16(),
-        bytes: resp.bytes().await.unwrap().to_vec(),
-    })
+    let bytes = resp.bytes().await.unwrap().to_vec();
+    if let (200, Some(lifetime), true) = (status, lifetime, use_cache) {
+        store(uri, &bytes, lifetime);
+    }
+
+    Ok(Response { status, bytes })
 }
 
 pub async fn head(uri: String) -> Result<u16, FetchError> {
-    let client = reqwest::Client::new();
-
-    let resp = client
-        .head(uri)
-        .header("User-Agent", USER_AGENT)
-        .send()
-        .await?;
+    let uri = upstream(uri);
+    let host = host(&uri);
+    let resp = send(CLIENT.with(|c| c.head(uri)), &host).await?;
 
     Ok(resp.status().as_u16())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_freshness() {
+        assert_eq!(
+            freshness(Some("public, max-age=60"), None),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            freshness(Some("max-age=60, s-maxage=30"), Some("10")),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(freshness(Some("max-age=60"), Some("60")), None);
+        assert_eq!(freshness(Some("no-cache, max-age=60"), None), None);
+        assert_eq!(freshness(Some("public"), None), None);
+        assert_eq!(freshness(None, None), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2022-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(retry_after(Some("120"), now), Duration::from_secs(120));
+        assert_eq!(
+            retry_after(Some("Wed, 01 Jun 2022 12:05:00 GMT"), now),
+            Duration::from_secs(300)
+        );
+        assert_eq!(retry_after(Some("soon"), now), DEFAULT_RETRY_AFTER);
+        assert_eq!(retry_after(None, now), DEFAULT_RETRY_AFTER);
+    }
+}
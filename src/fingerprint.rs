@@ -0,0 +1,370 @@
+use std::{
+    collections::{hash_map, HashMap},
+    f32::consts::PI,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+
+// Frequency bands compared, in Hz, which survive re-encoding at any bit rate
+const MIN_HZ: f32 = 300.0;
+const MAX_HZ: f32 = 2000.0;
+// One more band than bits, as each bit compares neighbouring bands
+const BANDS: usize = 33;
+
+/// Frames kept from the middle of an episode, about three minutes
+const WINDOW_FRAMES: usize = 1024;
+/// Frames the windows of two episodes may be shifted by, e.g. by a different announcement
+const MAX_SHIFT: usize = 256;
+/// Most bits of two windows which may differ for them to be the same audio
+const MAX_BIT_ERROR_RATE: f64 = 0.35;
+/// How much longer one episode may be than the other for them to be the same audio
+const MAX_LENGTH_DIFFERENCE: f64 = 0.05;
+const MIN_LENGTH_DIFFERENCE_SECS: f64 = 60.0;
+
+/// A compact summary of some audio, which stays the same when it's re-encoded
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub secs: u64,
+    /// 32 bits for each frame of a window from the middle of the audio
+    pub words: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// Whether the fingerprints are of the same audio, allowing for it being shifted
+    pub fn matches(&self, other: &Fingerprint) -> bool {
+        let (longer, shorter) = (self.secs.max(other.secs), self.secs.min(other.secs));
+        let allowed = (longer as f64 * MAX_LENGTH_DIFFERENCE).max(MIN_LENGTH_DIFFERENCE_SECS);
+        if (longer - shorter) as f64 > allowed {
+            return false;
+        }
+        self.bit_error_rate(other)
+            .map_or(false, |rate| rate <= MAX_BIT_ERROR_RATE)
+    }
+
+    /// Least fraction of differing bits, across the shifts which overlap by at least half
+    fn bit_error_rate(&self, other: &Fingerprint) -> Option<f64> {
+        let min_overlap = self.words.len().min(other.words.len()) / 2;
+        let shifts = (0..=MAX_SHIFT)
+            .flat_map(|shift| [(shift, 0), (0, shift)])
+            .skip(1);
+        shifts
+            .filter_map(|(a_shift, b_shift)| {
+                let a = self.words.get(a_shift..)?;
+                let b = other.words.get(b_shift..)?;
+                let overlap = a.len().min(b.len());
+                if overlap == 0 || overlap < min_overlap {
+                    return None;
+                }
+                let errors: u32 = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum();
+                Some(errors as f64 / (overlap * 32) as f64)
+            })
+            .min_by(|a, b| a.partial_cmp(b).unwrap())
+    }
+}
+
+/// In-place radix-2 FFT; the length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Fingerprints audio as it's decoded, from the energy differences between neighbouring
+/// frequency bands and frames (after Haitsma and Kalker)
+pub struct Fingerprinter {
+    sample_rate: u32,
+    frame_len: usize,
+    window: Vec<f32>,
+    /// Samples not yet fingerprinted, which the next frame starts with
+    pending: Vec<f32>,
+    previous: Option<[f32; BANDS]>,
+    samples: u64,
+    words: Vec<u32>,
+}
+
+impl Fingerprinter {
+    pub fn new(sample_rate: u32) -> Self {
+        // about an eighth of a second, whatever the sample rate
+        let frame_len = (sample_rate as usize / 8).next_power_of_two().max(64);
+        let window = (0..frame_len)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / frame_len as f32).cos())
+            .collect();
+        Fingerprinter {
+            sample_rate,
+            frame_len,
+            window,
+            pending: Vec::with_capacity(frame_len * 2),
+            previous: None,
+            samples: 0,
+            words: Vec::new(),
+        }
+    }
+
+    /// Adds mono samples
+    pub fn push(&mut self, samples: &[f32]) {
+        self.samples += samples.len() as u64;
+        self.pending.extend_from_slice(samples);
+        // frames overlap by half
+        let hop = self.frame_len / 2;
+        while self.pending.len() >= self.frame_len {
+            let energies = self.band_energies();
+            if let Some(previous) = self.previous {
+                self.words.push(word(&previous, &energies));
+            }
+            self.previous = Some(energies);
+            self.pending.drain(..hop);
+        }
+    }
+
+    fn band_energies(&self) -> [f32; BANDS] {
+        let mut re = self.pending[..self.frame_len]
+            .iter()
+            .zip(&self.window)
+            .map(|(s, w)| s * w)
+            .collect::<Vec<_>>();
+        let mut im = vec![0.0; self.frame_len];
+        fft(&mut re, &mut im);
+
+        let hz_per_bin = self.sample_rate as f32 / self.frame_len as f32;
+        let mut energies = [0.0; BANDS];
+        for (band, energy) in energies.iter_mut().enumerate() {
+            // bands are spaced logarithmically, as hearing is
+            let edge = |b: usize| MIN_HZ * (MAX_HZ / MIN_HZ).powf(b as f32 / BANDS as f32);
+            let low = (edge(band) / hz_per_bin) as usize;
+            let high = ((edge(band + 1) / hz_per_bin) as usize).max(low + 1);
+            *energy = (low..high.min(self.frame_len / 2))
+                .map(|bin| re[bin] * re[bin] + im[bin] * im[bin])
+                .sum();
+        }
+        energies
+    }
+
+    /// The fingerprint, or None if there was too little audio
+    pub fn finish(self) -> Option<Fingerprint> {
+        if self.words.len() < WINDOW_FRAMES / 4 {
+            return None;
+        }
+        let start = self.words.len().saturating_sub(WINDOW_FRAMES) / 2;
+        let end = (start + WINDOW_FRAMES).min(self.words.len());
+        Some(Fingerprint {
+            secs: self.samples / u64::from(self.sample_rate.max(1)),
+            words: self.words[start..end].to_vec(),
+        })
+    }
+}
+
+/// A bit for each pair of neighbouring bands: whether the difference between them grew
+fn word(previous: &[f32; BANDS], current: &[f32; BANDS]) -> u32 {
+    (0..BANDS - 1).fold(0, |word, band| {
+        let difference =
+            (current[band] - current[band + 1]) - (previous[band] - previous[band + 1]);
+        (word << 1) | u32::from(difference > 0.0)
+    })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub fingerprint: Fingerprint,
+    /// The episode first fingerprinted with the same audio, if this isn't it
+    pub duplicate_of: Option<String>,
+}
+
+/// Fingerprints of transcoded episodes, by episode id
+static FINGERPRINTS: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(Mutex::default);
+
+/// File fingerprints are kept in, so they outlast restarts
+static FINGERPRINT_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Counts changes to the fingerprints, so a snapshot of them knows its place
+static VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// Version of the fingerprints last saved, held while saving so writers take turns
+static SAVED_VERSION: Lazy<Mutex<u64>> = Lazy::new(Mutex::default);
+
+/// Loads fingerprints saved to the file, and saves them there from now on
+pub fn load(path: &Path) {
+    let _ = FINGERPRINT_FILE.set(path.to_path_buf());
+    match std::fs::read(path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(fingerprints) => *FINGERPRINTS.lock().unwrap() = fingerprints,
+            Err(e) => log::warn!("Ignoring invalid fingerprint file: {}", e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::warn!("Failed to read fingerprint file: {}", e),
+    }
+}
+
+/// The fingerprints as saved, with their version. Taken while they're locked, so versions
+/// follow the order of changes.
+fn snapshot(fingerprints: &HashMap<String, Entry>) -> (u64, String) {
+    let version = VERSION.fetch_add(1, Ordering::AcqRel) + 1;
+    (version, serde_json::to_string(fingerprints).unwrap())
+}
+
+/// Saves a snapshot, unless a newer one has been saved already
+fn save((version, json): (u64, String)) {
+    if let Some(path) = FINGERPRINT_FILE.get() {
+        let mut saved_version = SAVED_VERSION.lock().unwrap();
+        if *saved_version >= version {
+            return;
+        }
+        // replaced in one go, so a crash never leaves half a file, and named for the
+        // process, as a successor may be saving too
+        let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let result = std::fs::write(&temp, json).and_then(|_| std::fs::rename(&temp, path));
+        match result {
+            Ok(()) => *saved_version = version,
+            Err(e) => log::warn!("Failed to save fingerprints: {}", e),
+        }
+    }
+}
+
+/// Keeps an episode's fingerprint, noting which episode it duplicates if any
+pub fn record(episode_id: &str, fingerprint: Fingerprint) {
+    // compared outside the lock, as comparing with every fingerprint takes a while
+    let originals = FINGERPRINTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, entry)| *id != episode_id && entry.duplicate_of.is_none())
+        .map(|(id, entry)| (id.clone(), entry.fingerprint.clone()))
+        .collect::<Vec<_>>();
+    let duplicate_of = originals
+        .into_iter()
+        .find(|(_, original)| original.matches(&fingerprint))
+        .map(|(id, _)| id);
+    if let Some(original) = &duplicate_of {
+        log::info!("{} has the same audio as {}", episode_id, original);
+    }
+
+    let snapshot = {
+        let mut fingerprints = FINGERPRINTS.lock().unwrap();
+        fingerprints.insert(
+            episode_id.to_string(),
+            Entry {
+                fingerprint,
+                duplicate_of,
+            },
+        );
+        snapshot(&fingerprints)
+    };
+    save(snapshot);
+}
+
+/// Every fingerprint, by episode id
+pub fn all() -> HashMap<String, Entry> {
+    FINGERPRINTS.lock().unwrap().clone()
+}
+
+/// Adds fingerprints carried over from elsewhere, keeping any already here.
+/// Returns how many were added.
+pub fn restore(restored: HashMap<String, Entry>) -> usize {
+    let (count, snapshot) = {
+        let mut fingerprints = FINGERPRINTS.lock().unwrap();
+        let mut count = 0;
+        for (episode_id, entry) in restored {
+            if let hash_map::Entry::Vacant(vacant) = fingerprints.entry(episode_id) {
+                vacant.insert(entry);
+                count += 1;
+            }
+        }
+        (count, snapshot(&fingerprints))
+    };
+    save(snapshot);
+    count
+}
+
+/// The episode first found with the same audio as this one, if it's a duplicate
+pub fn original(episode_id: &str) -> Option<String> {
+    FINGERPRINTS
+        .lock()
+        .unwrap()
+        .get(episode_id)?
+        .duplicate_of
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Deterministic noise, shaped into tones which change every quarter second
+    fn audio(seed: u32, secs: usize, sample_rate: usize) -> Vec<f32> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        let mut samples = Vec::new();
+        for _ in 0..secs * 4 {
+            let (hz, noise) = (MIN_HZ + next() * (MAX_HZ - MIN_HZ), next() * 0.2);
+            for i in 0..sample_rate / 4 {
+                let t = i as f32 / sample_rate as f32;
+                samples.push((2.0 * PI * hz * t).sin() + noise * (next() - 0.5));
+            }
+        }
+        samples
+    }
+
+    fn fingerprint(samples: &[f32], sample_rate: u32) -> Fingerprint {
+        let mut fingerprinter = Fingerprinter::new(sample_rate);
+        for chunk in samples.chunks(1024) {
+            fingerprinter.push(chunk);
+        }
+        fingerprinter.finish().unwrap()
+    }
+
+    #[test]
+    fn test_matches() {
+        let rate = 8000;
+        let original = audio(1, 120, rate);
+        let a = fingerprint(&original, rate as u32);
+
+        // the same audio, quieter and after a couple of seconds of something else
+        let mut rebroadcast = audio(2, 2, rate);
+        rebroadcast.extend(original.iter().map(|s| s * 0.5));
+        let b = fingerprint(&rebroadcast, rate as u32);
+        assert!(a.matches(&b));
+
+        let other = fingerprint(&audio(3, 120, rate), rate as u32);
+        assert!(!a.matches(&other));
+
+        let longer = fingerprint(&audio(1, 300, rate), rate as u32);
+        assert!(!a.matches(&longer));
+    }
+}
@@ -0,0 +1,239 @@
+use std::pin::Pin;
+
+use futures::{SinkExt, Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{bbc, config::Config, parents, sounds_proxy, web_utils};
+
+pub mod proto {
+    tonic::include_proto!("sounds_proxy");
+}
+
+use proto::sounds_proxy_server::{SoundsProxy, SoundsProxyServer};
+
+impl From<bbc::BbcResponseError> for Status {
+    fn from(err: bbc::BbcResponseError) -> Self {
+        let message = err.to_string();
+        match web_utils::get_http_response_for_bbc_error(&err).0 {
+            400 => Status::invalid_argument(message),
+            403 => Status::permission_denied(message),
+            404 => Status::not_found(message),
+            501 => Status::unimplemented(message),
+            503 => Status::unavailable(message),
+            _ => Status::internal(message),
+        }
+    }
+}
+
+impl From<sounds_proxy::Episode> for proto::Episode {
+    fn from(e: sounds_proxy::Episode) -> Self {
+        proto::Episode {
+            pid: e.id,
+            title: e.title.unwrap_or_default(),
+            summary: e.summary.unwrap_or_default(),
+            url: e.url,
+            file_size: e.file_size,
+            content_type: e.content_type,
+            duration: e.duration,
+            pub_date: e.pub_date.map(|d| d.to_rfc3339()).unwrap_or_default(),
+            image: e.image.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<sounds_proxy::Resolution> for proto::Resolution {
+    fn from(r: sounds_proxy::Resolution) -> Self {
+        proto::Resolution {
+            pid: r.pid,
+            media_url_status: r.media_url_status.unwrap_or_default().into(),
+            media: r
+                .media
+                .into_iter()
+                .map(|m| proto::resolution::Media {
+                    kind: m.kind,
+                    bitrate: m.bitrate,
+                    encoding: m.encoding,
+                    connections: m.connections as u32,
+                })
+                .collect(),
+            connection: r.connection.map(|c| proto::resolution::Connection {
+                href: c.href,
+                protocol: c.protocol,
+                supplier: c.supplier.unwrap_or_default(),
+            }),
+            playlist: r.playlist.map(|p| proto::resolution::Playlist {
+                master: p.master,
+                bandwidth: p.bandwidth.unwrap_or_default(),
+                codecs: p.codecs.unwrap_or_default(),
+                segments: p.segments as u32,
+                encrypted: p.encrypted,
+            }),
+            mode: match r.mode {
+                Some(sounds_proxy::ResolutionMode::Redirect) => "redirect",
+                Some(sounds_proxy::ResolutionMode::AacPassthrough) => "aac_passthrough",
+                Some(sounds_proxy::ResolutionMode::Remux) => "remux",
+                None => "",
+            }
+            .to_string(),
+            error: r.error.unwrap_or_default(),
+        }
+    }
+}
+
+struct SoundsProxyService {
+    config: Config,
+    base_url: String,
+}
+
+impl SoundsProxyService {
+    fn check_permitted(&self, pid: &bbc::Pid) -> Result<(), bbc::BbcResponseError> {
+        if self.config.is_permitted(pid, &[]) {
+            Ok(())
+        } else {
+            Err(bbc::BbcResponseError::NotPermitted)
+        }
+    }
+
+    /// Episodes are checked by the shows the BBC says they're part of
+    async fn check_episode_permitted(
+        &self,
+        episode_id: &bbc::Pid,
+    ) -> Result<(), bbc::BbcResponseError> {
+        if !self.config.has_show_lists() {
+            return Ok(());
+        }
+        let parents = parents::get_parents(episode_id).await?;
+        if self.config.is_permitted(episode_id, &parents) {
+            Ok(())
+        } else {
+            Err(bbc::BbcResponseError::NotPermitted)
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SoundsProxy for SoundsProxyService {
+    async fn get_show(
+        &self,
+        request: Request<proto::ShowRequest>,
+    ) -> Result<Response<proto::Show>, Status> {
+        let pid: bbc::Pid = request.get_ref().pid.parse()?;
+        self.check_permitted(&pid)?;
+
+        let (show, _) =
+            sounds_proxy::get_show(&self.base_url, &pid, &self.config.feed_options(&pid)).await?;
+
+        Ok(Response::new(proto::Show {
+            pid: pid.to_string(),
+            title: show.title,
+            subtitle: show.subtitle.unwrap_or_default(),
+            author: show.author,
+            link: show.link,
+            image: show.image.unwrap_or_default(),
+        }))
+    }
+
+    async fn list_episodes(
+        &self,
+        request: Request<proto::ShowRequest>,
+    ) -> Result<Response<proto::EpisodeList>, Status> {
+        let pid: bbc::Pid = request.get_ref().pid.parse()?;
+        self.check_permitted(&pid)?;
+
+        let (_, episodes) =
+            sounds_proxy::get_show(&self.base_url, &pid, &self.config.feed_options(&pid)).await?;
+
+        Ok(Response::new(proto::EpisodeList {
+            episodes: episodes.into_iter().map(proto::Episode::from).collect(),
+        }))
+    }
+
+    async fn resolve_episode(
+        &self,
+        request: Request<proto::EpisodeRequest>,
+    ) -> Result<Response<proto::Resolution>, Status> {
+        let pid: bbc::Pid = request.get_ref().pid.parse()?;
+        self.check_episode_permitted(&pid).await?;
+        let show = parents::configured_show(&self.config, &pid).await?;
+
+        let resolution = sounds_proxy::resolve_episode(
+            &pid,
+            &self.config.media_variants(show.as_ref()),
+            self.config.hls_bandwidth(show.as_ref()),
+        )
+        .await;
+
+        Ok(Response::new(resolution.into()))
+    }
+
+    type GetEpisodeAudioStream =
+        Pin<Box<dyn Stream<Item = Result<proto::AudioChunk, Status>> + Send>>;
+
+    async fn get_episode_audio(
+        &self,
+        request: Request<proto::EpisodeRequest>,
+    ) -> Result<Response<Self::GetEpisodeAudioStream>, Status> {
+        let pid: bbc::Pid = request.get_ref().pid.parse()?;
+        self.check_episode_permitted(&pid).await?;
+        let show = parents::configured_show(&self.config, &pid).await?;
+        let audio_format = self.config.audio_format(show.as_ref());
+        let variants = self.config.media_variants(show.as_ref());
+        let hls_bandwidth = self.config.hls_bandwidth(show.as_ref());
+
+        // The episode stream can't be sent between threads, so it runs on its own thread
+        let (mut tx, rx) = futures::channel::mpsc::channel(16);
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async move {
+                let mut stream = match sounds_proxy::get_episode(
+                    &pid,
+                    audio_format,
+                    &variants,
+                    hls_bandwidth,
+                    None,
+                )
+                .await
+                {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = tx.send(Err(e.into())).await;
+                        return;
+                    }
+                };
+
+                while let Some(data) = stream.next().await {
+                    let chunk = data
+                        .map(|data| proto::AudioChunk { data })
+                        .map_err(Status::from);
+                    if tx.send(chunk).await.is_err() {
+                        // client has gone away
+                        break;
+                    }
+                }
+            });
+        });
+
+        Ok(Response::new(Box::pin(rx)))
+    }
+}
+
+/// Runs the gRPC service until it fails
+pub async fn serve(config: Config, port: u16) {
+    let service = SoundsProxyService {
+        base_url: config.base_url.clone().unwrap_or_default(),
+        config,
+    };
+
+    let result = Server::builder()
+        .add_service(SoundsProxyServer::new(service))
+        .serve(([0, 0, 0, 0], port).into())
+        .await;
+
+    if let Err(e) = result {
+        log::error!("gRPC server failed: {}", e);
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    env, io,
+    net::TcpListener,
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::{Child, Command},
+    sync::Arc,
+};
+
+use actix_web::{
+    dev::ServerHandle,
+    rt::signal::unix::{signal, SignalKind},
+};
+use futures::{Future, Stream, StreamExt};
+
+use crate::stats::ResolutionStats;
+
+/// Tells a process started by a handoff which descriptor its listener is on
+const LISTEN_FD_VAR: &str = "SOUNDS_PROXY_LISTEN_FD";
+
+// Where a handed over listener is put, the same place systemd puts its first socket
+const HANDOFF_FD: RawFd = 3;
+
+/// A listener passed from the process which started this one, by a handoff or
+/// systemd socket activation
+fn inherited_fd() -> Option<RawFd> {
+    if let Ok(fd) = env::var(LISTEN_FD_VAR) {
+        // not for this process's own successors
+        env::remove_var(LISTEN_FD_VAR);
+        return fd.parse().ok();
+    }
+
+    let for_us =
+        env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(std::process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or(0);
+    if for_us && count > 0 {
+        Some(HANDOFF_FD)
+    } else {
+        None
+    }
+}
+
+/// The listening socket, taken over from a previous process if it handed one over,
+/// otherwise bound to `port`
+pub fn listener(port: u16) -> io::Result<TcpListener> {
+    match inherited_fd() {
+        Some(fd) => {
+            log::info!("Taking over listener on descriptor {}", fd);
+            Ok(unsafe { TcpListener::from_raw_fd(fd) })
+        }
+        None => TcpListener::bind(("0.0.0.0", port)),
+    }
+}
+
+/// Starts this program again, as invoked (so a newly deployed binary is the one
+/// run), handing it the listener so both accept connections until this one stops
+fn spawn_successor(listener_fd: RawFd) -> io::Result<Child> {
+    let mut args = env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no program name"))?;
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env(LISTEN_FD_VAR, HANDOFF_FD.to_string());
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 leaves the copy open across exec, but does nothing if it's already in place
+            let result = if listener_fd == HANDOFF_FD {
+                libc::fcntl(listener_fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(listener_fd, HANDOFF_FD)
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// Why handing over can't work under whatever started this process, if it can't. Given
+/// the process id and a way to read the environment.
+fn unsupported_supervisor(pid: u32, var: impl Fn(&str) -> Option<String>) -> Option<&'static str> {
+    if pid == 1 {
+        // e.g. a container's entrypoint, whose container stops when it exits
+        return Some("this is PID 1, so the new process would be stopped along with it");
+    }
+    // systemd stops a service once its main process exits, unless told of the new one
+    if var("INVOCATION_ID").is_some() && var("NOTIFY_SOCKET").is_none() {
+        return Some("under systemd the service needs Type=notify and NotifyAccess=all");
+    }
+    None
+}
+
+/// Tells systemd (with `Type=notify`) about the service, e.g. that this process is now
+/// its main process. Does nothing unless systemd is listening.
+pub fn notify_systemd(state: &str) {
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        if let Err(e) = send_datagram(&path, state.as_bytes()) {
+            log::warn!("Couldn't notify systemd: {}", e);
+        }
+    }
+}
+
+fn send_datagram(path: &str, message: &[u8]) -> io::Result<()> {
+    unsafe {
+        let mut addr: libc::sockaddr_un = std::mem::zeroed();
+        let path = path.as_bytes();
+        if path.is_empty() || path.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bad socket path",
+            ));
+        }
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (to, from) in addr.sun_path.iter_mut().zip(path) {
+            *to = *from as libc::c_char;
+        }
+        // an abstract socket, whose name starts with a NUL
+        if path[0] == b'@' {
+            addr.sun_path[0] = 0;
+        }
+        let len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sent = libc::sendto(
+            fd,
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        );
+        let result = if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+/// On each signal, starts a successor, until one starts. Then drains this process, and
+/// returns true. A successor which fails to start leaves this process serving, as does
+/// a supervisor which wouldn't keep the successor running.
+async fn hand_over<S, F>(
+    mut signals: impl Stream<Item = ()> + Unpin,
+    unsupported: Option<&str>,
+    mut spawn: impl FnMut() -> S,
+    drain: impl FnOnce() -> F,
+) -> bool
+where
+    S: Future<Output = io::Result<u32>>,
+    F: Future<Output = ()>,
+{
+    while signals.next().await.is_some() {
+        if let Some(reason) = unsupported {
+            log::error!("Can't hand over to a new process: {}", reason);
+            continue;
+        }
+        match spawn().await {
+            Ok(pid) => {
+                log::info!("Handed over to process {}, finishing running streams", pid);
+                drain().await;
+                return true;
+            }
+            // keep serving rather than leave nothing listening
+            Err(e) => log::error!("Failed to start a new process, not reloading: {}", e),
+        }
+    }
+    false
+}
+
+/// On SIGUSR2, starts a successor on the same listener then stops accepting connections.
+/// Streams already running carry on until they finish or the shutdown timeout passes.
+pub async fn reload_on_signal(
+    listener_fd: RawFd,
+    server: ServerHandle,
+    stats: Arc<ResolutionStats>,
+) {
+    let signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            log::warn!("Can't listen for reload signals: {}", e);
+            return;
+        }
+    };
+    let signals = Box::pin(futures::stream::unfold(signals, |mut signals| async {
+        signals.recv().await.map(|_| ((), signals))
+    }));
+
+    let unsupported = unsupported_supervisor(std::process::id(), |name| env::var(name).ok());
+    hand_over(
+        signals,
+        unsupported,
+        || async {
+            // the successor carries on from the counts saved now
+            stats.hand_over().await;
+            let spawned = spawn_successor(listener_fd).map(|child| child.id());
+            if spawned.is_err() {
+                stats.take_back();
+            }
+            spawned
+        },
+        || server.stop(true),
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_unsupported_supervisor() {
+        let env = |vars: &'static [&'static str]| {
+            move |name: &str| vars.contains(&name).then(|| "1".to_string())
+        };
+
+        assert!(unsupported_supervisor(1234, env(&[])).is_none());
+        assert!(unsupported_supervisor(1, env(&[])).is_some());
+        assert!(unsupported_supervisor(1234, env(&["INVOCATION_ID"])).is_some());
+        assert!(unsupported_supervisor(1234, env(&["INVOCATION_ID", "NOTIFY_SOCKET"])).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_hand_over() {
+        let spawned = Cell::new(0);
+        let drained = Cell::new(false);
+
+        // the first successor fails to start, so this keeps serving until the second
+        let mut signals = futures::stream::iter(vec![(), (), ()]);
+        let handed_over = hand_over(
+            &mut signals,
+            None,
+            || {
+                spawned.set(spawned.get() + 1);
+                futures::future::ready(match spawned.get() {
+                    1 => Err(io::Error::new(io::ErrorKind::NotFound, "gone")),
+                    _ => Ok(1234),
+                })
+            },
+            || async { drained.set(true) },
+        )
+        .await;
+        assert!(handed_over);
+        assert_eq!(spawned.get(), 2);
+        assert!(drained.get());
+        // and stops waiting for signals once draining
+        assert_eq!(signals.count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_hand_over_unsupported() {
+        let spawned = Cell::new(0);
+        let handed_over = hand_over(
+            futures::stream::iter(vec![(), ()]),
+            Some("this is PID 1"),
+            || {
+                spawned.set(spawned.get() + 1);
+                futures::future::ready(Ok(1234))
+            },
+            || async {},
+        )
+        .await;
+        assert!(!handed_over);
+        assert_eq!(spawned.get(), 0);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    failures::FailureTracker,
+    fetch,
+    limits::{StreamLimiter, BUFFERS},
+    readiness::Readiness,
+    s3, transcode,
+};
+
+// Hosts serving the BBC's API, rather than media segments or images
+const API_HOSTS: &[&str] = &["rms.api.bbc.co.uk", "open.live.bbc.co.uk"];
+
+// Longer than this to reach the bucket counts as down
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Subsystems which take the whole proxy down with them, unless configured otherwise,
+/// as nothing can be streamed without them
+pub const DEFAULT_CRITICAL: &[Subsystem] = &[Subsystem::BbcApi, Subsystem::Transcoder];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Ok,
+    /// Working, but some requests will fail or be slow
+    Degraded,
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// The BBC's API and media hosts, as seen by the circuit breaker and rate limits
+    BbcApi,
+    /// The S3 bucket
+    Storage,
+    /// ffmpeg, and capacity to run more streams through it
+    Transcoder,
+    /// Cached BBC responses and stream buffers
+    Cache,
+    /// Background uploads
+    JobQueue,
+}
+
+#[derive(Serialize)]
+pub struct SubsystemHealth {
+    pub status: Level,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl SubsystemHealth {
+    fn new(status: Level, detail: impl Into<Option<String>>) -> Self {
+        SubsystemHealth {
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Health {
+    pub status: Level,
+    pub subsystems: BTreeMap<Subsystem, SubsystemHealth>,
+}
+
+/// The proxy's overall level: down if a critical subsystem is down, degraded if anything isn't ok
+pub fn overall(subsystems: &BTreeMap<Subsystem, SubsystemHealth>, critical: &[Subsystem]) -> Level {
+    subsystems
+        .iter()
+        .map(|(subsystem, health)| match health.status {
+            Level::Down if !critical.contains(subsystem) => Level::Degraded,
+            status => status,
+        })
+        .max()
+        .unwrap_or(Level::Ok)
+}
+
+fn bbc_api() -> SubsystemHealth {
+    let failing = fetch::failing_hosts();
+    let stopped = |api: bool| {
+        failing
+            .iter()
+            .filter(|(host, stopped)| *stopped && API_HOSTS.contains(&&host[..]) == api)
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>()
+    };
+
+    let api_stopped = stopped(true);
+    if !api_stopped.is_empty() {
+        return SubsystemHealth::new(
+            Level::Down,
+            format!("requests stopped to {}", api_stopped.join(", ")),
+        );
+    }
+    if failing.is_empty() {
+        return SubsystemHealth::new(Level::Ok, None);
+    }
+    let media_stopped = stopped(false);
+    let detail = if media_stopped.is_empty() {
+        format!("{} hosts failing", failing.len())
+    } else {
+        format!("requests stopped to {}", media_stopped.join(", "))
+    };
+    SubsystemHealth::new(Level::Degraded, detail)
+}
+
+async fn storage(config: &Config, readiness: &Readiness) -> SubsystemHealth {
+    let bucket = match &config.s3_bucket {
+        Some(bucket) => bucket,
+        None => return SubsystemHealth::new(Level::Ok, "not configured".to_string()),
+    };
+    if !readiness.status().storage {
+        return SubsystemHealth::new(Level::Down, "not checked yet".to_string());
+    }
+
+    match tokio::time::timeout(
+        STORAGE_TIMEOUT,
+        s3::connect(bucket, &config.s3_endpoint_url),
+    )
+    .await
+    {
+        Ok(Ok(_)) => SubsystemHealth::new(Level::Ok, None),
+        Ok(Err(e)) => SubsystemHealth::new(Level::Down, e.to_string()),
+        Err(_) => SubsystemHealth::new(Level::Down, "timed out".to_string()),
+    }
+}
+
+fn transcoder(limiter: &StreamLimiter) -> SubsystemHealth {
+    if let Err(e) = ffmpeg_next::init() {
+        return SubsystemHealth::new(Level::Down, e.to_string());
+    }
+    let copy_only = transcode::options().copy_only;
+    if !copy_only && ffmpeg_next::encoder::find(ffmpeg_next::codec::Id::AAC).is_none() {
+        return SubsystemHealth::new(Level::Down, "no AAC encoder".to_string());
+    }
+    if limiter.is_saturated() {
+        return SubsystemHealth::new(
+            Level::Degraded,
+            format!("refusing new streams, {} running", limiter.active()),
+        );
+    }
+    SubsystemHealth::new(Level::Ok, None)
+}
+
+fn cache() -> SubsystemHealth {
+    let entries = format!("{} responses cached", fetch::cache_entries());
+    if BUFFERS.is_full() {
+        SubsystemHealth::new(
+            Level::Degraded,
+            format!("stream buffers are full, {}", entries),
+        )
+    } else {
+        SubsystemHealth::new(Level::Ok, entries)
+    }
+}
+
+fn job_queue(failures: &FailureTracker) -> SubsystemHealth {
+    let quarantined = failures.list().iter().filter(|f| f.quarantined).count();
+    if quarantined > 0 {
+        SubsystemHealth::new(
+            Level::Degraded,
+            format!(
+                "{} episodes quarantined after repeated failures",
+                quarantined
+            ),
+        )
+    } else {
+        SubsystemHealth::new(Level::Ok, None)
+    }
+}
+
+/// Checks each subsystem, including a request to the S3 bucket if there is one
+pub async fn check(
+    config: &Config,
+    readiness: &Readiness,
+    limiter: &StreamLimiter,
+    failures: &FailureTracker,
+) -> Health {
+    let mut subsystems = BTreeMap::new();
+    subsystems.insert(Subsystem::BbcApi, bbc_api());
+    subsystems.insert(Subsystem::Storage, storage(config, readiness).await);
+    subsystems.insert(Subsystem::Transcoder, transcoder(limiter));
+    subsystems.insert(Subsystem::Cache, cache());
+    subsystems.insert(Subsystem::JobQueue, job_queue(failures));
+
+    let critical = config
+        .health_critical
+        .as_deref()
+        .unwrap_or(DEFAULT_CRITICAL);
+    Health {
+        status: overall(&subsystems, critical),
+        subsystems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_overall() {
+        let subsystems = |levels: &[(Subsystem, Level)]| {
+            levels
+                .iter()
+                .map(|(s, l)| (*s, SubsystemHealth::new(*l, None)))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let all_ok = subsystems(&[
+            (Subsystem::BbcApi, Level::Ok),
+            (Subsystem::Storage, Level::Ok),
+        ]);
+        assert_eq!(overall(&all_ok, DEFAULT_CRITICAL), Level::Ok);
+
+        // streaming still works without S3
+        let storage_down = subsystems(&[
+            (Subsystem::BbcApi, Level::Ok),
+            (Subsystem::Storage, Level::Down),
+        ]);
+        assert_eq!(overall(&storage_down, DEFAULT_CRITICAL), Level::Degraded);
+        assert_eq!(overall(&storage_down, &[Subsystem::Storage]), Level::Down);
+
+        let bbc_down = subsystems(&[
+            (Subsystem::BbcApi, Level::Down),
+            (Subsystem::Storage, Level::Ok),
+        ]);
+        assert_eq!(overall(&bbc_down, DEFAULT_CRITICAL), Level::Down);
+        assert_eq!(overall(&bbc_down, &[]), Level::Degraded);
+    }
+}
@@ -1,18 +1,30 @@
-use std::panic;
 use std::{
+    any::Any,
     os::unix::prelude::AsRawFd,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
     thread,
 };
 
 use ffmpeg_next::codec::Id;
 use ffmpeg_next::{codec, encoder, format, media};
-use futures::{Future, FutureExt, Stream};
+use futures::{stream, stream::LocalBoxStream, Future, FutureExt, Stream, StreamExt};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, OnceCell},
+};
 use tokio_pipe::PipeRead;
 
+use crate::fetch::{self, FetchError};
+use crate::fingerprint;
+use crate::id3;
+use crate::limits::{Reservation, BUFFERS};
+use crate::m3u8::{self, MediaPlaylist, Playlist, Segment, Variant};
+use crate::segment_cache;
+use crate::transcode::{self, AudioFormat, Transcoder};
+
 #[derive(Error, Debug)]
 pub enum HlsError {
     #[error("No audio stream found")]
@@ -26,15 +38,103 @@ pub enum HlsError {
 
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
+
+    #[error("Fetch error: {0}")]
+    FetchError(#[from] FetchError),
+
+    #[error("Playlist not understood")]
+    PlaylistError,
+
+    #[error("Audio is protected by DRM")]
+    Drm,
+
+    #[error("Audio is truncated: {0}")]
+    Truncated(String),
+
+    #[error("ffmpeg has no {0} filter")]
+    MissingFilter(&'static str),
+
+    #[error("The {0} stream went missing")]
+    MissingStream(&'static str),
+
+    #[error("ffmpeg thread panicked: {0}")]
+    Panicked(String),
 }
 
 type Result<T, E = HlsError> = std::result::Result<T, E>;
 
+/// Timed ID3 metadata found in a stream, e.g. what's now playing. It's sent as it's
+/// read, alongside the audio it's in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimedMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+// Updates held for a stream's listener, beyond which they're dropped
+pub const METADATA_CAPACITY: usize = 16;
+
+pub type MetadataSender = mpsc::Sender<TimedMetadata>;
+pub type MetadataReceiver = mpsc::Receiver<TimedMetadata>;
+
+/// Sends on the tag's text, if it has any. Tags without text aren't sent.
+fn send_metadata(sender: &MetadataSender, tag: id3::Tag) {
+    if tag.title.is_some() || tag.artist.is_some() {
+        // nobody may be listening, or be behind, which is fine
+        let _ = sender.try_send(TimedMetadata {
+            title: tag.title,
+            artist: tag.artist,
+        });
+    }
+}
+
 type PollResult = Result<(Option<Vec<u8>>, PipeRead)>;
 
+// Linux's default pipe capacity, counted for each stream's pipe from ffmpeg
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+// Audio may be this much shorter than the playlist says, as segment durations are rounded
+const SEGMENT_TOLERANCE_SECS: f64 = 0.5;
+// Remuxed audio may be this much shorter than the whole playlist
+const TOTAL_TOLERANCE_SECS: f64 = 2.0;
+
+/// Part of a stream to keep, in seconds from its start
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start_secs: f64,
+    /// None to keep the rest of the stream
+    pub end_secs: Option<f64>,
+}
+
+impl Span {
+    /// How long the span is, if it ends
+    pub fn secs(&self) -> Option<f64> {
+        self.end_secs.map(|end| (end - self.start_secs).max(0.0))
+    }
+
+    fn contains(&self, secs: f64) -> bool {
+        self.start_secs <= secs && self.end_secs.map_or(true, |end| secs < end)
+    }
+
+    fn is_past(&self, secs: f64) -> bool {
+        self.end_secs.map_or(false, |end| secs >= end)
+    }
+}
+
 pub struct HlsStream {
     ff_thread: Option<thread::JoinHandle<Result<(), HlsError>>>,
     poll: Pin<Box<dyn Future<Output = PollResult>>>,
+    /// Why segments piped to ffmpeg stopped, which says more than ffmpeg's own error
+    input_error: Arc<Mutex<Option<HlsError>>>,
+    _pipe_buffer: Reservation<'static>,
+}
+
+/// What ffmpeg reads an HLS stream from
+pub enum Input {
+    /// A playlist, whose segments ffmpeg fetches itself
+    Playlist(String),
+    /// Packed AAC segments, fetched (with their fallbacks) and piped to ffmpeg
+    Segments(LocalBoxStream<'static, Result<Vec<u8>>>),
 }
 
 async fn poll_next_async(mut rx: PipeRead) -> PollResult {
@@ -47,25 +147,210 @@ async fn poll_next_async(mut rx: PipeRead) -> PollResult {
     Ok((Some(buf), rx))
 }
 
+/// Picks the index of the stream to remux. Segments may carry several programs
+/// and streams (e.g. timed ID3 metadata alongside the audio), so an AAC stream is
+/// preferred, then any other audio. Packets of every other stream are skipped.
+fn select_audio_stream(streams: impl Iterator<Item = (media::Type, codec::Id)>) -> Option<usize> {
+    let audio = streams
+        .enumerate()
+        .filter(|(_, (medium, _))| *medium == media::Type::Audio)
+        .collect::<Vec<_>>();
+    audio
+        .iter()
+        .find(|(_, (_, id))| *id == Id::AAC)
+        .or_else(|| audio.first())
+        .map(|(i, _)| *i)
+}
+
+/// Span of the audio packets read, in the audio stream's time base
+#[derive(Default)]
+struct Coverage {
+    start: Option<i64>,
+    end: Option<i64>,
+}
+
+impl Coverage {
+    fn add(&mut self, packet: &ffmpeg_next::Packet) {
+        if let Some(pts) = packet.pts() {
+            self.start = Some(self.start.map_or(pts, |start| start.min(pts)));
+            let end = pts + packet.duration();
+            self.end = Some(self.end.map_or(end, |e| e.max(end)));
+        }
+    }
+
+    /// Fails if less audio was read than the playlist lists
+    fn verify(&self, time_base: ffmpeg_next::Rational, expected_secs: f64) -> Result<()> {
+        let secs = match (self.start, self.end) {
+            (Some(start), Some(end)) => (end - start) as f64 * f64::from(time_base),
+            _ => 0.0,
+        };
+        if secs + TOTAL_TOLERANCE_SECS < expected_secs {
+            log::warn!(
+                "Remuxed {:.1}s of audio, the playlist lists {:.1}s",
+                secs,
+                expected_secs
+            );
+            return Err(HlsError::Truncated(format!(
+                "{:.1}s of audio, the playlist lists {:.1}s",
+                secs, expected_secs
+            )));
+        }
+        Ok(())
+    }
+}
+
 impl HlsStream {
-    pub fn new(url: String) -> Result<Self> {
+    /// Remuxes the HLS stream to ADTS, re-encoding it first if an audio format is given.
+    /// Timed ID3 metadata in the stream is sent to `metadata`. If the playlist's duration
+    /// is given, the stream fails at the end if less audio than that was read. Given a
+    /// span, only the audio within it is kept (and only its duration is expected).
+    /// Transcoded audio is fingerprinted as `episode_id`, if fingerprinting is enabled.
+    pub fn new(
+        input: Input,
+        audio_format: Option<AudioFormat>,
+        expected_secs: Option<f64>,
+        span: Option<Span>,
+        episode_id: Option<String>,
+        metadata: MetadataSender,
+    ) -> Result<Self> {
         let (rx, tx) = tokio_pipe::pipe()?;
 
+        let input_error = Arc::new(Mutex::new(None));
+        let (url, in_pipe) = match input {
+            Input::Playlist(url) => (url, None),
+            Input::Segments(mut segments) => {
+                let (in_rx, mut in_tx) = tokio_pipe::pipe()?;
+                let input_error = input_error.clone();
+                // ends when the segments do, or ffmpeg stops reading
+                actix_web::rt::spawn(async move {
+                    while let Some(data) = segments.next().await {
+                        let written = match data {
+                            Ok(data) => in_tx.write_all(&data).await.map_err(HlsError::from),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = written {
+                            *input_error.lock().unwrap() = Some(e);
+                            break;
+                        }
+                    }
+                });
+                (format!("pipe:{}", in_rx.as_raw_fd()), Some(in_rx))
+            }
+        };
+        // the segments are one continuous stream, which can't be seeked
+        let seekable = in_pipe.is_none();
+
         let ff_thread = thread::spawn(move || {
+            let _in_pipe = in_pipe;
             let out_pipe = format!("pipe:{}", tx.as_raw_fd());
 
-            ffmpeg_next::init()?;
+            let options = transcode::options();
+            transcode::set_thread_priority(options);
+            if options.copy_only {
+                // remuxing needs nothing beyond the (always registered) formats and networking
+                ffmpeg_next::format::network::init();
+            } else {
+                ffmpeg_next::init()?;
+            }
             ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Warning);
 
             let mut input = format::input(&url)?;
             let mut output = format::output_as(&out_pipe, "adts")?;
 
-            let (audio_stream_index, audio_stream) = input
+            let audio_stream_index = select_audio_stream(
+                input
+                    .streams()
+                    .map(|s| (s.parameters().medium(), s.parameters().id())),
+            )
+            .ok_or(HlsError::NoAudio)?;
+            let audio_stream = input
+                .stream(audio_stream_index)
+                .ok_or(HlsError::MissingStream("audio"))?;
+            let audio_time_base = audio_stream.time_base();
+            let stream_start = match audio_stream.start_time() {
+                // AV_NOPTS_VALUE
+                i64::MIN => 0,
+                start => start,
+            };
+            // where a packet falls relative to the span, by its time from the stream's start
+            let in_span = |packet: &ffmpeg_next::Packet| match (span, packet.pts()) {
+                (Some(span), Some(pts)) => {
+                    let secs = (pts - stream_start) as f64 * f64::from(audio_time_base);
+                    if span.is_past(secs) {
+                        None
+                    } else {
+                        Some(span.contains(secs))
+                    }
+                }
+                _ => Some(true),
+            };
+            // only the span's audio is expected, or what remains after its start if less
+            let expected_secs = match span {
+                Some(span) => {
+                    let remaining = expected_secs.map(|e| (e - span.start_secs).max(0.0));
+                    match (span.secs(), remaining) {
+                        (Some(secs), Some(remaining)) => Some(secs.min(remaining)),
+                        (secs, remaining) => secs.or(remaining),
+                    }
+                }
+                None => expected_secs,
+            };
+            let mut coverage = Coverage::default();
+            let verify = |coverage: &Coverage| match expected_secs {
+                Some(expected_secs) => coverage.verify(audio_time_base, expected_secs),
+                None => Ok(()),
+            };
+
+            let metadata_streams = input
                 .streams()
-                .into_iter()
-                .enumerate()
-                .find(|(_, s)| s.parameters().medium() == media::Type::Audio)
-                .ok_or(HlsError::NoAudio)?;
+                .filter(|s| s.parameters().id() == Id::TIMED_ID3)
+                .map(|s| s.index())
+                .collect::<Vec<_>>();
+            let read_metadata = |stream: &format::stream::Stream, packet: &ffmpeg_next::Packet| {
+                if metadata_streams.contains(&stream.index()) {
+                    if let Some(tag) = packet.data().and_then(id3::parse) {
+                        send_metadata(&metadata, tag);
+                    }
+                }
+            };
+
+            if let Some(audio_format) = audio_format {
+                let mut transcoder = Transcoder::new(&audio_stream, &mut output, &audio_format)?;
+                // only the whole episode is fingerprinted
+                if options.fingerprint && span.is_none() {
+                    transcoder.fingerprint();
+                }
+
+                output.set_metadata(input.metadata().to_owned());
+                output.write_header()?;
+                if seekable {
+                    seek_to_span(&mut input, span, stream_start, audio_time_base)?;
+                }
+
+                for (stream, packet) in input.packets() {
+                    read_metadata(&stream, &packet);
+                    if stream.index() == audio_stream_index {
+                        match in_span(&packet) {
+                            Some(true) => {}
+                            Some(false) => continue,
+                            None => break,
+                        }
+                        coverage.add(&packet);
+                        transcoder.send_packet(&packet, &mut output)?;
+                    }
+                }
+                transcoder.finish(&mut output)?;
+
+                output.write_trailer()?;
+
+                verify(&coverage)?;
+                if let Some((episode_id, fingerprint)) =
+                    episode_id.zip(transcoder.take_fingerprint())
+                {
+                    fingerprint::record(&episode_id, fingerprint);
+                }
+                return Ok(());
+            }
 
             if audio_stream.parameters().id() != Id::AAC {
                 return Err(HlsError::UnsupportedCodec);
@@ -83,13 +368,23 @@ impl HlsStream {
 
             output.set_metadata(input.metadata().to_owned());
             output.write_header()?;
+            if seekable {
+                seek_to_span(&mut input, span, stream_start, time_base)?;
+            }
 
             for (stream, mut packet) in input.packets() {
+                read_metadata(&stream, &packet);
                 if stream.index() != audio_stream_index {
                     continue;
                 }
+                match in_span(&packet) {
+                    Some(true) => {}
+                    Some(false) => continue,
+                    None => break,
+                }
+                coverage.add(&packet);
 
-                let output_stream = output.stream(0).unwrap();
+                let output_stream = output.stream(0).ok_or(HlsError::MissingStream("output"))?;
                 packet.rescale_ts(time_base, output_stream.time_base());
                 packet.set_position(-1);
                 packet.set_stream(0);
@@ -98,7 +393,7 @@ impl HlsStream {
 
             output.write_trailer()?;
 
-            Ok(())
+            verify(&coverage)
         });
 
         let poll = Box::pin(poll_next_async(rx));
@@ -106,7 +401,326 @@ impl HlsStream {
         Ok(HlsStream {
             ff_thread: Some(ff_thread),
             poll,
+            input_error,
+            _pipe_buffer: BUFFERS.track(PIPE_BUFFER_SIZE),
+        })
+    }
+}
+
+/// Seeks to the segment holding the start of the span, so the audio before it isn't all
+/// downloaded just to be dropped
+fn seek_to_span(
+    input: &mut format::context::Input,
+    span: Option<Span>,
+    stream_start: i64,
+    time_base: ffmpeg_next::Rational,
+) -> Result<()> {
+    if let Some(span) = span.filter(|s| s.start_secs > 0.0) {
+        // in AV_TIME_BASE (microseconds), as no stream is given
+        let start_us = stream_start as f64 * f64::from(time_base) * 1_000_000.0;
+        let target = (start_us + span.start_secs * 1_000_000.0) as i64;
+        input.seek(target, ..=target)?;
+    }
+    Ok(())
+}
+
+async fn get_playlist(url: &str) -> Result<Playlist> {
+    let text = fetch::get(url.to_string()).await?.text()?;
+    m3u8::parse(url, &text).ok_or(HlsError::PlaylistError)
+}
+
+fn is_aac_segment(uri: &str) -> bool {
+    url::Url::parse(uri).map_or(false, |u| u.path().ends_with(".aac"))
+}
+
+/// A media playlist, and if it was chosen from a master playlist, the variant it's for
+pub struct MediaSelection {
+    pub variant: Option<Variant>,
+    /// Of every variant in the master playlist
+    pub bandwidths: Vec<u64>,
+    pub playlist: MediaPlaylist,
+}
+
+/// The variant with the bandwidth nearest the pinned one, or else the highest
+fn select_variant(variants: Vec<Variant>, pinned_bandwidth: Option<u64>) -> Option<Variant> {
+    match pinned_bandwidth {
+        Some(pinned) => variants
+            .into_iter()
+            .min_by_key(|v| v.bandwidth.abs_diff(pinned)),
+        None => variants.into_iter().max_by_key(|v| v.bandwidth),
+    }
+}
+
+/// Fetches the media playlist for an HLS stream. If the URL is a master playlist, a
+/// variant is chosen by `select_variant` and returned alongside it.
+pub async fn get_media_playlist(
+    url: &str,
+    pinned_bandwidth: Option<u64>,
+) -> Result<MediaSelection> {
+    match get_playlist(url).await? {
+        Playlist::Master(variants) => {
+            let bandwidths = variants.iter().map(|v| v.bandwidth).collect();
+            let variant =
+                select_variant(variants, pinned_bandwidth).ok_or(HlsError::PlaylistError)?;
+
+            match get_playlist(&variant.uri).await? {
+                Playlist::Media(playlist) => Ok(MediaSelection {
+                    variant: Some(variant),
+                    bandwidths,
+                    playlist,
+                }),
+                Playlist::Master(_) => Err(HlsError::PlaylistError),
+            }
+        }
+        Playlist::Media(playlist) => Ok(MediaSelection {
+            variant: None,
+            bandwidths: Vec::new(),
+            playlist,
+        }),
+    }
+}
+
+/// Other CDNs' copies of a stream, which segments the chosen CDN fails to serve are
+/// fetched from instead
+#[derive(Clone, Debug, Default)]
+pub struct Fallbacks {
+    /// Playlists of the other connections, in the order they're tried
+    urls: Vec<String>,
+    /// Of the variant streamed, so the same one is chosen from their master playlists
+    bandwidth: Option<u64>,
+    /// Segments of the other connections' playlists, fetched when a segment first fails
+    /// and shared by the rest of the stream
+    segments: Arc<OnceCell<Vec<Vec<Segment>>>>,
+}
+
+impl Fallbacks {
+    pub fn new(urls: Vec<String>, bandwidth: Option<u64>) -> Self {
+        Fallbacks {
+            urls,
+            bandwidth,
+            segments: Arc::default(),
+        }
+    }
+
+    /// Segments of each other CDN whose playlist matches the stream's. Playlists only
+    /// match segment for segment if they list as many segments.
+    async fn playlists(&self, count: usize) -> &[Vec<Segment>] {
+        self.segments
+            .get_or_init(|| async {
+                let mut playlists = vec![];
+                for url in &self.urls {
+                    match get_media_playlist(url, self.bandwidth).await {
+                        Ok(selection) if selection.playlist.segments.len() == count => {
+                            playlists.push(selection.playlist.segments)
+                        }
+                        Ok(_) => log::warn!("Fallback playlist {} lists different segments", url),
+                        Err(e) => log::warn!("Fallback playlist {} failed: {}", url, e),
+                    }
+                }
+                playlists
+            })
+            .await
+    }
+
+    /// Fetches a segment from the first other CDN that serves it
+    async fn segment(&self, index: usize, count: usize) -> Option<Vec<u8>> {
+        for segments in self.playlists(count).await {
+            match segment_cache::get(&segments[index].uri).await {
+                Ok(data) => return Some(data),
+                Err(e) => log::warn!("Fallback segment {} failed: {}", segments[index].uri, e),
+            }
+        }
+        None
+    }
+}
+
+/// Returns the segments if the media playlist consists of packed AAC segments,
+/// which can be concatenated as-is without remuxing through ffmpeg.
+pub fn aac_segments(variant: Option<&Variant>, media: &MediaPlaylist) -> Option<Vec<Segment>> {
+    if let Some(codecs) = variant.and_then(|v| v.codecs.as_ref()) {
+        if !codecs.split(',').all(|c| c.trim().starts_with("mp4a")) {
+            return None;
+        }
+    }
+
+    if media.encrypted
+        || media.segments.is_empty()
+        || !media.segments.iter().all(|s| is_aac_segment(&s.uri))
+    {
+        return None;
+    }
+
+    Some(media.segments.clone())
+}
+
+/// Packed audio segments begin with an ID3 tag,
+/// which must be removed so the result is a continuous ADTS stream.
+/// Returns the tags found alongside the audio.
+fn strip_id3(mut data: Vec<u8>) -> (Vec<id3::Tag>, Vec<u8>) {
+    let mut tags = Vec::new();
+    while let Some(tag_len) = id3::tag_len(&data) {
+        tags.extend(id3::parse(&data));
+        data.drain(..tag_len.min(data.len()));
+    }
+    (tags, data)
+}
+
+fn is_adts(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == 0xff && data[1] & 0xf0 == 0xf0
+}
+
+/// Whether the first segment with audio is ADTS once its ID3 tags are removed, so the
+/// segments can be passed through. False if it can't be fetched, leaving them to ffmpeg.
+pub async fn is_passthrough(segments: &[Segment]) -> bool {
+    for segment in segments {
+        match segment_cache::get(&segment.uri).await {
+            Ok(data) => match strip_id3(data).1 {
+                // only metadata, so it says nothing about the audio
+                audio if audio.is_empty() => continue,
+                audio => return is_adts(&audio),
+            },
+            Err(e) => {
+                log::warn!("Couldn't fetch {} to check it's AAC: {}", segment.uri, e);
+                return false;
+            }
+        }
+    }
+    false
+}
+
+const ADTS_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Seconds of audio in a run of ADTS frames, up to the first frame that's cut short
+fn adts_duration(data: &[u8]) -> f64 {
+    let mut secs = 0.0;
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + 7) {
+        if !is_adts(header) {
+            break;
+        }
+        let frame_len = (usize::from(header[3] & 0x03) << 11)
+            | (usize::from(header[4]) << 3)
+            | usize::from(header[5] >> 5);
+        let sample_rate = match ADTS_SAMPLE_RATES.get(usize::from((header[2] >> 2) & 0x0f)) {
+            Some(rate) => *rate,
+            None => break,
+        };
+        if frame_len < 7 || offset + frame_len > data.len() {
+            break;
+        }
+        // each raw data block is 1024 samples
+        let blocks = u32::from(header[6] & 0x03) + 1;
+        secs += f64::from(blocks * 1024) / f64::from(sample_rate);
+        offset += frame_len;
+    }
+    secs
+}
+
+/// Fails if a segment has noticeably less audio than the playlist says it should
+fn verify_segment(index: usize, segment: &Segment, audio: &[u8]) -> Result<()> {
+    let secs = adts_duration(audio);
+    if secs + SEGMENT_TOLERANCE_SECS < segment.duration {
+        log::warn!(
+            "Segment {} has {:.2}s of audio, the playlist says {:.2}s: {}",
+            index + 1,
+            secs,
+            segment.duration,
+            segment.uri
+        );
+        return Err(HlsError::Truncated(format!(
+            "segment {} has {:.2}s of audio, the playlist says {:.2}s",
+            index + 1,
+            secs,
+            segment.duration
+        )));
+    }
+    Ok(())
+}
+
+/// A segment's audio, once its ID3 tags are sent to `metadata`, or None if it's only tags
+/// and the playlist doesn't say it has any audio
+fn segment_audio(
+    index: usize,
+    segment: &Segment,
+    data: Vec<u8>,
+    metadata: &MetadataSender,
+) -> Result<Option<Vec<u8>>> {
+    let (tags, data) = strip_id3(data);
+
+    for tag in tags {
+        send_metadata(metadata, tag);
+    }
+
+    if data.is_empty() {
+        // a segment the playlist gives a length is missing its audio
+        verify_segment(index, segment, &data)?;
+        log::debug!("Skipping segment {} of only ID3 tags", index + 1);
+        return Ok(None);
+    }
+    if !is_adts(&data) {
+        return Err(HlsError::UnsupportedCodec);
+    }
+    verify_segment(index, segment, &data)?;
+
+    Ok(Some(data))
+}
+
+/// Concatenates packed AAC segments into a single ADTS stream, failing if any
+/// segment is shorter than the playlist says. A segment that can't be downloaded is
+/// fetched from the fallbacks before the stream fails. Metadata in the segments' ID3
+/// tags is sent to `metadata`, and segments holding nothing else are skipped.
+pub fn aac_segment_stream(
+    segments: Vec<Segment>,
+    fallbacks: Fallbacks,
+    metadata: MetadataSender,
+) -> impl Stream<Item = Result<Vec<u8>>> {
+    let count = segments.len();
+    stream::iter(segments.into_iter().enumerate())
+        .then(move |(index, segment)| {
+            let fallbacks = fallbacks.clone();
+            async move {
+                let data = match segment_cache::get(&segment.uri).await {
+                    Ok(data) => data,
+                    Err(e) => {
+                        log::warn!("Segment {} failed, trying other CDNs: {}", segment.uri, e);
+                        fallbacks.segment(index, count).await.ok_or(e)?
+                    }
+                };
+                Ok::<_, HlsError>((index, segment, data))
+            }
         })
+        .filter_map(move |result| {
+            let audio = result
+                .and_then(|(index, segment, data)| segment_audio(index, &segment, data, &metadata));
+            futures::future::ready(audio.transpose())
+        })
+}
+
+/// The message a thread panicked with, if it's a string
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string())
+}
+
+/// Waits for a stream's first chunk, so a failure before any audio (e.g. the input
+/// not opening, or ffmpeg panicking) is an error response rather than an empty 200
+pub async fn started<S, T, E>(mut stream: S) -> Result<LocalBoxStream<'static, Result<T, E>>, E>
+where
+    S: Stream<Item = Result<T, E>> + Unpin + 'static,
+    T: 'static,
+    E: 'static,
+{
+    match stream.next().await {
+        Some(Err(e)) => Err(e),
+        Some(Ok(first)) => Ok(stream::once(async { Ok(first) })
+            .chain(stream)
+            .boxed_local()),
+        None => Ok(stream.boxed_local()),
     }
 }
 
@@ -124,13 +738,224 @@ impl Stream for HlsStream {
 
             Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
 
-            Poll::Ready(Ok((None, _))) => match self.ff_thread.take().unwrap().join() {
-                Ok(result) => match result {
-                    Ok(_) => Poll::Ready(None),
-                    Err(e) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(Ok((None, _))) => match self.ff_thread.take().map(|t| t.join()) {
+                None => Poll::Ready(None),
+                Some(Ok(result)) => match (self.input_error.lock().unwrap().take(), result) {
+                    (Some(e), _) | (None, Err(e)) => Poll::Ready(Some(Err(e))),
+                    (None, Ok(_)) => Poll::Ready(None),
                 },
-                Err(e) => panic::resume_unwind(e),
+                Some(Err(panic)) => {
+                    let cause = panic_message(panic.as_ref());
+                    log::error!("ffmpeg thread panicked: {}", cause);
+                    Poll::Ready(Some(Err(HlsError::Panicked(cause))))
+                }
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fallback_segments() {
+        // the chosen CDN can't be reached, so each segment comes from the other, whose
+        // segments were fetched before
+        let segment = |host: &str, n: usize| Segment {
+            uri: format!("{}/seg{}.aac", host, n),
+            duration: 0.0,
+        };
+        let segments = (0..2).map(|n| segment("unreachable://down", n)).collect();
+        let fallback = (0..2)
+            .map(|n| segment("https://fallback.example.com", n))
+            .collect::<Vec<_>>();
+        segment_cache::SEGMENTS.set_capacity(1024);
+        for (n, segment) in fallback.iter().enumerate() {
+            segment_cache::SEGMENTS.insert(&segment.uri, vec![0xff, 0xf1, n as u8].into());
+        }
+        let fallbacks = Fallbacks::new(vec![], None);
+        fallbacks.segments.set(vec![fallback]).unwrap();
+
+        let (metadata, _) = mpsc::channel(METADATA_CAPACITY);
+        let audio = aac_segment_stream(segments, fallbacks, metadata)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(audio, vec![vec![0xff, 0xf1, 0], vec![0xff, 0xf1, 1]]);
+    }
+
+    #[tokio::test]
+    async fn test_id3_only_segments() {
+        // timed metadata carried in a segment of its own, between two of audio
+        let tag = [
+            b"ID3\x03\x00\x00\x00\x00\x00\x10".as_ref(),
+            b"TIT2\x00\x00\x00\x06\x00\x00\x00Title",
+        ]
+        .concat();
+        segment_cache::SEGMENTS.set_capacity(1024);
+        let segments = |tag_duration| {
+            [
+                (vec![0xff, 0xf1, 0], 0.0),
+                (tag.clone(), tag_duration),
+                (vec![0xff, 0xf1, 1], 0.0),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(n, (data, duration))| {
+                let uri = format!("https://id3.example.com/{}/seg{}.aac", tag_duration, n);
+                segment_cache::SEGMENTS.insert(&uri, data.into());
+                Segment { uri, duration }
+            })
+            .collect::<Vec<_>>()
+        };
+        let stream = |segments| {
+            let (metadata, metadata_rx) = mpsc::channel(METADATA_CAPACITY);
+            let audio = aac_segment_stream(segments, Fallbacks::new(vec![], None), metadata)
+                .collect::<Vec<_>>();
+            (audio, metadata_rx)
+        };
+
+        let metadata_only = segments(0.0);
+        assert!(is_passthrough(&metadata_only[1..]).await);
+        let (audio, mut metadata_rx) = stream(metadata_only);
+        let audio = audio.await.into_iter().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(audio, vec![vec![0xff, 0xf1, 0], vec![0xff, 0xf1, 1]]);
+        assert_eq!(
+            metadata_rx.try_recv().unwrap().title.as_deref(),
+            Some("Title")
+        );
+
+        // the playlist says it's 4s long, so its audio is missing
+        let (audio, _) = stream(segments(4.0));
+        assert!(matches!(
+            audio.await.into_iter().collect::<Result<Vec<_>>>(),
+            Err(HlsError::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn test_strip_id3() {
+        // ID3v2.3 tag with a 6 byte TIT2 frame
+        let tag = [
+            b"ID3\x03\x00\x00\x00\x00\x00\x10".as_ref(),
+            b"TIT2\x00\x00\x00\x06\x00\x00\x00Title",
+        ]
+        .concat();
+        let adts = vec![0xff, 0xf1, 0x50, 0x80];
+
+        let (tags, audio) = strip_id3([tag.clone(), tag.clone(), adts.clone()].concat());
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].title.as_deref(), Some("Title"));
+        assert_eq!(audio, adts);
+
+        // no tag, and a tag cut short
+        assert_eq!(strip_id3(adts.clone()), (vec![], adts));
+        let (_, audio) = strip_id3(tag[..14].to_vec());
+        assert!(audio.is_empty());
+    }
+
+    #[test]
+    fn test_is_adts() {
+        assert!(is_adts(&[0xff, 0xf1, 0x50, 0x80]));
+        assert!(is_adts(&[0xff, 0xf9]));
+        assert!(!is_adts(&[0xff]));
+        assert!(!is_adts(&[0xff, 0xe1]));
+        assert!(!is_adts(b"ID3\x04"));
+        // an MPEG-TS sync byte
+        assert!(!is_adts(&[0x47, 0x40, 0x11]));
+    }
+
+    #[test]
+    fn test_select_variant() {
+        let variants = [48000, 96000, 320000]
+            .iter()
+            .map(|&bandwidth| Variant {
+                uri: format!("https://example.com/{}.m3u8", bandwidth),
+                bandwidth,
+                codecs: None,
+            })
+            .collect::<Vec<_>>();
+        let bandwidth = |pinned| select_variant(variants.clone(), pinned).unwrap().bandwidth;
+        assert_eq!(bandwidth(None), 320000);
+        assert_eq!(bandwidth(Some(128000)), 96000);
+        assert_eq!(bandwidth(Some(1_000_000)), 320000);
+        assert_eq!(select_variant(Vec::new(), None), None);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = std::thread::spawn(|| panic!("bad {}", "input"))
+            .join()
+            .unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "bad input");
+        let panic = std::thread::spawn(|| std::panic::panic_any(1))
+            .join()
+            .unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "unknown cause");
+    }
+
+    #[tokio::test]
+    async fn test_started() {
+        let failed = stream::iter(vec![Err::<u8, _>("no input")]);
+        assert_eq!(started(failed).await.err(), Some("no input"));
+
+        let ok = stream::iter(vec![Ok::<_, &str>(1), Err("later")]);
+        let items = started(ok).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(items, [Ok(1), Err("later")]);
+    }
+
+    #[test]
+    fn test_select_audio_stream() {
+        // a segment with ID3 timed metadata in its own program, before the audio
+        let streams = [
+            (media::Type::Data, Id::TIMED_ID3),
+            (media::Type::Audio, Id::MP3),
+            (media::Type::Audio, Id::AAC),
+        ];
+        assert_eq!(select_audio_stream(streams.into_iter()), Some(2));
+
+        assert_eq!(select_audio_stream(streams[..2].iter().copied()), Some(1));
+        assert_eq!(select_audio_stream(streams[..1].iter().copied()), None);
+    }
+
+    // An ADTS frame of one raw data block at 48 kHz, padded to `len` bytes
+    fn adts_frame(len: usize) -> Vec<u8> {
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            0x40 | (3 << 2),
+            0x80 | ((len >> 11) & 0x03) as u8,
+            ((len >> 3) & 0xff) as u8,
+            (((len & 0x07) << 5) | 0x1f) as u8,
+            0xfc,
+        ];
+        frame.resize(len, 0);
+        frame
+    }
+
+    #[test]
+    fn test_verify_segment() {
+        // 1024 samples at 48 kHz a frame, so 4s is 187.5 frames
+        let four_secs = (0..188).flat_map(|_| adts_frame(100)).collect::<Vec<_>>();
+        let segment = Segment {
+            uri: "https://example.com/segment-1.aac".into(),
+            duration: 4.0,
+        };
+
+        assert!((adts_duration(&four_secs) - 4.0107).abs() < 0.001);
+        assert!(verify_segment(0, &segment, &four_secs).is_ok());
+
+        // cut off half way through
+        let truncated = &four_secs[..four_secs.len() / 2 + 50];
+        assert!((adts_duration(truncated) - 2.0053).abs() < 0.001);
+        assert!(matches!(
+            verify_segment(0, &segment, truncated),
+            Err(HlsError::Truncated(_))
+        ));
+        assert!(verify_segment(0, &segment, &[]).is_err());
+    }
+}
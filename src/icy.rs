@@ -0,0 +1,150 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Stream, TryStreamExt};
+
+use crate::hls::{MetadataReceiver, TimedMetadata};
+
+/// Bytes of audio between metadata blocks
+pub const META_INT: usize = 16000;
+
+// The length byte counts 16 byte units
+const MAX_METADATA_LEN: usize = 255 * 16;
+
+/// True if the client asked for ICY metadata with `Icy-MetaData: 1`
+pub fn requested(req: &actix_web::HttpRequest) -> bool {
+    req.headers()
+        .get("Icy-MetaData")
+        .map_or(false, |v| v.as_bytes() == b"1")
+}
+
+/// Formats a metadata block giving the stream title
+fn metadata_block(title: &str) -> Bytes {
+    // there's no escaping, so quotes would end the title early
+    let mut title = title.replace('\'', "\u{2019}");
+
+    // must fit in one block
+    let mut max_len = MAX_METADATA_LEN - "StreamTitle='';".len();
+    if title.len() > max_len {
+        while !title.is_char_boundary(max_len) {
+            max_len -= 1;
+        }
+        title.truncate(max_len);
+    }
+
+    let text = format!("StreamTitle='{}';", title);
+
+    let units = text.len() / 16 + usize::from(text.len() % 16 != 0);
+    let mut block = BytesMut::with_capacity(1 + units * 16);
+    block.put_u8(units as u8);
+    block.put_slice(text.as_bytes());
+    block.resize(1 + units * 16, 0);
+    block.freeze()
+}
+
+/// Title for timed metadata, e.g. `Artist - Title`
+fn now_playing(metadata: TimedMetadata) -> Option<String> {
+    match (metadata.artist, metadata.title) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        (artist, title) => title.or(artist),
+    }
+}
+
+/// Interleaves ICY metadata into audio, with a block after every `META_INT` bytes.
+/// The title is sent in the first block. Later blocks carry timed metadata from the
+/// stream when it changes, and are otherwise empty.
+pub fn with_metadata<S, E>(
+    stream: S,
+    title: &str,
+    mut updates: Option<MetadataReceiver>,
+) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let mut metadata = Some(metadata_block(title));
+    let mut until_metadata = META_INT;
+    let mut next_update = move || {
+        let mut latest = None;
+        while let Some(update) = updates.as_mut().and_then(|u| u.try_recv().ok()) {
+            latest = now_playing(update).or(latest);
+        }
+        latest.map(|title| metadata_block(&title))
+    };
+
+    stream
+        .map_ok(move |mut chunk| {
+            let mut pieces = Vec::new();
+            while chunk.len() >= until_metadata {
+                pieces.push(chunk.split_to(until_metadata));
+                let update = next_update();
+                pieces.push(
+                    update
+                        .or_else(|| metadata.take())
+                        .unwrap_or_else(|| Bytes::from_static(&[0])),
+                );
+                // the episode title isn't worth sending after what's playing
+                metadata = None;
+                until_metadata = META_INT;
+            }
+            until_metadata -= chunk.len();
+            pieces.push(chunk);
+            futures::stream::iter(pieces.into_iter().map(Ok))
+        })
+        .try_flatten()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[test]
+    fn test_metadata_block() {
+        let block = metadata_block("It's on");
+        assert_eq!(block[0], 2);
+        assert_eq!(block.len(), 33);
+        assert!(block[1..].starts_with("StreamTitle='It\u{2019}s on';".as_bytes()));
+
+        let block = metadata_block(&"x".repeat(5000));
+        assert_eq!(block[0], 255);
+        assert_eq!(block.len(), 1 + MAX_METADATA_LEN);
+        assert!(block.ends_with(b"';"));
+    }
+
+    #[tokio::test]
+    async fn test_with_metadata() {
+        let audio = vec![1u8; META_INT * 2 + 10];
+        let chunks = audio
+            .chunks(7000)
+            .map(|c| Ok::<_, ()>(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+
+        let output = with_metadata(futures::stream::iter(chunks.clone()), "Title", None)
+            .map(|c| c.unwrap())
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+
+        let first = metadata_block("Title");
+        assert_eq!(output.len(), audio.len() + first.len() + 1);
+        assert_eq!(&output[META_INT..META_INT + first.len()], &first[..]);
+        assert_eq!(output[META_INT * 2 + first.len()], 0);
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        sender
+            .try_send(TimedMetadata {
+                title: Some("Song".to_string()),
+                artist: Some("Band".to_string()),
+            })
+            .unwrap();
+        let output = with_metadata(futures::stream::iter(chunks), "Title", Some(receiver))
+            .map(|c| c.unwrap())
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+
+        let playing = metadata_block("Band - Song");
+        assert_eq!(&output[META_INT..META_INT + playing.len()], &playing[..]);
+        assert_eq!(output[META_INT * 2 + playing.len()], 0);
+    }
+}
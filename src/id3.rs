@@ -0,0 +1,151 @@
+/// The parts of an ID3 tag used from HLS streams
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tag {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f))
+}
+
+/// Length of the ID3v2 tag at the start of the data, if there is one
+pub fn tag_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 10 || &data[0..3] != b"ID3" {
+        return None;
+    }
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + synchsafe(&data[6..10]) + footer)
+}
+
+fn decode_text(data: &[u8]) -> Option<String> {
+    let (encoding, text) = data.split_first()?;
+    let utf16 = |text: &[u8], big_endian: bool| {
+        let units = text
+            .chunks_exact(2)
+            .map(|c| {
+                if big_endian {
+                    u16::from_be_bytes([c[0], c[1]])
+                } else {
+                    u16::from_le_bytes([c[0], c[1]])
+                }
+            })
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    };
+    let text = match encoding {
+        0 => text.iter().map(|b| *b as char).collect(),
+        1 => match text {
+            [0xff, 0xfe, rest @ ..] => utf16(rest, false),
+            [0xfe, 0xff, rest @ ..] => utf16(rest, true),
+            _ => utf16(text, true),
+        },
+        2 => utf16(text, true),
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => return None,
+    };
+    let text = text.trim_end_matches('\0').trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+/// Parses the ID3v2.3 or 2.4 tag at the start of the data
+pub fn parse(data: &[u8]) -> Option<Tag> {
+    let len = tag_len(data)?.min(data.len());
+    let version = data[3];
+    if !(3..=4).contains(&version) {
+        return None;
+    }
+
+    let mut pos = 10;
+    if data[5] & 0x40 != 0 {
+        // extended header, its size includes itself only in 2.4
+        let size = data.get(10..14)?;
+        pos += match version {
+            4 => synchsafe(size),
+            _ => u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize + 4,
+        };
+    }
+
+    let mut tag = Tag::default();
+    while pos + 10 <= len && data[pos] != 0 {
+        let id = &data[pos..pos + 4];
+        let size = &data[pos + 4..pos + 8];
+        let size = match version {
+            4 => synchsafe(size),
+            _ => u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize,
+        };
+        let body = match data.get(pos + 10..pos + 10 + size) {
+            Some(body) => body,
+            None => break,
+        };
+        pos += 10 + size;
+
+        match id {
+            b"TIT2" => tag.title = decode_text(body),
+            b"TPE1" => tag.artist = decode_text(body),
+            _ => {}
+        }
+    }
+
+    Some(tag)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn frame(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend((body.len() as u32).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(body);
+        frame
+    }
+
+    fn tag(frames: &[Vec<u8>]) -> Vec<u8> {
+        let frames = frames.concat();
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let len = frames.len() + 4;
+        tag.extend([
+            (len >> 21) as u8 & 0x7f,
+            (len >> 14) as u8 & 0x7f,
+            (len >> 7) as u8 & 0x7f,
+            len as u8 & 0x7f,
+        ]);
+        tag.extend(frames);
+        // padding
+        tag.extend([0; 4]);
+        tag
+    }
+
+    #[test]
+    fn test_parse() {
+        // packed audio's timestamp, which is skipped
+        let mut priv_body = b"com.apple.streaming.transportStreamTimestamp".to_vec();
+        priv_body.push(0);
+        priv_body.extend(900000u64.to_be_bytes());
+
+        let data = tag(&[
+            frame(b"PRIV", &priv_body),
+            frame(b"TIT2", b"\x03Now playing\x00"),
+            frame(b"TPE1", b"\x01\xff\xfeA\x00B\x00"),
+        ]);
+        assert_eq!(tag_len(&data), Some(data.len()));
+        assert_eq!(
+            parse(&data),
+            Some(Tag {
+                title: Some("Now playing".to_string()),
+                artist: Some("AB".to_string()),
+            })
+        );
+
+        assert_eq!(parse(b"\xff\xf1 not a tag"), None);
+    }
+}
@@ -0,0 +1,279 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+
+use crate::bbc::BbcResponseError;
+
+/// Resident memory of this process, if it can be read (Linux only)
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    vm_rss(&status)
+}
+
+/// Resident memory in bytes from the contents of /proc/<pid>/status
+fn vm_rss(status: &str) -> Option<u64> {
+    let kb = status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/// Bytes held in stream buffers by every pipeline: pipes from ffmpeg, S3 upload
+/// parts, and what listeners following an upload haven't read yet, and by the
+/// segment cache they share.
+pub static BUFFERS: Lazy<BufferBudget> = Lazy::new(BufferBudget::default);
+
+/// Counts buffered bytes against an optional ceiling
+#[derive(Default)]
+pub struct BufferBudget {
+    used: AtomicUsize,
+    /// Bytes held by reservations which were waited for
+    reserved: AtomicUsize,
+    /// 0 for no limit
+    limit: AtomicUsize,
+    released: Notify,
+}
+
+impl BufferBudget {
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Release);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Acquire) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// True once the buffers have reached the limit
+    pub fn is_full(&self) -> bool {
+        self.limit().map_or(false, |limit| self.used() >= limit)
+    }
+
+    /// Counts bytes which are already held, without waiting
+    pub fn track(&self, bytes: usize) -> Reservation<'_> {
+        self.used.fetch_add(bytes, Ordering::AcqRel);
+        Reservation {
+            budget: self,
+            bytes,
+            reserved: false,
+        }
+    }
+
+    /// Waits until the bytes fit under the limit, then counts them. This is
+    /// granted whenever no other reservation is outstanding, even if tracked
+    /// bytes alone are over the limit, so waiting always ends.
+    pub async fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        loop {
+            // register before checking so a release in between isn't missed
+            let released = self.released.notified();
+            let fits = match self.limit() {
+                None => true,
+                Some(limit) => {
+                    self.reserved.load(Ordering::Acquire) == 0 || self.used() + bytes <= limit
+                }
+            };
+            if fits {
+                self.reserved.fetch_add(bytes, Ordering::AcqRel);
+                self.used.fetch_add(bytes, Ordering::AcqRel);
+                return Reservation {
+                    budget: self,
+                    bytes,
+                    reserved: true,
+                };
+            }
+            released.await;
+        }
+    }
+}
+
+/// Buffered bytes, which are uncounted when this is dropped
+pub struct Reservation<'a> {
+    budget: &'a BufferBudget,
+    bytes: usize,
+    reserved: bool,
+}
+
+impl Reservation<'_> {
+    /// Counts more bytes, without waiting
+    pub fn grow(&mut self, bytes: usize) {
+        self.budget.used.fetch_add(bytes, Ordering::AcqRel);
+        if self.reserved {
+            self.budget.reserved.fetch_add(bytes, Ordering::AcqRel);
+        }
+        self.bytes += bytes;
+    }
+
+    /// Stops counting bytes which are no longer held
+    pub fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.budget.used.fetch_sub(bytes, Ordering::AcqRel);
+        if self.reserved {
+            self.budget.reserved.fetch_sub(bytes, Ordering::AcqRel);
+        }
+        self.bytes -= bytes;
+        self.budget.released.notify_waiters();
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+        if self.reserved {
+            self.budget.reserved.fetch_sub(self.bytes, Ordering::AcqRel);
+        }
+        self.budget.released.notify_waiters();
+    }
+}
+
+/// Held while a stream or upload is running
+pub struct StreamPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl StreamPermit {
+    /// Keeps the permit until the stream is dropped
+    pub fn hold<S: Stream>(self, stream: S) -> impl Stream<Item = S::Item> {
+        stream.map(move |item| {
+            let _permit = &self;
+            item
+        })
+    }
+}
+
+/// Refuses new streams once too many are running or memory use is too high,
+/// so a small container sheds load rather than running out of memory.
+#[derive(Default)]
+pub struct StreamLimiter {
+    active: Arc<AtomicUsize>,
+    max_streams: Option<usize>,
+    memory_limit: Option<u64>,
+}
+
+impl StreamLimiter {
+    pub fn new(max_streams: Option<usize>, memory_limit: Option<u64>) -> Self {
+        StreamLimiter {
+            active: Arc::default(),
+            max_streams,
+            memory_limit,
+        }
+    }
+
+    /// Streams and uploads running
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// True while new streams would be refused for running streams or memory use
+    pub fn is_saturated(&self) -> bool {
+        let streams_full = self
+            .max_streams
+            .map_or(false, |max_streams| self.active() >= max_streams);
+        let memory_full = self.memory_limit.map_or(false, |limit| {
+            resident_memory().map_or(false, |used| used > limit)
+        });
+        streams_full || memory_full
+    }
+
+    pub fn try_acquire(&self) -> Result<StreamPermit, BbcResponseError> {
+        if BUFFERS.is_full() {
+            log::warn!("Stream buffers are full, refusing new stream");
+            return Err(BbcResponseError::Overloaded);
+        }
+
+        if let Some(limit) = self.memory_limit {
+            if resident_memory().map_or(false, |used| used > limit) {
+                log::warn!("Memory limit reached, refusing new stream");
+                return Err(BbcResponseError::Overloaded);
+            }
+        }
+
+        let max_streams = self.max_streams.unwrap_or(usize::MAX);
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max_streams).then(|| active + 1)
+            })
+            .map_err(|_| {
+                log::warn!("{} streams running, refusing new stream", max_streams);
+                BbcResponseError::Overloaded
+            })?;
+
+        Ok(StreamPermit {
+            active: self.active.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_max_streams() {
+        let limiter = StreamLimiter::new(Some(2), None);
+
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_err());
+
+        drop(first);
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_budget() {
+        let budget = BufferBudget::default();
+        budget.set_limit(Some(10));
+
+        let tracked = budget.track(8);
+        assert!(!budget.is_full());
+
+        // granted though over the limit, as nothing else is reserved
+        let first = budget.reserve(4).await;
+        assert_eq!(budget.used(), 12);
+        assert!(budget.is_full());
+
+        let mut second = Box::pin(budget.reserve(4));
+        assert!(futures::poll!(second.as_mut()).is_pending());
+
+        drop(first);
+        drop(tracked);
+        let mut second = second.await;
+        second.grow(2);
+        assert_eq!(budget.used(), 6);
+
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_vm_rss() {
+        let status =
+            "Name:\tsounds-proxy\nVmPeak:\t  204800 kB\nVmRSS:\t   10240 kB\nThreads:\t4\n";
+        assert_eq!(vm_rss(status), Some(10240 * 1024));
+        assert_eq!(vm_rss("Name:\tsounds-proxy\n"), None);
+        assert_eq!(vm_rss("VmRSS:\t   unknown kB\n"), None);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use bytes::Bytes;
+use futures::{future::join_all, StreamExt};
+use serde_json::Value;
+
+use crate::{
+    bbc,
+    config::Config,
+    fetch,
+    limits::{self, StreamLimiter},
+    segment_cache, sounds_proxy,
+};
+
+/// Show whose feed is fetched, the one in `payload_examples/container.json`
+const FEED_PID: &str = "p02pc9pj";
+const CONTAINER: &str = include_str!("../payload_examples/container.json");
+const MEDIA: &str = include_str!("../payload_examples/media.json");
+
+// Each mock episode is this many segments of this length
+const SEGMENTS: usize = 60;
+const SEGMENT_SECS: f64 = 10.0;
+// Memory is sampled this often while the test runs
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Serves what the proxy asks the BBC for: the recorded container for any show, and
+/// for any episode a media selector response pointing at a synthetic AAC playlist
+struct MockUpstream {
+    base_url: String,
+    media: Value,
+    listing: String,
+    segment: Bytes,
+}
+
+/// Ten seconds of AAC-LC ADTS frames. The audio isn't valid, but passing AAC
+/// through only reads the frame headers.
+fn mock_segment() -> Vec<u8> {
+    let frame_len: usize = 64;
+    let frames = (SEGMENT_SECS * 44100.0 / 1024.0).ceil() as usize;
+    let header = [
+        0xff,
+        0xf1,
+        // AAC-LC, 44.1 kHz
+        0x50,
+        // stereo
+        0x80 | ((frame_len >> 11) & 0x03) as u8,
+        ((frame_len >> 3) & 0xff) as u8,
+        (((frame_len & 0x07) << 5) | 0x1f) as u8,
+        0xfc,
+    ];
+    let mut frame = header.to_vec();
+    frame.resize(frame_len, 0);
+    frame.repeat(frames)
+}
+
+/// A page of the container's listing, as the BBC serves the pages after the first
+fn mock_listing() -> serde_json::Result<String> {
+    let container: Value = serde_json::from_str(CONTAINER)?;
+    let list = container["data"]
+        .as_array()
+        .and_then(|modules| modules.iter().find(|m| m["id"] == "container_list"))
+        .map_or(Value::Null, |list| list["data"].clone());
+    serde_json::to_string(&serde_json::json!({ "data": list }))
+}
+
+/// Points every connection in the media selector response at the mock playlist
+fn with_hrefs(value: &mut Value, href: &str) {
+    match value {
+        Value::Object(fields) => {
+            for (field, value) in fields {
+                if field == "href" {
+                    *value = Value::String(href.to_string());
+                } else {
+                    with_hrefs(value, href);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| with_hrefs(v, href)),
+        _ => {}
+    }
+}
+
+impl MockUpstream {
+    fn playlist(&self, pid: &str) -> String {
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:1\n",
+            SEGMENT_SECS
+        );
+        for n in 0..SEGMENTS {
+            playlist += &format!(
+                "#EXTINF:{:.1},\n{}/mock/{}/{}.aac\n",
+                SEGMENT_SECS, self.base_url, pid, n
+            );
+        }
+        playlist + "#EXT-X-ENDLIST\n"
+    }
+
+    fn media(&self, pid: &str) -> Value {
+        let mut media = self.media.clone();
+        with_hrefs(
+            &mut media,
+            &format!("{}/mock/{}/playlist.m3u8", self.base_url, pid),
+        );
+        media
+    }
+}
+
+async fn mock_upstream(req: HttpRequest, mock: web::Data<MockUpstream>) -> HttpResponse {
+    let path = req.path();
+    if path.starts_with("/rms.api.bbc.co.uk/v2/experience/inline/container/") {
+        return HttpResponse::Ok()
+            .content_type("application/json")
+            .body(CONTAINER);
+    }
+    // the rest of the container's listing, which is just its first page again, as
+    // repeated episodes are left out of feeds
+    if path.starts_with("/rms.api.bbc.co.uk/v2/programmes/playable") {
+        return HttpResponse::Ok()
+            .content_type("application/json")
+            .body(mock.listing.clone());
+    }
+    if let Some(rest) = path.strip_prefix("/open.live.bbc.co.uk/mediaselector/6/select/") {
+        let pid = rest
+            .split("/vpid/")
+            .nth(1)
+            .and_then(|p| p.split('/').next())
+            .unwrap_or_default();
+        return HttpResponse::Ok().json(mock.media(pid));
+    }
+    if let Some((pid, file)) = path.strip_prefix("/mock/").and_then(|p| p.split_once('/')) {
+        if file == "playlist.m3u8" {
+            return HttpResponse::Ok()
+                .content_type("application/vnd.apple.mpegurl")
+                .body(mock.playlist(pid));
+        }
+        if file.ends_with(".aac") {
+            return HttpResponse::Ok()
+                .content_type("audio/aac")
+                .body(mock.segment.clone());
+        }
+    }
+    // including the public mp3 redirector, so every episode is proxied
+    HttpResponse::NotFound().finish()
+}
+
+/// The latency `p` (0 to 1) of the way through the sorted latencies
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn summary(mut latencies: Vec<Duration>) -> String {
+    latencies.sort();
+    let ms = |p| percentile(&latencies, p).as_millis();
+    format!(
+        "p50 {}ms, p90 {}ms, p99 {}ms, max {}ms",
+        ms(0.5),
+        ms(0.9),
+        ms(0.99),
+        ms(1.0)
+    )
+}
+
+enum StreamOutcome {
+    /// Turned away by the stream limiter
+    Refused,
+    Failed,
+    Finished {
+        first_byte: Duration,
+        total: Duration,
+    },
+}
+
+async fn stream_episode(
+    limiter: &StreamLimiter,
+    pid: bbc::Pid,
+    variants: &[sounds_proxy::MediaVariant],
+) -> StreamOutcome {
+    let start = Instant::now();
+    let _permit = match limiter.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => return StreamOutcome::Refused,
+    };
+    let mut stream = match sounds_proxy::get_episode(&pid, None, variants, None, None).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("Streaming {} failed: {}", pid, e);
+            return StreamOutcome::Failed;
+        }
+    };
+    let mut first_byte = None;
+    while let Some(chunk) = stream.next().await {
+        if let Err(e) = chunk {
+            log::warn!("Streaming {} failed: {}", pid, e);
+            return StreamOutcome::Failed;
+        }
+        first_byte.get_or_insert_with(|| start.elapsed());
+    }
+    StreamOutcome::Finished {
+        first_byte: first_byte.unwrap_or_else(|| start.elapsed()),
+        total: start.elapsed(),
+    }
+}
+
+/// Fetches `feeds` feeds and streams `streams` episodes at once, from a mock upstream,
+/// with the proxy's limits configured as they would be for serving. Prints latency
+/// percentiles and the peak memory used.
+pub async fn run(config: &Config, feeds: usize, streams: usize) -> std::io::Result<()> {
+    limits::BUFFERS.set_limit(config.buffer_limit());
+    segment_cache::SEGMENTS.set_capacity(config.segment_cache_size());
+    let limiter = StreamLimiter::new(Some(config.max_streams()), config.memory_limit());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let mock = web::Data::new(MockUpstream {
+        base_url: base_url.clone(),
+        media: serde_json::from_str(MEDIA)?,
+        listing: mock_listing()?,
+        segment: mock_segment().into(),
+    });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(mock.clone())
+            .default_service(web::to(mock_upstream))
+    })
+    .workers(2)
+    .listen(listener)?
+    .run();
+    let server_handle = server.handle();
+    actix_web::rt::spawn(server);
+    fetch::set_upstream(base_url);
+
+    let feed_pid: bbc::Pid = FEED_PID.parse()?;
+    let options = config.feed_options(&feed_pid);
+    let feed_requests = (0..feeds).map(|_| async {
+        let start = Instant::now();
+        let result =
+            sounds_proxy::get_podcast_feed("http://localhost", &feed_pid, &options, 1).await;
+        if let Err(e) = &result {
+            log::warn!("Fetching the feed failed: {}", e);
+        }
+        (result.is_ok(), start.elapsed())
+    });
+
+    let variants = config.media_variants(None);
+    let stream_requests = (0..streams).map(|n| {
+        let pid: bbc::Pid = format!("p{:07}", n).parse().unwrap();
+        stream_episode(&limiter, pid, &variants)
+    });
+
+    println!(
+        "Fetching {} feeds and streaming {} {}s episodes at once",
+        feeds,
+        streams,
+        SEGMENTS as f64 * SEGMENT_SECS
+    );
+    let done = Cell::new(false);
+    let peak_memory = Cell::new(0);
+    let peak_buffers = Cell::new(0);
+    let work = async {
+        let results =
+            futures::future::join(join_all(feed_requests), join_all(stream_requests)).await;
+        done.set(true);
+        results
+    };
+    let sample = async {
+        while !done.get() {
+            peak_memory.set(
+                peak_memory
+                    .get()
+                    .max(limits::resident_memory().unwrap_or(0)),
+            );
+            peak_buffers.set(peak_buffers.get().max(limits::BUFFERS.used()));
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+        }
+    };
+    let ((feed_results, stream_results), ()) = futures::future::join(work, sample).await;
+    server_handle.stop(false).await;
+
+    let feeds_ok = feed_results.iter().filter(|(ok, _)| *ok).count();
+    println!(
+        "Feeds: {} ok, {} failed, {}",
+        feeds_ok,
+        feed_results.len() - feeds_ok,
+        summary(
+            feed_results
+                .into_iter()
+                .map(|(_, latency)| latency)
+                .collect()
+        )
+    );
+
+    let (mut refused, mut failed) = (0, 0);
+    let (mut first_bytes, mut totals) = (Vec::new(), Vec::new());
+    for outcome in stream_results {
+        match outcome {
+            StreamOutcome::Refused => refused += 1,
+            StreamOutcome::Failed => failed += 1,
+            StreamOutcome::Finished { first_byte, total } => {
+                first_bytes.push(first_byte);
+                totals.push(total);
+            }
+        }
+    }
+    println!(
+        "Streams: {} finished, {} refused, {} failed",
+        totals.len(),
+        refused,
+        failed
+    );
+    println!("  First byte: {}", summary(first_bytes));
+    println!("  Complete: {}", summary(totals));
+    println!(
+        "Peak memory: {} MB resident, {} MB in stream buffers",
+        peak_memory.get() / 1024 / 1024,
+        peak_buffers.get() / 1024 / 1024
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use url::Url;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    pub uri: String,
+    pub bandwidth: u64,
+    pub codecs: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub uri: String,
+    pub duration: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaPlaylist {
+    pub segments: Vec<Segment>,
+    pub encrypted: bool,
+    /// Encrypted with something other than AES-128, which ffmpeg can't decrypt
+    pub drm: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Playlist {
+    Master(Vec<Variant>),
+    Media(MediaPlaylist),
+}
+
+fn parse_attributes(attrs: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = attrs;
+
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let value = &quoted[..end];
+            rest = quoted.get(end + 1..).unwrap_or("");
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+
+        result.insert(key, value.to_string());
+        rest = rest.trim_start_matches(',');
+    }
+
+    result
+}
+
+fn resolve(base_url: &Url, uri: &str) -> Option<String> {
+    base_url.join(uri).ok().map(|u| u.to_string())
+}
+
+/// Parses a master or media playlist, resolving URIs relative to `base_url`.
+pub fn parse(base_url: &str, text: &str) -> Option<Playlist> {
+    let base_url = Url::parse(base_url).ok()?;
+
+    let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+    if lines.next()? != "#EXTM3U" {
+        return None;
+    }
+
+    let mut variants = Vec::new();
+    let mut segments = Vec::new();
+    let mut encrypted = false;
+    let mut drm = false;
+
+    let mut pending_variant: Option<HashMap<String, String>> = None;
+    let mut pending_duration: Option<f64> = None;
+
+    for line in lines {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending_variant = Some(parse_attributes(attrs));
+        } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let duration = info.split(',').next().unwrap_or("");
+            pending_duration = duration.trim().parse().ok();
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            let method = parse_attributes(attrs).remove("METHOD");
+            encrypted = method.as_ref().map_or(false, |m| m != "NONE");
+            drm = drm || method.map_or(false, |m| m != "NONE" && m != "AES-128");
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(attrs) = pending_variant.take() {
+            variants.push(Variant {
+                uri: resolve(&base_url, line)?,
+                bandwidth: attrs
+                    .get("BANDWIDTH")
+                    .and_then(|b| b.parse().ok())
+                    .unwrap_or(0),
+                codecs: attrs.get("CODECS").cloned(),
+            });
+        } else {
+            segments.push(Segment {
+                uri: resolve(&base_url, line)?,
+                duration: pending_duration.take().unwrap_or(0.0),
+            });
+        }
+    }
+
+    if !variants.is_empty() {
+        Some(Playlist::Master(variants))
+    } else {
+        Some(Playlist::Media(MediaPlaylist {
+            segments,
+            encrypted,
+            drm,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_master() {
+        let text = "#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=101760,CODECS=\"mp4a.40.5\"\n\
+            low/index.m3u8?token=1\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=355200,CODECS=\"mp4a.40.2\"\n\
+            https://cdn.example.com/high/index.m3u8\n";
+
+        let playlist = parse("https://example.com/a/master.m3u8", text).unwrap();
+
+        assert_eq!(
+            playlist,
+            Playlist::Master(vec![
+                Variant {
+                    uri: "https://example.com/a/low/index.m3u8?token=1".into(),
+                    bandwidth: 101760,
+                    codecs: Some("mp4a.40.5".into()),
+                },
+                Variant {
+                    uri: "https://cdn.example.com/high/index.m3u8".into(),
+                    bandwidth: 355200,
+                    codecs: Some("mp4a.40.2".into()),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_media() {
+        let text = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:7\n\
+            #EXTINF:6.4,\n\
+            segment-1.aac\n\
+            #EXTINF:3.2,\n\
+            segment-2.aac\n\
+            #EXT-X-ENDLIST\n";
+
+        let playlist = parse("https://example.com/a/index.m3u8", text).unwrap();
+
+        assert_eq!(
+            playlist,
+            Playlist::Media(MediaPlaylist {
+                segments: vec![
+                    Segment {
+                        uri: "https://example.com/a/segment-1.aac".into(),
+                        duration: 6.4,
+                    },
+                    Segment {
+                        uri: "https://example.com/a/segment-2.aac".into(),
+                        duration: 3.2,
+                    },
+                ],
+                encrypted: false,
+                drm: false,
+            })
+        );
+    }
+}
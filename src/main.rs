@@ -1,24 +1,115 @@
+use std::{os::unix::io::AsRawFd, sync::Arc};
+
 use actix_web::{
-    get, http::StatusCode, middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
-    ResponseError,
+    delete,
+    dev::Service,
+    get,
+    http::header::{Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue},
+    http::StatusCode,
+    middleware, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
 };
+use admission::{Admission, AdmissionTracker};
 use bytes::Bytes;
-use figment::{providers::Env, Figment};
-use futures::TryStreamExt;
-use serde::Deserialize;
+use config::{Config, PublicRedirect};
+use failures::{Blocked, FailureTracker};
+use futures::{Stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use limits::{StreamLimiter, StreamPermit};
+use notify::{Notification, Notifier};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use quality::Quality;
+use readiness::Readiness;
+use serde::{Deserialize, Serialize};
+use sounds_proxy::ResolutionStrategy;
+use stats::ResolutionStats;
+use tee::TeeBuffer;
+use transcode::AudioFormat;
+use upload_status::{Integrity, UploadState, UploadStatus};
 
+mod admin;
+mod admission;
+mod archive;
 mod bbc;
+mod browse;
+mod cdn;
+mod circuit;
+mod config;
+mod contract;
+mod credits;
+mod export;
+mod failures;
 mod fetch;
+mod fingerprint;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handoff;
+mod health;
 mod hls;
-mod s3_upload;
+mod icy;
+mod id3;
+mod limits;
+mod loadtest;
+mod m3u8;
+mod metrics;
+mod negotiate;
+mod notify;
+mod oembed;
+mod omnibus;
+mod opml;
+mod parents;
+mod progress;
+mod quality;
+mod range;
+mod readiness;
+mod resolver;
+mod s3;
+mod security_headers;
+mod segment_cache;
+mod signing;
 mod sounds_proxy;
+mod state;
+mod stats;
+mod tee;
+mod throttle;
+mod torrent;
+mod tracklist;
+mod transcode;
+mod tuning;
+mod upload_status;
+mod variant;
+mod version;
 mod web_utils;
 
+// Suggested polling interval for clients waiting on a background upload
+const UPLOAD_RETRY_AFTER_SECS: u32 = 30;
+
+// Newest private episodes uploaded by a prefetch, unless the request says
+const DEFAULT_PREFETCH_COUNT: usize = 5;
+// How long a prefetch waits for a stream to come free before trying again
+const PREFETCH_WAIT_SECS: u64 = 30;
+// Upcoming episodes are prefetched this long after they're due, as their media can lag behind
+const UPCOMING_GRACE_SECS: i64 = 5 * 60;
+
+// Listeners simulated by a load test, unless it says
+const DEFAULT_LOADTEST_FEEDS: usize = 50;
+const DEFAULT_LOADTEST_STREAMS: usize = 10;
+
+// Largest state bundle which may be imported
+const MAX_IMPORT_BYTES: usize = 256 * 1024 * 1024;
+
+// Streams are given this long to finish when stopping or reloading. Listeners cut off
+// after this reconnect, resuming with a range request.
+const DEFAULT_SHUTDOWN_SECS: u64 = 5 * 60;
+
 impl ResponseError for bbc::BbcResponseError {
     fn error_response(&self) -> HttpResponse {
         let (code, msg) = web_utils::get_http_response_for_bbc_error(self);
         let status = StatusCode::from_u16(code).unwrap();
-        HttpResponse::build(status).body(msg.unwrap_or_else(|| "".into()))
+        let mut response = HttpResponse::build(status);
+        if let Some(secs) = web_utils::retry_after_for_bbc_error(self) {
+            response.insert_header(("Retry-After", secs.to_string()));
+        }
+        response.body(msg.unwrap_or_else(|| "".into()))
     }
 
     fn status_code(&self) -> StatusCode {
@@ -27,194 +118,2322 @@ impl ResponseError for bbc::BbcResponseError {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize)]
-struct Config {
-    pub base_url: Option<String>,
-    pub listen_port: Option<u16>,
-    pub s3_bucket: Option<String>,
-    pub s3_base_url: Option<String>,
-    pub s3_endpoint_url: Option<String>,
-}
-
 #[get("/ok")]
 async fn ok() -> impl Responder {
     HttpResponse::Ok().body("ok")
 }
 
+/// The request's API key, if it has a configured one
+fn check_api_key(req: &HttpRequest, config: &Config) -> Result<String, bbc::BbcResponseError> {
+    let authorization = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .map(|h| h.to_str())
+        .transpose()?;
+    config
+        .api_key(authorization)
+        .map(str::to_string)
+        .ok_or(bbc::BbcResponseError::Forbidden)
+}
+
+fn check_admin(req: &HttpRequest, config: &Config) -> Result<(), bbc::BbcResponseError> {
+    let authorization = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .map(|h| h.to_str())
+        .transpose()?;
+    if config.is_admin(authorization) {
+        Ok(())
+    } else {
+        Err(bbc::BbcResponseError::Forbidden)
+    }
+}
+
+fn check_permitted(config: &Config, pid: &bbc::Pid) -> Result<(), bbc::BbcResponseError> {
+    if config.is_permitted(pid, &[]) {
+        Ok(())
+    } else {
+        Err(bbc::BbcResponseError::NotPermitted)
+    }
+}
+
+/// Checks an episode against the allow and deny lists by the shows the BBC says it's
+/// part of, as the `show` a client sends could be anything
+async fn check_episode_permitted(
+    config: &Config,
+    episode_id: &bbc::Pid,
+) -> Result<(), bbc::BbcResponseError> {
+    if !config.has_show_lists() {
+        return Ok(());
+    }
+    let parents = parents::get_parents(episode_id).await?;
+    if config.is_permitted(episode_id, &parents) {
+        Ok(())
+    } else {
+        Err(bbc::BbcResponseError::NotPermitted)
+    }
+}
+
+fn get_base_url(req: &HttpRequest, config: &Config) -> Result<String, bbc::BbcResponseError> {
+    match (&config.base_url, req.headers().get("Host")) {
+        (Some(url), _) => Ok(url.clone()),
+        (None, Some(host)) => Ok("https://".to_string() + host.to_str()?),
+        _ => Err(bbc::BbcResponseError::BadRequest),
+    }
+}
+
+/// True if the request is from a browser
+fn wants_html(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |accept| accept.contains("text/html"))
+}
+
+#[get("/browse/{category}")]
+async fn get_category(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    category: web::Path<String>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let category = category.into_inner();
+    let base_url = get_base_url(&req, &config)?;
+
+    let shows = browse::get_category(&base_url, &category).await?;
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("Cache-Control", "public, max-age=3600"));
+
+    // Browsers get a page, anything else JSON
+    if wants_html(&req) {
+        Ok(response
+            .content_type("text/html; charset=utf-8")
+            .body(browse::render_category(&category, &shows)))
+    } else {
+        Ok(response.json(shows))
+    }
+}
+
+/// The shows listed for OPML, as a subscription list of their feeds
+#[get("/opml")]
+async fn get_opml(
+    req: HttpRequest,
+    config: web::Data<Config>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let shows = config
+        .opml_shows
+        .as_ref()
+        .ok_or(bbc::BbcResponseError::NotFound)?
+        .iter()
+        .filter(|pid| config.is_permitted(pid, &[]))
+        .cloned()
+        .collect::<Vec<_>>();
+    let base_url = get_base_url(&req, &config)?;
+
+    let outlines = opml::get_outlines(&base_url, &shows).await;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/x-opml; charset=utf-8")
+        .insert_header(("Cache-Control", "public, max-age=3600"))
+        .body(opml::render("BBC Sounds", &outlines)))
+}
+
+#[get("/show/{pid}.m3u")]
+async fn get_m3u_playlist(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let id = pid.into_inner();
+    check_permitted(&config, &id)?;
+
+    let base_url = get_base_url(&req, &config)?;
+
+    let response =
+        sounds_proxy::get_m3u_playlist(&base_url, &id, &config.feed_options(&id)).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("Content-Type", "audio/x-mpegurl; charset=utf-8"))
+        .insert_header(("Cache-Control", "public, max-age=900"));
+    sign_response(&mut builder, &response);
+
+    Ok(cdn::with_headers(
+        config.cdn_max_age,
+        &[cdn::show_tag(&id)],
+        builder.body(response),
+    ))
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    /// Page of a feed too long for one document, from 1
+    page: Option<usize>,
+    /// Only episodes published from this date or time on
+    since: Option<String>,
+    /// Only episodes published before this date or time
+    until: Option<String>,
+    /// Only the most recent episodes, up to this many
+    limit: Option<usize>,
+    /// Oldest or newest episodes first, rather than the show's order
+    order: Option<sounds_proxy::EpisodeOrder>,
+}
+
+/// The show's feed options, narrowed by the query
+async fn query_feed_options(
+    req: &HttpRequest,
+    config: &Config,
+    s3_client: &s3::SharedClient,
+    id: &bbc::Pid,
+    query: &FeedQuery,
+) -> Result<sounds_proxy::FeedOptions, bbc::BbcResponseError> {
+    let mut options = config.feed_options(id);
+    let date = |value: &Option<String>| match value {
+        Some(value) => sounds_proxy::parse_date(value)
+            .map(Some)
+            .ok_or(bbc::BbcResponseError::BadRequest),
+        None => Ok(None),
+    };
+    options.since = date(&query.since)?;
+    options.until = date(&query.until)?;
+    if let Some(limit) = query.limit {
+        // which also stops a backfilled listing being read past the newest episodes
+        options.max_items = Some(options.max_items.map_or(limit, |max| max.min(limit)));
+    }
+    if let Some(order) = query.order {
+        options.order = order;
+    }
+    options.accept_languages = negotiate::languages(
+        req.headers()
+            .get(actix_web::http::header::ACCEPT_LANGUAGE)
+            .and_then(|h| h.to_str().ok()),
+    );
+    // Sizes of episodes already in S3 are exact, and used to estimate the rest, otherwise
+    // sizes are estimated from the bitrate
+    if let (Some(s3_client), Some(bucket)) = (s3_client.get(), &config.s3_bucket) {
+        match archive::get_archived_sizes(s3_client, bucket).await {
+            Ok(sizes) => options.known_sizes = sizes,
+            Err(e) => log::warn!("Couldn't list episode sizes: {}", e),
+        }
+    }
+    Ok(options)
+}
+
+#[get("/show/{pid}.json")]
+async fn get_json_feed(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    s3_client: web::Data<s3::SharedClient>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<FeedQuery>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let id = pid.into_inner();
+    check_permitted(&config, &id)?;
+
+    let base_url = get_base_url(&req, &config)?;
+    let options = query_feed_options(&req, &config, &s3_client, &id, &query).await?;
+
+    let response =
+        sounds_proxy::get_json_feed(&base_url, &id, &options, query.page.unwrap_or(1)).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("Content-Type", "application/feed+json"))
+        .insert_header(("Vary", "Accept-Language"))
+        .insert_header(("Cache-Control", "public, max-age=900"));
+    sign_response(&mut builder, &response);
+
+    Ok(cdn::with_headers(
+        config.cdn_max_age,
+        &[cdn::show_tag(&id)],
+        builder.body(response),
+    ))
+}
+
+#[get("/show/{pid}.atom")]
+async fn get_atom_feed(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    s3_client: web::Data<s3::SharedClient>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<FeedQuery>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let id = pid.into_inner();
+    check_permitted(&config, &id)?;
+
+    let base_url = get_base_url(&req, &config)?;
+    let options = query_feed_options(&req, &config, &s3_client, &id, &query).await?;
+
+    let response =
+        sounds_proxy::get_atom_feed(&base_url, &id, &options, query.page.unwrap_or(1)).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("Content-Type", negotiate::FeedFormat::Atom.content_type()))
+        .insert_header(("Vary", "Accept-Language"))
+        .insert_header(("Cache-Control", "public, max-age=900"));
+    sign_response(&mut builder, &response);
+
+    Ok(cdn::with_headers(
+        config.cdn_max_age,
+        &[cdn::show_tag(&id)],
+        builder.body(response),
+    ))
+}
+
 #[get("/show/{pid}")]
 async fn get_podcast_feed(
     req: HttpRequest,
     config: web::Data<Config>,
-    pid: web::Path<String>,
+    s3_client: web::Data<s3::SharedClient>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<FeedQuery>,
 ) -> Result<impl Responder, bbc::BbcResponseError> {
     let id = pid.into_inner();
+    check_permitted(&config, &id)?;
+
+    let format = negotiate::feed_format(
+        req.headers()
+            .get(actix_web::http::header::ACCEPT)
+            .and_then(|h| h.to_str().ok()),
+    )
+    .ok_or(bbc::BbcResponseError::NotAcceptable)?;
+
+    let base_url = get_base_url(&req, &config)?;
+    let options = query_feed_options(&req, &config, &s3_client, &id, &query).await?;
 
-    let base_url = match (&config.base_url, req.headers().get("Host")) {
-        (Some(url), _) => url.clone(),
-        (None, Some(host)) => "https://".to_string() + host.to_str()?,
-        _ => return Err(bbc::BbcResponseError::BadRequest),
+    let page = query.page.unwrap_or(1);
+    let feed_url = format!("{}/show/{}", base_url, id);
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("Content-Type", format.content_type()))
+        .insert_header(("Vary", "Accept, Accept-Language"))
+        .insert_header(("Cache-Control", "public, max-age=900"))
+        .insert_header((
+            actix_web::http::header::LINK,
+            oembed::discovery_link(&base_url, &feed_url),
+        ));
+
+    let response = match format {
+        negotiate::FeedFormat::Atom => {
+            let response = sounds_proxy::get_atom_feed(&base_url, &id, &options, page).await?;
+            sign_response(&mut builder, &response);
+            builder.body(response)
+        }
+        // a signature covers the whole body, so only unsigned feeds are written as they're listed
+        _ if signing::verifying_key().is_none() => {
+            match sounds_proxy::get_podcast_feed_chunks(&base_url, &id, &options, page).await? {
+                sounds_proxy::PodcastFeed::Whole(response) => builder.body(response),
+                sounds_proxy::PodcastFeed::Chunked(chunks) => {
+                    // a page of the listing failing leaves the feed cut short, which
+                    // mustn't be kept
+                    builder.insert_header(("Cache-Control", "no-store"));
+                    builder.streaming(chunks.map_ok(web::Bytes::from))
+                }
+            }
+        }
+        _ => {
+            let response = sounds_proxy::get_podcast_feed(&base_url, &id, &options, page).await?;
+            sign_response(&mut builder, &response);
+            builder.body(response)
+        }
     };
 
-    let response = sounds_proxy::get_podcast_feed(&base_url, &id).await?;
+    Ok(cdn::with_headers(
+        config.cdn_max_age,
+        &[cdn::show_tag(&id)],
+        response,
+    ))
+}
+
+#[derive(Deserialize)]
+struct CombinedQuery {
+    /// Comma separated ids of the shows to merge
+    pids: String,
+}
+
+/// One feed of several shows' episodes, e.g. `/combined?pids=p02pc9pj,b006qykl`
+#[get("/combined")]
+async fn get_combined_feed(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    query: web::Query<CombinedQuery>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let pids = query
+        .pids
+        .split(',')
+        .map(str::trim)
+        .filter(|pid| !pid.is_empty())
+        .map(|pid| pid.parse::<bbc::Pid>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| bbc::BbcResponseError::BadRequest)?
+        .into_iter()
+        .unique()
+        .collect::<Vec<_>>();
+    if pids.is_empty() || pids.len() > sounds_proxy::MAX_COMBINED_SHOWS {
+        return Err(bbc::BbcResponseError::BadRequest);
+    }
+    for pid in &pids {
+        check_permitted(&config, pid)?;
+    }
+
+    let base_url = get_base_url(&req, &config)?;
+    let feed_url = format!("{}/combined?pids={}", base_url, pids.iter().join(","));
+    let tags = pids.iter().map(cdn::show_tag).collect::<Vec<_>>();
+    let shows = pids
+        .into_iter()
+        .map(|pid| {
+            let options = config.feed_options(&pid);
+            (pid, options)
+        })
+        .collect::<Vec<_>>();
+
+    let response = sounds_proxy::get_combined_feed(&base_url, &shows, &feed_url).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header(("Content-Type", negotiate::FeedFormat::Rss.content_type()))
+        .insert_header(("Cache-Control", "public, max-age=900"));
+    sign_response(&mut builder, &response);
+
+    Ok(cdn::with_headers(
+        config.cdn_max_age,
+        &tags,
+        builder.body(response),
+    ))
+}
+
+/// Adds a detached signature of the body, if feeds are signed. It's of the body before
+/// any compression.
+fn sign_response(builder: &mut actix_web::HttpResponseBuilder, body: &str) {
+    if let Some(signature) = signing::sign(body.as_bytes()) {
+        builder.insert_header((signing::SIGNATURE_HEADER, signature));
+    }
+}
+
+/// Key feed signatures can be checked with
+#[get("/pubkey")]
+async fn get_public_key() -> Result<impl Responder, bbc::BbcResponseError> {
+    let key = signing::verifying_key().ok_or(bbc::BbcResponseError::NotFound)?;
 
     Ok(HttpResponse::Ok()
-        .insert_header(("Content-Type", "application/rss+xml"))
-        .insert_header(("Cache-Control", "public, max-age=900"))
-        .body(response))
+        .content_type("text/plain; charset=utf-8")
+        .insert_header(("Cache-Control", "public, max-age=86400"))
+        .body(key))
+}
+
+#[get("/feed.xsl")]
+async fn get_feed_stylesheet() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/xsl; charset=utf-8")
+        .insert_header(("Cache-Control", "public, max-age=86400"))
+        .body(sounds_proxy::FEED_STYLESHEET)
+}
+
+/// Proves to podcast directories that whoever runs the proxy owns its feeds
+#[get("/.well-known/podcast-verification")]
+async fn get_podcast_verification(
+    config: web::Data<Config>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let token = config
+        .verification_token
+        .clone()
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(token))
+}
+
+#[derive(Deserialize)]
+struct EpisodeQuery {
+    /// Show the episode belongs to, so per-show settings can be applied
+    show: Option<bbc::Pid>,
+    /// `1` to upload the episode to S3 again, replacing any existing copy (admin only)
+    refresh: Option<u8>,
+    /// Rendition to serve from S3, if a low quality one is kept
+    quality: Option<Quality>,
+    /// Part of an omnibus to serve, rather than the whole episode
+    part: Option<usize>,
+    /// Seconds into the episode to start from
+    start: Option<u64>,
+    /// Seconds into the episode to stop at
+    end: Option<u64>,
+}
+
+impl EpisodeQuery {
+    /// The span of the episode to serve, if it's trimmed
+    fn trim(&self) -> Result<Option<hls::Span>, bbc::BbcResponseError> {
+        match (self.start, self.end) {
+            (None, None) => Ok(None),
+            // offsets within a part aren't supported
+            _ if self.part.is_some() => Err(bbc::BbcResponseError::BadRequest),
+            (Some(start), Some(end)) if end <= start => Err(bbc::BbcResponseError::BadRequest),
+            (start, end) => Ok(Some(hls::Span {
+                start_secs: start.unwrap_or(0) as f64,
+                end_secs: end.map(|end| end as f64),
+            })),
+        }
+    }
 }
 
 #[get("/episode/{pid}.aac")]
+#[allow(clippy::too_many_arguments)]
 async fn get_episode_aac(
+    req: HttpRequest,
     config: web::Data<Config>,
-    pid: web::Path<String>,
-) -> Result<impl Responder, bbc::BbcResponseError> {
+    upload_status: web::Data<UploadStatus>,
+    failures: web::Data<FailureTracker>,
+    limiter: web::Data<StreamLimiter>,
+    stats: web::Data<ResolutionStats>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<EpisodeQuery>,
+) -> Result<HttpResponse, bbc::BbcResponseError> {
+    check_episode_permitted(&config, &pid).await?;
+    let show = query.show.clone();
+    let result = serve_episode_aac(req, config, upload_status, failures, limiter, pid, query).await;
+    match &result {
+        // the proxy was busy or is backing off, which says nothing about the episode
+        Ok(response) if response.status().is_server_error() => {}
+        _ => stats.record(show.as_ref(), &result),
+    }
+    result
+}
+
+async fn serve_episode_aac(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    upload_status: web::Data<UploadStatus>,
+    failures: web::Data<FailureTracker>,
+    limiter: web::Data<StreamLimiter>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<EpisodeQuery>,
+) -> Result<HttpResponse, bbc::BbcResponseError> {
+    let tags = cdn::episode_tags(&pid, query.show.as_ref());
     {
         let episode_id = pid.into_inner();
+        // per-show settings follow the show the BBC lists the episode in
+        let show = parents::configured_show(&config, &episode_id).await?;
+        let resolution = config.resolution(show.as_ref());
+        let audio_format = config.audio_format(show.as_ref());
 
-        if let Some(url) = sounds_proxy::get_episode_url(&episode_id).await? {
+        let refresh = query.refresh == Some(1);
+        if refresh {
+            check_admin(&req, &config)?;
+        }
+
+        // Parts of an omnibus and trimmed audio are cut from the whole episode,
+        // so they're never redirected to
+        let trim = query.trim()?;
+        let part = match query.part {
+            Some(number) => Some(omnibus::get_part(&episode_id, number).await?),
+            None => None,
+        };
+        let public_url = match (&part, trim) {
+            (None, None) => sounds_proxy::get_episode_url(&episode_id, &resolution).await?,
+            _ => None,
+        };
+
+        if let Some(url) = public_url {
             // Public episode
 
-            Ok(HttpResponse::PermanentRedirect()
-                .insert_header((actix_web::http::header::LOCATION, url))
-                .finish())
-        } else {
-            // Private episode, serve directly
+            Ok(public_redirect(&config, url))
+        } else if !resolution.contains(&ResolutionStrategy::Proxy) {
+            Err(bbc::BbcResponseError::NotFound)
+        } else if let Some((s3_client, region)) = match trim {
+            // Any number of trims may be asked for, so they're streamed rather than kept
+            Some(_) => None,
+            None => s3::create_client(&config.s3_bucket, &config.s3_endpoint_url).await,
+        } {
+            // Private episode, serve from S3
+
+            let bucket = config.s3_bucket.clone().unwrap();
+            let quality = match config.s3_low_quality {
+                Some(_) => Quality::for_request(&req, query.quality),
+                None => Quality::Hi,
+            };
+            let audio_format = match quality {
+                Quality::Hi => audio_format,
+                Quality::Lo => config.s3_low_quality,
+            };
+            let s3_path = quality::s3_key(
+                &episode_id,
+                quality,
+                config.rendition(show.as_ref()).as_deref(),
+            );
+            let s3_path = match &part {
+                Some(part) => omnibus::s3_key(&s3_path, part.number),
+                None => s3_path,
+            };
+            let url = s3_url(&config, &bucket, &region, &s3_path);
+            // Caches mustn't give one client's rendition to another
+            let vary = config
+                .s3_low_quality
+                .map(|_| (actix_web::http::header::VARY, quality::HINT_HEADERS));
 
-            let stream = sounds_proxy::get_episode(&episode_id).await?;
+            if !refresh && s3::object_exists(&s3_client, &bucket, &s3_path).await? {
+                let mut response = HttpResponse::TemporaryRedirect();
+                if let Some(vary) = vary {
+                    response.insert_header(vary);
+                }
+                return Ok(response
+                    .insert_header((actix_web::http::header::LOCATION, url))
+                    .finish());
+            }
 
-            if let Some((s3_client, region)) =
-                create_s3_client(&config.s3_bucket, &config.s3_endpoint_url).await
+            // Episodes which aren't worth keeping are streamed without being uploaded,
+            // unless an admin asks for them to be, or they're being uploaded anyway
+            let uploading = upload_status.get(&s3_path) == Some(UploadState::InProgress);
+            if !refresh
+                && !uploading
+                && !admitted(&req, &config, show.as_ref(), &episode_id).await?
             {
-                let bucket = config.s3_bucket.clone().unwrap();
-                let stream = stream.map_ok(Bytes::from).map_err(|e| e.into());
-
-                let s3_path = format!("{}.aac", episode_id);
-                log::debug!("Uploading episode to s3://{}/{}", bucket, s3_path);
-
-                s3_upload::try_put_async_stream(
-                    &s3_client,
-                    &bucket,
-                    stream,
-                    &s3_path,
-                    Some("audio/aac"),
+                return stream_private_episode(
+                    &req,
+                    &config,
+                    &limiter,
+                    query.show.as_ref(),
+                    &episode_id,
+                    part.as_ref().map(omnibus::Part::span),
+                    part.as_ref(),
                 )
-                .await?;
+                .await;
+            }
 
-                let url = match &config.s3_base_url {
-                    Some(base_url) => format!("{}/{}.aac", base_url, episode_id),
-                    None => format!(
-                        "https://{}.s3.{}.amazonaws.com/{}.aac",
-                        bucket, region, episode_id
-                    ),
-                };
+            // An admin refresh retries regardless
+            if !refresh {
+                match failures.check(&episode_id, chrono::Utc::now()) {
+                    Some(Blocked::RetryAt(retry_at)) => {
+                        let retry_after = (retry_at - chrono::Utc::now()).num_seconds().max(1);
+                        return Ok(HttpResponse::ServiceUnavailable()
+                            .insert_header((actix_web::http::header::RETRY_AFTER, retry_after))
+                            .body("Episode failed recently, retry later"));
+                    }
+                    Some(Blocked::Quarantined) => {
+                        return Ok(HttpResponse::ServiceUnavailable()
+                            .body("Episode quarantined after repeated failures"));
+                    }
+                    None => {}
+                }
+            }
 
-                Ok(HttpResponse::TemporaryRedirect()
-                    .insert_header((actix_web::http::header::LOCATION, url))
-                    .finish())
-            } else {
-                let stream = stream.map_ok(|bytes| bytes.into());
+            // Either an upload or a listener following one
+            let permit = limiter.try_acquire()?;
+
+            let buffer = match upload_status.try_start(&s3_path, refresh) {
+                Some(buffer) => buffer,
+                None => {
+                    // Already being uploaded, so serve the data uploaded so far
+                    // rather than starting another upload
+                    let follower = upload_status.buffer(&s3_path).map(TeeBuffer::follow);
+                    return match follower {
+                        Some(Some(stream)) => {
+                            let title = episode_title(query.show.as_ref(), &episode_id).await;
+                            let title = part_title(title, part.as_ref());
+                            Ok(stream_episode(
+                                &req,
+                                &title,
+                                "no-store",
+                                permit.hold(stream),
+                                None,
+                            ))
+                        }
+                        // too far along to follow from the start
+                        Some(None) => {
+                            drop(permit);
+                            stream_private_episode(
+                                &req,
+                                &config,
+                                &limiter,
+                                query.show.as_ref(),
+                                &episode_id,
+                                part.as_ref().map(omnibus::Part::span),
+                                part.as_ref(),
+                            )
+                            .await
+                        }
+                        // finished in the meantime
+                        None => Ok(HttpResponse::TemporaryRedirect()
+                            .insert_header((actix_web::http::header::LOCATION, url))
+                            .finish()),
+                    };
+                }
+            };
+
+            let title = episode_title(query.show.as_ref(), &episode_id).await;
+            let title = part_title(title, part.as_ref());
+            // Runs on its own, so it finishes even if this listener goes away
+            let upload = actix_web::rt::spawn(
+                EpisodeUpload {
+                    upload_status: upload_status.clone(),
+                    failures: failures.clone(),
+                    notifier: req.app_data::<web::Data<Notifier>>().cloned(),
+                    s3_client,
+                    bucket,
+                    episode_id: episode_id.clone(),
+                    s3_path,
+                    audio_format,
+                    media_variants: config.media_variants(show.as_ref()),
+                    hls_bandwidth: config.hls_bandwidth(show.as_ref()),
+                    span: part.as_ref().map(omnibus::Part::span),
+                    encryption: config.s3_encryption(),
+                    bandwidth: config.upload_bytes_per_sec(),
+                    title,
+                    refresh,
+                    // a refreshed episode replaces what the CDN has cached
+                    cdn_purge: config.cdn_purge.clone().filter(|_| refresh),
+                }
+                .run(permit, buffer),
+            );
 
-                Ok(HttpResponse::Ok()
-                    .content_type("audio/aac".to_string())
-                    .insert_header(("Cache-Control", "public, max-age=604800"))
-                    .streaming(stream))
+            if config.s3_async_upload.unwrap_or(false) {
+                return Ok(HttpResponse::Accepted()
+                    .insert_header((
+                        actix_web::http::header::LOCATION,
+                        format!(
+                            "{}/api/status/{}{}",
+                            config.base_url.as_ref().unwrap_or(&"".to_string()),
+                            episode_id,
+                            status_query(quality, part.as_ref())
+                        ),
+                    ))
+                    .insert_header((
+                        actix_web::http::header::RETRY_AFTER,
+                        UPLOAD_RETRY_AFTER_SECS,
+                    ))
+                    .finish());
             }
+
+            upload
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+
+            let mut response = HttpResponse::TemporaryRedirect();
+            if let Some(vary) = vary {
+                response.insert_header(vary);
+            }
+            Ok(response
+                .insert_header((actix_web::http::header::LOCATION, url))
+                .finish())
+        } else {
+            // Private episode, serve directly
+
+            stream_private_episode(
+                &req,
+                &config,
+                &limiter,
+                query.show.as_ref(),
+                &episode_id,
+                trim.or_else(|| part.as_ref().map(omnibus::Part::span)),
+                part.as_ref(),
+            )
+            .await
         }
     }
+    .map(|response| cdn::with_headers(config.cdn_max_age, &tags, response))
     .map_err(|e| {
         log::debug!("{}", e);
         e
     })
 }
 
-#[get("/episode/{pid}")]
-async fn get_episode(
-    config: web::Data<Config>,
-    pid: web::Path<String>,
+/// Whether a private episode not yet in S3 should be uploaded there, under the admission
+/// policy of the show with its settings. Counts the request towards `repeat` admission.
+async fn admitted(
+    req: &HttpRequest,
+    config: &Config,
+    show: Option<&bbc::Pid>,
+    episode_id: &bbc::Pid,
+) -> Result<bool, bbc::BbcResponseError> {
+    Ok(match config.s3_admission(show) {
+        Admission::Always => true,
+        // subscribed to any show the BBC lists it in
+        Admission::Subscribed => {
+            let parents = parents::get_parents(episode_id).await?;
+            config
+                .subscriptions
+                .iter()
+                .flatten()
+                .any(|s| parents.contains(s))
+        }
+        Admission::Repeat => {
+            req.app_data::<web::Data<AdmissionTracker>>()
+                .map_or(true, |tracker| {
+                    tracker.repeat_request(
+                        episode_id,
+                        config.s3_admission_window(),
+                        chrono::Utc::now(),
+                    )
+                })
+        }
+    })
+}
+
+/// Streams a private episode from the BBC, without keeping it anywhere. `show` is only
+/// used to title it.
+async fn stream_private_episode(
+    req: &HttpRequest,
+    config: &Config,
+    limiter: &StreamLimiter,
+    show: Option<&bbc::Pid>,
+    episode_id: &bbc::Pid,
+    span: Option<hls::Span>,
+    part: Option<&omnibus::Part>,
+) -> Result<HttpResponse, bbc::BbcResponseError> {
+    let permit = limiter.try_acquire()?;
+
+    let settings = parents::configured_show(config, episode_id).await?;
+    let (stream, metadata, _) = sounds_proxy::get_episode_with_metadata(
+        episode_id,
+        config.audio_format(settings.as_ref()),
+        &config.media_variants(settings.as_ref()),
+        config.hls_bandwidth(settings.as_ref()),
+        span,
+    )
+    .await?;
+    let stream = stream.map_ok(|bytes| bytes.into());
+
+    let title = episode_title(show, episode_id).await;
+    let title = part_title(title, part);
+    Ok(stream_episode(
+        req,
+        &title,
+        "public, max-age=604800",
+        permit.hold(stream),
+        Some(metadata),
+    ))
+}
+
+#[get("/episode/{pid}/chapters.json")]
+async fn get_episode_chapters(
+    pid: web::Path<bbc::Pid>,
 ) -> Result<impl Responder, bbc::BbcResponseError> {
     let episode_id = pid.into_inner();
 
-    if let Some(url) = sounds_proxy::get_episode_url(&episode_id).await? {
-        // Public episode
+    let chapters = sounds_proxy::get_chapters(&episode_id).await?;
 
-        Ok(HttpResponse::PermanentRedirect()
-            .insert_header((actix_web::http::header::LOCATION, url))
-            .finish())
-    } else {
-        // Private episode, serve directly
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", sounds_proxy::CHAPTERS_CONTENT_TYPE))
+        .insert_header(("Cache-Control", "public, max-age=900"))
+        .body(chapters))
+}
 
-        // At the moment only aac streams are supported
-        Ok(HttpResponse::TemporaryRedirect()
-            .insert_header((
-                actix_web::http::header::LOCATION,
-                format!(
-                    "{}/episode/{}.aac",
-                    config.base_url.as_ref().unwrap_or(&"".to_string()),
-                    episode_id
-                ),
-            ))
-            .finish())
-    }
+#[get("/archive/{pid}/")]
+async fn get_archive_show(
+    config: web::Data<Config>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let id = pid.into_inner();
+    check_permitted(&config, &id)?;
+
+    let (s3_client, _) = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url)
+        .await
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let bucket = config.s3_bucket.clone().unwrap();
+
+    let rendition = config.rendition(Some(&id));
+    let index = archive::get_show_index(&id, rendition.as_deref(), &s3_client, &bucket).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(index))
 }
 
-async fn create_s3_client(
-    bucket: &Option<String>,
-    endpoint: &Option<String>,
-) -> Option<(aws_sdk_s3::client::Client, String)> {
-    if let Some(bucket) = bucket {
-        let config_loader = aws_config::from_env();
-        let config_loader = match endpoint {
-            Some(endpoint) => {
-                let url = endpoint.parse().unwrap();
-                config_loader.endpoint_resolver(aws_sdk_s3::Endpoint::immutable(url))
-            }
-            None => config_loader,
-        };
-        let config = config_loader.load().await;
-        let client = aws_sdk_s3::Client::new(&config);
+#[get("/archive/{pid}/{year}/")]
+async fn get_archive_year(
+    config: web::Data<Config>,
+    path: web::Path<(bbc::Pid, String)>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let (id, year) = path.into_inner();
+    check_permitted(&config, &id)?;
 
-        let region = client
-            .get_bucket_location()
-            .bucket(bucket)
-            .send()
-            .await
-            .unwrap_or_else(|_| panic!("Failed to get bucket location for {}", bucket))
-            .location_constraint
-            .map_or_else(|| "us-east-1".to_string(), |region| region.as_str().into());
+    let (s3_client, _) = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url)
+        .await
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let bucket = config.s3_bucket.clone().unwrap();
+
+    let rendition = config.rendition(Some(&id));
+    let index =
+        archive::get_year_index(&id, rendition.as_deref(), &year, &s3_client, &bucket).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(index))
+}
+
+#[get("/archive/{pid}/{year}/{filename}")]
+async fn get_archive_episode(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    path: web::Path<(bbc::Pid, String, String)>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let (id, _, filename) = path.into_inner();
+    check_permitted(&config, &id)?;
+
+    let episode_id =
+        archive::parse_episode_filename(&filename).ok_or(bbc::BbcResponseError::NotFound)?;
+    check_episode_permitted(&config, &episode_id).await?;
 
-        Some((client, region))
+    let (s3_client, _) = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url)
+        .await
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let bucket = config.s3_bucket.clone().unwrap();
+    let s3_path = quality::s3_key(
+        &episode_id,
+        Quality::Hi,
+        config.rendition(Some(&id)).as_deref(),
+    );
+
+    // The size is needed up front to answer range requests, which players use to seek
+    let head = s3::object_head(&s3_client, &bucket, &s3_path)
+        .await?
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let size = head.size;
+    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok());
+    // a download resumed after the episode was replaced starts again
+    let range = if range::if_range_matches(
+        header(actix_web::http::header::IF_RANGE),
+        head.e_tag.as_deref(),
+        head.last_modified,
+    ) {
+        range::parse(header(actix_web::http::header::RANGE), size)
     } else {
-        None
+        range::ByteRange::Full
+    };
+
+    let mut response = match range {
+        range::ByteRange::Full => HttpResponse::Ok(),
+        range::ByteRange::Partial(_, _) => HttpResponse::PartialContent(),
+        range::ByteRange::Unsatisfiable => HttpResponse::RangeNotSatisfiable(),
+    };
+    response.insert_header((actix_web::http::header::ACCEPT_RANGES, "bytes"));
+    // validators for If-Range, so an interrupted download can be resumed
+    if let Some(e_tag) = &head.e_tag {
+        response.insert_header((actix_web::http::header::ETAG, e_tag.as_str()));
+    }
+    if let Some(last_modified) = head.last_modified {
+        response.insert_header((
+            actix_web::http::header::LAST_MODIFIED,
+            last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ));
+    }
+    if let Some(content_range) = range.content_range(size) {
+        response.insert_header((actix_web::http::header::CONTENT_RANGE, content_range));
     }
+    let object_range = match range {
+        range::ByteRange::Full => None,
+        range::ByteRange::Partial(start, end) => Some((start, end)),
+        range::ByteRange::Unsatisfiable => return Ok(response.finish()),
+    };
+
+    // Served directly rather than redirected, so the file keeps its archive name
+    let object = s3::get_object(&s3_client, &bucket, &s3_path, object_range)
+        .await?
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+
+    Ok(response
+        .content_type(
+            object
+                .content_type
+                .unwrap_or_else(|| "audio/aac".to_string()),
+        )
+        .insert_header(("Cache-Control", "public, max-age=604800"))
+        .no_chunking(object.content_length)
+        .streaming(object.body))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
+#[get("/archive/{pid}/{year}/{filename}.torrent")]
+async fn get_archive_torrent(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    path: web::Path<(bbc::Pid, String, String)>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let (id, year, filename) = path.into_inner();
+    check_permitted(&config, &id)?;
+    // the year goes into the web seed, so it mustn't be anything else
+    if !archive::is_year_folder(&year) {
+        return Err(bbc::BbcResponseError::BadRequest);
+    }
 
-    let figment = Figment::new().merge(Env::prefixed("SOUNDS_PROXY_"));
-    let config: Config = figment
-        .extract()
-        .map_err(|e| {
-            println!("{}", e);
-            println!("Set config fields by prefixing environment variables with 'SOUNDS_PROXY_'");
-            e
-        })
-        .unwrap();
-    let port = config.listen_port.unwrap_or(8080);
+    let episode_id =
+        archive::parse_episode_filename(&filename).ok_or(bbc::BbcResponseError::NotFound)?;
+    check_episode_permitted(&config, &episode_id).await?;
+    let base_url = get_base_url(&req, &config)?;
 
-    // create bucket to test config (will panic if bad)
-    create_s3_client(&config.s3_bucket, &config.s3_endpoint_url).await;
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(config.clone()))
-            .wrap(middleware::Compress::default())
-            .service(get_podcast_feed)
-            .service(get_episode_aac)
-            .service(get_episode)
+    let (s3_client, region) = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url)
+        .await
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let bucket = config.s3_bucket.clone().unwrap();
+    let s3_path = quality::s3_key(
+        &episode_id,
+        Quality::Hi,
+        config.rendition(Some(&id)).as_deref(),
+    );
+
+    // the file is hashed once, until it's replaced and its ETag changes
+    let head = s3::object_head(&s3_client, &bucket, &s3_path)
+        .await?
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let key = format!("{}:{}", s3_path, head.e_tag.as_deref().unwrap_or_default());
+    let pieces = torrent::cached_pieces(&key, || async {
+        let object = s3::get_object(&s3_client, &bucket, &s3_path, None)
+            .await?
+            .ok_or(bbc::BbcResponseError::NotFound)?;
+        Ok::<_, bbc::BbcResponseError>(torrent::hash_pieces(object.body).await?)
     })
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+    .await?;
+
+    // Peers can download from the archive or straight from storage
+    let web_seeds = vec![
+        format!(
+            "{}/archive/{}/{}/{}",
+            base_url,
+            id,
+            year,
+            utf8_percent_encode(&filename, NON_ALPHANUMERIC)
+        ),
+        s3_url(&config, &bucket, &region, &s3_path),
+    ];
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-bittorrent")
+        .insert_header(("Cache-Control", "public, max-age=604800"))
+        .body(torrent::build_torrent(&filename, &pieces, web_seeds)))
+}
+
+#[derive(Deserialize)]
+struct OEmbedQuery {
+    url: String,
+    maxwidth: Option<u32>,
+    format: Option<String>,
+}
+
+#[get("/oembed")]
+async fn get_oembed(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    query: web::Query<OEmbedQuery>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    // Only JSON is supported, and the spec asks for a 501 for anything else
+    if query.format.as_deref().map_or(false, |f| f != "json") {
+        return Err(bbc::BbcResponseError::NotImplemented);
+    }
+
+    let base_url = get_base_url(&req, &config)?;
+    let embed = oembed::get_oembed(&base_url, &config, &query.url, query.maxwidth).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=3600"))
+        .json(embed))
+}
+
+#[get("/api/resolve/{pid}")]
+async fn get_episode_resolution(
+    config: web::Data<Config>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let episode_id = pid.into_inner();
+    check_episode_permitted(&config, &episode_id).await?;
+
+    let show = parents::configured_show(&config, &episode_id).await?;
+    let variants = config.media_variants(show.as_ref());
+    let resolution =
+        sounds_proxy::resolve_episode(&episode_id, &variants, config.hls_bandwidth(show.as_ref()))
+            .await;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(resolution))
+}
+
+#[get("/api/version")]
+async fn get_version(config: web::Data<Config>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(version::version_info(&config))
+}
+
+#[get("/metrics")]
+async fn get_metrics(
+    limiter: web::Data<StreamLimiter>,
+    stats: web::Data<ResolutionStats>,
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(metrics::render(&limiter, &stats))
+}
+
+/// Episodes resolved and failed by show, with why they failed
+#[get("/api/stats")]
+async fn get_stats(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    stats: web::Data<ResolutionStats>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(stats.shows()))
+}
+
+#[get("/ready")]
+async fn get_readiness(readiness: web::Data<Readiness>) -> impl Responder {
+    let status = readiness.status();
+
+    let mut response = if status.is_ready() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response
+        .insert_header(("Cache-Control", "no-store"))
+        .json(status)
+}
+
+#[get("/healthz")]
+async fn get_health(
+    config: web::Data<Config>,
+    readiness: web::Data<Readiness>,
+    limiter: web::Data<StreamLimiter>,
+    failures: web::Data<FailureTracker>,
+) -> impl Responder {
+    let health = health::check(&config, &readiness, &limiter, &failures).await;
+
+    let mut response = match health.status {
+        health::Level::Down => HttpResponse::ServiceUnavailable(),
+        _ => HttpResponse::Ok(),
+    };
+    response
+        .insert_header(("Cache-Control", "no-store"))
+        .json(health)
+}
+
+/// Objects in the S3 bucket and their sizes, none without a bucket
+async fn archived_objects(
+    config: &Config,
+) -> Result<std::collections::HashMap<String, u64>, bbc::BbcResponseError> {
+    match s3::create_client(&config.s3_bucket, &config.s3_endpoint_url).await {
+        Some((s3_client, _)) => {
+            Ok(s3::list_objects(&s3_client, config.s3_bucket.as_ref().unwrap()).await?)
+        }
+        None => Ok(Default::default()),
+    }
+}
+
+/// The proxy's state, to move it to another host
+#[get("/api/export")]
+async fn get_export(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    failures: web::Data<FailureTracker>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+
+    let archive = archived_objects(&config).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                "sounds-proxy-state.json".to_string(),
+            )],
+        })
+        .json(state::export(&config, &failures, archive)))
+}
+
+/// Restores state exported from another host
+#[post("/api/import")]
+async fn post_import(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    failures: web::Data<FailureTracker>,
+    mut payload: web::Payload,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    // before reading the (possibly large) body
+    check_admin(&req, &config)?;
+
+    let mut body = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| bbc::BbcResponseError::BadRequest)?;
+        if body.len() + chunk.len() > MAX_IMPORT_BYTES {
+            return Err(bbc::BbcResponseError::BadRequest);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let bundle: state::Bundle =
+        serde_json::from_slice(&body).map_err(|_| bbc::BbcResponseError::BadRequest)?;
+    if bundle.version > state::BUNDLE_VERSION {
+        return Err(bbc::BbcResponseError::BadRequest);
+    }
+
+    let archive = archived_objects(&config).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(state::import(bundle, &config, &failures, &archive)))
+}
+
+/// Where the API key's listener got to in the episode
+#[get("/api/progress/{pid}")]
+async fn get_progress(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let api_key = check_api_key(&req, &config)?;
+
+    let position = progress::get(&api_key, &pid).ok_or(bbc::BbcResponseError::NotFound)?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(position))
+}
+
+/// Saves where the API key's listener got to in the episode, responding with the
+/// position kept, which is a later one if another player synced one
+#[post("/api/progress/{pid}")]
+async fn post_progress(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pid: web::Path<bbc::Pid>,
+    update: web::Json<progress::PositionUpdate>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let api_key = check_api_key(&req, &config)?;
+
+    let position = progress::set(&api_key, &pid, update.into_inner(), chrono::Utc::now());
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(position))
+}
+
+#[get("/api/admin/failures")]
+async fn get_failures(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    failures: web::Data<FailureTracker>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(failures.list()))
+}
+
+#[delete("/api/admin/failures/{pid}")]
+async fn clear_failures(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    failures: web::Data<FailureTracker>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+
+    if failures.clear(&pid) {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(bbc::BbcResponseError::NotFound)
+    }
+}
+
+#[get("/api/admin/subscriptions")]
+async fn get_subscriptions(
+    req: HttpRequest,
+    config: web::Data<Config>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(config.subscriptions.clone().unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct QueuedUpload {
+    key: String,
+    state: UploadState,
+}
+
+/// Uploads waiting to be prefetched or in progress
+#[get("/api/admin/queue")]
+async fn get_queue(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    upload_status: web::Data<UploadStatus>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+
+    let queue = upload_status
+        .active()
+        .into_iter()
+        .map(|(key, state)| QueuedUpload { key, state })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(queue))
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    /// Objects deleted from S3
+    deleted: Vec<String>,
+}
+
+/// Deletes everything kept of an episode: its renditions and parts in S3, its failures
+/// and its responses in the CDN. The next request for it starts afresh.
+#[delete("/api/admin/episodes/{pid}")]
+async fn purge_episode(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    upload_status: web::Data<UploadStatus>,
+    failures: web::Data<FailureTracker>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+    let episode_id = pid.into_inner();
+
+    let mut deleted = vec![];
+    if let Some((s3_client, _)) =
+        s3::create_client(&config.s3_bucket, &config.s3_endpoint_url).await
+    {
+        let bucket = config.s3_bucket.clone().unwrap();
+        let objects = s3::list_prefix(&s3_client, &bucket, episode_id.as_str()).await?;
+        // e.g. `<pid>.aac`, `<pid>-lo.aac` and `<pid>-part2.aac`
+        let keys = objects.into_keys().filter(|key| {
+            key.strip_prefix(episode_id.as_str())
+                .map_or(false, |rest| rest.starts_with('.') || rest.starts_with('-'))
+        });
+        for key in keys {
+            s3::delete_object(&s3_client, &bucket, &key).await?;
+            upload_status.clear(&key);
+            deleted.push(key);
+        }
+        deleted.sort();
+    }
+    failures.clear(&episode_id);
+    if let Some(cdn) = &config.cdn_purge {
+        if let Err(e) = cdn::purge(cdn, &[cdn::episode_tag(&episode_id)]).await {
+            log::error!("Purging {} from the CDN failed: {}", episode_id, e);
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(PurgeResponse { deleted }))
+}
+
+#[post("/api/admin/purge/{pid}")]
+async fn purge_show(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pid: web::Path<bbc::Pid>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+    let cdn = config
+        .cdn_purge
+        .as_ref()
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+
+    Ok(match cdn::purge(cdn, &[cdn::show_tag(&pid)]).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Purging {} from the CDN failed: {}", pid, e);
+            HttpResponse::BadGateway().body(e.to_string())
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct PrefetchQuery {
+    /// How many of the newest private episodes to upload
+    count: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ScheduledPrefetch {
+    pid: bbc::Pid,
+    /// When the episode becomes available
+    at: chrono::DateTime<chrono::FixedOffset>,
+}
+
+#[derive(Serialize)]
+struct PrefetchResponse {
+    /// Episodes queued for upload, newest first
+    queued: Vec<bbc::Pid>,
+    /// Upcoming episodes, which are uploaded once they're available
+    scheduled: Vec<ScheduledPrefetch>,
+}
+
+/// Clears a prefetch's queued state if it's dropped before its upload starts (e.g. the
+/// prefetch failed checking a later episode, or the episode was published after all), so
+/// it isn't left queued
+struct Queued {
+    upload_status: web::Data<UploadStatus>,
+    s3_path: String,
+}
+
+impl Queued {
+    /// Queues the upload, unless it's already queued (e.g. by an earlier prefetch),
+    /// running or complete
+    fn new(upload_status: web::Data<UploadStatus>, s3_path: &str) -> Option<Self> {
+        upload_status.try_queue(s3_path).then(|| Queued {
+            upload_status,
+            s3_path: s3_path.to_string(),
+        })
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.upload_status.clear_queued(&self.s3_path);
+    }
+}
+
+/// Uploads an episode for a prefetch once a stream is free, so listeners come first
+async fn run_prefetch(
+    upload: EpisodeUpload,
+    queued: Queued,
+    limiter: &StreamLimiter,
+    window: Option<throttle::UploadWindow>,
+) {
+    if let Some(window) = &window {
+        window.wait().await;
+    }
+    // a listener may have started it in the meantime
+    let buffer = match upload.upload_status.try_start(&upload.s3_path, false) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    drop(queued);
+    let permit = loop {
+        match limiter.try_acquire() {
+            Ok(permit) => break permit,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_secs(PREFETCH_WAIT_SECS)).await,
+        }
+    };
+    // failures are recorded by the upload
+    let _ = upload.run(permit, buffer).await;
+}
+
+#[post("/api/prefetch/{pid}")]
+async fn prefetch_show(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    upload_status: web::Data<UploadStatus>,
+    failures: web::Data<FailureTracker>,
+    limiter: web::Data<StreamLimiter>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<PrefetchQuery>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    check_admin(&req, &config)?;
+    let id = pid.into_inner();
+    check_permitted(&config, &id)?;
+    let count = query.count.unwrap_or(DEFAULT_PREFETCH_COUNT);
+
+    let resolution = config.resolution(Some(&id));
+    if !resolution.contains(&ResolutionStrategy::Proxy) {
+        return Err(bbc::BbcResponseError::NotFound);
+    }
+    let (s3_client, _) = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url)
+        .await
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let bucket = config.s3_bucket.clone().unwrap();
+    let base_url = get_base_url(&req, &config)?;
+
+    let mut options = config.feed_options(&id);
+    options.upcoming = true;
+    let (_, mut episodes) = sounds_proxy::get_show(&base_url, &id, &options).await?;
+    episodes.sort_by_key(|e| std::cmp::Reverse(e.pub_date));
+
+    let now = chrono::Utc::now();
+    let mut uploads = vec![];
+    let mut scheduled = vec![];
+    for episode in episodes {
+        if uploads.len() + scheduled.len() == count {
+            break;
+        }
+        let episode_id: bbc::Pid = episode.id.parse()?;
+        // public episodes are never uploaded. Upcoming ones can't be resolved yet, so are
+        // checked once they're available.
+        let upcoming = episode.is_upcoming(now);
+        if !upcoming
+            && sounds_proxy::get_episode_url(&episode_id, &resolution)
+                .await?
+                .is_some()
+        {
+            continue;
+        }
+        let show = parents::configured_show(&config, &episode_id).await?;
+        let s3_path = quality::s3_key(
+            &episode_id,
+            Quality::Hi,
+            config.rendition(show.as_ref()).as_deref(),
+        );
+        if failures.check(&episode_id, chrono::Utc::now()).is_some()
+            || matches!(
+                upload_status.get(&s3_path),
+                Some(UploadState::Queued | UploadState::InProgress)
+            )
+            || s3::object_exists(&s3_client, &bucket, &s3_path).await?
+        {
+            continue;
+        }
+        let title = episode_title(Some(&id), &episode_id).await;
+        let upload = EpisodeUpload {
+            upload_status: upload_status.clone(),
+            failures: failures.clone(),
+            notifier: req.app_data::<web::Data<Notifier>>().cloned(),
+            s3_client: s3_client.clone(),
+            bucket: bucket.clone(),
+            episode_id,
+            s3_path,
+            audio_format: config.audio_format(show.as_ref()),
+            media_variants: config.media_variants(show.as_ref()),
+            hls_bandwidth: config.hls_bandwidth(show.as_ref()),
+            span: None,
+            encryption: config.s3_encryption(),
+            bandwidth: config.upload_bytes_per_sec(),
+            title,
+            refresh: false,
+            cdn_purge: None,
+        };
+        let queued = match Queued::new(upload_status.clone(), &upload.s3_path) {
+            Some(queued) => queued,
+            None => continue,
+        };
+        match episode.available_from {
+            Some(at) if upcoming => scheduled.push((at, upload, queued)),
+            _ => uploads.push((upload, queued)),
+        }
+    }
+
+    let response = PrefetchResponse {
+        queued: uploads.iter().map(|(u, _)| u.episode_id.clone()).collect(),
+        scheduled: scheduled
+            .iter()
+            .map(|(at, u, _)| ScheduledPrefetch {
+                pid: u.episode_id.clone(),
+                at: *at,
+            })
+            .collect(),
+    };
+
+    let limiter = limiter.into_inner();
+    let window = config.upload_window;
+    for (at, upload, queued) in scheduled {
+        let limiter = limiter.clone();
+        let resolution = resolution.clone();
+        actix_web::rt::spawn(async move {
+            let due =
+                at.with_timezone(&chrono::Utc) + chrono::Duration::seconds(UPCOMING_GRACE_SECS);
+            let wait = (due - chrono::Utc::now()).to_std().unwrap_or_default();
+            log::info!(
+                "Prefetching upcoming episode {} in {}s",
+                upload.episode_id,
+                wait.as_secs()
+            );
+            tokio::time::sleep(wait).await;
+            match sounds_proxy::get_episode_url(&upload.episode_id, &resolution).await {
+                Ok(None) => run_prefetch(upload, queued, &limiter, window).await,
+                Ok(Some(_)) => {}
+                Err(e) => log::warn!(
+                    "Upcoming episode {} couldn't be resolved: {}",
+                    upload.episode_id,
+                    e
+                ),
+            }
+        });
+    }
+
+    // One at a time, so they don't take every free stream
+    actix_web::rt::spawn(async move {
+        for (upload, queued) in uploads {
+            run_prefetch(upload, queued, &limiter, window).await;
+        }
+    });
+
+    Ok(HttpResponse::Accepted()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(response))
+}
+
+#[derive(Serialize)]
+struct UploadStatusResponse {
+    pid: bbc::Pid,
+    status: Option<UploadState>,
+    /// Only known for uploads since the proxy started
+    integrity: Option<Integrity>,
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UploadStatusQuery {
+    quality: Option<Quality>,
+    part: Option<usize>,
+}
+
+#[get("/api/status/{pid}")]
+async fn get_upload_status(
+    config: web::Data<Config>,
+    upload_status: web::Data<UploadStatus>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<UploadStatusQuery>,
+) -> Result<impl Responder, bbc::BbcResponseError> {
+    let episode_id = pid.into_inner();
+
+    let (s3_client, region) = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url)
+        .await
+        .ok_or(bbc::BbcResponseError::NotFound)?;
+    let bucket = config.s3_bucket.clone().unwrap();
+    let show = parents::configured_show(&config, &episode_id).await?;
+    let s3_path = quality::s3_key(
+        &episode_id,
+        query.quality.unwrap_or(Quality::Hi),
+        config.rendition(show.as_ref()).as_deref(),
+    );
+    let s3_path = match query.part {
+        Some(number) => omnibus::s3_key(&s3_path, number),
+        None => s3_path,
+    };
+
+    let state = match upload_status.get(&s3_path) {
+        Some(state @ (UploadState::Queued | UploadState::InProgress)) => Some(state),
+        _ if s3::object_exists(&s3_client, &bucket, &s3_path).await? => Some(UploadState::Complete),
+        state => state,
+    };
+
+    let mut response = UploadStatusResponse {
+        pid: episode_id,
+        status: state,
+        integrity: upload_status.integrity(&s3_path),
+        url: None,
+    };
+
+    match state {
+        Some(UploadState::Complete) => {
+            let url = s3_url(&config, &bucket, &region, &s3_path);
+            response.url = Some(url.clone());
+            Ok(HttpResponse::SeeOther()
+                .insert_header((actix_web::http::header::LOCATION, url))
+                .json(response))
+        }
+        Some(UploadState::Queued | UploadState::InProgress) => Ok(HttpResponse::Ok()
+            .insert_header((
+                actix_web::http::header::RETRY_AFTER,
+                UPLOAD_RETRY_AFTER_SECS,
+            ))
+            .json(response)),
+        Some(UploadState::Failed) => Ok(HttpResponse::Ok().json(response)),
+        None => Ok(HttpResponse::NotFound().json(response)),
+    }
+}
+
+/// Redirects to a public episode's URL, permanently only if configured to
+fn public_redirect(config: &Config, url: String) -> HttpResponse {
+    match config.public_redirect() {
+        PublicRedirect::Temporary => HttpResponse::Found(),
+        PublicRedirect::Permanent => HttpResponse::PermanentRedirect(),
+    }
+    .insert_header((actix_web::http::header::LOCATION, url))
+    .finish()
+}
+
+#[get("/episode/{pid}")]
+async fn get_episode(
+    config: web::Data<Config>,
+    stats: web::Data<ResolutionStats>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<EpisodeQuery>,
+) -> Result<HttpResponse, bbc::BbcResponseError> {
+    check_episode_permitted(&config, &pid).await?;
+    let result = redirect_episode(&config, &pid.into_inner(), query.show.as_ref(), false).await;
+    stats.record(query.show.as_ref(), &result);
+    result
+}
+
+/// Same as `/episode/{pid}`, but the redirect is never cached. Feeds can link here
+/// so clients which cached a permanent redirect to a now dead URL pick up the new one.
+#[get("/episode/{pid}/audio")]
+async fn get_episode_revalidated(
+    config: web::Data<Config>,
+    stats: web::Data<ResolutionStats>,
+    pid: web::Path<bbc::Pid>,
+    query: web::Query<EpisodeQuery>,
+) -> Result<HttpResponse, bbc::BbcResponseError> {
+    check_episode_permitted(&config, &pid).await?;
+    let result = redirect_episode(&config, &pid.into_inner(), query.show.as_ref(), true).await;
+    stats.record(query.show.as_ref(), &result);
+    result
+}
+
+async fn redirect_episode(
+    config: &Config,
+    episode_id: &bbc::Pid,
+    show: Option<&bbc::Pid>,
+    revalidate: bool,
+) -> Result<HttpResponse, bbc::BbcResponseError> {
+    let settings = parents::configured_show(config, episode_id).await?;
+    let resolution = config.resolution(settings.as_ref());
+
+    let mut response =
+        if let Some(url) = sounds_proxy::get_episode_url(episode_id, &resolution).await? {
+            // Public episode
+
+            if revalidate {
+                HttpResponse::Found()
+                    .insert_header((actix_web::http::header::LOCATION, url))
+                    .finish()
+            } else {
+                public_redirect(config, url)
+            }
+        } else if !resolution.contains(&ResolutionStrategy::Proxy) {
+            return Err(bbc::BbcResponseError::NotFound);
+        } else {
+            // Private episode, serve directly
+
+            // At the moment only aac streams are supported
+            HttpResponse::TemporaryRedirect()
+                .insert_header((
+                    actix_web::http::header::LOCATION,
+                    format!(
+                        "{}/episode/{}.aac{}",
+                        config.base_url.as_ref().unwrap_or(&"".to_string()),
+                        episode_id,
+                        show.map(|show| format!("?show={}", show))
+                            .unwrap_or_default()
+                    ),
+                ))
+                .finish()
+        };
+
+    if revalidate {
+        response.headers_mut().insert(
+            actix_web::http::header::CACHE_CONTROL,
+            actix_web::http::header::HeaderValue::from_static("no-cache"),
+        );
+        return Ok(response);
+    }
+    Ok(cdn::with_headers(
+        config.cdn_max_age,
+        &cdn::episode_tags(episode_id, show),
+        response,
+    ))
+}
+
+/// Upload of an episode to S3, tracking its progress and failures
+struct EpisodeUpload {
+    upload_status: web::Data<UploadStatus>,
+    failures: web::Data<FailureTracker>,
+    notifier: Option<web::Data<Notifier>>,
+    s3_client: aws_sdk_s3::client::Client,
+    bucket: String,
+    episode_id: bbc::Pid,
+    s3_path: String,
+    audio_format: Option<AudioFormat>,
+    media_variants: Vec<sounds_proxy::MediaVariant>,
+    hls_bandwidth: Option<u64>,
+    /// Part of the episode to upload, if not all of it
+    span: Option<hls::Span>,
+    encryption: s3::Encryption,
+    /// Bytes per second the upload may use
+    bandwidth: Option<u64>,
+    title: String,
+    /// Replace any existing copy
+    refresh: bool,
+    cdn_purge: Option<cdn::CdnPurge>,
+}
+
+impl EpisodeUpload {
+    /// Runs the upload, copying the audio to `buffer` for anyone listening along
+    async fn run(
+        self,
+        permit: StreamPermit,
+        buffer: Arc<TeeBuffer>,
+    ) -> Result<(), bbc::BbcResponseError> {
+        let _permit = permit;
+        let mut unfinished = Unfinished {
+            upload_status: self.upload_status.clone(),
+            s3_path: self.s3_path.clone(),
+            buffer: buffer.clone(),
+            finished: false,
+        };
+        let EpisodeUpload {
+            upload_status,
+            failures,
+            notifier,
+            s3_client,
+            bucket,
+            episode_id,
+            s3_path,
+            audio_format,
+            media_variants,
+            hls_bandwidth,
+            span,
+            encryption,
+            bandwidth,
+            title,
+            refresh,
+            cdn_purge,
+        } = self;
+        // S3 serves the object with this header
+        let content_disposition = episode_content_disposition(&title).to_string();
+
+        let result = match sounds_proxy::get_episode_with_metadata(
+            &episode_id,
+            audio_format,
+            &media_variants,
+            hls_bandwidth,
+            span,
+        )
+        .await
+        {
+            Ok((mut episode, _, expected_size)) => {
+                // The upload reads from the buffer like any listener, so only it's throttled
+                // and listeners can get ahead of it
+                let sink = buffer.clone().sink().map_err(bbc::BbcResponseError::from);
+                let sink = match bandwidth {
+                    Some(bytes_per_sec) => throttle::throttle(sink, bytes_per_sec).boxed_local(),
+                    None => sink.boxed_local(),
+                };
+                let source = async {
+                    while let Some(bytes) = episode.next().await {
+                        // the upload failed, so there's no one left to wait for
+                        if buffer.is_finished() {
+                            break;
+                        }
+                        buffer.push(Bytes::from(bytes?)).await;
+                    }
+                    Ok(())
+                };
+                let source = async {
+                    let result = source.await;
+                    buffer.finish(result.is_ok());
+                    result
+                };
+                let upload = async {
+                    let result = upload_episode(
+                        &s3_client,
+                        &bucket,
+                        sink,
+                        &s3_path,
+                        &encryption,
+                        &content_disposition,
+                        refresh,
+                        expected_size,
+                    )
+                    .await;
+                    buffer.finish(result.is_ok());
+                    result
+                };
+                // the source's error says more than the upload's
+                let (source, upload) = futures::join!(source, upload);
+                source.and(upload)
+            }
+            Err(e) => Err(e),
+        };
+        buffer.finish(result.is_ok());
+        let state = match &result {
+            Ok(_) => {
+                failures.record_success(&episode_id);
+                UploadState::Complete
+            }
+            Err(_) => UploadState::Failed,
+        };
+        let integrity = match &result {
+            Ok(_) => Some(Integrity::Verified),
+            Err(e) if e.is_truncated() => Some(Integrity::Truncated),
+            Err(_) => None,
+        };
+        upload_status.set(&s3_path, state, integrity);
+        unfinished.finished = true;
+        if let Err(e) = &result {
+            log::error!("Upload of {} failed: {}", episode_id, e);
+            let quarantined =
+                failures.record_failure(&episode_id, e.to_string(), chrono::Utc::now());
+            // sent in the background, so a slow notifier doesn't hold up the upload's end
+            if let (true, Some(notifier)) = (quarantined, notifier) {
+                let notification = Notification {
+                    event: notify::Event::Failure,
+                    title: format!("{} failed to upload", title),
+                    message: format!("Quarantined after repeated failures, the last was: {}", e),
+                    url: None,
+                };
+                actix_web::rt::spawn(async move { notifier.notify(notification).await });
+            }
+        }
+        if let (Ok(_), Some(cdn)) = (&result, cdn_purge) {
+            if let Err(e) = cdn::purge(&cdn, &[cdn::episode_tag(&episode_id)]).await {
+                log::error!("Purging {} from the CDN failed: {}", episode_id, e);
+            }
+        }
+        result
+    }
+}
+
+/// Marks an upload failed if it stops before finishing (e.g. the proxy is shutting down),
+/// so it isn't left in progress with listeners waiting on it
+struct Unfinished {
+    upload_status: web::Data<UploadStatus>,
+    s3_path: String,
+    buffer: Arc<TeeBuffer>,
+    finished: bool,
+}
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.buffer.finish(false);
+        self.upload_status
+            .set(&self.s3_path, UploadState::Failed, None);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_episode(
+    s3_client: &aws_sdk_s3::client::Client,
+    bucket: &str,
+    episode: impl Stream<Item = Result<Bytes, bbc::BbcResponseError>> + Unpin,
+    s3_path: &str,
+    encryption: &s3::Encryption,
+    content_disposition: &str,
+    overwrite: bool,
+    expected_size: Option<u64>,
+) -> Result<(), bbc::BbcResponseError> {
+    let stream = episode.map_err(|e| e.into());
+
+    log::debug!("Uploading episode to s3://{}/{}", bucket, s3_path);
+
+    if overwrite {
+        s3::put_async_stream(
+            s3_client,
+            bucket,
+            stream,
+            s3_path,
+            Some("audio/aac"),
+            Some(content_disposition),
+            encryption,
+            expected_size,
+        )
+        .await
+    } else {
+        s3::try_put_async_stream(
+            s3_client,
+            bucket,
+            stream,
+            s3_path,
+            Some("audio/aac"),
+            Some(content_disposition),
+            encryption,
+            expected_size,
+        )
+        .await
+    }?;
+
+    Ok(())
+}
+
+/// Replaces characters which can't be sent in a plain header value
+fn ascii_only(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Title of the show and episode, falling back to the pid if they can't be looked up
+async fn episode_title(show: Option<&bbc::Pid>, episode_id: &bbc::Pid) -> String {
+    match show {
+        Some(show) => sounds_proxy::get_episode_title(show, episode_id)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to look up title of {}: {}", episode_id, e);
+                None
+            }),
+        None => None,
+    }
+    .unwrap_or_else(|| episode_id.to_string())
+}
+
+/// Title of a part of an omnibus, or of the whole episode
+fn part_title(title: String, part: Option<&omnibus::Part>) -> String {
+    match part {
+        Some(part) => format!("{} (Part {})", title, part.number),
+        None => title,
+    }
+}
+
+/// Query for `/api/status` to follow an upload of a rendition or part
+fn status_query(quality: Quality, part: Option<&omnibus::Part>) -> String {
+    let mut params = Vec::new();
+    if quality == Quality::Lo {
+        params.push("quality=lo".to_string());
+    }
+    if let Some(part) = part {
+        params.push(format!("part={}", part.number));
+    }
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+/// Names downloads after the episode's title
+fn episode_content_disposition(title: &str) -> ContentDisposition {
+    let filename = archive::sanitize_filename(title) + ".aac";
+
+    // Plain filename for clients without RFC 6266 support
+    let ascii_filename = ascii_only(&filename);
+
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![
+            DispositionParam::Filename(ascii_filename),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: filename.into_bytes(),
+            }),
+        ],
+    }
+}
+
+/// Streams an episode. ICY metadata, if requested, follows timed metadata
+/// in the stream when there is any.
+fn stream_episode<S, E>(
+    req: &HttpRequest,
+    title: &str,
+    cache_control: &str,
+    stream: S,
+    metadata: Option<hls::MetadataReceiver>,
+) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: Into<Box<dyn std::error::Error>> + 'static,
+{
+    let stats = req.app_data::<web::Data<ResolutionStats>>().cloned();
+    let stream = stream.map(move |item| {
+        if let (Some(stats), Ok(bytes)) = (&stats, &item) {
+            stats.record_served(bytes.len());
+        }
+        item
+    });
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("audio/aac".to_string())
+        .insert_header(("Cache-Control", cache_control.to_string()))
+        // caches mustn't give metadata interleaved in the audio to players not expecting it
+        .insert_header((actix_web::http::header::VARY, "Icy-MetaData"))
+        .insert_header(episode_content_disposition(title));
+
+    if icy::requested(req) {
+        response
+            .insert_header(("icy-metaint", icy::META_INT.to_string()))
+            .insert_header(("icy-name", ascii_only(title)))
+            .streaming(icy::with_metadata(stream, title, metadata))
+    } else {
+        response.streaming(stream)
+    }
+}
+
+fn s3_url(config: &Config, bucket: &str, region: &str, s3_path: &str) -> String {
+    match &config.s3_base_url {
+        Some(base_url) => format!("{}/{}", base_url, s3_path),
+        None => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, s3_path),
+    }
+}
+
+/// Verifies storage and starts background tasks, updating readiness as they complete
+async fn startup(
+    config: Config,
+    readiness: Arc<Readiness>,
+    notifier: Arc<Notifier>,
+    shared_client: Arc<s3::SharedClient>,
+) {
+    // create bucket to test config (will panic if bad)
+    let s3_client = s3::create_client(&config.s3_bucket, &config.s3_endpoint_url).await;
+    if let Some((s3_client, _)) = &s3_client {
+        shared_client.set(s3_client.clone());
+    }
+    readiness.set_storage_ready();
+
+    match (config.export_interval_hours, s3_client, &config.base_url) {
+        (Some(hours), Some((s3_client, _)), Some(base_url)) => {
+            let exporter = export::FeedExporter {
+                s3_client,
+                bucket: config.s3_bucket.clone().unwrap(),
+                base_url: base_url.clone(),
+                config: config.clone(),
+                interval: std::time::Duration::from_secs(hours * 3600),
+                readiness,
+            };
+            actix_web::rt::spawn(exporter.run());
+        }
+        (Some(_), _, _) => {
+            log::warn!("Feed export requires an S3 bucket and base URL, not exporting");
+            readiness.set_subscriptions_ready();
+        }
+        (None, _, _) => readiness.set_subscriptions_ready(),
+    }
+
+    if notifier.wants(notify::Event::NewEpisode) && config.subscriptions.is_some() {
+        let watcher = notify::EpisodeWatcher {
+            interval: std::time::Duration::from_secs(
+                config.notify_interval_minutes.unwrap_or(60) * 60,
+            ),
+            config,
+            notifier,
+        };
+        actix_web::rt::spawn(watcher.run());
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+
+    // `sounds-proxy check-payloads [[brand:]pid...]` reports drift in the BBC's payloads,
+    // rather than serving
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("check-payloads") => {
+            let programmes = args
+                .map(|arg| arg.parse())
+                .collect::<Result<Vec<contract::Programme>, _>>()?;
+            return contract::check_live(&programmes).await;
+        }
+        // `sounds-proxy record-payloads [[brand:]pid...]` records the BBC's live payloads as
+        // examples
+        Some("record-payloads") => {
+            let programmes = args
+                .map(|arg| arg.parse())
+                .collect::<Result<Vec<contract::Programme>, _>>()?;
+            return contract::record_live(&programmes).await;
+        }
+        // `sounds-proxy import <file>` restores fingerprints from an exported bundle, and
+        // prints the environment variables for its settings
+        Some("import") => {
+            let path = args.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "usage: import <file>")
+            })?;
+            let bundle: state::Bundle = serde_json::from_slice(&std::fs::read(path)?)?;
+            let config = Config::from_env();
+            match &config.fingerprint_file {
+                Some(fingerprint_file) => {
+                    fingerprint::load(std::path::Path::new(fingerprint_file));
+                    let count = fingerprint::restore(bundle.fingerprints.clone());
+                    eprintln!("Restored {} fingerprints", count);
+                }
+                None => eprintln!("No FINGERPRINT_FILE is set, so fingerprints weren't restored"),
+            }
+            for setting in state::settings(&bundle) {
+                println!("{}", setting);
+            }
+            return Ok(());
+        }
+        // `sounds-proxy admin [--server <url>] [--token <token>] <command>` manages a proxy
+        // through its admin API
+        Some("admin") => return admin::run(args, Config::from_env().admin_token).await,
+        // `sounds-proxy loadtest [feeds] [streams]` measures how the configured limits cope
+        // with that many listeners at once, against a mock BBC
+        Some("loadtest") => {
+            let mut count = |default| {
+                args.next().map_or(Ok(default), |n| {
+                    n.parse().map_err(|_| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "usage: loadtest [feeds] [streams]",
+                        )
+                    })
+                })
+            };
+            let feeds = count(DEFAULT_LOADTEST_FEEDS)?;
+            let streams = count(DEFAULT_LOADTEST_STREAMS)?;
+            return loadtest::run(&Config::from_env(), feeds, streams).await;
+        }
+        _ => {}
+    }
+
+    let config = Config::from_env();
+    let port = config.listen_port.unwrap_or(8080);
+
+    let readiness = web::Data::new(Readiness::default());
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = config.grpc_port {
+        actix_web::rt::spawn(grpc::serve(config.clone(), grpc_port));
+    }
+
+    let upload_status = web::Data::new(UploadStatus::default());
+    let s3_client = web::Data::new(s3::SharedClient::default());
+    let failures = web::Data::new(FailureTracker::default());
+    let admission = web::Data::new(AdmissionTracker::default());
+    let stats = web::Data::new(match &config.stats_file {
+        Some(path) => ResolutionStats::load(std::path::Path::new(path), config.configured_shows()),
+        None => ResolutionStats::new(config.configured_shows()),
+    });
+    if stats.is_persistent() {
+        let stats = stats.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                tokio::time::sleep(stats::SAVE_INTERVAL).await;
+                stats.save().await;
+            }
+        });
+    }
+    let notifier = web::Data::new(Notifier::new(config.notifiers.clone().unwrap_or_default()));
+    let limiter = web::Data::new(StreamLimiter::new(
+        Some(config.max_streams()),
+        config.memory_limit(),
+    ));
+    log::info!(
+        "Tuned for {} CPUs and {} of memory: {} workers, {} streams, {} S3 parts at once",
+        tuning::RESOURCES.cpus,
+        tuning::RESOURCES
+            .memory
+            .map_or("an unknown amount".to_string(), |m| format!(
+                "{} MB",
+                m / 1024 / 1024
+            )),
+        config.workers(),
+        config.max_streams(),
+        config.s3_part_concurrency()
+    );
+    s3::set_part_concurrency(config.s3_part_concurrency());
+    limits::BUFFERS.set_limit(config.buffer_limit());
+    segment_cache::SEGMENTS.set_capacity(config.segment_cache_size());
+    transcode::set_options(config.ffmpeg_options());
+    sounds_proxy::set_preferred_suppliers(config.preferred_suppliers.clone().unwrap_or_default());
+    if let Some(path) = &config.fingerprint_file {
+        fingerprint::load(std::path::Path::new(path));
+    }
+    if let Some(path) = &config.progress_file {
+        progress::load(std::path::Path::new(path));
+    }
+    security_headers::set_frame_ancestors(config.frame_ancestors.as_deref().unwrap_or_default())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if let Some(key) = &config.signing_key {
+        signing::set_key(key)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    }
+
+    let listener = handoff::listener(port)?;
+    let listener_fd = listener.as_raw_fd();
+
+    let server = {
+        let config = config.clone();
+        let readiness = readiness.clone();
+        let notifier = notifier.clone();
+        let stats = stats.clone();
+        let s3_client = s3_client.clone();
+        let workers = config.workers();
+        let shutdown_timeout = config
+            .shutdown_timeout_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_SECS);
+        let alt_svc = config.alt_svc.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(config.clone()))
+                .app_data(upload_status.clone())
+                .app_data(failures.clone())
+                .app_data(admission.clone())
+                .app_data(notifier.clone())
+                .app_data(limiter.clone())
+                .app_data(stats.clone())
+                .app_data(readiness.clone())
+                .app_data(s3_client.clone())
+                .app_data(web::PathConfig::default().error_handler(|err, _| {
+                    // e.g. an invalid pid
+                    actix_web::error::ErrorBadRequest(err)
+                }))
+                .wrap(middleware::Compress::default())
+                // actix can't serve HTTP/3 itself, but a proxy in front may
+                .wrap(middleware::Condition::new(
+                    alt_svc.is_some(),
+                    middleware::DefaultHeaders::new()
+                        .add(("Alt-Svc", alt_svc.clone().unwrap_or_default())),
+                ))
+                .wrap_fn(|req, srv| {
+                    let response = srv.call(req);
+                    async {
+                        let mut response = response.await?;
+                        security_headers::add(response.headers_mut());
+                        Ok(response)
+                    }
+                })
+                .service(get_m3u_playlist)
+                .service(get_json_feed)
+                .service(get_atom_feed)
+                .service(get_combined_feed)
+                .service(get_opml)
+                .service(get_podcast_feed)
+                .service(get_feed_stylesheet)
+                .service(get_podcast_verification)
+                .service(get_public_key)
+                .service(get_episode_aac)
+                .service(get_episode)
+                .service(get_episode_revalidated)
+                .service(get_episode_chapters)
+                .service(get_upload_status)
+                .service(get_episode_resolution)
+                .service(get_archive_show)
+                .service(get_archive_year)
+                .service(get_archive_torrent)
+                .service(get_archive_episode)
+                .service(get_readiness)
+                .service(get_health)
+                .service(get_metrics)
+                .service(get_stats)
+                .service(get_version)
+                .service(get_oembed)
+                .service(get_category)
+                .service(get_progress)
+                .service(post_progress)
+                .service(get_failures)
+                .service(get_export)
+                .service(post_import)
+                .service(clear_failures)
+                .service(purge_show)
+                .service(get_subscriptions)
+                .service(get_queue)
+                .service(purge_episode)
+                .service(prefetch_show)
+        })
+        .listen(listener)?
+        .shutdown_timeout(shutdown_timeout);
+
+        server.workers(workers).run()
+    };
+    actix_web::rt::spawn(handoff::reload_on_signal(
+        listener_fd,
+        server.handle(),
+        stats.clone().into_inner(),
+    ));
+    // a successor takes over as the service's main process
+    handoff::notify_systemd(&format!("MAINPID={}\nREADY=1", std::process::id()));
+
+    // The server starts answering (as not ready) while this runs
+    let (result, _) = futures::join!(
+        server,
+        startup(
+            config,
+            readiness.into_inner(),
+            notifier.into_inner(),
+            s3_client.into_inner()
+        )
+    );
+    // including anything counted since the last save
+    stats.save().await;
+    result
 }
@@ -0,0 +1,85 @@
+use std::fmt::Write;
+
+use crate::{
+    limits::{StreamLimiter, BUFFERS},
+    segment_cache::SEGMENTS,
+    stats::ResolutionStats,
+};
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} gauge", name).unwrap();
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+/// Current metrics in the Prometheus text format
+pub fn render(limiter: &StreamLimiter, stats: &ResolutionStats) -> String {
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "sounds_proxy_active_streams",
+        "Episodes being streamed or uploaded",
+        limiter.active() as u64,
+    );
+    gauge(
+        &mut out,
+        "sounds_proxy_buffered_bytes",
+        "Bytes held in stream buffers across all streams and uploads",
+        BUFFERS.used() as u64,
+    );
+    if let Some(limit) = BUFFERS.limit() {
+        gauge(
+            &mut out,
+            "sounds_proxy_buffer_limit_bytes",
+            "Most bytes stream buffers may hold",
+            limit as u64,
+        );
+    }
+    gauge(
+        &mut out,
+        "sounds_proxy_segment_cache_bytes",
+        "Bytes held by recently fetched HLS segments",
+        SEGMENTS.used() as u64,
+    );
+
+    let name = "sounds_proxy_served_bytes_total";
+    writeln!(out, "# HELP {} Bytes of audio streamed to listeners", name).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    writeln!(out, "{} {}", name, stats.bytes_served()).unwrap();
+
+    let shows = stats.shows();
+    let name = "sounds_proxy_resolutions_total";
+    writeln!(
+        out,
+        "# HELP {} Episodes served or redirected to, by show",
+        name
+    )
+    .unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    for (show, show_stats) in &shows {
+        writeln!(out, "{}{{show=\"{}\"}} {}", name, show, show_stats.resolved).unwrap();
+    }
+
+    let name = "sounds_proxy_resolution_failures_total";
+    writeln!(
+        out,
+        "# HELP {} Episodes which couldn't be resolved, by show and reason",
+        name
+    )
+    .unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    for (show, show_stats) in shows {
+        for (reason, count) in show_stats.failed {
+            writeln!(
+                out,
+                "{}{{show=\"{}\",reason=\"{}\"}} {}",
+                name,
+                show,
+                reason.as_str(),
+                count
+            )
+            .unwrap();
+        }
+    }
+    out
+}
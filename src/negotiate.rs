@@ -0,0 +1,144 @@
+/// Representations of a show at `/show/{pid}`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedFormat {
+    Rss,
+    /// The RSS feed with its stylesheet, which browsers render as a page
+    Html,
+    Atom,
+}
+
+/// Media types offered for a show, in order of preference when the client doesn't mind
+const FEED_TYPES: &[(&str, FeedFormat)] = &[
+    ("application/rss+xml", FeedFormat::Rss),
+    ("application/xml", FeedFormat::Rss),
+    ("text/xml", FeedFormat::Rss),
+    ("text/html", FeedFormat::Html),
+    ("application/atom+xml", FeedFormat::Atom),
+];
+
+impl FeedFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/rss+xml",
+            // Browsers only apply the feed's stylesheet to plain XML
+            FeedFormat::Html => "application/xml; charset=utf-8",
+            FeedFormat::Atom => "application/atom+xml",
+        }
+    }
+}
+
+/// Ranges of an Accept or Accept-Language header, with their quality
+fn ranges(accept: &str) -> Vec<(String, f32)> {
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media_type = params.next()?.trim().to_ascii_lowercase();
+            if media_type.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media_type, quality))
+        })
+        .collect()
+}
+
+// How closely a media range matches a type, or None if it doesn't
+fn specificity(range: &str, media_type: &str) -> Option<u8> {
+    if range == media_type {
+        Some(2)
+    } else if range == "*/*" {
+        Some(0)
+    } else {
+        let (kind, _) = media_type.split_once('/')?;
+        range.strip_suffix("/*").filter(|r| *r == kind).map(|_| 1)
+    }
+}
+
+/// Picks the representation of a show for an Accept header (RFC 7231), RSS if there's
+/// no preference. None if nothing acceptable is offered.
+pub fn feed_format(accept: Option<&str>) -> Option<FeedFormat> {
+    let ranges = match accept.map(ranges) {
+        Some(ranges) if !ranges.is_empty() => ranges,
+        _ => return Some(FeedFormat::Rss),
+    };
+
+    let mut best: Option<(f32, FeedFormat)> = None;
+    for (media_type, format) in FEED_TYPES {
+        // the most specific range matching the type says how acceptable it is
+        let quality = ranges
+            .iter()
+            .filter_map(|(range, q)| specificity(range, media_type).map(|s| (s, *q)))
+            .max_by_key(|(s, _)| *s)
+            .map_or(0.0, |(_, q)| q);
+        if quality > 0.0 && best.map_or(true, |(q, _)| quality > q) {
+            best = Some((quality, *format));
+        }
+    }
+    best.map(|(_, format)| format)
+}
+
+/// Language tags of an Accept-Language header (RFC 7231), most preferred first, leaving
+/// out the wildcard and any the client won't accept
+pub fn languages(accept_language: Option<&str>) -> Vec<String> {
+    let mut ranges = accept_language.map(ranges).unwrap_or_default();
+    ranges.retain(|(tag, quality)| tag != "*" && *quality > 0.0);
+    // stable, so tags of equal quality keep their order
+    ranges.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    ranges.into_iter().map(|(tag, _)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_feed_format() {
+        assert_eq!(feed_format(None), Some(FeedFormat::Rss));
+        assert_eq!(feed_format(Some("")), Some(FeedFormat::Rss));
+        assert_eq!(feed_format(Some("*/*")), Some(FeedFormat::Rss));
+        assert_eq!(
+            feed_format(Some("application/rss+xml, application/xml;q=0.9")),
+            Some(FeedFormat::Rss)
+        );
+        // a browser
+        assert_eq!(
+            feed_format(Some(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            )),
+            Some(FeedFormat::Html)
+        );
+        assert_eq!(
+            feed_format(Some("text/html;q=0.5, application/rss+xml")),
+            Some(FeedFormat::Rss)
+        );
+        assert_eq!(feed_format(Some("text/*")), Some(FeedFormat::Rss));
+        assert_eq!(
+            feed_format(Some(
+                "*/*, application/rss+xml;q=0, text/xml;q=0, application/xml;q=0"
+            )),
+            Some(FeedFormat::Html)
+        );
+
+        assert_eq!(
+            feed_format(Some("application/atom+xml, application/rss+xml;q=0.5")),
+            Some(FeedFormat::Atom)
+        );
+
+        assert_eq!(feed_format(Some("application/json")), None);
+        assert_eq!(feed_format(Some("*/*;q=0")), None);
+    }
+
+    #[test]
+    fn test_languages() {
+        assert!(languages(None).is_empty());
+        assert_eq!(
+            languages(Some("en-GB, cy;q=0.8, en;q=0.9, *;q=0.5, gd;q=0")),
+            vec!["en-gb", "en", "cy"]
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{bbc, config::Config, sounds_proxy};
+
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("Reqwest error: {0}")]
+    ReqwestError(#[from] reqwest::Error),
+
+    #[error("SMTP connection error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("SMTP server response: {0}")]
+    SmtpResponse(String),
+}
+
+type Result<T, E = NotifyError> = core::result::Result<T, E>;
+
+// How long each target has to take a notification
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A subscribed show has a new episode
+    NewEpisode,
+    /// An episode has failed to upload too many times, and is quarantined
+    Failure,
+}
+
+/// Where notifications are sent
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Target {
+    /// Notifications are POSTed as JSON
+    Webhook { url: String },
+    /// ntfy server and topic, e.g. `https://ntfy.sh`
+    Ntfy { url: String, topic: String },
+    /// Gotify server, and an application token
+    Gotify { url: String, token: String },
+    /// SMTP relay as `host:port`. Only plain, unauthenticated SMTP is
+    /// supported, so this should be a local relay (e.g. a sidecar MTA).
+    Smtp {
+        server: String,
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub target: Target,
+    /// Events to send, or all of them if not set
+    pub events: Option<Vec<Event>>,
+}
+
+impl NotifierConfig {
+    fn wants(&self, event: Event) -> bool {
+        self.events
+            .as_ref()
+            .map_or(true, |events| events.contains(&event))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub title: String,
+    pub message: String,
+    pub url: Option<String>,
+}
+
+/// Encodes a header as an RFC 2047 encoded word, if it isn't plain ASCII
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return value.to_string();
+    }
+    let encoded = value
+        .bytes()
+        .map(|b| match b {
+            b' ' => "_".to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("={:02X}", b),
+        })
+        .collect::<String>();
+    format!("=?UTF-8?Q?{}?=", encoded)
+}
+
+async fn send_email(server: &str, from: &str, to: &str, n: &Notification) -> Result<()> {
+    let stream = TcpStream::connect(server).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    // Reads a (possibly multiline) reply, checking its code
+    async fn reply(reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>, ok: u8) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            if line.len() < 4 || line.as_bytes()[0] != ok {
+                return Err(NotifyError::SmtpResponse(line.trim_end().to_string()));
+            }
+            if line.as_bytes()[3] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    reply(&mut reader, b'2').await?;
+    for command in [
+        "EHLO sounds-proxy".to_string(),
+        format!("MAIL FROM:<{}>", from),
+        format!("RCPT TO:<{}>", to),
+    ] {
+        writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        reply(&mut reader, b'2').await?;
+    }
+    writer.write_all(b"DATA\r\n").await?;
+    reply(&mut reader, b'3').await?;
+
+    let body = n.url.iter().fold(n.message.clone(), |body, url| {
+        format!("{}\n\n{}", body, url)
+    });
+    let body = body
+        .lines()
+        // lines starting with a dot would otherwise be read as the end
+        .map(|l| {
+            if l.starts_with('.') {
+                format!(".{}", l)
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n.\r\n",
+        from,
+        to,
+        encode_header(&n.title),
+        body
+    );
+    writer.write_all(message.as_bytes()).await?;
+    reply(&mut reader, b'2').await?;
+
+    writer.write_all(b"QUIT\r\n").await?;
+    Ok(())
+}
+
+/// Sends notifications of events to the configured targets
+pub struct Notifier {
+    notifiers: Vec<NotifierConfig>,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(notifiers: Vec<NotifierConfig>) -> Self {
+        Notifier {
+            notifiers,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// True if any target wants the event
+    pub fn wants(&self, event: Event) -> bool {
+        self.notifiers.iter().any(|n| n.wants(event))
+    }
+
+    async fn send(&self, target: &Target, n: &Notification) -> Result<()> {
+        match target {
+            Target::Webhook { url } => {
+                self.client
+                    .post(url)
+                    .json(n)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Target::Ntfy { url, topic } => {
+                self.client
+                    .post(url)
+                    .json(&serde_json::json!({
+                        "topic": topic,
+                        "title": n.title,
+                        "message": n.message,
+                        "click": n.url,
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Target::Gotify { url, token } => {
+                self.client
+                    .post(format!("{}/message", url.trim_end_matches('/')))
+                    .header("X-Gotify-Key", token)
+                    .json(&serde_json::json!({
+                        "title": n.title,
+                        "message": n.message,
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Target::Smtp { server, from, to } => send_email(server, from, to, n).await?,
+        }
+        Ok(())
+    }
+
+    /// Sends to each target which wants the event, giving up on any which don't answer in
+    /// time. Errors are logged, not returned.
+    pub async fn notify(&self, n: Notification) {
+        for notifier in self.notifiers.iter().filter(|c| c.wants(n.event)) {
+            match tokio::time::timeout(SEND_TIMEOUT, self.send(&notifier.target, &n)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Notification \"{}\" failed: {}", n.title, e),
+                Err(_) => log::error!("Notification \"{}\" timed out", n.title),
+            }
+        }
+    }
+}
+
+/// Periodically checks subscribed shows, notifying of episodes not seen before
+pub struct EpisodeWatcher {
+    pub config: Config,
+    pub notifier: Arc<Notifier>,
+    pub interval: Duration,
+}
+
+impl EpisodeWatcher {
+    pub async fn run(self) {
+        let mut seen: HashMap<bbc::Pid, HashSet<String>> = HashMap::new();
+        loop {
+            for programme_id in self.config.subscriptions.iter().flatten() {
+                let options = self.config.feed_options(programme_id);
+                let (show, episodes) =
+                    match sounds_proxy::get_show("", programme_id, &options).await {
+                        Ok(show) => show,
+                        Err(e) => {
+                            log::warn!("Couldn't check {} for new episodes: {}", programme_id, e);
+                            continue;
+                        }
+                    };
+
+                // Everything is new the first time a show is checked
+                let previous = match seen.get_mut(programme_id) {
+                    Some(previous) => previous,
+                    None => {
+                        seen.insert(
+                            programme_id.clone(),
+                            episodes.into_iter().map(|e| e.id).collect(),
+                        );
+                        continue;
+                    }
+                };
+
+                for episode in episodes {
+                    if previous.insert(episode.id.clone()) {
+                        self.notifier
+                            .notify(Notification {
+                                event: Event::NewEpisode,
+                                title: format!("New episode of {}", show.title),
+                                message: episode.title.unwrap_or_else(|| episode.id.clone()),
+                                url: Some(format!(
+                                    "https://www.bbc.co.uk/sounds/play/{}",
+                                    episode.id
+                                )),
+                            })
+                            .await;
+                    }
+                }
+            }
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_encode_header() {
+        assert_eq!(encode_header("New episode"), "New episode");
+        assert_eq!(encode_header("Café time"), "=?UTF-8?Q?Caf=C3=A9_time?=");
+    }
+
+    #[test]
+    fn test_notifier_config() {
+        let config: NotifierConfig = serde_json::from_str(
+            r#"{"type": "ntfy", "url": "https://ntfy.sh", "topic": "radio", "events": ["new_episode"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.target,
+            Target::Ntfy {
+                url: "https://ntfy.sh".to_string(),
+                topic: "radio".to_string()
+            }
+        );
+
+        let notifier = Notifier::new(vec![config]);
+        assert!(notifier.wants(Event::NewEpisode));
+        assert!(!notifier.wants(Event::Failure));
+    }
+}
@@ -0,0 +1,200 @@
+use serde::Serialize;
+use url::Url;
+
+use crate::{
+    archive::html_escape,
+    bbc,
+    config::Config,
+    sounds_proxy::{self, Show},
+};
+
+type Result<T, E = bbc::BbcResponseError> = core::result::Result<T, E>;
+
+// Size of artwork from sounds_proxy::template_url
+const THUMBNAIL_SIZE: u32 = 400;
+
+// Size of a browser's audio controls
+const PLAYER_WIDTH: u32 = 400;
+const PLAYER_HEIGHT: u32 = 54;
+
+/// An oEmbed response (https://oembed.com/)
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    author_name: String,
+    author_url: String,
+    provider_name: &'static str,
+    provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+/// A proxy URL that can be embedded
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    Show(bbc::Pid),
+    Episode {
+        episode: bbc::Pid,
+        show: Option<bbc::Pid>,
+    },
+}
+
+/// Works out what a URL links to, if it's one of this proxy's show or episode URLs
+fn parse_target(base_url: &str, url: &str) -> Option<Target> {
+    let base = Url::parse(base_url).ok()?;
+    let url = Url::parse(url).ok()?;
+    if url.origin() != base.origin() {
+        return None;
+    }
+    let path = url.path().strip_prefix(base.path().trim_end_matches('/'))?;
+
+    match path.trim_start_matches('/').split('/').collect::<Vec<_>>()[..] {
+        ["show", pid] => Some(Target::Show(pid.parse().ok()?)),
+        ["episode", pid] | ["episode", pid, "audio"] => {
+            let show = url
+                .query_pairs()
+                .find(|(k, _)| k == "show")
+                .and_then(|(_, v)| v.parse().ok());
+            Some(Target::Episode {
+                episode: pid.trim_end_matches(".aac").parse().ok()?,
+                show,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn embed(base_url: &str, show: &Show, title: String, image: Option<String>) -> OEmbed {
+    let thumbnail_size = image.as_ref().map(|_| THUMBNAIL_SIZE);
+    OEmbed {
+        version: "1.0",
+        kind: "link",
+        title,
+        author_name: show.author.clone(),
+        author_url: show.link.clone(),
+        provider_name: "sounds-proxy",
+        provider_url: base_url.to_string(),
+        thumbnail_url: image,
+        thumbnail_width: thumbnail_size,
+        thumbnail_height: thumbnail_size,
+        html: None,
+        width: None,
+        height: None,
+    }
+}
+
+/// oEmbed for a show or episode URL. Episodes embed a player, no wider than `max_width`.
+pub async fn get_oembed(
+    base_url: &str,
+    config: &Config,
+    url: &str,
+    max_width: Option<u32>,
+) -> Result<OEmbed> {
+    match parse_target(base_url, url).ok_or(bbc::BbcResponseError::NotFound)? {
+        Target::Show(pid) => {
+            let (show, _) =
+                sounds_proxy::get_show(base_url, &pid, &config.feed_options(&pid)).await?;
+            let image = show.image.clone();
+            Ok(embed(base_url, &show, show.title.clone(), image))
+        }
+        Target::Episode { episode, show } => {
+            // Episodes are only found through their show
+            let show_id = show.ok_or(bbc::BbcResponseError::NotFound)?;
+            let (show, episodes) =
+                sounds_proxy::get_show(base_url, &show_id, &config.feed_options(&show_id)).await?;
+            let details = episodes
+                .into_iter()
+                .find(|e| e.id == episode.as_str())
+                .ok_or(bbc::BbcResponseError::NotFound)?;
+
+            let title = format!(
+                "{} - {}",
+                show.title,
+                details.title.as_deref().unwrap_or(&details.id)
+            );
+            let image = details.image.or_else(|| show.image.clone());
+            let width = max_width.map_or(PLAYER_WIDTH, |w| w.min(PLAYER_WIDTH));
+            let src = format!("{}/episode/{}?show={}", base_url, episode, show_id);
+
+            Ok(OEmbed {
+                kind: "rich",
+                html: Some(format!(
+                    "<audio controls preload=\"none\" style=\"width: {}px\" src=\"{}\" title=\"{}\"></audio>",
+                    width,
+                    html_escape(&src),
+                    html_escape(&title)
+                )),
+                width: Some(width),
+                height: Some(PLAYER_HEIGHT),
+                ..embed(base_url, &show, title, image)
+            })
+        }
+    }
+}
+
+/// Link header advertising the oEmbed for a URL, for consumers that discover it that way
+pub fn discovery_link(base_url: &str, url: &str) -> String {
+    format!(
+        "<{}/oembed?url={}>; rel=\"alternate\"; type=\"application/json+oembed\"",
+        base_url,
+        percent_encoding::utf8_percent_encode(url, percent_encoding::NON_ALPHANUMERIC)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        let base = "https://proxy.example.com";
+        assert_eq!(
+            parse_target(base, "https://proxy.example.com/show/p02pc9pj"),
+            Some(Target::Show("p02pc9pj".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_target(
+                base,
+                "https://proxy.example.com/episode/m0015ryz.aac?show=p02pc9pj"
+            ),
+            Some(Target::Episode {
+                episode: "m0015ryz".parse().unwrap(),
+                show: Some("p02pc9pj".parse().unwrap()),
+            })
+        );
+        assert_eq!(
+            parse_target(
+                "https://example.com/radio/",
+                "https://example.com/radio/episode/m0015ryz/audio"
+            ),
+            Some(Target::Episode {
+                episode: "m0015ryz".parse().unwrap(),
+                show: None,
+            })
+        );
+
+        assert_eq!(
+            parse_target(base, "https://elsewhere.example.com/show/p02pc9pj"),
+            None
+        );
+        assert_eq!(
+            parse_target(base, "https://proxy.example.com/archive/p02pc9pj/"),
+            None
+        );
+        assert_eq!(parse_target(base, "not a url"), None);
+    }
+}
@@ -0,0 +1,183 @@
+use itertools::Itertools;
+
+use crate::{
+    bbc::{BbcResponseError, Pid, SegmentItem},
+    hls::Span,
+    tracklist,
+};
+
+/// Segments of this type mark the episodes in an omnibus, where there are any
+const PART_SEGMENT_TYPE: &str = "chapter";
+
+// Segments starting sooner than this after the one before are part of it, e.g. a theme tune
+const MIN_PART_SECS: u64 = 120;
+
+/// One of the episodes making up an omnibus edition
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// From 1
+    pub number: usize,
+    pub title: String,
+    pub start_secs: u64,
+    /// None if the last part runs to the end of the episode
+    pub end_secs: Option<u64>,
+}
+
+impl Part {
+    pub fn span(&self) -> Span {
+        Span {
+            start_secs: self.start_secs as f64,
+            end_secs: self.end_secs.map(|end| end as f64),
+        }
+    }
+
+    /// How long the part is, in an episode lasting `duration` seconds
+    pub fn secs(&self, duration: u64) -> u64 {
+        self.end_secs
+            .unwrap_or(duration)
+            .min(duration)
+            .saturating_sub(self.start_secs)
+    }
+}
+
+/// Splits an episode into parts at its chapter markers, or at its segments if it has no
+/// chapters. Empty if it doesn't split into more than one part.
+pub fn parts(segments: &[SegmentItem]) -> Vec<Part> {
+    let has_chapters = segments
+        .iter()
+        .any(|s| s.segment_type.as_deref() == Some(PART_SEGMENT_TYPE));
+    let markers = segments
+        .iter()
+        .filter(|s| !has_chapters || s.segment_type.as_deref() == Some(PART_SEGMENT_TYPE))
+        .filter_map(|s| s.offset.as_ref().map(|offset| (offset, s)))
+        .sorted_by_key(|(offset, _)| offset.start);
+
+    // each marker runs until the next
+    let markers = markers
+        .dedup_by(|(a, _), (b, _)| a.start == b.start)
+        .collect::<Vec<_>>();
+    let bounds = markers.iter().enumerate().map(|(i, (offset, segment))| {
+        let end = markers
+            .get(i + 1)
+            .map(|(next, _)| next.start)
+            .or(offset.end);
+        (offset.start, end, *segment)
+    });
+
+    let mut parts: Vec<Part> = Vec::new();
+    // start of short markers at the beginning, which the first real part takes in
+    let mut lead_in = None;
+    for (start, end, segment) in bounds {
+        let short = end.map_or(false, |end| end < start + MIN_PART_SECS);
+        match parts.last_mut() {
+            Some(last) if short => last.end_secs = end,
+            None if short => lead_in = lead_in.or(Some(start)),
+            _ => {
+                let title = match &segment.titles.secondary {
+                    Some(secondary) => format!("{} - {}", segment.titles.primary, secondary),
+                    None => segment.titles.primary.clone(),
+                };
+                parts.push(Part {
+                    number: parts.len() + 1,
+                    title,
+                    start_secs: lead_in.take().unwrap_or(start),
+                    end_secs: end,
+                });
+            }
+        }
+    }
+
+    if parts.len() < 2 {
+        return Vec::new();
+    }
+    // the last part runs to the end, rather than stopping with its last segment
+    if let Some(last) = parts.last_mut() {
+        last.end_secs = None;
+    }
+    parts
+}
+
+/// An episode's parts, from the segments cached for tracklists and credits
+pub async fn get_parts(episode_id: &Pid) -> Result<Vec<Part>, BbcResponseError> {
+    let segments = tracklist::get_segments(episode_id).await?;
+    Ok(parts(&segments))
+}
+
+/// One part of an episode, failing if it doesn't have that part
+pub async fn get_part(episode_id: &Pid, number: usize) -> Result<Part, BbcResponseError> {
+    get_parts(episode_id)
+        .await?
+        .into_iter()
+        .find(|p| p.number == number)
+        .ok_or(BbcResponseError::NotFound)
+}
+
+/// S3 key of a part, alongside the whole episode's
+pub fn s3_key(episode_key: &str, number: usize) -> String {
+    match episode_key.strip_suffix(".aac") {
+        Some(stem) => format!("{}-part{}.aac", stem, number),
+        None => format!("{}-part{}", episode_key, number),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::bbc::{SegmentOffset, Titles};
+
+    use super::*;
+
+    fn segment(segment_type: &str, title: &str, start: u64, end: u64) -> SegmentItem {
+        SegmentItem {
+            id: title.to_string(),
+            segment_type: Some(segment_type.to_string()),
+            titles: Titles {
+                primary: title.to_string(),
+                secondary: None,
+            },
+            offset: Some(SegmentOffset {
+                start,
+                end: Some(end),
+            }),
+            contributions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parts() {
+        let omnibus = vec![
+            segment("music", "Theme", 0, 30),
+            segment("chapter", "Monday", 0, 780),
+            segment("chapter", "Tuesday", 780, 1560),
+            segment("music", "Barwick Green", 800, 830),
+            // a trail, folded into Tuesday
+            segment("chapter", "Coming up", 1500, 1560),
+            segment("chapter", "Wednesday", 1560, 2340),
+        ];
+        let parts = parts(&omnibus);
+        assert_eq!(
+            parts
+                .iter()
+                .map(|p| (p.number, &p.title[..], p.start_secs, p.end_secs))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, "Monday", 0, Some(780)),
+                (2, "Tuesday", 780, Some(1560)),
+                (3, "Wednesday", 1560, None),
+            ]
+        );
+        assert_eq!(parts[2].secs(2400), 840);
+
+        // without chapters, every segment marks a part
+        let parts = super::parts(&[
+            segment("speech", "One", 0, 600),
+            segment("speech", "Two", 600, 1200),
+        ]);
+        assert_eq!(parts.len(), 2);
+
+        assert!(super::parts(&[segment("chapter", "Whole", 0, 1800)]).is_empty());
+
+        assert_eq!(s3_key("b0abc123.aac", 2), "b0abc123-part2.aac");
+        assert_eq!(s3_key("b0abc123-lo.aac", 2), "b0abc123-lo-part2.aac");
+    }
+}
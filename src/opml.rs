@@ -0,0 +1,87 @@
+use crate::{archive::html_escape, bbc};
+
+/// A show's feed in an OPML subscription list
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outline {
+    pub title: String,
+    pub feed_url: String,
+    /// The show's page on BBC Sounds
+    pub page_url: String,
+}
+
+async fn show_title(programme_id: &bbc::Pid) -> Result<String, bbc::BbcResponseError> {
+    let urn = format!("urn:bbc:radio:series:{}", programme_id);
+    let container = bbc::get_container(&urn).await?;
+    container
+        .data
+        .iter()
+        .find_map(|d| d.item())
+        .map(|item| item.data.titles.primary.clone())
+        .ok_or(bbc::BbcResponseError::FormatError)
+}
+
+/// Outlines of the shows' feeds, in order. Shows are titled by their pid if the BBC
+/// doesn't answer, so the list is still complete.
+pub async fn get_outlines(base_url: &str, shows: &[bbc::Pid]) -> Vec<Outline> {
+    futures::future::join_all(shows.iter().map(|pid| async move {
+        let title = show_title(pid).await.unwrap_or_else(|e| {
+            log::warn!("Couldn't get title of {} for OPML: {}", pid, e);
+            pid.to_string()
+        });
+        Outline {
+            title,
+            feed_url: format!("{}/show/{}", base_url, pid),
+            page_url: format!("https://www.bbc.co.uk/sounds/series/{}", pid),
+        }
+    }))
+    .await
+}
+
+/// An OPML 2.0 subscription list, which podcast apps import in one go
+pub fn render(title: &str, outlines: &[Outline]) -> String {
+    let outlines = outlines
+        .iter()
+        .map(|o| {
+            format!(
+                "<outline type=\"rss\" text=\"{0}\" title=\"{0}\" xmlUrl=\"{1}\" htmlUrl=\"{2}\"/>",
+                html_escape(&o.title),
+                html_escape(&o.feed_url),
+                html_escape(&o.page_url)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n<head><title>{}</title></head>\n<body>\n{}\n</body>\n</opml>\n",
+        html_escape(title),
+        outlines
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let outlines = [Outline {
+            title: "Drama & Comedy".to_string(),
+            feed_url: "https://example.com/show/p02pc9pj".to_string(),
+            page_url: "https://www.bbc.co.uk/sounds/series/p02pc9pj".to_string(),
+        }];
+        assert_eq!(
+            render("BBC Sounds", &outlines),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <opml version=\"2.0\">\n\
+             <head><title>BBC Sounds</title></head>\n\
+             <body>\n\
+             <outline type=\"rss\" text=\"Drama &amp; Comedy\" title=\"Drama &amp; Comedy\" \
+             xmlUrl=\"https://example.com/show/p02pc9pj\" \
+             htmlUrl=\"https://www.bbc.co.uk/sounds/series/p02pc9pj\"/>\n\
+             </body>\n\
+             </opml>\n"
+        );
+    }
+}
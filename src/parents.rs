@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    bbc::{self, BbcResponseError, Pid, ProgrammeInfo},
+    config::Config,
+};
+
+// Episodes are rarely moved between shows or retitled, so they're kept for a while
+const CACHE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_CACHE_ENTRIES: usize = 8192;
+
+struct CacheEntry {
+    programme: Arc<ProgrammeInfo>,
+    expires: Instant,
+}
+
+static PROGRAMMES: Lazy<Mutex<HashMap<Pid, CacheEntry>>> = Lazy::new(Mutex::default);
+
+fn cached(pid: &Pid) -> Option<Arc<ProgrammeInfo>> {
+    let cache = PROGRAMMES.lock().unwrap();
+    match cache.get(pid) {
+        Some(entry) if entry.expires > Instant::now() => Some(entry.programme.clone()),
+        _ => None,
+    }
+}
+
+fn store(pid: &Pid, programme: Arc<ProgrammeInfo>) {
+    let now = Instant::now();
+    let mut cache = PROGRAMMES.lock().unwrap();
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        cache.retain(|_, e| e.expires > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            let soonest = cache
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(k, _)| k.clone());
+            if let Some(soonest) = soonest {
+                cache.remove(&soonest);
+            }
+        }
+    }
+    cache.insert(
+        pid.clone(),
+        CacheEntry {
+            programme,
+            expires: now + CACHE_LIFETIME,
+        },
+    );
+}
+
+/// An episode, or a show, as bbc.co.uk/programmes describes it
+pub async fn get_programme(pid: &Pid) -> Result<Arc<ProgrammeInfo>, BbcResponseError> {
+    if let Some(programme) = cached(pid) {
+        return Ok(programme);
+    }
+    let programme = Arc::new(bbc::get_programme(pid).await?);
+    store(pid, programme.clone());
+    Ok(programme)
+}
+
+/// The episode and series an episode belongs to, and their brand, nearest first, as the
+/// BBC lists them. Unlike the `show` clients send, these can be trusted.
+pub async fn get_parents(episode_id: &Pid) -> Result<Vec<Pid>, BbcResponseError> {
+    Ok(get_programme(episode_id).await?.ancestors.clone())
+}
+
+/// The nearest show the episode is part of with its own settings, if any, so per-show
+/// settings follow the BBC's listing rather than the `show` a client sends
+pub async fn configured_show(
+    config: &Config,
+    episode_id: &Pid,
+) -> Result<Option<Pid>, BbcResponseError> {
+    if config.shows.is_none() {
+        return Ok(None);
+    }
+    let parents = get_parents(episode_id).await?;
+    Ok(parents.iter().find(|p| config.show(p).is_some()).cloned())
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{bbc::Pid, state::token_hash};
+
+/// How far through an episode a listener is. Named as in gPodder's episode actions.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    /// Seconds from the start
+    pub position: u64,
+    /// Length of the episode in seconds, if the player knows it
+    pub total: Option<u64>,
+    /// When the listener got there
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A position sent by a player, stamped now if it doesn't say when it's from
+#[derive(Clone, Debug, Deserialize)]
+pub struct PositionUpdate {
+    pub position: u64,
+    pub total: Option<u64>,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+// Positions kept for each API key, the oldest making way for new ones past this
+const MAX_EPISODES_PER_KEY: usize = 1000;
+
+/// Positions by SHA-1 of the API key, so the file doesn't hold the keys, then by episode id
+type Positions = HashMap<String, HashMap<String, Position>>;
+
+static POSITIONS: Lazy<Mutex<Positions>> = Lazy::new(Mutex::default);
+
+/// File positions are kept in, so they outlast restarts
+static PROGRESS_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Wakes the task saving positions when they change
+static CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Loads positions saved to the file, and saves them there from now on
+pub fn load(path: &Path) {
+    if PROGRESS_FILE.set(path.to_path_buf()).is_ok() {
+        actix_web::rt::spawn(save_changes(path.to_path_buf()));
+    }
+    match std::fs::read(path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(positions) => *POSITIONS.lock().unwrap() = positions,
+            Err(e) => log::warn!("Ignoring invalid progress file: {}", e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::warn!("Failed to read progress file: {}", e),
+    }
+}
+
+/// Saves positions whenever they change, one save at a time, with changes made during a
+/// save saved together after it
+async fn save_changes(path: PathBuf) {
+    loop {
+        CHANGED.notified().await;
+        let json = serde_json::to_vec(&*POSITIONS.lock().unwrap()).unwrap();
+        let path = path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // replaced in one go, so a crash never leaves half a file, and named for this
+            // process, which may be handing over to another
+            let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+            std::fs::write(&temp, json).and_then(|_| std::fs::rename(&temp, &path))
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+        if let Err(e) = result {
+            log::warn!("Failed to save playback positions: {}", e);
+        }
+    }
+}
+
+/// Where the key's listener got to in the episode
+pub fn get(api_key: &str, episode_id: &Pid) -> Option<Position> {
+    POSITIONS
+        .lock()
+        .unwrap()
+        .get(&token_hash(api_key))?
+        .get(episode_id.as_str())
+        .cloned()
+}
+
+/// Keeps the position unless a later one is already kept, as players may sync out of
+/// order. Returns the position kept. A position from the future is taken to be from now,
+/// so a wrong clock can't keep it from ever being replaced.
+pub fn set(
+    api_key: &str,
+    episode_id: &Pid,
+    update: PositionUpdate,
+    now: DateTime<Utc>,
+) -> Position {
+    let position = Position {
+        position: update.position,
+        total: update.total,
+        timestamp: update.timestamp.map_or(now, |timestamp| timestamp.min(now)),
+    };
+    {
+        let mut positions = POSITIONS.lock().unwrap();
+        let episodes = positions.entry(token_hash(api_key)).or_default();
+        match episodes.get(episode_id.as_str()) {
+            Some(existing) if existing.timestamp > position.timestamp => {
+                return existing.clone();
+            }
+            Some(_) => {}
+            None if episodes.len() >= MAX_EPISODES_PER_KEY => {
+                let oldest = episodes
+                    .iter()
+                    .min_by_key(|(_, p)| p.timestamp)
+                    .map(|(id, _)| id.clone());
+                if let Some(oldest) = oldest {
+                    episodes.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        episodes.insert(episode_id.to_string(), position.clone());
+    }
+    CHANGED.notify_one();
+    position
+}
+
+#[cfg(test)]
+mod tests {
+
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_positions() {
+        let now = Utc::now();
+        let episode: Pid = "p0bzn8f1".parse().unwrap();
+        assert_eq!(get("listener", &episode), None);
+
+        let update = |position, timestamp| PositionUpdate {
+            position,
+            total: Some(1675),
+            timestamp,
+        };
+        let kept = set("listener", &episode, update(600, None), now);
+        assert_eq!(kept.timestamp, now);
+        assert_eq!(get("listener", &episode), Some(kept.clone()));
+        // each key has its own positions
+        assert_eq!(get("someone else", &episode), None);
+
+        // an earlier position synced late doesn't go back
+        let stale = set(
+            "listener",
+            &episode,
+            update(300, Some(now - Duration::minutes(5))),
+            now,
+        );
+        assert_eq!(stale, kept);
+
+        let later = set(
+            "listener",
+            &episode,
+            update(900, None),
+            now + Duration::minutes(1),
+        );
+        assert_eq!(get("listener", &episode).unwrap().position, 900);
+        assert_eq!(later.position, 900);
+
+        // a position from the future is from now, so later ones still replace it
+        let now = now + Duration::minutes(2);
+        let future = set(
+            "listener",
+            &episode,
+            update(1000, Some(now + Duration::days(365))),
+            now,
+        );
+        assert_eq!(future.timestamp, now);
+        set("listener", &episode, update(1100, None), now);
+        assert_eq!(get("listener", &episode).unwrap().position, 1100);
+    }
+
+    #[test]
+    fn test_max_episodes() {
+        let start = Utc::now();
+        let update = PositionUpdate {
+            position: 60,
+            total: None,
+            timestamp: None,
+        };
+        let episode = |n: usize| -> Pid { format!("p{:07}", n).parse().unwrap() };
+
+        for n in 0..=MAX_EPISODES_PER_KEY {
+            let now = start + Duration::seconds(n as i64);
+            set("busy listener", &episode(n), update.clone(), now);
+        }
+        // the oldest made way
+        assert_eq!(get("busy listener", &episode(0)), None);
+        assert!(get("busy listener", &episode(1)).is_some());
+        assert!(get("busy listener", &episode(MAX_EPISODES_PER_KEY)).is_some());
+    }
+}
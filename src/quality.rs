@@ -0,0 +1,101 @@
+use actix_web::HttpRequest;
+use serde::Deserialize;
+
+use crate::bbc::Pid;
+
+/// Client hints a rendition is chosen by, which responses vary on
+pub const HINT_HEADERS: &str = "Save-Data, ECT, Downlink";
+
+// Below this many Mbps, a connection gets the low quality rendition
+const LOW_QUALITY_DOWNLINK: f64 = 1.0;
+
+/// Rendition of an episode kept in S3
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    Hi,
+    Lo,
+}
+
+impl Quality {
+    /// The rendition for a request. A `quality` query parameter wins, otherwise
+    /// client hints that the connection is metered or slow choose the low one.
+    pub fn for_request(req: &HttpRequest, requested: Option<Quality>) -> Quality {
+        if let Some(quality) = requested {
+            return quality;
+        }
+
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.trim().to_ascii_lowercase())
+        };
+        let save_data = header("save-data").map_or(false, |h| h == "on");
+        let slow_ect = header("ect").map_or(false, |h| matches!(&h[..], "slow-2g" | "2g" | "3g"));
+        let slow_downlink = header("downlink")
+            .and_then(|h| h.parse::<f64>().ok())
+            .map_or(false, |mbps| mbps < LOW_QUALITY_DOWNLINK);
+
+        if save_data || slow_ect || slow_downlink {
+            Quality::Lo
+        } else {
+            Quality::Hi
+        }
+    }
+}
+
+/// Key of an episode's audio in S3. The high quality rendition keeps the key
+/// used before there were renditions, so existing uploads and the archive still find it.
+/// Shows with their own audio settings keep their episodes apart, by `rendition`.
+pub fn s3_key(episode_id: &Pid, quality: Quality, rendition: Option<&str>) -> String {
+    let rendition = rendition.map(|r| format!("-{}", r)).unwrap_or_default();
+    match quality {
+        Quality::Hi => format!("{}{}.aac", episode_id, rendition),
+        Quality::Lo => format!("{}-lo{}.aac", episode_id, rendition),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_for_request() {
+        let quality = |headers: &[(&str, &str)], requested| {
+            let req = headers
+                .iter()
+                .fold(TestRequest::default(), |req, &header| {
+                    req.insert_header(header)
+                })
+                .to_http_request();
+            Quality::for_request(&req, requested)
+        };
+
+        assert_eq!(quality(&[], None), Quality::Hi);
+        assert_eq!(quality(&[("Save-Data", "on")], None), Quality::Lo);
+        assert_eq!(quality(&[("ECT", "3g")], None), Quality::Lo);
+        assert_eq!(quality(&[("ECT", "4g")], None), Quality::Hi);
+        assert_eq!(quality(&[("Downlink", "0.5")], None), Quality::Lo);
+        assert_eq!(quality(&[("Downlink", "10")], None), Quality::Hi);
+        assert_eq!(
+            quality(&[("Save-Data", "on")], Some(Quality::Hi)),
+            Quality::Hi
+        );
+        assert_eq!(quality(&[], Some(Quality::Lo)), Quality::Lo);
+    }
+
+    #[test]
+    fn test_s3_key() {
+        let episode_id = "p0bzn8f1".parse().unwrap();
+        assert_eq!(s3_key(&episode_id, Quality::Hi, None), "p0bzn8f1.aac");
+        assert_eq!(s3_key(&episode_id, Quality::Lo, None), "p0bzn8f1-lo.aac");
+        assert_eq!(
+            s3_key(&episode_id, Quality::Lo, Some("0a1b2c3d")),
+            "p0bzn8f1-lo-0a1b2c3d.aac"
+        );
+    }
+}
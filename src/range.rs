@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+
+/// How to answer a request's Range header for a body of known length
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No usable range was asked for, so the whole body is sent (200)
+    Full,
+    /// Inclusive start and end of the bytes to send (206)
+    Partial(u64, u64),
+    /// None of the ranges overlap the body (416)
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Content-Range header for the response, if it needs one
+    pub fn content_range(&self, len: u64) -> Option<String> {
+        match self {
+            ByteRange::Full => None,
+            ByteRange::Partial(start, end) => Some(format!("bytes {}-{}/{}", start, end, len)),
+            ByteRange::Unsatisfiable => Some(format!("bytes */{}", len)),
+        }
+    }
+}
+
+/// Whether a range may be served under a request's If-Range header (RFC 7233), which a
+/// client resuming a download sends so it doesn't get the rest of a different file.
+/// The header holds either the entity tag or the modification date it has part of.
+pub fn if_range_matches(
+    header: Option<&str>,
+    e_tag: Option<&str>,
+    last_modified: Option<DateTime<Utc>>,
+) -> bool {
+    let header = match header {
+        Some(header) => header.trim(),
+        None => return true,
+    };
+    if header.starts_with('"') || header.starts_with("W/") {
+        // only strong tags match, and weak ones start with W/
+        e_tag.map_or(false, |e_tag| e_tag == header)
+    } else {
+        match (DateTime::parse_from_rfc2822(header), last_modified) {
+            (Ok(date), Some(last_modified)) => date.with_timezone(&Utc) == last_modified,
+            _ => false,
+        }
+    }
+}
+
+// One range-spec, or None if it's malformed
+fn parse_spec(spec: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let (first, last) = spec.trim().split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    let range = if first.is_empty() {
+        // suffix range, the last n bytes
+        let n: u64 = last.parse().ok()?;
+        if n == 0 || len == 0 {
+            None
+        } else {
+            Some((len.saturating_sub(n), len - 1))
+        }
+    } else {
+        let start: u64 = first.parse().ok()?;
+        let end = match last {
+            "" => None,
+            last => Some(last.parse::<u64>().ok()?),
+        };
+        if end.map_or(false, |end| end < start) {
+            return None;
+        }
+        if start >= len {
+            None
+        } else {
+            Some((start, end.map_or(len - 1, |end| end.min(len - 1))))
+        }
+    };
+    Some(range)
+}
+
+/// Interprets a Range header (RFC 7233) for a body of `len` bytes.
+///
+/// Headers that can't be parsed are ignored, as the spec requires. Several
+/// ranges are coalesced into the one range covering them all, since players
+/// only ask for more than one to probe the start and end of the file, and a
+/// single part is far better supported than multipart/byteranges.
+pub fn parse(header: Option<&str>, len: u64) -> ByteRange {
+    let specs = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(specs) => specs,
+        None => return ByteRange::Full,
+    };
+
+    let specs = specs
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .collect::<Vec<_>>();
+    if specs.is_empty() {
+        return ByteRange::Full;
+    }
+
+    let mut covered: Option<(u64, u64)> = None;
+    for spec in specs {
+        match parse_spec(spec, len) {
+            None => return ByteRange::Full,
+            Some(None) => {}
+            Some(Some((start, end))) => {
+                covered = Some(match covered {
+                    Some((s, e)) => (s.min(start), e.max(end)),
+                    None => (start, end),
+                })
+            }
+        }
+    }
+
+    match covered {
+        Some((start, end)) => ByteRange::Partial(start, end),
+        None => ByteRange::Unsatisfiable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(None, 1000), ByteRange::Full);
+        assert_eq!(parse(Some("items=0-1"), 1000), ByteRange::Full);
+        assert_eq!(parse(Some("bytes=5-1"), 1000), ByteRange::Full);
+        assert_eq!(parse(Some("bytes=a-"), 1000), ByteRange::Full);
+        assert_eq!(parse(Some("bytes="), 1000), ByteRange::Full);
+
+        assert_eq!(parse(Some("bytes=0-1"), 1000), ByteRange::Partial(0, 1));
+        assert_eq!(parse(Some("bytes=0-"), 1000), ByteRange::Partial(0, 999));
+        assert_eq!(
+            parse(Some("bytes=100-"), 1000),
+            ByteRange::Partial(100, 999)
+        );
+        assert_eq!(
+            parse(Some("bytes=500-5000"), 1000),
+            ByteRange::Partial(500, 999)
+        );
+        assert_eq!(
+            parse(Some("bytes=-100"), 1000),
+            ByteRange::Partial(900, 999)
+        );
+        assert_eq!(parse(Some("bytes=-5000"), 1000), ByteRange::Partial(0, 999));
+
+        // multiple ranges, ignoring the unsatisfiable ones
+        assert_eq!(
+            parse(Some("bytes=0-1, -10, 2000-"), 1000),
+            ByteRange::Partial(0, 999)
+        );
+        assert_eq!(
+            parse(Some("bytes=10-19,20-29"), 1000),
+            ByteRange::Partial(10, 29)
+        );
+
+        assert_eq!(parse(Some("bytes=1000-"), 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse(Some("bytes=-0"), 1000), ByteRange::Unsatisfiable);
+        assert_eq!(
+            parse(Some("bytes=1000-,2000-3000"), 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let last_modified = DateTime::parse_from_rfc3339("2022-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let e_tag = Some("\"9b2cf535f27731c974343645a3985328\"");
+        assert!(if_range_matches(None, e_tag, Some(last_modified)));
+        assert!(if_range_matches(
+            Some("\"9b2cf535f27731c974343645a3985328\""),
+            e_tag,
+            None
+        ));
+        assert!(!if_range_matches(Some("\"0cc175b9c0f1b6a8\""), e_tag, None));
+        assert!(!if_range_matches(
+            Some("W/\"9b2cf535f27731c974343645a3985328\""),
+            e_tag,
+            None
+        ));
+        assert!(if_range_matches(
+            Some("Wed, 01 Jun 2022 12:00:00 GMT"),
+            e_tag,
+            Some(last_modified)
+        ));
+        assert!(!if_range_matches(
+            Some("Wed, 01 Jun 2022 11:00:00 GMT"),
+            e_tag,
+            Some(last_modified)
+        ));
+        assert!(!if_range_matches(
+            Some("yesterday"),
+            e_tag,
+            Some(last_modified)
+        ));
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(ByteRange::Full.content_range(1000), None);
+        assert_eq!(
+            ByteRange::Partial(100, 999).content_range(1000),
+            Some("bytes 100-999/1000".to_string())
+        );
+        assert_eq!(
+            ByteRange::Unsatisfiable.content_range(1000),
+            Some("bytes */1000".to_string())
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+/// Tracks startup work which must finish before the server should be sent traffic
+#[derive(Default)]
+pub struct Readiness {
+    storage: AtomicBool,
+    subscriptions: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessStatus {
+    /// S3 is verified, or isn't configured
+    pub storage: bool,
+    /// Subscribed feeds have been exported once, or export isn't configured
+    pub subscriptions: bool,
+}
+
+impl ReadinessStatus {
+    pub fn is_ready(&self) -> bool {
+        self.storage && self.subscriptions
+    }
+}
+
+impl Readiness {
+    pub fn set_storage_ready(&self) {
+        self.storage.store(true, Ordering::Release);
+    }
+
+    pub fn set_subscriptions_ready(&self) {
+        self.subscriptions.store(true, Ordering::Release);
+    }
+
+    pub fn status(&self) -> ReadinessStatus {
+        ReadinessStatus {
+            storage: self.storage.load(Ordering::Acquire),
+            subscriptions: self.subscriptions.load(Ordering::Acquire),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+
+// The system resolver doesn't give record TTLs, so addresses are kept for this long
+const CACHE_TTL: Duration = Duration::from_secs(300);
+// Hosts whose addresses are kept at once
+const MAX_CACHED_HOSTS: usize = 1024;
+
+// Addresses for a host, and when they were looked up
+type Cache = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+/// Caches a host's addresses, making room by dropping expired hosts, then the one
+/// looked up longest ago
+fn store(cache: &mut Cache, host: String, addrs: Vec<SocketAddr>) {
+    if cache.len() >= MAX_CACHED_HOSTS && !cache.contains_key(&host) {
+        cache.retain(|_, (resolved, _)| resolved.elapsed() < CACHE_TTL);
+        if cache.len() >= MAX_CACHED_HOSTS {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, (resolved, _))| *resolved)
+                .map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+    }
+    cache.insert(host, (Instant::now(), addrs));
+}
+
+/// Caches resolved addresses, as segments come from many CDN hostnames
+/// and looking each up again adds a round trip before every download.
+#[derive(Default)]
+pub struct CachingResolver {
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&host)
+            .filter(|(resolved, _)| resolved.elapsed() < CACHE_TTL)
+            .map(|(_, addrs)| addrs.clone());
+        if let Some(addrs) = cached {
+            return Box::pin(futures::future::ready(Ok(
+                Box::new(addrs.into_iter()) as Addrs
+            )));
+        }
+
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            // the port is replaced by the connector
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            store(&mut cache.lock().unwrap(), host, addrs.clone());
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_store_bounded() {
+        let mut cache = Cache::new();
+        let a_minute_ago = Instant::now() - Duration::from_secs(60);
+        cache.insert("old.example.com".to_string(), (a_minute_ago, Vec::new()));
+        for i in 1..MAX_CACHED_HOSTS {
+            store(&mut cache, format!("host{}.example.com", i), Vec::new());
+        }
+        store(&mut cache, "new.example.com".to_string(), Vec::new());
+
+        assert_eq!(cache.len(), MAX_CACHED_HOSTS);
+        assert!(cache.contains_key("new.example.com"));
+        assert!(!cache.contains_key("old.example.com"));
+    }
+}
@@ -0,0 +1,725 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use aws_sdk_s3::{
+    error::{GetObjectError, GetObjectErrorKind, HeadObjectError, HeadObjectErrorKind},
+    model::{CompletedMultipartUpload, CompletedPart, ObjectCannedAcl, ServerSideEncryption},
+    types::{ByteStream, SdkError},
+    Client,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::FuturesUnordered;
+use futures::Future;
+use futures::Stream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+
+use crate::limits::{Reservation, BUFFERS};
+
+#[derive(Debug, thiserror::Error)]
+pub enum S3Error {
+    #[error("request error")]
+    RequestError,
+
+    #[error("io error {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("unknown error")]
+    UnknownError,
+}
+
+impl<E> From<SdkError<E>> for S3Error
+where
+    E: std::error::Error,
+{
+    fn from(err: SdkError<E>) -> Self {
+        log::error!("AWS SDK Error: {:?}", err);
+        S3Error::RequestError
+    }
+}
+
+impl From<hyper::Error> for S3Error {
+    fn from(err: hyper::Error) -> Self {
+        log::error!("Hyper Error: {:?}", err);
+        S3Error::RequestError
+    }
+}
+
+/// Creates a client for the bucket, returning it with the bucket's region.
+/// Returns None if no bucket is configured.
+pub async fn create_client(
+    bucket: &Option<String>,
+    endpoint: &Option<String>,
+) -> Option<(Client, String)> {
+    match bucket {
+        Some(bucket) => Some(
+            connect(bucket, endpoint)
+                .await
+                .unwrap_or_else(|_| panic!("Failed to get bucket location for {}", bucket)),
+        ),
+        None => None,
+    }
+}
+
+/// A client made once at startup, for requests that shouldn't each connect to the bucket
+#[derive(Default)]
+pub struct SharedClient(once_cell::sync::OnceCell<Client>);
+
+impl SharedClient {
+    pub fn set(&self, client: Client) {
+        let _ = self.0.set(client);
+    }
+
+    /// The client, or None before startup has made it or if no bucket is configured
+    pub fn get(&self) -> Option<&Client> {
+        self.0.get()
+    }
+}
+
+/// Creates a client for the bucket and looks up its region, failing if the bucket can't be reached
+pub async fn connect(bucket: &str, endpoint: &Option<String>) -> Result<(Client, String), S3Error> {
+    let config_loader = aws_config::from_env();
+    let config_loader = match endpoint {
+        Some(endpoint) => {
+            let url = endpoint.parse().unwrap();
+            config_loader.endpoint_resolver(aws_sdk_s3::Endpoint::immutable(url))
+        }
+        None => config_loader,
+    };
+    let config = config_loader.load().await;
+    let client = Client::new(&config);
+
+    let region = client
+        .get_bucket_location()
+        .bucket(bucket)
+        .send()
+        .await?
+        .location_constraint
+        .map_or_else(|| "us-east-1".to_string(), |region| region.as_str().into());
+
+    Ok((client, region))
+}
+
+/// Server-side encryption algorithm, as S3 names it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+pub enum Sse {
+    #[serde(rename = "AES256")]
+    Aes256,
+    #[serde(rename = "aws:kms")]
+    AwsKms,
+}
+
+impl From<Sse> for ServerSideEncryption {
+    fn from(sse: Sse) -> Self {
+        match sse {
+            Sse::Aes256 => ServerSideEncryption::Aes256,
+            Sse::AwsKms => ServerSideEncryption::AwsKms,
+        }
+    }
+}
+
+/// Server-side encryption applied to uploaded objects
+#[derive(Clone, Debug, Default)]
+pub struct Encryption {
+    pub algorithm: Option<Sse>,
+    /// KMS key to use with `aws:kms`, otherwise the bucket's default key is used
+    pub kms_key_id: Option<String>,
+}
+
+/// Size of the first parts of an upload, 5 MB, the minimum aws allows
+pub const BUFFER_SIZE: usize = 0x500000;
+
+// S3 limits
+const MAX_PARTS: i32 = 10000;
+const MAX_PART_SIZE: usize = 5 * 1024 * 1024 * 1024;
+
+// Part size doubles after this many parts, so streams of unknown length can
+// reach several terabytes without running out of parts
+const PART_SIZE_GROWTH_INTERVAL: i32 = 1000;
+
+// Parts of each upload sent at once
+static PART_CONCURRENCY: AtomicUsize = AtomicUsize::new(1);
+
+pub fn set_part_concurrency(concurrency: usize) {
+    PART_CONCURRENCY.store(concurrency.max(1), Ordering::Release);
+}
+
+fn part_size(base_size: usize, part_number: i32) -> usize {
+    let doublings = ((part_number - 1) / PART_SIZE_GROWTH_INTERVAL) as u32;
+    base_size
+        .checked_mul(1 << doublings)
+        .unwrap_or(MAX_PART_SIZE)
+        .min(MAX_PART_SIZE)
+}
+
+/// Size of the first parts of an upload of about `expected_size` bytes: the smallest S3
+/// allows, unless that many parts wouldn't hold it
+fn base_part_size(expected_size: Option<u64>) -> usize {
+    let max_parts = MAX_PARTS as u64;
+    let needed = expected_size.map_or(0, |size| {
+        size / max_parts + u64::from(size % max_parts != 0)
+    });
+    (needed as usize).clamp(BUFFER_SIZE, MAX_PART_SIZE)
+}
+
+/// Reserves a buffer for the next part. Parts already uploading carry on meanwhile, as
+/// it may be their buffers it's waiting for.
+async fn reserve_part<Fut>(
+    size: usize,
+    uploading: &mut FuturesUnordered<Fut>,
+    parts: &mut Vec<(i32, String)>,
+) -> Result<Reservation<'static>, S3Error>
+where
+    Fut: Future<Output = Result<(i32, String), S3Error>>,
+{
+    loop {
+        if uploading.is_empty() {
+            return Ok(BUFFERS.reserve(size).await);
+        }
+        tokio::select! {
+            reservation = BUFFERS.reserve(size) => return Ok(reservation),
+            Some(part) = uploading.next() => parts.push(part?),
+        }
+    }
+}
+
+/// Splits the stream into parts, growing the part size as the upload gets longer.
+/// Up to `concurrency` parts are uploaded at once. Parts are returned in order.
+async fn upload_parts<S, B, F, Fut>(
+    stream: S,
+    base_size: usize,
+    concurrency: usize,
+    mut upload_part: F,
+) -> Result<Vec<(i32, String)>, S3Error>
+where
+    S: Stream<Item = Result<B, std::io::Error>> + Unpin,
+    B: Buf,
+    F: FnMut(Bytes, i32) -> Fut,
+    Fut: Future<Output = Result<(i32, String), S3Error>>,
+{
+    let mut stream = stream.fuse();
+
+    let mut parts = Vec::new();
+    // each holds its buffer's reservation until it's uploaded
+    let mut uploading = FuturesUnordered::new();
+    let mut start_upload = |buff: BytesMut, part_number, reservation: Reservation<'static>| {
+        let upload = upload_part(buff.freeze(), part_number);
+        async move {
+            let part = upload.await;
+            drop(reservation);
+            part
+        }
+    };
+
+    let mut part_number = 1;
+    let mut size = part_size(base_size, part_number);
+    // waits here if too much is buffered across all streams, holding up the source
+    let mut reservation = BUFFERS.reserve(size).await;
+    let mut buff = BytesMut::with_capacity(size);
+    loop {
+        // parts already uploading carry on while the source is slow
+        let data = tokio::select! {
+            data = stream.next() => match data {
+                Some(data) => data,
+                None => break,
+            },
+            Some(part) = uploading.next(), if !uploading.is_empty() => {
+                parts.push(part?);
+                continue;
+            }
+        };
+        let mut data = data?;
+
+        while data.has_remaining() {
+            if buff.len() < size {
+                // buffer not full
+                if part_number > MAX_PARTS {
+                    log::error!("Upload exceeds {} parts", MAX_PARTS);
+                    return Err(S3Error::RequestError);
+                }
+                let mut piece = data.take(size - buff.len());
+                buff.put(&mut piece);
+                data = piece.into_inner();
+            }
+
+            if buff.len() >= size {
+                // buffer full
+                uploading.push(start_upload(buff, part_number, reservation));
+                while uploading.len() >= concurrency.max(1) {
+                    if let Some(part) = uploading.next().await {
+                        parts.push(part?);
+                    }
+                }
+                part_number += 1;
+                size = part_size(base_size, part_number);
+                reservation = reserve_part(size, &mut uploading, &mut parts).await?;
+                buff = BytesMut::with_capacity(size);
+            }
+        }
+    }
+    // final part
+    if !buff.is_empty() {
+        uploading.push(start_upload(buff, part_number, reservation));
+    }
+    while let Some(part) = uploading.next().await {
+        parts.push(part?);
+    }
+
+    parts.sort_by_key(|(part_number, _)| *part_number);
+    Ok(parts)
+}
+
+pub async fn object_exists(
+    client: &Client,
+    bucket_name: &str,
+    s3_path: &str,
+) -> Result<bool, S3Error> {
+    Ok(object_head(client, bucket_name, s3_path).await?.is_some())
+}
+
+/// An object's metadata, without its body
+pub struct ObjectHead {
+    pub size: u64,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// An object's size and validators, or None if it doesn't exist
+pub async fn object_head(
+    client: &Client,
+    bucket_name: &str,
+    s3_path: &str,
+) -> Result<Option<ObjectHead>, S3Error> {
+    let head_result = client
+        .head_object()
+        .bucket(bucket_name)
+        .key(s3_path)
+        .send()
+        .await;
+
+    let head = match head_result {
+        Ok(output) => Ok(Some(ObjectHead {
+            size: output.content_length() as u64,
+            e_tag: output.e_tag().map(str::to_string),
+            last_modified: output
+                .last_modified()
+                .and_then(|t| Utc.timestamp_opt(t.secs(), 0).single()),
+        })),
+        Err(SdkError::ServiceError {
+            err:
+                HeadObjectError {
+                    kind: HeadObjectErrorKind::NotFound(_),
+                    ..
+                },
+            ..
+        }) => Ok(None),
+        Err(err) => Err(err),
+    }?;
+
+    Ok(head)
+}
+
+pub async fn delete_object(
+    client: &Client,
+    bucket_name: &str,
+    s3_path: &str,
+) -> Result<(), S3Error> {
+    client
+        .delete_object()
+        .bucket(bucket_name)
+        .key(s3_path)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// An object being downloaded
+pub struct Object<S> {
+    pub content_length: u64,
+    pub content_type: Option<String>,
+    pub body: S,
+}
+
+/// Downloads an object as a stream, returning None if it doesn't exist.
+/// A range gives the inclusive first and last bytes to download.
+pub async fn get_object(
+    client: &Client,
+    bucket_name: &str,
+    s3_path: &str,
+    range: Option<(u64, u64)>,
+) -> Result<Option<Object<impl Stream<Item = Result<Bytes, S3Error>>>>, S3Error> {
+    let get_result = client
+        .get_object()
+        .bucket(bucket_name)
+        .key(s3_path)
+        .set_range(range.map(|(start, end)| format!("bytes={}-{}", start, end)))
+        .send()
+        .await;
+
+    let output = match get_result {
+        Ok(output) => output,
+        Err(SdkError::ServiceError {
+            err:
+                GetObjectError {
+                    kind: GetObjectErrorKind::NoSuchKey(_),
+                    ..
+                },
+            ..
+        }) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(Some(Object {
+        content_length: output.content_length() as u64,
+        content_type: output.content_type().map(|s| s.to_string()),
+        body: output.body.map_err(|e| S3Error::Io(e.into())),
+    }))
+}
+
+/// Returns the keys of all objects in the bucket, with their sizes
+pub async fn list_objects(
+    client: &Client,
+    bucket_name: &str,
+) -> Result<HashMap<String, u64>, S3Error> {
+    list_prefix(client, bucket_name, "").await
+}
+
+/// Returns the keys of the objects whose keys start with the prefix, with their sizes
+pub async fn list_prefix(
+    client: &Client,
+    bucket_name: &str,
+    prefix: &str,
+) -> Result<HashMap<String, u64>, S3Error> {
+    let mut keys = HashMap::new();
+    let mut continuation_token = None;
+
+    loop {
+        let resp = client
+            .list_objects_v2()
+            .bucket(bucket_name)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+
+        keys.extend(
+            resp.contents()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|o| o.key().map(|k| (k.to_string(), o.size() as u64))),
+        );
+
+        continuation_token = resp.next_continuation_token().map(|t| t.to_string());
+        if continuation_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(keys)
+}
+
+pub async fn put_bytes(
+    client: &Client,
+    bucket_name: &str,
+    bytes: Bytes,
+    s3_path: &str,
+    content_type: Option<&str>,
+    cache_control: &str,
+    encryption: &Encryption,
+) -> Result<(), S3Error> {
+    client
+        .put_object()
+        .bucket(bucket_name)
+        .key(s3_path)
+        .acl(ObjectCannedAcl::PublicRead)
+        .cache_control(cache_control)
+        .set_content_type(content_type.map(|s| s.to_string()))
+        .set_server_side_encryption(encryption.algorithm.map(ServerSideEncryption::from))
+        .set_ssekms_key_id(encryption.kms_key_id.clone())
+        .content_length(bytes.len() as i64)
+        .body(ByteStream::from(bytes))
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Uploads the stream, replacing the object if it already exists. Parts are sized for
+/// `expected_size` bytes, if that's known, to fit in S3's limit on their number.
+#[allow(clippy::too_many_arguments)]
+pub async fn put_async_stream<S, B>(
+    client: &Client,
+    bucket_name: &str,
+    stream: S,
+    s3_path: &str,
+    content_type: Option<&str>,
+    content_disposition: Option<&str>,
+    encryption: &Encryption,
+    expected_size: Option<u64>,
+) -> Result<(), S3Error>
+where
+    S: Stream<Item = Result<B, std::io::Error>> + Unpin,
+    B: Buf,
+{
+    let upload = client
+        .create_multipart_upload()
+        .bucket(bucket_name)
+        .key(s3_path)
+        .acl(ObjectCannedAcl::PublicRead)
+        .cache_control("public, max-age=604800") // 7 days
+        .set_content_type(content_type.map(|s| s.to_string()))
+        .set_content_disposition(content_disposition.map(|s| s.to_string()))
+        .set_server_side_encryption(encryption.algorithm.map(ServerSideEncryption::from))
+        .set_ssekms_key_id(encryption.kms_key_id.clone())
+        .send()
+        .await?;
+
+    let upload_id = upload.upload_id().unwrap();
+
+    let upload_part = |buff: Bytes, part_number| async move {
+        let len = buff.len();
+        let _md5 = md5::compute(&buff);
+        let body = ByteStream::from(buff);
+        let part = client
+            .upload_part()
+            .bucket(bucket_name)
+            .key(s3_path)
+            .body(body)
+            .content_length(len as i64)
+            // .content_md5(md5.to_string())
+            .upload_id(upload_id.to_string())
+            .part_number(part_number)
+            .send()
+            .await?;
+
+        Ok::<_, S3Error>((part_number, part.e_tag().unwrap().to_string()))
+    };
+
+    let concurrency = PART_CONCURRENCY.load(Ordering::Acquire);
+    let base_size = base_part_size(expected_size);
+    let parts = match upload_parts(stream, base_size, concurrency, upload_part).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            // don't leave the incomplete parts behind, but report why the upload failed
+            if let Err(abort_error) = client
+                .abort_multipart_upload()
+                .bucket(bucket_name)
+                .key(s3_path)
+                .upload_id(upload_id.to_string())
+                .send()
+                .await
+            {
+                log::error!("Couldn't abort upload of {}: {}", s3_path, abort_error);
+            }
+            return Err(e);
+        }
+    };
+
+    let multipart_upload = CompletedMultipartUpload::builder()
+        .set_parts(Some(
+            parts
+                .into_iter()
+                .map(|(part_number, e_tag)| {
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .e_tag(e_tag)
+                        .build()
+                })
+                .collect(),
+        ))
+        .build();
+
+    log::debug!("{:?}", multipart_upload);
+
+    client
+        .complete_multipart_upload()
+        .bucket(bucket_name)
+        .key(s3_path)
+        .upload_id(upload_id.to_string())
+        .multipart_upload(multipart_upload)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn try_put_async_stream<S, B>(
+    client: &Client,
+    bucket_name: &str,
+    stream: S,
+    s3_path: &str,
+    content_type: Option<&str>,
+    content_disposition: Option<&str>,
+    encryption: &Encryption,
+    expected_size: Option<u64>,
+) -> Result<(), S3Error>
+where
+    S: Stream<Item = Result<B, std::io::Error>> + Unpin,
+    B: Buf,
+{
+    let found = object_exists(client, bucket_name, s3_path).await?;
+
+    if !found {
+        log::debug!("S3 object {} not found, uploading", s3_path);
+
+        put_async_stream(
+            client,
+            bucket_name,
+            stream,
+            s3_path,
+            content_type,
+            content_disposition,
+            encryption,
+            expected_size,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_part_size_scales() {
+        let total: u64 = (1..=MAX_PARTS)
+            .map(|n| part_size(BUFFER_SIZE, n) as u64)
+            .sum();
+
+        assert!(total >= 4 * 1024 * 1024 * 1024 * 1024);
+        assert!((1..=MAX_PARTS).all(|n| part_size(BUFFER_SIZE, n) <= MAX_PART_SIZE));
+    }
+
+    #[test]
+    fn test_base_part_size() {
+        assert_eq!(base_part_size(None), BUFFER_SIZE);
+        // a few hours of audio
+        assert_eq!(base_part_size(Some(500 * 1024 * 1024)), BUFFER_SIZE);
+        // too long for 10,000 parts of the smallest size
+        let size = 100 * 1024 * 1024 * 1024;
+        let base = base_part_size(Some(size));
+        assert!(base > BUFFER_SIZE);
+        assert!(base as u64 * MAX_PARTS as u64 >= size);
+    }
+
+    #[tokio::test]
+    async fn test_upload_parts_grows_part_size() {
+        // 2500 parts worth of data at the base size, in uneven chunks
+        let total = 4 * 1000 + 8 * 1000 + 16 * 500;
+        let data = vec![0u8; total];
+        let chunks = data
+            .chunks(7)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect::<Vec<Result<Bytes, std::io::Error>>>();
+
+        let mut sizes = Vec::new();
+        let parts = upload_parts(futures::stream::iter(chunks), 4, 1, |buff, part_number| {
+            sizes.push(buff.len());
+            async move { Ok((part_number, part_number.to_string())) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(parts.len(), 2500);
+        assert_eq!(sizes.iter().sum::<usize>(), total);
+        assert!(sizes[..1000].iter().all(|s| *s == 4));
+        assert!(sizes[1000..2000].iter().all(|s| *s == 8));
+        assert!(sizes[2000..].iter().all(|s| *s == 16));
+    }
+
+    #[tokio::test]
+    async fn test_upload_parts_final_part() {
+        let chunks = vec![Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 10]))];
+
+        let mut sizes = Vec::new();
+        upload_parts(futures::stream::iter(chunks), 4, 1, |buff, part_number| {
+            sizes.push(buff.len());
+            async move { Ok((part_number, part_number.to_string())) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(sizes, vec![4, 4, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_upload_parts_max_parts() {
+        let most = (1..=MAX_PARTS).map(|n| part_size(1, n)).sum::<usize>();
+        let upload = |len| {
+            let chunks = vec![Ok::<_, std::io::Error>(Bytes::from(vec![0u8; len]))];
+            upload_parts(
+                futures::stream::iter(chunks),
+                1,
+                1,
+                |_, part_number| async move { Ok((part_number, part_number.to_string())) },
+            )
+        };
+
+        let parts = upload(most).await.unwrap();
+        assert_eq!(parts.len(), MAX_PARTS as usize);
+        assert!(upload(most + 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_parts_concurrently() {
+        let chunks = vec![Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 40]))];
+
+        // later parts finish first
+        let parts = upload_parts(
+            futures::stream::iter(chunks),
+            4,
+            3,
+            |_, part_number| async move {
+                let delay = 10 - part_number as u64;
+                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                Ok((part_number, part_number.to_string()))
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            parts.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            (1..=10).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_parts_while_source_waits() {
+        let (uploaded, wait_for_upload) = tokio::sync::oneshot::channel::<()>();
+        let mut uploaded = Some(uploaded);
+
+        // the rest of the source only arrives once the first part is uploaded
+        let chunks =
+            futures::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 4]))]).chain(
+                futures::stream::once(async move {
+                    wait_for_upload.await.unwrap();
+                    Ok(Bytes::from(vec![0u8; 2]))
+                }),
+            );
+
+        let upload = upload_parts(Box::pin(chunks), 4, 2, |_, part_number| {
+            let uploaded = uploaded.take();
+            async move {
+                tokio::task::yield_now().await;
+                if let Some(uploaded) = uploaded {
+                    uploaded.send(()).unwrap();
+                }
+                Ok((part_number, part_number.to_string()))
+            }
+        });
+        let parts = tokio::time::timeout(std::time::Duration::from_secs(5), upload)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(parts.len(), 2);
+    }
+}
@@ -0,0 +1,101 @@
+use actix_web::http::header::{
+    HeaderMap, HeaderValue, InvalidHeaderValue, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+    REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use once_cell::sync::OnceCell;
+
+struct Policy {
+    content_security_policy: HeaderValue,
+    /// Only for browsers without frame-ancestors, so only when nothing may frame pages
+    deny_frames: bool,
+}
+
+static POLICY: OnceCell<Policy> = OnceCell::new();
+
+/// The pages are plain markup, so they need nothing but images (artwork is on the BBC's
+/// image CDN). Framing is up to the configured origins, e.g. `https://example.com`.
+fn content_security_policy(frame_ancestors: &[String]) -> String {
+    let ancestors = if frame_ancestors.is_empty() {
+        "'none'".to_string()
+    } else {
+        frame_ancestors.join(" ")
+    };
+    format!(
+        "default-src 'none'; img-src 'self' https:; base-uri 'none'; form-action 'none'; \
+         frame-ancestors {}",
+        ancestors
+    )
+}
+
+/// Sets the origins allowed to embed the HTML pages in frames, at startup
+pub fn set_frame_ancestors(frame_ancestors: &[String]) -> Result<(), InvalidHeaderValue> {
+    let policy = Policy {
+        content_security_policy: HeaderValue::from_str(&content_security_policy(frame_ancestors))?,
+        deny_frames: frame_ancestors.is_empty(),
+    };
+    let _ = POLICY.set(policy);
+    Ok(())
+}
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .map_or(false, |t| t.starts_with("text/html"))
+}
+
+/// Adds hardening headers to an HTML response. Feeds, audio and JSON are left alone, as
+/// they aren't rendered as pages and are often fetched cross-origin.
+pub fn add(headers: &mut HeaderMap) {
+    if !is_html(headers) {
+        return;
+    }
+    let policy = POLICY.get_or_init(|| Policy {
+        content_security_policy: HeaderValue::from_str(&content_security_policy(&[])).unwrap(),
+        deny_frames: true,
+    });
+    headers.insert(
+        CONTENT_SECURITY_POLICY,
+        policy.content_security_policy.clone(),
+    );
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    if policy.deny_frames {
+        headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_add() {
+        let mut page = HeaderMap::new();
+        page.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        add(&mut page);
+        let csp = page.get(CONTENT_SECURITY_POLICY).unwrap().to_str().unwrap();
+        assert!(csp.starts_with("default-src 'none';"));
+        assert!(csp.ends_with("frame-ancestors 'none'"));
+        assert_eq!(page.get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(page.get(X_FRAME_OPTIONS).unwrap(), "DENY");
+
+        let mut feed = HeaderMap::new();
+        feed.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/rss+xml"),
+        );
+        add(&mut feed);
+        assert_eq!(feed.len(), 1);
+
+        assert!(content_security_policy(&[
+            "https://example.com".to_string(),
+            "https://*.example.org".to_string()
+        ])
+        .ends_with("frame-ancestors https://example.com https://*.example.org"));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    fetch::{self, FetchError},
+    limits::{Reservation, BUFFERS},
+};
+
+/// HLS segments fetched recently, shared by every pipeline, so listeners streaming
+/// the same episode at about the same time don't each fetch its segments
+pub static SEGMENTS: Lazy<SegmentCache> = Lazy::new(SegmentCache::default);
+
+#[derive(Default)]
+struct Entries {
+    /// Segment, and when it was last used
+    segments: HashMap<String, (Arc<[u8]>, u64)>,
+    /// URLs by when they were last used, least recent first
+    by_use: BTreeMap<u64, String>,
+    bytes: usize,
+    uses: u64,
+    /// Counts the segments held towards the buffer limit
+    reservation: Option<Reservation<'static>>,
+}
+
+/// Segments by URL, evicting the least recently used once they hold more than the
+/// capacity in bytes, or the stream buffers are full
+#[derive(Default)]
+pub struct SegmentCache {
+    entries: Mutex<Entries>,
+    /// 0 to cache nothing
+    capacity: AtomicUsize,
+}
+
+impl SegmentCache {
+    pub fn set_capacity(&self, bytes: usize) {
+        self.capacity.store(bytes, Ordering::Release);
+        let mut entries = self.entries.lock().unwrap();
+        Self::evict(&mut entries, bytes);
+    }
+
+    /// Bytes held
+    pub fn used(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+
+    pub fn get(&self, url: &str) -> Option<Arc<[u8]>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.uses += 1;
+        let now = entries.uses;
+        let (data, used) = entries.segments.get_mut(url)?;
+        let (data, last_used) = (data.clone(), std::mem::replace(used, now));
+        let url = entries.by_use.remove(&last_used)?;
+        entries.by_use.insert(now, url);
+        Some(data)
+    }
+
+    pub fn insert(&self, url: &str, data: Arc<[u8]>) {
+        let capacity = self.capacity.load(Ordering::Acquire);
+        // a segment bigger than the whole cache would only evict everything else
+        if data.len() > capacity {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.uses += 1;
+        let now = entries.uses;
+        let len = data.len();
+        entries.bytes += len;
+        entries
+            .reservation
+            .get_or_insert_with(|| BUFFERS.track(0))
+            .grow(len);
+        if let Some((old, last_used)) = entries.segments.insert(url.to_string(), (data, now)) {
+            Self::release(&mut entries, old.len());
+            entries.by_use.remove(&last_used);
+        }
+        entries.by_use.insert(now, url.to_string());
+        Self::evict(&mut entries, capacity);
+        // streams come first, so the cache gives up what they need
+        while BUFFERS.is_full() && Self::evict_one(&mut entries) {}
+    }
+
+    fn evict(entries: &mut Entries, capacity: usize) {
+        while entries.bytes > capacity && Self::evict_one(entries) {}
+    }
+
+    /// Drops the least recently used segment, false if there are none
+    fn evict_one(entries: &mut Entries) -> bool {
+        let url = match entries.by_use.iter().next() {
+            Some((&used, _)) => entries.by_use.remove(&used).unwrap(),
+            None => return false,
+        };
+        if let Some((data, _)) = entries.segments.remove(&url) {
+            Self::release(entries, data.len());
+        }
+        true
+    }
+
+    fn release(entries: &mut Entries, bytes: usize) {
+        entries.bytes -= bytes;
+        if let Some(reservation) = &mut entries.reservation {
+            reservation.shrink(bytes);
+        }
+    }
+}
+
+/// Gets a segment from the cache, or fetches and caches it
+pub async fn get(url: &str) -> Result<Vec<u8>, FetchError> {
+    if let Some(data) = SEGMENTS.get(url) {
+        return Ok(data.to_vec());
+    }
+    let data = fetch::get_uncached(url.to_string()).await?.into_bytes()?;
+    SEGMENTS.insert(url, data.as_slice().into());
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_eviction() {
+        let cache = SegmentCache::default();
+        cache.set_capacity(10);
+        let data = |len| Arc::from(vec![0u8; len]);
+
+        cache.insert("a", data(4));
+        cache.insert("b", data(4));
+        // using a makes b the least recently used
+        assert!(cache.get("a").is_some());
+        cache.insert("c", data(4));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.used(), 8);
+
+        // replacing a segment doesn't count it twice
+        cache.insert("c", data(2));
+        assert_eq!(cache.used(), 6);
+
+        cache.insert("huge", data(11));
+        assert!(cache.get("huge").is_none());
+        assert_eq!(cache.used(), 6);
+
+        cache.set_capacity(0);
+        assert_eq!(cache.used(), 0);
+        assert!(cache.get("a").is_none());
+    }
+}
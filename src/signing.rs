@@ -0,0 +1,70 @@
+use ed25519_dalek::{Signer, SigningKey};
+use once_cell::sync::OnceCell;
+
+/// Header carrying a feed's detached signature
+pub const SIGNATURE_HEADER: &str = "X-Signature-Ed25519";
+
+static KEY: OnceCell<SigningKey> = OnceCell::new();
+
+/// Sets the key feeds are signed with, from its base64 32 byte seed
+pub fn set_key(seed: &str) -> Result<(), String> {
+    let seed: [u8; 32] = base64::decode(seed.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "The signing key should be 32 bytes, base64 encoded".to_string())?;
+    // only ever set once, at startup
+    let _ = KEY.set(SigningKey::from_bytes(&seed));
+    Ok(())
+}
+
+fn signature(key: &SigningKey, body: &[u8]) -> String {
+    base64::encode(key.sign(body).to_bytes())
+}
+
+fn public_key(key: &SigningKey) -> String {
+    base64::encode(key.verifying_key().to_bytes())
+}
+
+/// Base64 signature of a response body, if feeds are signed
+pub fn sign(body: &[u8]) -> Option<String> {
+    KEY.get().map(|key| signature(key, body))
+}
+
+/// Base64 key signatures can be checked with, if feeds are signed
+pub fn verifying_key() -> Option<String> {
+    KEY.get().map(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 8032 test 1
+        let seed = hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let key = SigningKey::from_bytes(&seed.try_into().unwrap());
+
+        assert_eq!(
+            base64::decode(public_key(&key)).unwrap(),
+            hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+        );
+        assert_eq!(
+            base64::decode(signature(&key, b"")).unwrap(),
+            hex(concat!(
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+                "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+            ))
+        );
+
+        assert!(set_key("not a key").is_err());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
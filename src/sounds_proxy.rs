@@ -1,24 +1,293 @@
 use std::{
-    cmp::Ordering,
-    collections::{BTreeMap, HashMap},
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use crate::{bbc::QualityVariant, hls::HlsStream};
+use crate::{
+    archive,
+    credits::{self, Credits},
+    fingerprint, hls,
+    hls::HlsStream,
+    omnibus, parents, tracklist,
+    transcode::{self, AudioFormat},
+    variant,
+};
 
 use super::bbc;
 
-use chrono::DateTime;
-use futures::{stream::Stream, StreamExt};
+use chrono::{DateTime, Duration, FixedOffset, TimeZone, Utc};
+use futures::{
+    future::BoxFuture,
+    stream::{self, LocalBoxStream},
+    FutureExt, StreamExt,
+};
 use itertools::*;
+use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
 use rss::{
-    extension::itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder},
-    ChannelBuilder, EnclosureBuilder, GuidBuilder, ImageBuilder, ItemBuilder,
+    extension::{
+        dublincore::DublinCoreExtensionBuilder,
+        itunes::{
+            ITunesCategory, ITunesCategoryBuilder, ITunesChannelExtensionBuilder,
+            ITunesItemExtensionBuilder, ITunesOwnerBuilder,
+        },
+        Extension, ExtensionBuilder, ExtensionMap,
+    },
+    ChannelBuilder, EnclosureBuilder, Guid, GuidBuilder, ImageBuilder, ItemBuilder,
 };
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 
 type Result<T, E = bbc::BbcResponseError> = core::result::Result<T, E>;
 
-fn template_url(url: String) -> Option<String> {
+/// Ways of serving an episode, in order of preference
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolutionStrategy {
+    /// Public file URL from the container data, used directly in the feed
+    FileUrl,
+    /// BBC's public mp3 redirector
+    Redirector,
+    /// Remux the HLS stream through the proxy
+    Proxy,
+}
+
+/// Order of a feed's items by publication date
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EpisodeOrder {
+    /// Oldest first, for serials best listened to from the start
+    Asc,
+    /// Newest first, which some apps expect
+    Desc,
+}
+
+pub const DEFAULT_RESOLUTION: &[ResolutionStrategy] = &[
+    ResolutionStrategy::FileUrl,
+    ResolutionStrategy::Redirector,
+    ResolutionStrategy::Proxy,
+];
+
+/// Versions of an episode's audio the BBC may list alongside each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaVariant {
+    Standard,
+    /// With audio description
+    Described,
+    /// Accompanying a signed version
+    Signed,
+}
+
+impl MediaVariant {
+    /// Tells the variants apart by their kind and service, e.g. `audio-described`
+    fn of(media: &bbc::Media) -> Self {
+        let name = format!(
+            "{} {}",
+            media.kind,
+            media.service.as_deref().unwrap_or_default()
+        )
+        .to_ascii_lowercase();
+        if name.contains("described") || name.contains("description") {
+            MediaVariant::Described
+        } else if name.contains("signed") {
+            MediaVariant::Signed
+        } else {
+            MediaVariant::Standard
+        }
+    }
+}
+
+/// Only the standard audio is served unless configured otherwise
+pub const DEFAULT_MEDIA_VARIANTS: &[MediaVariant] = &[MediaVariant::Standard];
+
+pub const DEFAULT_AUTHOR: &str = "BBC";
+
+/// Episode GUIDs are the bare pid unless configured otherwise, as they always have been
+pub const DEFAULT_GUID_FORMAT: &str = "{pid}";
+
+// Namespace of the element tracing each item back to the BBC
+const SOURCE_NAMESPACE: &str = "https://github.com/hillnz/sounds-proxy/ns/source/1.0";
+
+/// UUIDv5 namespace of `podcast:guid`
+const PODCAST_GUID_NAMESPACE: [u8; 16] = [
+    0xea, 0xd4, 0xc2, 0x36, 0xbf, 0x58, 0x58, 0xc6, 0xa2, 0xc6, 0xa6, 0xb2, 0x8d, 0x12, 0x8c, 0xb6,
+];
+
+/// Feeds with more episodes than this are split into pages, as some clients can't cope
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// How far down containers nested in a show (e.g. a brand's series) are followed. None
+/// are by default, so feeds only change when it's asked for.
+pub const DEFAULT_NESTED_DEPTH: usize = 0;
+
+// Nested containers fetched at once
+const NESTED_CONCURRENCY: usize = 4;
+
+// Episodes whose tracklists or credits are looked up at once
+const LOOKUP_CONCURRENCY: usize = 8;
+
+// Used to estimate file sizes when nothing better is known
+const ESTIMATED_BYTES_PER_SEC: u64 = 50000;
+
+#[derive(Clone, Debug)]
+pub struct FeedOptions {
+    /// Link each item to a generated chapters file
+    pub chapters: bool,
+    /// Enabled resolution strategies
+    pub resolution: Vec<ResolutionStrategy>,
+    /// Add the show to proxied episode URLs, so per-show settings apply
+    pub link_show: bool,
+    /// Author used when the BBC doesn't give the show's network
+    pub default_author: String,
+    /// Actual sizes of previously proxied episodes, by episode id
+    pub known_sizes: HashMap<String, u64>,
+    /// Link proxied episodes to their never cached URL
+    pub revalidate_links: bool,
+    /// Format of item GUIDs, with `{pid}` replaced by the episode's pid
+    pub guid_format: String,
+    /// Hide episodes until this many hours after they're published
+    pub delay_hours: Option<u64>,
+    /// Leave out episodes published more than this many days ago
+    pub max_age_days: Option<u64>,
+    /// Most episodes to include, keeping the most recent
+    pub max_items: Option<usize>,
+    /// Leave out episodes published before this
+    pub since: Option<DateTime<Utc>>,
+    /// Leave out episodes published at or after this
+    pub until: Option<DateTime<Utc>>,
+    /// Order of the items in each document. Pages are split newest first either way.
+    pub order: EpisodeOrder,
+    /// Most episodes on each page of the feed
+    pub page_size: usize,
+    /// Read each page from its own slice of the BBC's listing, rather than paging the
+    /// episodes fetched. Pages are numbered by the BBC's count, and may hold fewer
+    /// episodes once filtered.
+    pub page_slices: bool,
+    /// Analytics redirect prefix for episode URLs, e.g. `https://op3.dev/e/`
+    pub enclosure_prefix: Option<String>,
+    /// Language tag of the feed's content, as the BBC doesn't say. Titles and synopses
+    /// the BBC translates into it are used, unless the listener asks for another.
+    pub language: Option<String>,
+    /// Languages the listener reads, most preferred first, from Accept-Language
+    pub accept_languages: Vec<String>,
+    /// Check public file URLs exist before putting them in the feed
+    pub validate_file_urls: bool,
+    /// Email address of whoever runs the proxy, which locks the feed to them
+    pub owner_email: Option<String>,
+    /// Token directories may ask to see in the feed, to prove ownership
+    pub verification_token: Option<String>,
+    /// Links for listeners to support the show or the proxy
+    pub funding: Vec<Funding>,
+    /// List each part of an omnibus as its own episode
+    pub split_parts: bool,
+    /// Leave out episodes with the same audio as another in the feed
+    pub dedupe: bool,
+    /// Include episodes which aren't available yet, dated when they will be
+    pub upcoming: bool,
+    /// How far down nested containers, such as a brand's series, are followed for
+    /// episodes. 0 leaves them out.
+    pub nested_depth: usize,
+    /// Append the music played, from the episode's segments, to its description
+    pub tracklist: bool,
+    /// Credit the episode's presenters and guests, from its segments
+    pub credits: bool,
+    /// Added to proxied episode URLs, so they change when episodes are resolved differently
+    pub cache_buster: Option<String>,
+    /// Fetch every page of the show's BBC listing, rather than a page at a time
+    pub backfill: bool,
+}
+
+/// As for a show with nothing configured
+impl Default for FeedOptions {
+    fn default() -> Self {
+        FeedOptions {
+            chapters: false,
+            resolution: DEFAULT_RESOLUTION.to_vec(),
+            link_show: false,
+            default_author: DEFAULT_AUTHOR.to_string(),
+            known_sizes: HashMap::new(),
+            revalidate_links: false,
+            guid_format: DEFAULT_GUID_FORMAT.to_string(),
+            delay_hours: None,
+            max_age_days: None,
+            max_items: None,
+            since: None,
+            until: None,
+            order: EpisodeOrder::Desc,
+            page_size: DEFAULT_PAGE_SIZE,
+            page_slices: false,
+            enclosure_prefix: None,
+            language: None,
+            accept_languages: Vec::new(),
+            validate_file_urls: false,
+            owner_email: None,
+            verification_token: None,
+            funding: Vec::new(),
+            split_parts: false,
+            dedupe: false,
+            upcoming: false,
+            nested_depth: DEFAULT_NESTED_DEPTH,
+            tracklist: false,
+            credits: false,
+            cache_buster: None,
+            backfill: false,
+        }
+    }
+}
+
+impl FeedOptions {
+    /// Language to show titles and synopses in: the listener's first choice, or else the
+    /// feed's. Only the first is tried, so someone who'd rather read English isn't given
+    /// the Welsh they also accept.
+    fn title_language(&self) -> Option<&str> {
+        self.accept_languages
+            .first()
+            .or(self.language.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// A `podcast:funding` link, e.g. to the licence fee or a page for hosting costs
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Funding {
+    pub url: String,
+    /// What the link is for, e.g. `Help pay for hosting`
+    pub text: String,
+}
+
+/// Prefixes an episode URL with an analytics redirect. As is usual for these services
+/// the scheme is left out for https URLs.
+fn prefix_url(prefix: &str, url: &str) -> String {
+    format!("{}{}", prefix, url.strip_prefix("https://").unwrap_or(url))
+}
+
+/// Adds the cache buster, if any, to a proxied episode's URL. Clients holding a redirect
+/// they were given for the old URL, e.g. a permanent one, see a new URL and ask again.
+fn bust_cache(url: String, options: &FeedOptions) -> String {
+    match &options.cache_buster {
+        Some(buster) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{}{}v={}", url, separator, buster)
+        }
+        None => url,
+    }
+}
+
+/// An episode's GUID. This depends only on the pid and GUID format, never the
+/// episode's URL, so clients don't see an episode as new when its URL changes.
+fn episode_guid(options: &FeedOptions, episode_id: &str) -> Guid {
+    GuidBuilder::default()
+        .value(options.guid_format.replace("{pid}", episode_id))
+        .permalink(false)
+        .build()
+}
+
+pub fn template_url(url: String) -> Option<String> {
     let url_vars = HashMap::from([("recipe", "400x400")]);
     let re_url_vars = Regex::new(r"\{([^\{\}]+)\}").unwrap();
 
@@ -41,10 +310,605 @@ fn template_url(url: String) -> Option<String> {
     }
 }
 
-pub async fn get_podcast_feed(base_url: &str, programme_id: &str) -> Result<String> {
+#[derive(Clone, Debug)]
+pub struct Show {
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub author: String,
+    pub link: String,
+    pub image: Option<String>,
+    /// iTunes category of the show's genre, if it has one that maps to one
+    pub category: Option<ITunesCategory>,
+}
+
+/// iTunes categories of BBC subgenres, by genre and subgenre
+const SUBGENRE_CATEGORIES: &[(&str, &str, &str, Option<&str>)] = &[
+    ("comedy", "standup", "Comedy", Some("Stand-Up")),
+    ("comedy", "sitcoms", "Fiction", Some("Comedy Fiction")),
+    ("drama", "comedy", "Fiction", Some("Comedy Fiction")),
+    (
+        "drama",
+        "scifiandfantasy",
+        "Fiction",
+        Some("Science Fiction"),
+    ),
+    ("factual", "artscultureandthemedia", "Arts", None),
+    ("factual", "crimeandjustice", "True Crime", None),
+    ("factual", "foodanddrink", "Arts", Some("Food")),
+    ("factual", "healthandwellbeing", "Health & Fitness", None),
+    ("factual", "history", "History", None),
+    (
+        "factual",
+        "homesandgardens",
+        "Leisure",
+        Some("Home & Garden"),
+    ),
+    ("factual", "moneyandbusiness", "Business", None),
+    ("factual", "politics", "News", Some("Politics")),
+    ("factual", "scienceandnature", "Science", None),
+    (
+        "factual",
+        "travel",
+        "Society & Culture",
+        Some("Places & Travel"),
+    ),
+    ("news", "business", "News", Some("Business News")),
+    ("news", "politics", "News", Some("Politics")),
+    ("sport", "cricket", "Sports", Some("Cricket")),
+    ("sport", "football", "Sports", Some("Soccer")),
+    ("sport", "golf", "Sports", Some("Golf")),
+    ("sport", "rugbyleague", "Sports", Some("Rugby")),
+    ("sport", "rugbyunion", "Sports", Some("Rugby")),
+    ("sport", "tennis", "Sports", Some("Tennis")),
+];
+
+/// iTunes categories of BBC genres, and of BBC Sounds categories, which are named alike
+const GENRE_CATEGORIES: &[(&str, &str, Option<&str>)] = &[
+    ("arts", "Arts", None),
+    ("childrens", "Kids & Family", None),
+    ("comedy", "Comedy", None),
+    ("crime", "True Crime", None),
+    ("documentaries", "Society & Culture", Some("Documentary")),
+    ("drama", "Fiction", Some("Drama")),
+    ("entertainment", "Leisure", None),
+    ("factual", "Society & Culture", Some("Documentary")),
+    ("history", "History", None),
+    ("learning", "Education", None),
+    ("music", "Music", None),
+    ("news", "News", None),
+    ("politics", "News", Some("Politics")),
+    ("religionandethics", "Religion & Spirituality", None),
+    ("science", "Science", None),
+    ("sport", "Sports", None),
+    ("weather", "News", None),
+];
+
+/// The iTunes category of the first of a show's genres that has one. A genre's id names
+/// the genre then any subgenre, e.g. `factual/history` or `comedy-standup`.
+fn itunes_category(categories: &[bbc::Category]) -> Option<ITunesCategory> {
+    categories.iter().find_map(|c| {
+        let id = c.id.to_ascii_lowercase();
+        let words = id.split(['/', '-', ':']).collect::<Vec<_>>();
+        let (genre, subgenre) = (words.first()?, words.last()?);
+        let (category, subcategory) = SUBGENRE_CATEGORIES
+            .iter()
+            .find(|(g, s, _, _)| g == genre && s == subgenre)
+            .map(|(_, _, category, subcategory)| (category, subcategory))
+            .or_else(|| {
+                // a genre, or failing that a subgenre which names a genre of its own
+                [genre, subgenre].iter().find_map(|word| {
+                    GENRE_CATEGORIES
+                        .iter()
+                        .find(|(g, _, _)| g == *word)
+                        .map(|(_, category, subcategory)| (category, subcategory))
+                })
+            })?;
+        let subcategory = subcategory.map(|text| {
+            ITunesCategoryBuilder::default()
+                .text(text.to_string())
+                .build()
+        });
+        Some(
+            ITunesCategoryBuilder::default()
+                .text(category.to_string())
+                .subcategory(subcategory.map(Box::new))
+                .build(),
+        )
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct Episode {
+    pub id: String,
+    pub title: Option<String>,
+    pub summary: Option<String>,
+    pub url: String,
+    pub file_size: u64,
+    pub content_type: String,
+    pub duration: u64,
+    pub pub_date: Option<DateTime<FixedOffset>>,
+    pub image: Option<String>,
+    /// Which part of an omnibus this is, if it's been split
+    pub part: Option<usize>,
+    /// When the episode can first be played, if the BBC says
+    pub available_from: Option<DateTime<FixedOffset>>,
+    /// Series the episode is in, if it's numbered
+    pub season: Option<u32>,
+    /// Number of the episode in its series
+    pub number: Option<u32>,
+    /// Who presents the episode and who's on it, if looked up
+    pub credits: Credits,
+}
+
+impl Episode {
+    /// True if the episode is listed but can't be played yet
+    pub fn is_upcoming(&self, now: DateTime<Utc>) -> bool {
+        self.available_from.map_or(false, |d| d > now)
+    }
+}
+
+impl Episode {
+    fn from_container_data(
+        base_url: &str,
+        programme_id: &bbc::Pid,
+        d: &bbc::ContainerListData,
+        best_variant: Option<&bbc::QualityVariant>,
+        options: &FeedOptions,
+        measured_bytes_per_sec: Option<u64>,
+    ) -> Self {
+        log::debug!("{:#?}", d);
+
+        let file_url = if options.resolution.contains(&ResolutionStrategy::FileUrl) {
+            best_variant.and_then(|v| v.file_url.clone())
+        } else {
+            None
+        };
+
+        let url = file_url.clone().unwrap_or_else(|| {
+            // No public url - we will proxy it instead
+            let path = if options.revalidate_links {
+                "/audio"
+            } else {
+                ""
+            };
+            let url = if options.link_show {
+                format!(
+                    "{}/episode/{}{}?show={}",
+                    base_url, d.id, path, programme_id
+                )
+            } else {
+                format!("{}/episode/{}{}", base_url, d.id, path)
+            };
+            bust_cache(url, options)
+        });
+        let url = match &options.enclosure_prefix {
+            Some(prefix) => prefix_url(prefix, &url),
+            None => url,
+        };
+
+        let file_size = match (&file_url, best_variant.and_then(|v| v.file_size)) {
+            (Some(_), Some(s)) => s,
+            _ => match options.known_sizes.get(&d.id) {
+                Some(s) => *s,
+                None => {
+                    // estimate based on duration
+                    let bytes_per_sec = measured_bytes_per_sec
+                        .or_else(|| best_variant.and_then(|v| v.bitrate).map(|b| b * 1000 / 8))
+                        .unwrap_or(ESTIMATED_BYTES_PER_SEC);
+                    bytes_per_sec * d.duration.value
+                }
+            },
+        };
+
+        let content_type = match &file_url {
+            Some(f) => match f.split('.').last() {
+                Some("mp3") => "audio/mpeg".to_string(),
+                Some("m4a") | Some("mp4") => "audio/mp4".to_string(),
+                _ => "audio/mpeg".to_string(),
+            },
+            None => "audio/aac".to_string(),
+        };
+
+        let (titles, synopses) = d.localised(options.title_language());
+        let summary = synopses
+            .long
+            .clone()
+            .or_else(|| synopses.medium.clone())
+            .or_else(|| synopses.short.clone());
+
+        Episode {
+            id: d.id.clone(),
+            title: titles.secondary.clone(),
+            summary,
+            url,
+            file_size,
+            content_type,
+            duration: d.duration.value,
+            pub_date: DateTime::parse_from_rfc3339(&d.release.date).ok(),
+            image: d.image_url.clone().and_then(template_url),
+            part: None,
+            available_from: d
+                .availability
+                .as_ref()
+                .and_then(|a| a.from.as_deref())
+                .and_then(|d| DateTime::parse_from_rfc3339(d).ok()),
+            // only where the BBC numbers them, as titles' numbers can be anything
+            season: d.container.as_ref().and_then(|c| c.position),
+            number: d.position,
+            credits: Credits::default(),
+        }
+    }
+}
+
+/// The show's `podcast:guid`, from its BBC page rather than the feed URL, so it's the
+/// same through every proxy and apps can tell the feeds are of the same show
+fn podcast_guid(programme_id: &bbc::Pid) -> String {
+    uuid_v5(&format!("www.bbc.co.uk/sounds/series/{}", programme_id))
+}
+
+/// UUIDv5 in the `podcast:guid` namespace, of a URL without its scheme
+fn uuid_v5(name: &str) -> String {
+    let hash = Sha1::new()
+        .chain_update(PODCAST_GUID_NAMESPACE)
+        .chain_update(name.as_bytes())
+        .finalize();
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&hash[..16]);
+    // version 5, RFC 4122 variant
+    uuid[6] = (uuid[6] & 0x0f) | 0x50;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    let hex = uuid
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Average bytes per second of this show's episodes with a known size
+fn measure_bytes_per_sec(
+    episode_data: &[bbc::ContainerListData],
+    known_sizes: &HashMap<String, u64>,
+) -> Option<u64> {
+    let (bytes, secs) = episode_data
+        .iter()
+        .filter_map(|d| known_sizes.get(&d.id).map(|s| (*s, d.duration.value)))
+        .fold((0, 0), |(bytes, secs), (b, s)| (bytes + b, secs + s));
+
+    bytes.checked_div(secs)
+}
+
+/// False if the episode is still within the show's publication delay
+fn is_visible(
+    pub_date: Option<DateTime<FixedOffset>>,
+    delay_hours: Option<u64>,
+    now: DateTime<Utc>,
+) -> bool {
+    match (pub_date, delay_hours) {
+        (Some(pub_date), Some(hours)) => pub_date + Duration::hours(hours as i64) <= now,
+        _ => true,
+    }
+}
+
+/// Episodes of the containers nested in a show, such as a brand's series, following them
+/// `depth` levels down. Each episode's title is prefixed with its series' name.
+fn nested_episodes(
+    containers: Vec<bbc::ContainerItemData>,
+    depth: usize,
+    all_pages: bool,
+) -> BoxFuture<'static, Vec<bbc::ContainerListData>> {
+    async move {
+        if depth == 0 {
+            return Vec::new();
+        }
+        stream::iter(containers)
+            .map(|container| async move {
+                // as listed, as a nested container needn't be a series
+                let urn = container
+                    .urn
+                    .clone()
+                    .unwrap_or_else(|| format!("urn:bbc:radio:series:{}", container.id));
+                let list = match bbc::get_container(&urn).await {
+                    Ok(response) => response.data.into_iter().find_map(|d| d.into_list()),
+                    Err(e) => {
+                        log::warn!("Couldn't fetch nested container {}: {}", container.id, e);
+                        None
+                    }
+                };
+                let list = match list {
+                    Some(list) => list,
+                    None => return Vec::new(),
+                };
+                let data = list.episodes(all_pages).await.unwrap_or_else(|e| {
+                    log::warn!("Couldn't fetch all of {}: {}", container.id, e);
+                    list.data.clone()
+                });
+                let mut episodes = nested_episodes(list.containers, depth - 1, all_pages).await;
+                episodes.extend(data);
+                let series = container
+                    .titles
+                    .secondary
+                    .unwrap_or(container.titles.primary);
+                for episode in &mut episodes {
+                    let title = match episode.titles.secondary.take() {
+                        Some(title) => title,
+                        None => episode.titles.primary.clone(),
+                    };
+                    episode.titles.secondary = Some(format!("{}: {}", series, title));
+                }
+                episodes
+            })
+            .buffered(NESTED_CONCURRENCY)
+            .concat()
+            .await
+    }
+    .boxed()
+}
+
+/// Leaves out episodes which can't be played yet, or if the feed includes them, dates them
+/// for when they can be
+fn embargo_episodes(
+    episodes: Vec<Episode>,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> Vec<Episode> {
+    episodes
+        .into_iter()
+        .filter_map(|mut e| {
+            if !e.is_upcoming(now) {
+                Some(e)
+            } else if options.upcoming {
+                e.pub_date = e.available_from;
+                Some(e)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether an episode published then is recent enough, and in the range of dates asked for
+fn is_retained(
+    pub_date: Option<DateTime<FixedOffset>>,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> bool {
+    let recent = match options.max_age_days {
+        // episodes without a date can't be aged, so are kept
+        Some(days) => pub_date.map_or(true, |d| d >= now - Duration::days(days as i64)),
+        None => true,
+    };
+    let in_range = options.since.is_none() && options.until.is_none()
+        // but they can't be in a range of dates either
+        || pub_date.map_or(false, |d| {
+            options.since.map_or(true, |since| d >= since)
+                && options.until.map_or(true, |until| d < until)
+        });
+    recent && in_range
+}
+
+/// Applies the feed's retention settings. Only when `max_items` is set are episodes
+/// sorted newest first, to keep the newest; otherwise their order is left alone.
+fn retain_episodes(
+    mut episodes: Vec<Episode>,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> Vec<Episode> {
+    episodes.retain(|e| is_retained(e.pub_date, options, now));
+    if let Some(max_items) = options.max_items {
+        episodes.sort_by_key(|e| Reverse(e.pub_date));
+        episodes.truncate(max_items);
+    }
+    episodes
+}
+
+/// When a listed episode will be dated in the feed, as `embargo_episodes` dates it, or
+/// None if it'll be left out for not being playable yet
+fn listed_date(
+    d: &bbc::ContainerListData,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> Option<Option<DateTime<FixedOffset>>> {
+    let available_from = d
+        .availability
+        .as_ref()
+        .and_then(|a| a.from.as_deref())
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok());
+    match available_from {
+        Some(from) if from > now && !options.upcoming => None,
+        Some(from) if from > now => Some(Some(from)),
+        _ => Some(DateTime::parse_from_rfc3339(&d.release.date).ok()),
+    }
+}
+
+/// Whether a listed episode will be in the feed, going by its dates
+fn is_listed_retained(
+    d: &bbc::ContainerListData,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> bool {
+    listed_date(d, options, now).map_or(false, |pub_date| {
+        is_visible(pub_date, options.delay_hours, now) && is_retained(pub_date, options, now)
+    })
+}
+
+/// Applies the feed's retention settings to the BBC's listing, so episodes left out of
+/// the feed aren't built, which can mean checking their files or fingerprinting their
+/// audio. Without `dedupe`, which may leave out more, only the `max_items` newest are kept.
+fn retain_listed(
+    mut data: Vec<bbc::ContainerListData>,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> Vec<bbc::ContainerListData> {
+    data.retain(|d| is_listed_retained(d, options, now));
+    if let Some(max_items) = options.max_items.filter(|_| !options.dedupe) {
+        data.sort_by_key(|d| Reverse(listed_date(d, options, now).flatten()));
+        data.truncate(max_items);
+    }
+    data
+}
+
+/// The show's listing, with `backfill` the rest of it if the BBC left some out. That's
+/// read a page at a time, stopping once it holds the `max_items` newest episodes the
+/// feed keeps, which the listing being newest first (as the BBC's usually are) says it does.
+async fn read_listing(
+    list: &bbc::ContainerList,
+    options: &FeedOptions,
+    now: DateTime<Utc>,
+) -> Result<Vec<bbc::ContainerListData>> {
+    let mut data = list.data.clone();
+    let pagination = match &list.pagination {
+        Some(pagination) if options.backfill && list.is_partial() => pagination.clone(),
+        _ => return Ok(data),
+    };
+    let has_newest = |data: &[bbc::ContainerListData]| {
+        let max_items = match options.max_items.filter(|_| !options.dedupe) {
+            Some(max_items) => max_items,
+            None => return false,
+        };
+        let dates = data
+            .iter()
+            .map(|d| DateTime::parse_from_rfc3339(&d.release.date).ok())
+            .collect::<Vec<_>>();
+        dates.windows(2).all(|w| w[0] >= w[1])
+            && data
+                .iter()
+                .filter(|d| is_listed_retained(d, options, now))
+                .count()
+                >= max_items
+    };
+
+    let listed = data.len();
+    let mut pages = Box::pin(bbc::list_pages(pagination, listed));
+    while !has_newest(&data) {
+        match pages.next().await {
+            Some(page) => data.extend(page?),
+            None => break,
+        }
+    }
+    Ok(data)
+}
+
+/// A date from a query, either RFC 3339 or just the day, e.g. `2022-03-01`, meaning
+/// its start in UTC
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|d| d.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            let day = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+            Some(Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0)?))
+        })
+}
+
+/// Fetches a show and its episodes from the BBC
+pub async fn get_show(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+) -> Result<(Show, Vec<Episode>)> {
+    let info = get_show_info(programme_id, options).await?;
+    let (show, episodes, _) = show_page(base_url, programme_id, options, None, info, false).await?;
+    Ok((show, episodes))
+}
+
+/// The show and its episodes, given its container. Given a page of the feed and
+/// `page_slices`, a listing the BBC splits into pages is read from that page's slice of
+/// it, rather than just its first page, and how many episodes are listed in all is
+/// returned too. With `retain`, episodes the feed's retention settings leave out aren't
+/// built, or where the listing allows, read.
+async fn show_page(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: Option<usize>,
+    (show, list): (Show, bbc::ContainerList),
+    retain: bool,
+) -> Result<(Show, Vec<Episode>, Option<usize>)> {
+    let now = Utc::now();
+    let page_size = options.page_size.max(1);
+    let (listed, list_data) = match (page, &list.pagination) {
+        (Some(page), Some(pagination)) if options.page_slices && list.is_partial() => {
+            let listed = options
+                .max_items
+                .map_or(pagination.total, |max| max.min(pagination.total));
+            if page == 0 || page > page_count(listed, page_size) {
+                return Err(bbc::BbcResponseError::NotFound);
+            }
+            let offset = (page - 1) * page_size;
+            let limit = page_size.min(listed - offset);
+            let data = if offset + limit <= list.data.len() {
+                list.data[offset..offset + limit].to_vec()
+            } else {
+                bbc::get_list_slice(pagination, offset, limit).await?
+            };
+            (Some(listed), data)
+        }
+        _ if retain => (None, read_listing(&list, options, now).await?),
+        _ => (None, list.episodes(options.backfill).await?),
+    };
+    // nested containers' episodes only go on the first page
+    let nested_depth = match (listed, page) {
+        (Some(_), Some(page)) if page > 1 => 0,
+        _ => options.nested_depth,
+    };
+
+    // a brand's own list may repeat its series' episodes, which have the better titles
+    let mut episode_data = nested_episodes(list.containers, nested_depth, options.backfill).await;
+    episode_data.extend(list_data);
+    let mut seen = HashSet::new();
+    episode_data.retain(|d| seen.insert(d.id.clone()));
+    let episode_data = if retain {
+        retain_listed(episode_data, options, now)
+    } else {
+        episode_data
+    };
+
+    let measured_bytes_per_sec = measure_bytes_per_sec(&episode_data, &options.known_sizes);
+    let episodes = list_episodes(
+        base_url,
+        programme_id,
+        &episode_data,
+        options,
+        measured_bytes_per_sec,
+    )
+    .await;
+    // here, so every feed and listing of the show leaves them out
+    let episodes = if options.dedupe {
+        dedupe_episodes(episodes, fingerprint::original)
+    } else {
+        episodes
+    };
+
+    Ok((show, episodes, listed))
+}
+
+/// The show, as its container describes it, and the container's list of episodes
+async fn get_show_info(
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+) -> Result<(Show, bbc::ContainerList)> {
     let urn = format!("urn:bbc:radio:series:{}", programme_id);
 
-    let container = bbc::get_container(&urn).await?;
+    // the container doesn't list genres, but bbc.co.uk/programmes does
+    let (container, programme) = futures::join!(
+        bbc::get_container(&urn),
+        parents::get_programme(programme_id)
+    );
+    let container = container?;
+    let genres = match programme {
+        Ok(programme) => programme.genres.clone(),
+        Err(e) => {
+            log::warn!("Couldn't get genres of {}: {}", programme_id, e);
+            Vec::new()
+        }
+    };
 
     let show_info = &container
         .data
@@ -55,187 +919,2468 @@ pub async fn get_podcast_feed(base_url: &str, programme_id: &str) -> Result<Stri
 
     log::debug!("{:?}", show_info);
 
-    let image = show_info.image_url.clone().and_then(template_url);
-
-    let subtitle = show_info
-        .synopses
+    let (titles, synopses) = show_info.localised(options.title_language());
+    let subtitle = synopses
         .short
         .clone()
-        .or_else(|| show_info.synopses.medium.clone())
-        .or_else(|| show_info.synopses.long.clone());
+        .or_else(|| synopses.medium.clone())
+        .or_else(|| synopses.long.clone());
 
-    let rss_itunes = ITunesChannelExtensionBuilder::default()
-        .author(Some(show_info.network.short_title.clone()))
-        .block(Some("Yes".into()))
-        .image(image.clone())
-        .subtitle(subtitle)
-        .build();
+    let show = Show {
+        title: titles.primary.clone(),
+        subtitle,
+        author: show_info
+            .network
+            .as_ref()
+            .map_or_else(|| options.default_author.clone(), |n| n.short_title.clone()),
+        link: "https://www.bbc.co.uk/sounds/series/".to_string() + programme_id.as_str(),
+        image: show_info.image_url.clone().and_then(template_url),
+        category: itunes_category(&genres),
+    };
 
-    let namespaces = BTreeMap::from([(
-        "itunes".to_string(),
-        "http://www.itunes.com/dtds/podcast-1.0.dtd".to_string(),
-    )]);
+    let list = container
+        .data
+        .into_iter()
+        .find_map(|d| d.into_list())
+        .ok_or(bbc::BbcResponseError::FormatError)?;
 
-    let mut most_recent_pubdate = None;
+    Ok((show, list))
+}
 
-    let episodes = container
-        .data
-        .iter()
-        .find_map(|d| d.list())
-        .ok_or(bbc::BbcResponseError::FormatError)?
-        .data
-        .clone()
-        .iter()
-        .map(|d| {
-            log::debug!("{:#?}", d);
+/// The episodes in a show's list which are visible, with the variants they're served from
+async fn list_episodes(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    episode_data: &[bbc::ContainerListData],
+    options: &FeedOptions,
+    measured_bytes_per_sec: Option<u64>,
+) -> Vec<Episode> {
+    let variants = if options.validate_file_urls
+        && options.resolution.contains(&ResolutionStrategy::FileUrl)
+    {
+        let variants = episode_data
+            .iter()
+            .map(|d| d.download.quality_variants.clone())
+            .collect::<Vec<_>>();
+        stream::iter(variants)
+            .map(|variants| async move { variant::best_available(&variants).await })
+            .buffered(LOOKUP_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+    } else {
+        episode_data
+            .iter()
+            .map(|d| variant::best(&d.download.quality_variants).cloned())
+            .collect()
+    };
 
-            let variants = &d.download.quality_variants;
-            let best_variant = variants
-                .high
-                .as_ref()
-                .or(variants.medium.as_ref())
-                .or(variants.low.as_ref());
-            let url = best_variant
-                .and_then(|v| v.file_url.clone())
-                .unwrap_or_else(||
-                    // No public url - we will proxy it instead
-                    format!("{}/episode/{}", base_url, d.id));
-
-            let file_size = match best_variant {
-                Some(QualityVariant {
-                    file_url: Some(_),
-                    file_size: Some(s),
-                }) => *s,
-                _ => 50000 * d.duration.value, // estimate based on duration
-            };
+    let now = Utc::now();
+    let episodes = episode_data
+        .iter()
+        .zip(&variants)
+        .map(|(d, v)| {
+            Episode::from_container_data(
+                base_url,
+                programme_id,
+                d,
+                v.as_ref(),
+                options,
+                measured_bytes_per_sec,
+            )
+        })
+        .collect();
+    embargo_episodes(episodes, options, now)
+        .into_iter()
+        .filter(|e| is_visible(e.pub_date, options.delay_hours, now))
+        .collect()
+}
 
-            let content_type = match best_variant {
-                Some(QualityVariant {
-                    file_url: Some(f), ..
-                }) => match f.split('.').last() {
-                    Some("mp3") => "audio/mpeg".to_string(),
-                    Some("m4a") | Some("mp4") => "audio/mp4".to_string(),
-                    _ => "audio/mpeg".to_string(),
-                },
-                _ => "audio/aac".to_string(),
-            };
+// Episode titles looked up for downloads are kept this long, for this many shows
+const TITLES_LIFETIME: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const MAX_TITLE_SHOWS: usize = 256;
+// Shows fetched at once to look up episode titles
+const TITLE_LOOKUP_CONCURRENCY: usize = 4;
 
-            let duration = format!(
-                "{}:{:02}:{:02}",
-                d.duration.value / 3600,
-                (d.duration.value / 60) % 60,
-                d.duration.value % 60
-            );
+struct TitlesEntry {
+    /// Full titles by episode id
+    titles: Arc<HashMap<String, String>>,
+    expires: Instant,
+}
 
-            let guid = GuidBuilder::default().value(d.id.clone()).build();
+static TITLES: Lazy<Mutex<HashMap<bbc::Pid, TitlesEntry>>> = Lazy::new(Mutex::default);
+static TITLE_LOOKUPS: Lazy<tokio::sync::Semaphore> =
+    Lazy::new(|| tokio::sync::Semaphore::new(TITLE_LOOKUP_CONCURRENCY));
 
-            let pub_date = DateTime::parse_from_rfc3339(&d.release.date).ok();
+fn cached_titles(programme_id: &bbc::Pid) -> Option<Arc<HashMap<String, String>>> {
+    let cache = TITLES.lock().unwrap();
+    match cache.get(programme_id) {
+        Some(entry) if entry.expires > Instant::now() => Some(entry.titles.clone()),
+        _ => None,
+    }
+}
 
-            if most_recent_pubdate.is_none()
-                || pub_date.is_some() && pub_date.unwrap() > most_recent_pubdate.unwrap()
-            {
-                most_recent_pubdate = pub_date;
+fn store_titles(programme_id: &bbc::Pid, titles: Arc<HashMap<String, String>>) {
+    let now = Instant::now();
+    let mut cache = TITLES.lock().unwrap();
+    if cache.len() >= MAX_TITLE_SHOWS {
+        cache.retain(|_, e| e.expires > now);
+        if cache.len() >= MAX_TITLE_SHOWS {
+            let soonest = cache
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(k, _)| k.clone());
+            if let Some(soonest) = soonest {
+                cache.remove(&soonest);
             }
+        }
+    }
+    cache.insert(
+        programme_id.clone(),
+        TitlesEntry {
+            titles,
+            expires: now + TITLES_LIFETIME,
+        },
+    );
+}
 
-            let summary = d
-                .synopses
-                .long
-                .clone()
-                .or_else(|| d.synopses.medium.clone())
-                .or_else(|| d.synopses.short.clone());
-
-            let enclosure = EnclosureBuilder::default()
-                .url(url)
-                .length(file_size.to_string())
-                .mime_type(content_type)
-                .build();
+/// Full title of an episode, e.g. `Show - Episode Title (2022-01-31)`.
+/// Returns None if the episode isn't one of the show's. The titles of all the show's
+/// episodes are kept for an hour, so downloads don't each fetch the show.
+pub async fn get_episode_title(
+    programme_id: &bbc::Pid,
+    episode_id: &bbc::Pid,
+) -> Result<Option<String>> {
+    if let Some(titles) = cached_titles(programme_id) {
+        return Ok(titles.get(episode_id.as_str()).cloned());
+    }
+    let _permit = TITLE_LOOKUPS.acquire().await.unwrap();
+    // another lookup may have fetched the show while this waited
+    if let Some(titles) = cached_titles(programme_id) {
+        return Ok(titles.get(episode_id.as_str()).cloned());
+    }
+    let titles = Arc::new(get_episode_titles(programme_id).await?);
+    store_titles(programme_id, titles.clone());
+    Ok(titles.get(episode_id.as_str()).cloned())
+}
 
-            let image = d.image_url.clone().and_then(template_url);
+async fn get_episode_titles(programme_id: &bbc::Pid) -> Result<HashMap<String, String>> {
+    let options = FeedOptions {
+        upcoming: true,
+        ..Default::default()
+    };
+    let (show, episodes) = get_show("", programme_id, &options).await?;
 
-            let it_item = ITunesItemExtensionBuilder::default()
-                .duration(Some(duration))
-                .author(Some(show_info.network.short_title.clone()))
-                .subtitle(d.titles.secondary.clone())
-                .summary(summary.clone())
-                .image(image)
-                .build();
+    Ok(episodes
+        .into_iter()
+        .map(|episode| {
+            let title = episode.title.as_deref().unwrap_or(&episode.id);
+            let date = episode
+                .pub_date
+                .map(|d| d.format(" (%Y-%m-%d)").to_string())
+                .unwrap_or_default();
+            let title = format!("{} - {}{}", show.title, title, date);
+            (episode.id, title)
+        })
+        .collect())
+}
+
+/// Renders feeds as a page when they're opened in a browser
+pub const FEED_STYLESHEET: &str = include_str!("../assets/feed.xsl");
+
+/// Adds a stylesheet processing instruction after the XML declaration
+fn with_stylesheet(xml: &str, stylesheet_url: &str) -> String {
+    let instruction = format!(
+        "<?xml-stylesheet type=\"text/xsl\" href=\"{}\"?>",
+        archive::html_escape(stylesheet_url)
+    );
+    // it has to come after the declaration, if there is one
+    match xml.split_once("?>") {
+        Some((declaration, rest)) if xml.starts_with("<?xml ") => {
+            format!("{}?>\n{}{}", declaration, instruction, rest)
+        }
+        _ => format!("{}{}", instruction, xml),
+    }
+}
+
+/// How many pages a feed of this many episodes is split into
+fn page_count(episodes: usize, page_size: usize) -> usize {
+    // an empty feed still has its first page
+    (episodes.max(1) - 1) / page_size.max(1) + 1
+}
+
+/// The episodes on a page of the feed (numbered from 1), and how many pages there are.
+/// Returns None if there's no such page.
+fn page_episodes(
+    mut episodes: Vec<Episode>,
+    page: usize,
+    page_size: usize,
+) -> Option<(Vec<Episode>, usize)> {
+    let page_size = page_size.max(1);
+    let pages = page_count(episodes.len(), page_size);
+    if page == 0 || page > pages {
+        return None;
+    }
+    if pages > 1 {
+        episodes.sort_by_key(|e| Reverse(e.pub_date));
+    }
+    let episodes = episodes
+        .into_iter()
+        .skip((page - 1) * page_size)
+        .take(page_size)
+        .collect();
+    Some((episodes, pages))
+}
+
+/// Sorts a page's episodes by publication date. Undated episodes go last.
+fn order_episodes(mut episodes: Vec<Episode>, order: EpisodeOrder) -> Vec<Episode> {
+    match order {
+        EpisodeOrder::Asc => episodes.sort_by_key(|e| (e.pub_date.is_none(), e.pub_date)),
+        EpisodeOrder::Desc => episodes.sort_by_key(|e| Reverse(e.pub_date)),
+    }
+    episodes
+}
+
+/// The page's own URL, and if the feed is split, links between its pages (RFC 5005),
+/// by relation. The first page is the feed itself.
+fn page_links(feed_url: &str, page: usize, pages: usize) -> Vec<(&'static str, String)> {
+    let page_url = |p: usize| {
+        if p == 1 {
+            feed_url.to_string()
+        } else {
+            format!("{}?page={}", feed_url, p)
+        }
+    };
+
+    let mut links = vec![("self", page)];
+    if pages > 1 {
+        links.extend([("first", 1), ("last", pages)]);
+    }
+    if page > 1 {
+        links.push(("previous", page - 1));
+    }
+    if page < pages {
+        links.push(("next", page + 1));
+    }
+    links
+        .into_iter()
+        .map(|(rel, p)| (rel, page_url(p)))
+        .collect()
+}
 
-            ItemBuilder::default()
-                .title(d.titles.secondary.clone())
-                .description(summary)
-                .enclosure(Some(enclosure))
-                .guid(Some(guid))
-                .pub_date(pub_date.map(|d| d.to_rfc2822()))
-                .itunes_ext(Some(it_item))
+/// The page links, as `atom:link` elements of an RSS feed
+fn feed_links(feed_url: &str, page: usize, pages: usize) -> Vec<Extension> {
+    page_links(feed_url, page, pages)
+        .into_iter()
+        .map(|(rel, href)| {
+            ExtensionBuilder::default()
+                .name("atom:link")
+                .attrs(BTreeMap::from([
+                    ("rel".to_string(), rel.to_string()),
+                    ("href".to_string(), href),
+                ]))
                 .build()
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
 
-    let image = image.map(|img| {
-        ImageBuilder::default()
-            .url(img)
-            .width(Some("400".to_string()))
-            .height(Some("400".to_string()))
-            .build()
-    });
+/// `podcast:locked` for the owner, so directories won't let anyone else import the feed,
+/// and the verification token for directories which check for one in `podcast:txt`
+fn ownership(
+    owner_email: Option<&str>,
+    verification_token: Option<&str>,
+) -> BTreeMap<String, Vec<Extension>> {
+    let mut tags = BTreeMap::new();
+    if let Some(email) = owner_email {
+        let locked = ExtensionBuilder::default()
+            .name("podcast:locked")
+            .attrs(BTreeMap::from([("owner".to_string(), email.to_string())]))
+            .value(Some("yes".to_string()))
+            .build();
+        tags.insert("locked".to_string(), vec![locked]);
+    }
+    if let Some(token) = verification_token {
+        let txt = ExtensionBuilder::default()
+            .name("podcast:txt")
+            .attrs(BTreeMap::from([(
+                "purpose".to_string(),
+                "verify".to_string(),
+            )]))
+            .value(Some(token.to_string()))
+            .build();
+        tags.insert("txt".to_string(), vec![txt]);
+    }
+    tags
+}
 
-    let mut rss_channel_builder = ChannelBuilder::default();
-    rss_channel_builder
-        .title(show_info.titles.primary.clone())
-        .link("https://www.bbc.co.uk/sounds/series/".to_string() + programme_id)
-        .itunes_ext(Some(rss_itunes))
-        .namespaces(namespaces)
-        .items(episodes)
-        .pub_date(most_recent_pubdate.map(|d| d.to_rfc2822()))
-        .image(image)
-        .build();
+/// Leaves out episodes whose fingerprint shows they have the same audio as another of
+/// the episodes, keeping the first published
+fn dedupe_episodes(
+    episodes: Vec<Episode>,
+    original: impl Fn(&str) -> Option<String>,
+) -> Vec<Episode> {
+    dedupe_by(episodes, |e| e, original)
+}
 
-    Ok(rss_channel_builder.build().to_string())
+/// Leaves out items whose episodes have the same audio as another's, keeping the first
+/// published, e.g. episodes of several shows
+fn dedupe_by<T>(
+    items: Vec<T>,
+    episode: impl Fn(&T) -> &Episode,
+    original: impl Fn(&str) -> Option<String>,
+) -> Vec<T> {
+    let mut firsts: HashMap<String, (Option<DateTime<FixedOffset>>, usize)> = HashMap::new();
+    for (i, e) in items.iter().map(episode).enumerate() {
+        let key = original(&e.id).unwrap_or_else(|| e.id.clone());
+        let first = firsts.entry(key).or_insert((e.pub_date, i));
+        // an episode without a date can't be shown to be first, so is taken as the latest
+        let earlier = match (e.pub_date, first.0) {
+            (Some(date), Some(first)) => date < first,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if earlier {
+            *first = (e.pub_date, i);
+        }
+    }
+    let keep = firsts.values().map(|(_, i)| *i).collect::<HashSet<_>>();
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep.contains(i))
+        .map(|(_, item)| item)
+        .collect()
 }
 
-type TryBytes = Result<Vec<u8>>;
+/// The episode as an item for each of its parts. Parts are served by the proxy, as only
+/// it can cut them from the whole episode.
+fn split_episode(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    episode: Episode,
+    parts: Vec<omnibus::Part>,
+    options: &FeedOptions,
+) -> Vec<Episode> {
+    if parts.is_empty() {
+        return vec![episode];
+    }
+    parts
+        .into_iter()
+        .map(|part| {
+            let secs = part.secs(episode.duration);
+            let mut url = format!(
+                "{}/episode/{}.aac?part={}",
+                base_url, episode.id, part.number
+            );
+            if options.link_show {
+                url += &format!("&show={}", programme_id);
+            }
+            let url = bust_cache(url, options);
+            let url = match &options.enclosure_prefix {
+                Some(prefix) => prefix_url(prefix, &url),
+                None => url,
+            };
+            let title = episode.title.as_deref().unwrap_or_default();
+            Episode {
+                id: episode.id.clone(),
+                title: Some(format!("{} (Part {}: {})", title, part.number, part.title)),
+                summary: episode.summary.clone(),
+                url,
+                file_size: episode.file_size * secs / episode.duration.max(1),
+                content_type: "audio/aac".to_string(),
+                duration: secs,
+                // a second apart, so apps keep the parts in order
+                pub_date: episode
+                    .pub_date
+                    .map(|d| d + Duration::seconds(part.number as i64)),
+                image: episode.image.clone(),
+                part: Some(part.number),
+                available_from: episode.available_from,
+                season: episode.season,
+                // the parts aren't episodes of the series
+                number: None,
+                credits: episode.credits.clone(),
+            }
+        })
+        .collect()
+}
 
-pub async fn get_episode_url(episode_id: &str) -> Result<Option<String>> {
-    bbc::get_media_url(episode_id).await
+/// Splits omnibus episodes into their parts, if enabled. Episodes whose parts can't be
+/// found are left whole.
+async fn split_episodes(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    episodes: Vec<Episode>,
+    options: &FeedOptions,
+) -> Vec<Episode> {
+    if !options.split_parts {
+        return episodes;
+    }
+    let parts = stream::iter(&episodes)
+        .map(|e| async move {
+            let pid = e.id.parse::<bbc::Pid>().ok()?;
+            omnibus::get_parts(&pid)
+                .await
+                .map_err(|err| log::warn!("Failed to get parts of {}: {}", e.id, err))
+                .ok()
+        })
+        .buffered(LOOKUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    episodes
+        .into_iter()
+        .zip(parts)
+        .flat_map(|(episode, parts)| {
+            split_episode(
+                base_url,
+                programme_id,
+                episode,
+                parts.unwrap_or_default(),
+                options,
+            )
+        })
+        .collect()
 }
 
-pub async fn get_episode(episode_id: &str) -> Result<impl Stream<Item = TryBytes>> {
-    let media = bbc::get_media(episode_id).await?;
+/// Looks up who presents and who's on each episode, adding them to its description
+async fn add_credits(episodes: Vec<Episode>, options: &FeedOptions) -> Vec<Episode> {
+    if !options.credits {
+        return episodes;
+    }
+    let episodes = stream::iter(episodes).map(|mut episode| async move {
+        // the credits are of the whole episode
+        if episode.part.is_some() {
+            return episode;
+        }
+        let pid = match episode.id.parse::<bbc::Pid>() {
+            Ok(pid) => pid,
+            Err(_) => return episode,
+        };
+        match credits::get_credits(&pid).await {
+            Ok(credits) => {
+                if let Some(described) = credits.describe() {
+                    episode.summary = Some(tracklist::append(episode.summary.take(), &described));
+                }
+                episode.credits = credits;
+            }
+            Err(err) => log::warn!("Failed to get credits of {}: {}", episode.id, err),
+        }
+        episode
+    });
+    episodes.buffered(LOOKUP_CONCURRENCY).collect().await
+}
 
-    // locate highest quality audio
-    let audio_url = media
-        .media
-        .iter()
-        .filter(|m| m.kind == "audio")
-        .sorted_by_key(|m| m.bitrate.parse::<u32>().unwrap_or(0))
-        .last()
-        .ok_or(bbc::BbcResponseError::NotFound)?
-        .connection
+/// Adds the tracklists of whole episodes to their summaries, if enabled. Episodes whose
+/// segments can't be found are left as they are.
+async fn add_tracklists(episodes: Vec<Episode>, options: &FeedOptions) -> Vec<Episode> {
+    if !options.tracklist {
+        return episodes;
+    }
+    let episodes = stream::iter(episodes).map(|mut episode| async move {
+        // the tracklist is of the whole episode
+        if episode.part.is_some() {
+            return episode;
+        }
+        let pid = match episode.id.parse::<bbc::Pid>() {
+            Ok(pid) => pid,
+            Err(_) => return episode,
+        };
+        match tracklist::get_tracklist(&pid).await {
+            Ok(Some(tracks)) => {
+                episode.summary = Some(tracklist::append(episode.summary.take(), &tracks));
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("Failed to get tracklist of {}: {}", episode.id, err),
+        }
+        episode
+    });
+    // in order, as the feed's order is already decided
+    episodes.buffered(LOOKUP_CONCURRENCY).collect().await
+}
+
+/// `podcast:funding` tags for the links
+fn funding(links: &[Funding]) -> Vec<Extension> {
+    links
         .iter()
-        .sorted_by(|a, b| {
-            if a.protocol == b.protocol {
-                Ordering::Equal
-            } else if a.protocol == "http" {
-                Ordering::Less
-            } else {
-                Ordering::Greater
+        .map(|link| {
+            ExtensionBuilder::default()
+                .name("podcast:funding")
+                .attrs(BTreeMap::from([("url".to_string(), link.url.clone())]))
+                .value(Some(link.text.clone()))
+                .build()
+        })
+        .collect()
+}
+
+/// The show and the episodes on a page of its feed (numbered from 1), ready to be
+/// rendered, and how many pages there are
+async fn get_feed_page(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+) -> Result<(Show, Vec<Episode>, usize)> {
+    let info = get_show_info(programme_id, options).await?;
+    feed_page(base_url, programme_id, options, page, info).await
+}
+
+async fn feed_page(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+    info: (Show, bbc::ContainerList),
+) -> Result<(Show, Vec<Episode>, usize)> {
+    let (show, episodes, listed) =
+        show_page(base_url, programme_id, options, Some(page), info, true).await?;
+    let episodes = retain_episodes(episodes, options, Utc::now());
+    let (episodes, pages) = match listed {
+        // only this page's episodes were fetched
+        Some(listed) => (episodes, page_count(listed, options.page_size)),
+        None => page_episodes(episodes, page, options.page_size)
+            .ok_or(bbc::BbcResponseError::NotFound)?,
+    };
+    let episodes = order_episodes(episodes, options.order);
+    let episodes = split_episodes(base_url, programme_id, episodes, options).await;
+    let episodes = add_credits(episodes, options).await;
+    let episodes = add_tracklists(episodes, options).await;
+    Ok((show, episodes, pages))
+}
+
+/// Namespaces of the extensions in RSS feeds
+fn rss_namespaces() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        (
+            "itunes".to_string(),
+            "http://www.itunes.com/dtds/podcast-1.0.dtd".to_string(),
+        ),
+        (
+            "podcast".to_string(),
+            "https://podcastindex.org/namespace/1.0".to_string(),
+        ),
+        (
+            "dc".to_string(),
+            "http://purl.org/dc/elements/1.1/".to_string(),
+        ),
+        ("sounds".to_string(), SOURCE_NAMESPACE.to_string()),
+        (
+            "atom".to_string(),
+            "http://www.w3.org/2005/Atom".to_string(),
+        ),
+    ])
+}
+
+/// `podcast:person` tags for the people credited, in the cast's host and guest roles,
+/// linking to their BBC pages where they have them
+fn people(credits: &Credits) -> Vec<Extension> {
+    let hosts = credits.presenters.iter().map(|p| ("host", p));
+    let guests = credits.guests.iter().map(|p| ("guest", p));
+    hosts
+        .chain(guests)
+        .map(|(role, person)| {
+            let mut attrs = BTreeMap::from([("role".to_string(), role.to_string())]);
+            if let Some(page) = person.page() {
+                attrs.insert("href".to_string(), page);
             }
+            ExtensionBuilder::default()
+                .name("podcast:person")
+                .attrs(attrs)
+                .value(Some(person.name.clone()))
+                .build()
         })
-        .last()
-        .unwrap()
-        .href
-        .clone();
+        .collect()
+}
+
+/// A feed item for the episode of the show
+fn rss_item(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    show: &Show,
+    e: Episode,
+    options: &FeedOptions,
+) -> rss::Item {
+    let duration = format!(
+        "{}:{:02}:{:02}",
+        e.duration / 3600,
+        (e.duration / 60) % 60,
+        e.duration % 60
+    );
 
-    if !audio_url.contains(".m3u8") {
-        return Err(bbc::BbcResponseError::UnsupportedMedia(
-            episode_id.into(),
-            audio_url,
-        ));
+    let mut guid = episode_guid(options, &e.id);
+    if let Some(part) = e.part {
+        guid.set_value(format!("{}#part-{}", guid.value(), part));
     }
 
-    log::debug!("m3u8 url: {}", audio_url);
+    let enclosure = EnclosureBuilder::default()
+        .url(e.url)
+        .length(e.file_size.to_string())
+        .mime_type(e.content_type)
+        .build();
+
+    // the presenters, where they're known, so apps can find episodes by them
+    let author = if e.credits.presenters.is_empty() {
+        show.author.clone()
+    } else {
+        e.credits.presenters.iter().map(|p| &p.name).join(", ")
+    };
+    let it_item = ITunesItemExtensionBuilder::default()
+        .duration(Some(duration))
+        .author(Some(author))
+        .subtitle(e.title.clone())
+        .summary(e.summary.clone())
+        .image(e.image)
+        .season(e.season.map(|n| n.to_string()))
+        .episode(e.number.map(|n| n.to_string()))
+        .build();
 
-    let stream = HlsStream::new(audio_url)?.map(|r| r.map_err(|e| e.into()));
+    let dublin_core = DublinCoreExtensionBuilder::default()
+        .creators(vec![show.author.clone()])
+        .build();
 
-    Ok(stream)
+    // The episode's pid and BBC page, for anyone cataloguing the feed
+    let mut source_attrs = BTreeMap::from([
+        ("pid".to_string(), e.id.clone()),
+        ("show".to_string(), programme_id.to_string()),
+        (
+            "url".to_string(),
+            format!("https://www.bbc.co.uk/programmes/{}", e.id),
+        ),
+    ]);
+    if let Some(part) = e.part {
+        source_attrs.insert("part".to_string(), part.to_string());
+    }
+    let source = ExtensionBuilder::default()
+        .name("sounds:source")
+        .attrs(source_attrs)
+        .build();
+
+    let mut extensions = ExtensionMap::new();
+    extensions.insert(
+        "sounds".to_string(),
+        BTreeMap::from([("source".to_string(), vec![source])]),
+    );
+    let mut podcast_tags = BTreeMap::new();
+    // the chapters are of the whole episode
+    if options.chapters && e.part.is_none() {
+        let chapters = ExtensionBuilder::default()
+            .name("podcast:chapters")
+            .attrs(BTreeMap::from([
+                (
+                    "url".to_string(),
+                    format!("{}/episode/{}/chapters.json", base_url, e.id),
+                ),
+                ("type".to_string(), CHAPTERS_CONTENT_TYPE.to_string()),
+            ]))
+            .build();
+        podcast_tags.insert("chapters".to_string(), vec![chapters]);
+    }
+    let numbers = [("season", e.season), ("episode", e.number)];
+    for (name, number) in numbers {
+        if let Some(number) = number {
+            let tag = ExtensionBuilder::default()
+                .name(format!("podcast:{}", name))
+                .value(Some(number.to_string()))
+                .build();
+            podcast_tags.insert(name.to_string(), vec![tag]);
+        }
+    }
+    let people = people(&e.credits);
+    if !people.is_empty() {
+        podcast_tags.insert("person".to_string(), people);
+    }
+    if !podcast_tags.is_empty() {
+        extensions.insert("podcast".to_string(), podcast_tags);
+    }
+
+    ItemBuilder::default()
+        .title(e.title)
+        .description(e.summary)
+        .enclosure(Some(enclosure))
+        .guid(Some(guid))
+        .pub_date(e.pub_date.map(|d| d.to_rfc2822()))
+        .itunes_ext(Some(it_item))
+        .dublin_core_ext(Some(dublin_core))
+        .extensions(extensions)
+        .build()
+}
+
+pub async fn get_podcast_feed(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+) -> Result<String> {
+    let (show, episodes, pages) = get_feed_page(base_url, programme_id, options, page).await?;
+    Ok(podcast_feed(
+        base_url,
+        programme_id,
+        options,
+        page,
+        (show, episodes, pages),
+    ))
+}
+
+fn podcast_feed(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+    (show, episodes, pages): (Show, Vec<Episode>, usize),
+) -> String {
+    let most_recent_pubdate = episodes.iter().filter_map(|e| e.pub_date).max();
+
+    let items = episodes
+        .into_iter()
+        .map(|e| rss_item(base_url, programme_id, &show, e, options))
+        .collect::<Vec<_>>();
+
+    let mut channel = rss_channel(programme_id, show, options);
+    channel.set_items(items);
+    finish_rss_channel(
+        &mut channel,
+        base_url,
+        programme_id,
+        (page, pages),
+        most_recent_pubdate,
+    );
+
+    with_stylesheet(&channel.to_string(), &format!("{}/feed.xsl", base_url))
+}
+
+/// A show's RSS channel, without its items or what's only known once they're all listed
+fn rss_channel(programme_id: &bbc::Pid, show: Show, options: &FeedOptions) -> rss::Channel {
+    let owner = options.owner_email.as_ref().map(|email| {
+        ITunesOwnerBuilder::default()
+            .email(Some(email.clone()))
+            .build()
+    });
+
+    let rss_itunes = ITunesChannelExtensionBuilder::default()
+        .author(Some(show.author.clone()))
+        .owner(owner)
+        .block(Some("Yes".into()))
+        .image(show.image.clone())
+        .subtitle(show.subtitle.clone())
+        .categories(show.category.clone().into_iter().collect::<Vec<_>>())
+        .build();
+
+    let namespaces = rss_namespaces();
+
+    let image = show.image.map(|img| {
+        ImageBuilder::default()
+            .url(img)
+            .width(Some("400".to_string()))
+            .height(Some("400".to_string()))
+            .build()
+    });
+
+    let mut channel_extensions = ExtensionMap::new();
+    let mut podcast_tags = ownership(
+        options.owner_email.as_deref(),
+        options.verification_token.as_deref(),
+    );
+    let guid = ExtensionBuilder::default()
+        .name("podcast:guid")
+        .value(Some(podcast_guid(programme_id)))
+        .build();
+    podcast_tags.insert("guid".to_string(), vec![guid]);
+    if !options.funding.is_empty() {
+        podcast_tags.insert("funding".to_string(), funding(&options.funding));
+    }
+    channel_extensions.insert("podcast".to_string(), podcast_tags);
+
+    ChannelBuilder::default()
+        .title(show.title)
+        .link(show.link)
+        .language(options.language.clone())
+        .itunes_ext(Some(rss_itunes))
+        .namespaces(namespaces)
+        .image(image)
+        .extensions(channel_extensions)
+        .build()
+}
+
+/// Adds the date of the newest item, and the links between the feed's pages
+fn finish_rss_channel(
+    channel: &mut rss::Channel,
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    (page, pages): (usize, usize),
+    most_recent_pubdate: Option<DateTime<FixedOffset>>,
+) {
+    channel.set_pub_date(most_recent_pubdate.map(|d| d.to_rfc2822()));
+    channel.set_last_build_date(most_recent_pubdate.map(|d| d.to_rfc2822()));
+
+    let feed_url = format!("{}/show/{}", base_url, programme_id);
+    let mut extensions = channel.extensions().clone();
+    extensions.insert(
+        "atom".to_string(),
+        BTreeMap::from([("link".to_string(), feed_links(&feed_url, page, pages))]),
+    );
+    channel.set_extensions(extensions);
+}
+
+/// What's written in a channel after its title, link and description, to be written
+/// into another channel
+fn channel_body(channel: &rss::Channel) -> String {
+    let xml = channel.to_string();
+    let start = xml
+        .find("</description>")
+        .map_or(0, |i| i + "</description>".len());
+    let end = xml.rfind("</channel>").unwrap_or(xml.len());
+    xml[start..end.max(start)].to_string()
+}
+
+/// Items to write into a channel split by `split_channel`
+fn rss_items(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    show: &Show,
+    episodes: Vec<Episode>,
+    options: &FeedOptions,
+) -> String {
+    let items = episodes
+        .into_iter()
+        .map(|e| rss_item(base_url, programme_id, show, e, options))
+        .collect::<Vec<_>>();
+    channel_body(&ChannelBuilder::default().items(items).build())
+}
+
+/// What follows the items of a feed written in one page
+fn rss_tail(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    most_recent_pubdate: Option<DateTime<FixedOffset>>,
+) -> String {
+    let mut channel = rss::Channel::default();
+    finish_rss_channel(
+        &mut channel,
+        base_url,
+        programme_id,
+        (1, 1),
+        most_recent_pubdate,
+    );
+    channel_body(&channel)
+}
+
+/// The channel's XML split where its items go: everything before them, and the end tags
+fn split_channel(channel: &rss::Channel, base_url: &str) -> (String, String) {
+    let xml = with_stylesheet(&channel.to_string(), &format!("{}/feed.xsl", base_url));
+    let (head, foot) = xml.split_at(xml.rfind("</channel>").unwrap_or(xml.len()));
+    (head.to_string(), foot.to_string())
+}
+
+/// Whether a feed can be written a BBC page at a time: its whole listing is one page of
+/// the feed, in the BBC's order (newest first), and nothing needs every episode at once
+fn writes_in_chunks(list: &bbc::ContainerList, options: &FeedOptions, page: usize) -> bool {
+    let total = match &list.pagination {
+        Some(pagination) if list.is_partial() => pagination.total,
+        _ => return false,
+    };
+    page == 1
+        && options.backfill
+        && !options.page_slices
+        && total <= options.page_size
+        && options.order == EpisodeOrder::Desc
+        && options.max_items.is_none()
+        && !options.dedupe
+        && (options.nested_depth == 0 || list.containers.is_empty())
+}
+
+/// An RSS feed, whole or in pieces
+pub enum PodcastFeed {
+    Whole(String),
+    /// Written as the BBC's pages of the listing arrive, so it's cut short if one fails
+    Chunked(LocalBoxStream<'static, Result<String>>),
+}
+
+/// The RSS feed, in pieces where the BBC splits its listing into pages. Those are written
+/// as they arrive, rather than once they all have: the channel once the first has, then
+/// the items on each, then the date of the newest.
+pub async fn get_podcast_feed_chunks(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+) -> Result<PodcastFeed> {
+    let (show, list) = get_show_info(programme_id, options).await?;
+    let pagination = match &list.pagination {
+        Some(pagination) if writes_in_chunks(&list, options, page) => pagination.clone(),
+        _ => {
+            let info = (show, list);
+            let feed_page = feed_page(base_url, programme_id, options, page, info).await?;
+            let feed = podcast_feed(base_url, programme_id, options, page, feed_page);
+            return Ok(PodcastFeed::Whole(feed));
+        }
+    };
+
+    let (head, foot) = split_channel(&rss_channel(programme_id, show.clone(), options), base_url);
+    // sizes are estimated from the first page's episodes, as the rest aren't listed yet
+    let measured_bytes_per_sec = measure_bytes_per_sec(&list.data, &options.known_sizes);
+    let most_recent_pubdate = Rc::new(Cell::new(None));
+
+    let context = Rc::new((
+        base_url.to_string(),
+        programme_id.clone(),
+        options.clone(),
+        show,
+    ));
+    let listed = list.data.len();
+    let pages = stream::once(async { Ok(list.data) }).chain(bbc::list_pages(pagination, listed));
+    let items = {
+        let context = context.clone();
+        let most_recent_pubdate = most_recent_pubdate.clone();
+        pages.then(move |data| {
+            let context = context.clone();
+            let most_recent_pubdate = most_recent_pubdate.clone();
+            async move {
+                let (base_url, programme_id, options, show) = &*context;
+                let now = Utc::now();
+                let data = retain_listed(data?, options, now);
+                let episodes = list_episodes(
+                    base_url,
+                    programme_id,
+                    &data,
+                    options,
+                    measured_bytes_per_sec,
+                )
+                .await;
+                let episodes = retain_episodes(episodes, options, now);
+                let episodes = order_episodes(episodes, options.order);
+                let episodes = split_episodes(base_url, programme_id, episodes, options).await;
+                let episodes = add_credits(episodes, options).await;
+                let episodes = add_tracklists(episodes, options).await;
+
+                let newest = episodes.iter().filter_map(|e| e.pub_date).max();
+                most_recent_pubdate.set(most_recent_pubdate.get().max(newest));
+                Ok(rss_items(base_url, programme_id, show, episodes, options))
+            }
+        })
+    };
+    let tail = stream::once(async move {
+        let (base_url, programme_id, ..) = &*context;
+        Ok(rss_tail(base_url, programme_id, most_recent_pubdate.get()) + &foot)
+    });
+
+    Ok(PodcastFeed::Chunked(
+        stream::once(async { Ok(head) })
+            .chain(items)
+            .chain(tail)
+            .boxed_local(),
+    ))
+}
+
+const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+#[derive(Debug, Serialize)]
+struct JsonFeedAuthor {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonFeedAttachment {
+    url: String,
+    mime_type: String,
+    size_in_bytes: u64,
+    duration_in_seconds: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    content_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_published: Option<String>,
+    attachments: Vec<JsonFeedAttachment>,
+}
+
+/// A show as a JSON Feed (https://jsonfeed.org/version/1.1)
+#[derive(Debug, Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: String,
+    home_page_url: String,
+    feed_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    authors: Vec<JsonFeedAuthor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    items: Vec<JsonFeedItem>,
+}
+
+/// The page of the feed as JSON Feed. Items have the same ids and enclosures as in the
+/// RSS feed, so apps can switch between them.
+fn json_feed(
+    show: Show,
+    episodes: Vec<Episode>,
+    feed_url: &str,
+    page: usize,
+    pages: usize,
+    options: &FeedOptions,
+) -> JsonFeed {
+    let items = episodes
+        .into_iter()
+        .map(|e| {
+            let mut guid = episode_guid(options, &e.id);
+            if let Some(part) = e.part {
+                guid.set_value(format!("{}#part-{}", guid.value(), part));
+            }
+            JsonFeedItem {
+                id: guid.value().to_string(),
+                url: format!("https://www.bbc.co.uk/programmes/{}", e.id),
+                // an item needs some content, even if the BBC gives no summary
+                content_text: e.summary.or_else(|| e.title.clone()).unwrap_or_default(),
+                title: e.title,
+                image: e.image,
+                date_published: e.pub_date.map(|d| d.to_rfc3339()),
+                attachments: vec![JsonFeedAttachment {
+                    url: e.url,
+                    mime_type: e.content_type,
+                    size_in_bytes: e.file_size,
+                    duration_in_seconds: e.duration,
+                }],
+            }
+        })
+        .collect();
+
+    JsonFeed {
+        version: JSON_FEED_VERSION,
+        title: show.title,
+        home_page_url: show.link,
+        feed_url: feed_url.to_string(),
+        description: show.subtitle,
+        next_url: if page < pages {
+            Some(format!("{}?page={}", feed_url, page + 1))
+        } else {
+            None
+        },
+        icon: show.image,
+        authors: vec![JsonFeedAuthor { name: show.author }],
+        language: options.language.clone(),
+        items,
+    }
+}
+
+/// Generates a JSON Feed of the show, from the same episodes as its RSS feed
+pub async fn get_json_feed(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+) -> Result<String> {
+    let (show, episodes, pages) = get_feed_page(base_url, programme_id, options, page).await?;
+    let feed_url = format!("{}/show/{}.json", base_url, programme_id);
+    let feed = json_feed(show, episodes, &feed_url, page, pages, options);
+    serde_json::to_string(&feed).map_err(|_| bbc::BbcResponseError::FormatError)
+}
+
+/// Most shows one combined feed may merge, as each is fetched from the BBC
+pub const MAX_COMBINED_SHOWS: usize = 20;
+
+// Shows of a combined feed fetched at once
+const COMBINED_CONCURRENCY: usize = 4;
+
+/// Interleaves the shows' episodes newest first, each with the index of its show, keeping
+/// up to `max_items`
+fn merge_episodes(shows: Vec<Vec<Episode>>, max_items: usize) -> Vec<(usize, Episode)> {
+    shows
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, episodes)| episodes.into_iter().map(move |e| (i, e)))
+        .sorted_by_key(|(_, e)| Reverse(e.pub_date))
+        .take(max_items)
+        .collect()
+}
+
+/// Merges the shows' feeds into one, newest first, e.g. for a single catch-up feed. Each
+/// show's episodes are as in its own feed, with its own options, and titled with the show,
+/// but without tracklists or credits, which would take a lookup for every episode.
+/// Shows which can't be fetched are left out, unless none can be.
+pub async fn get_combined_feed(
+    base_url: &str,
+    shows: &[(bbc::Pid, FeedOptions)],
+    feed_url: &str,
+) -> Result<String> {
+    let pages = stream::iter(shows)
+        .map(|(pid, options)| async move {
+            let options = FeedOptions {
+                tracklist: false,
+                credits: false,
+                ..options.clone()
+            };
+            get_feed_page(base_url, pid, &options, 1).await
+        })
+        .buffered(COMBINED_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    let mut fetched = Vec::new();
+    let mut error = None;
+    for ((pid, options), page) in shows.iter().zip(pages) {
+        match page {
+            Ok((show, episodes, _)) => fetched.push((pid, options, show, episodes)),
+            Err(e) => {
+                log::warn!("Leaving {} out of combined feed: {}", pid, e);
+                error.get_or_insert(e);
+            }
+        }
+    }
+    if fetched.is_empty() {
+        return Err(error.unwrap_or(bbc::BbcResponseError::BadRequest));
+    }
+
+    let max_items = fetched
+        .iter()
+        .map(|(_, o, _, _)| o.page_size)
+        .max()
+        .unwrap_or(0);
+    let dedupe = fetched.iter().any(|(_, o, _, _)| o.dedupe);
+    let episodes = fetched
+        .iter_mut()
+        .map(|(_, _, _, episodes)| std::mem::take(episodes))
+        .collect();
+    let episodes = merge_episodes(episodes, usize::MAX);
+    // the same audio may be in several of the shows, e.g. under each station's ID
+    let episodes = if dedupe {
+        dedupe_by(episodes, |(_, e)| e, fingerprint::original)
+    } else {
+        episodes
+    };
+    let episodes = episodes.into_iter().take(max_items).collect::<Vec<_>>();
+    let most_recent_pubdate = episodes.iter().filter_map(|(_, e)| e.pub_date).max();
+
+    let items = episodes
+        .into_iter()
+        .map(|(i, e)| {
+            let (pid, options, show, _) = &fetched[i];
+            let title = match &e.title {
+                Some(title) => format!("{}: {}", show.title, title),
+                None => show.title.clone(),
+            };
+            let mut item = rss_item(base_url, pid, show, e, options);
+            item.set_title(title);
+            item
+        })
+        .collect::<Vec<_>>();
+
+    let titles = fetched
+        .iter()
+        .map(|(_, _, show, _)| show.title.as_str())
+        .join(", ");
+    let authors = fetched
+        .iter()
+        .map(|(_, _, show, _)| show.author.as_str())
+        .unique()
+        .join(", ");
+    let rss_itunes = ITunesChannelExtensionBuilder::default()
+        .author(Some(authors))
+        .block(Some("Yes".into()))
+        .build();
+
+    let mut channel_extensions = ExtensionMap::new();
+    channel_extensions.insert(
+        "atom".to_string(),
+        BTreeMap::from([("link".to_string(), feed_links(feed_url, 1, 1))]),
+    );
+    let guid = ExtensionBuilder::default()
+        .name("podcast:guid")
+        .value(Some(uuid_v5(
+            feed_url
+                .split_once("://")
+                .map_or(feed_url, |(_, rest)| rest),
+        )))
+        .build();
+    channel_extensions.insert(
+        "podcast".to_string(),
+        BTreeMap::from([("guid".to_string(), vec![guid])]),
+    );
+
+    let channel = ChannelBuilder::default()
+        .title(titles.clone())
+        .link(feed_url.to_string())
+        .description(format!("Episodes of {}", titles))
+        .itunes_ext(Some(rss_itunes))
+        .namespaces(rss_namespaces())
+        .items(items)
+        .pub_date(most_recent_pubdate.map(|d| d.to_rfc2822()))
+        .last_build_date(most_recent_pubdate.map(|d| d.to_rfc2822()))
+        .extensions(channel_extensions)
+        .build();
+
+    Ok(with_stylesheet(
+        &channel.to_string(),
+        &format!("{}/feed.xsl", base_url),
+    ))
+}
+
+/// The page of the feed as Atom (RFC 4287). Entries link to their audio as enclosures,
+/// the same as in the RSS feed, and are identified by their BBC pages, as Atom ids have
+/// to be IRIs.
+fn atom_feed(
+    show: Show,
+    programme_id: &bbc::Pid,
+    episodes: Vec<Episode>,
+    feed_url: &str,
+    page: usize,
+    pages: usize,
+    options: &FeedOptions,
+) -> atom_syndication::Feed {
+    let now = Utc::now().into();
+    let author = atom_syndication::PersonBuilder::default()
+        .name(show.author.clone())
+        .build();
+    let updated = episodes.iter().filter_map(|e| e.pub_date).max();
+
+    let entries = episodes
+        .into_iter()
+        .map(|e| {
+            let mut page_url = format!("https://www.bbc.co.uk/programmes/{}", e.id);
+            if let Some(part) = e.part {
+                page_url += &format!("#part-{}", part);
+            }
+            let page = atom_syndication::LinkBuilder::default()
+                .href(page_url.clone())
+                .rel("alternate")
+                .mime_type(Some("text/html".to_string()))
+                .build();
+            let enclosure = atom_syndication::LinkBuilder::default()
+                .href(e.url)
+                .rel("enclosure")
+                .mime_type(Some(e.content_type))
+                .length(Some(e.file_size.to_string()))
+                .build();
+            atom_syndication::EntryBuilder::default()
+                .title(e.title.unwrap_or_else(|| show.title.clone()))
+                .id(page_url)
+                .updated(e.pub_date.unwrap_or(now))
+                .published(e.pub_date)
+                .summary(e.summary.map(atom_syndication::Text::plain))
+                .authors(vec![author.clone()])
+                .links(vec![page, enclosure])
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let mut links = vec![atom_syndication::LinkBuilder::default()
+        .href(show.link)
+        .rel("alternate")
+        .mime_type(Some("text/html".to_string()))
+        .build()];
+    links.extend(
+        page_links(feed_url, page, pages)
+            .into_iter()
+            .map(|(rel, href)| {
+                atom_syndication::LinkBuilder::default()
+                    .href(href)
+                    .rel(rel)
+                    .mime_type(Some("application/atom+xml".to_string()))
+                    .build()
+            }),
+    );
+
+    atom_syndication::FeedBuilder::default()
+        .title(show.title)
+        // the same as the RSS feed's podcast:guid
+        .id(format!("urn:uuid:{}", podcast_guid(programme_id)))
+        .updated(updated.unwrap_or(now))
+        .authors(vec![author])
+        .links(links)
+        .subtitle(show.subtitle.map(atom_syndication::Text::plain))
+        .logo(show.image)
+        .lang(options.language.clone())
+        .entries(entries)
+        .build()
+}
+
+/// Generates an Atom feed of the show, from the same episodes as its RSS feed
+pub async fn get_atom_feed(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+    page: usize,
+) -> Result<String> {
+    let (show, episodes, pages) = get_feed_page(base_url, programme_id, options, page).await?;
+    let feed_url = format!("{}/show/{}.atom", base_url, programme_id);
+    Ok(atom_feed(
+        show,
+        programme_id,
+        episodes,
+        &feed_url,
+        page,
+        pages,
+        options,
+    )
+    .to_string())
+}
+
+/// Generates an extended M3U playlist of the show's episodes
+pub async fn get_m3u_playlist(
+    base_url: &str,
+    programme_id: &bbc::Pid,
+    options: &FeedOptions,
+) -> Result<String> {
+    let info = get_show_info(programme_id, options).await?;
+    let (show, episodes, _) = show_page(base_url, programme_id, options, None, info, true).await?;
+    let episodes = retain_episodes(episodes, options, Utc::now());
+
+    let mut playlist = format!("#EXTM3U\n#PLAYLIST:{}\n", show.title);
+
+    for e in episodes {
+        let title = e.title.unwrap_or_else(|| show.title.clone());
+        playlist += &format!(
+            "#EXTINF:{},{} - {}\n{}\n",
+            e.duration,
+            show.title,
+            title.replace('\n', " "),
+            e.url
+        );
+    }
+
+    Ok(playlist)
+}
+
+pub const CHAPTERS_CONTENT_TYPE: &str = "application/json+chapters";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Chapter {
+    start_time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_time: Option<u64>,
+    title: String,
+}
+
+#[derive(Serialize)]
+struct Chapters {
+    version: String,
+    chapters: Vec<Chapter>,
+}
+
+/// A chapter for each timed segment, so players show the track playing in music shows
+fn chapters(segments: &[bbc::SegmentItem]) -> Chapters {
+    let chapters = segments
+        .iter()
+        .filter_map(|s| {
+            let offset = s.offset.as_ref()?;
+            let title = match (&s.titles.secondary, s.segment_type.as_deref()) {
+                (_, Some(tracklist::MUSIC_SEGMENT_TYPE)) => tracklist::track(s),
+                (Some(secondary), _) => format!("{} - {}", s.titles.primary, secondary),
+                (None, _) => s.titles.primary.clone(),
+            };
+            Some(Chapter {
+                start_time: offset.start,
+                end_time: offset.end,
+                title,
+            })
+        })
+        .sorted_by_key(|c| c.start_time)
+        .collect();
+
+    Chapters {
+        version: "1.2.0".to_string(),
+        chapters,
+    }
+}
+
+/// Generates a JSON chapters file from the episode's segments
+pub async fn get_chapters(episode_id: &bbc::Pid) -> Result<String> {
+    let segments = tracklist::get_segments(episode_id).await?;
+
+    serde_json::to_string(&chapters(&segments)).map_err(|_| bbc::BbcResponseError::FormatError)
+}
+
+type TryBytes = Result<Vec<u8>>;
+
+/// Returns the public URL for an episode, if it has one and the redirector is enabled
+pub async fn get_episode_url(
+    episode_id: &bbc::Pid,
+    resolution: &[ResolutionStrategy],
+) -> Result<Option<String>> {
+    if !resolution.contains(&ResolutionStrategy::Redirector) {
+        return Ok(None);
+    }
+
+    bbc::get_media_url(episode_id).await
+}
+
+/// CDNs to try before others, e.g. `cloudfront`
+static PREFERRED_SUPPLIERS: OnceCell<Vec<String>> = OnceCell::new();
+
+pub fn set_preferred_suppliers(suppliers: Vec<String>) {
+    let _ = PREFERRED_SUPPLIERS.set(suppliers);
+}
+
+/// Locates the highest quality audio of the most preferred variant, and returns its
+/// connections in the order they should be tried (see `bbc::Connection::rank`).
+/// Variants which aren't in `variants` are never used.
+fn select_connections<'a>(
+    media: &'a bbc::MediaList,
+    variants: &[MediaVariant],
+    preferred_suppliers: &[String],
+) -> Vec<&'a bbc::Connection> {
+    let best = media
+        .media
+        .iter()
+        .filter(|m| m.kind.starts_with("audio"))
+        .filter_map(|m| {
+            let preference = variants.iter().position(|v| *v == MediaVariant::of(m))?;
+            Some((preference, m))
+        })
+        .max_by_key(|(preference, m)| {
+            (Reverse(*preference), m.bitrate.parse::<u32>().unwrap_or(0))
+        });
+    match best {
+        Some((_, media)) => media
+            .connection
+            .iter()
+            .sorted_by_key(|c| c.rank(preferred_suppliers))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The first connection whose HLS playlist can be fetched, failing over to the next CDN
+/// when one errors
+async fn first_playlist<'a>(
+    episode_id: &bbc::Pid,
+    connections: &[&'a bbc::Connection],
+    hls_bandwidth: Option<u64>,
+) -> Result<(&'a bbc::Connection, hls::MediaSelection)> {
+    let mut error = bbc::BbcResponseError::NotFound;
+    for connection in connections {
+        if !connection.href.contains(".m3u8") {
+            error = bbc::BbcResponseError::UnsupportedMedia(
+                episode_id.to_string(),
+                connection.href.clone(),
+            );
+            continue;
+        }
+        match hls::get_media_playlist(&connection.href, hls_bandwidth).await {
+            Ok(selection) => return Ok((connection, selection)),
+            Err(e) => {
+                log::warn!(
+                    "Fetching {}'s playlist from {} failed: {}",
+                    episode_id,
+                    connection.supplier.as_deref().unwrap_or("unknown supplier"),
+                    e
+                );
+                error = e.into();
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Streams an episode's audio (or just the span of it), re-encoded to `audio_format` if given.
+/// `hls_bandwidth` pins the HLS variant with the nearest bandwidth, rather than the highest.
+pub async fn get_episode(
+    episode_id: &bbc::Pid,
+    audio_format: Option<AudioFormat>,
+    variants: &[MediaVariant],
+    hls_bandwidth: Option<u64>,
+    span: Option<hls::Span>,
+) -> Result<LocalBoxStream<'static, TryBytes>> {
+    let (stream, ..) =
+        get_episode_with_metadata(episode_id, audio_format, variants, hls_bandwidth, span).await?;
+    Ok(stream)
+}
+
+/// Streams an episode's audio, along with timed metadata found in the stream, and
+/// roughly how many bytes of audio there'll be, where the playlist says its bit rate
+pub async fn get_episode_with_metadata(
+    episode_id: &bbc::Pid,
+    audio_format: Option<AudioFormat>,
+    variants: &[MediaVariant],
+    hls_bandwidth: Option<u64>,
+    span: Option<hls::Span>,
+) -> Result<(
+    LocalBoxStream<'static, TryBytes>,
+    hls::MetadataReceiver,
+    Option<u64>,
+)> {
+    let (metadata, metadata_rx) = tokio::sync::mpsc::channel(hls::METADATA_CAPACITY);
+    let audio_format = audio_format.filter(|_| !transcode::options().copy_only);
+
+    let media = bbc::get_media(episode_id).await?;
+
+    let connections = select_connections(
+        &media,
+        variants,
+        PREFERRED_SUPPLIERS.get().map_or(&[], Vec::as_slice),
+    );
+    // The playlist says how much audio there should be, to check none is missing
+    let (connection, selection) = first_playlist(episode_id, &connections, hls_bandwidth).await?;
+    let audio_url = connection.href.clone();
+    let hls::MediaSelection {
+        variant, playlist, ..
+    } = selection;
+
+    log::debug!("m3u8 url: {}", audio_url);
+
+    if playlist.drm {
+        return Err(hls::HlsError::Drm.into());
+    }
+    let expected_secs: f64 = playlist.segments.iter().map(|s| s.duration).sum();
+    let bits_per_sec = match &audio_format {
+        Some(format) => Some(format.bit_rate() as u64),
+        None => variant.as_ref().map(|v| v.bandwidth),
+    };
+    let expected_size = bits_per_sec.map(|bits| (expected_secs * bits as f64 / 8.0) as u64);
+
+    // Packed AAC segments are fetched here, so a segment the CDN fails to serve can be
+    // fetched from another, whether they're passed through or given to ffmpeg. If they
+    // turn out not to be AAC, ffmpeg reads the playlist itself.
+    let segments = match hls::aac_segments(variant.as_ref(), &playlist) {
+        Some(segments) if hls::is_passthrough(&segments).await => Some(segments),
+        Some(_) => {
+            log::warn!(
+                "{}'s segments aren't ADTS, remuxing with ffmpeg",
+                episode_id
+            );
+            None
+        }
+        None => None,
+    };
+    let segments = segments.map(|segments| {
+        // the connections ranked after the one used, as those before it already failed
+        let fallbacks = hls::Fallbacks::new(
+            connections
+                .iter()
+                .skip_while(|c| !std::ptr::eq(**c, connection))
+                .skip(1)
+                .filter(|c| c.href.contains(".m3u8"))
+                .map(|c| c.href.clone())
+                .collect(),
+            variant.as_ref().map(|v| v.bandwidth),
+        );
+        let count = segments.len();
+        (
+            count,
+            hls::aac_segment_stream(segments, fallbacks, metadata.clone()).boxed_local(),
+        )
+    });
+
+    // only ffmpeg can cut a span out of the segments
+    let segments = match segments {
+        Some((count, segments)) if audio_format.is_none() && span.is_none() => {
+            log::debug!(
+                "Passing through {} AAC segments from the {} b/s HLS variant",
+                count,
+                variant.as_ref().map_or(0, |v| v.bandwidth)
+            );
+            let stream = segments.map(|r| r.map_err(|e| e.into()));
+            return Ok((stream.boxed_local(), metadata_rx, expected_size));
+        }
+        segments => segments.map(|(_, segments)| segments),
+    };
+
+    // ffmpeg is given the chosen variant, rather than left to choose from the master playlist
+    let audio_url = match &variant {
+        Some(variant) => {
+            log::info!(
+                "Streaming {} from the {} b/s HLS variant ({})",
+                episode_id,
+                variant.bandwidth,
+                variant.codecs.as_deref().unwrap_or("unknown codecs")
+            );
+            variant.uri.clone()
+        }
+        None => audio_url,
+    };
+    let input = match segments {
+        Some(segments) => hls::Input::Segments(segments),
+        None => hls::Input::Playlist(audio_url),
+    };
+    let stream = HlsStream::new(
+        input,
+        audio_format,
+        Some(expected_secs),
+        span,
+        Some(episode_id.to_string()),
+        metadata,
+    )?;
+    let stream = hls::started(stream).await?.map(|r| r.map_err(|e| e.into()));
+
+    Ok((stream.boxed_local(), metadata_rx, expected_size))
+}
+
+#[derive(Serialize)]
+pub struct MediaSummary {
+    pub kind: String,
+    pub variant: MediaVariant,
+    pub bitrate: String,
+    pub encoding: String,
+    pub connections: usize,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionSummary {
+    pub href: String,
+    pub protocol: String,
+    pub supplier: Option<String>,
+    pub priority: Option<u32>,
+    /// Connections tried before this one, which failed
+    pub failed_over: usize,
+}
+
+#[derive(Serialize)]
+pub struct PlaylistSummary {
+    pub master: bool,
+    /// Of the variant chosen
+    pub bandwidth: Option<u64>,
+    /// Of every variant, any of which can be pinned
+    pub available_bandwidths: Vec<u64>,
+    pub pinned_bandwidth: Option<u64>,
+    pub codecs: Option<String>,
+    pub segments: usize,
+    pub encrypted: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolutionMode {
+    Redirect,
+    AacPassthrough,
+    Remux,
+}
+
+/// A trace of each step taken to resolve an episode's audio
+#[derive(Default, Serialize)]
+pub struct Resolution {
+    pub pid: String,
+    pub media_url_status: Option<u16>,
+    pub media: Vec<MediaSummary>,
+    pub connection: Option<ConnectionSummary>,
+    pub playlist: Option<PlaylistSummary>,
+    pub mode: Option<ResolutionMode>,
+    pub error: Option<String>,
+}
+
+pub async fn resolve_episode(
+    episode_id: &bbc::Pid,
+    variants: &[MediaVariant],
+    hls_bandwidth: Option<u64>,
+) -> Resolution {
+    let mut resolution = Resolution {
+        pid: episode_id.to_string(),
+        ..Default::default()
+    };
+
+    if let Err(e) = trace_resolution(episode_id, variants, hls_bandwidth, &mut resolution).await {
+        resolution.error = Some(e.to_string());
+    }
+
+    resolution
+}
+
+async fn trace_resolution(
+    episode_id: &bbc::Pid,
+    variants: &[MediaVariant],
+    hls_bandwidth: Option<u64>,
+    resolution: &mut Resolution,
+) -> Result<()> {
+    let status = bbc::get_media_url_status(episode_id).await?;
+    resolution.media_url_status = Some(status);
+    if status == 200 {
+        resolution.mode = Some(ResolutionMode::Redirect);
+        return Ok(());
+    }
+
+    let media = bbc::get_media(episode_id).await?;
+    resolution.media = media
+        .media
+        .iter()
+        .map(|m| MediaSummary {
+            kind: m.kind.clone(),
+            variant: MediaVariant::of(m),
+            bitrate: m.bitrate.clone(),
+            encoding: m.encoding.clone(),
+            connections: m.connection.len(),
+        })
+        .collect();
+
+    let connections = select_connections(
+        &media,
+        variants,
+        PREFERRED_SUPPLIERS.get().map_or(&[], Vec::as_slice),
+    );
+    let (connection, selection) = first_playlist(episode_id, &connections, hls_bandwidth).await?;
+    resolution.connection = Some(ConnectionSummary {
+        href: connection.href.clone(),
+        protocol: connection.protocol.clone(),
+        supplier: connection.supplier.clone(),
+        priority: connection.priority(),
+        // those ranked ahead of it failed
+        failed_over: connections
+            .iter()
+            .take_while(|c| !std::ptr::eq(**c, connection))
+            .count(),
+    });
+
+    let hls::MediaSelection {
+        variant,
+        bandwidths,
+        playlist,
+    } = selection;
+    resolution.playlist = Some(PlaylistSummary {
+        master: variant.is_some(),
+        bandwidth: variant.as_ref().map(|v| v.bandwidth),
+        available_bandwidths: bandwidths,
+        pinned_bandwidth: hls_bandwidth,
+        codecs: variant.as_ref().and_then(|v| v.codecs.clone()),
+        segments: playlist.segments.len(),
+        encrypted: playlist.encrypted,
+    });
+
+    resolution.mode = match hls::aac_segments(variant.as_ref(), &playlist) {
+        Some(segments) if hls::is_passthrough(&segments).await => {
+            Some(ResolutionMode::AacPassthrough)
+        }
+        _ => Some(ResolutionMode::Remux),
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_episode_guid() {
+        let guid = episode_guid(&FeedOptions::default(), "p0btf00q");
+        assert_eq!(guid.value(), "p0btf00q");
+        assert!(!guid.is_permalink());
+
+        // unaffected by settings which change the episode's URL
+        let options = FeedOptions {
+            resolution: vec![ResolutionStrategy::Proxy],
+            link_show: true,
+            revalidate_links: true,
+            enclosure_prefix: Some("https://op3.dev/e/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(episode_guid(&options, "p0btf00q"), guid);
+
+        let options = FeedOptions {
+            guid_format: "urn:bbc:pid:{pid}".to_string(),
+            ..options
+        };
+        assert_eq!(
+            episode_guid(&options, "p0btf00q").value(),
+            "urn:bbc:pid:p0btf00q"
+        );
+    }
+
+    #[test]
+    fn test_bust_cache() {
+        let url = "https://proxy.example.com/episode/p0btf00q".to_string();
+        assert_eq!(bust_cache(url.clone(), &FeedOptions::default()), url);
+
+        let options = FeedOptions {
+            cache_buster: Some("1a2b3c4d".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            bust_cache(url.clone(), &options),
+            "https://proxy.example.com/episode/p0btf00q?v=1a2b3c4d"
+        );
+        assert_eq!(
+            bust_cache(url + "?show=p02pc9pj", &options),
+            "https://proxy.example.com/episode/p0btf00q?show=p02pc9pj&v=1a2b3c4d"
+        );
+    }
+
+    #[test]
+    fn test_is_visible() {
+        let now = Utc::now();
+        let published = Some((now - Duration::hours(2)).into());
+
+        assert!(is_visible(published, None, now));
+        assert!(is_visible(published, Some(2), now));
+        assert!(!is_visible(published, Some(3), now));
+        assert!(is_visible(None, Some(3), now));
+    }
+
+    fn episode(id: &str, pub_date: DateTime<Utc>) -> Episode {
+        Episode {
+            id: id.to_string(),
+            title: None,
+            summary: None,
+            url: String::new(),
+            file_size: 0,
+            content_type: String::new(),
+            duration: 0,
+            pub_date: Some(pub_date.into()),
+            image: None,
+            part: None,
+            available_from: None,
+            season: None,
+            number: None,
+            credits: Credits::default(),
+        }
+    }
+
+    #[test]
+    fn test_embargo_episodes() {
+        let now = Utc::now();
+        let live = now + Duration::hours(6);
+        let episodes = vec![
+            episode("p0000001", now - Duration::days(1)),
+            Episode {
+                available_from: Some((now - Duration::hours(1)).into()),
+                ..episode("p0000002", now - Duration::hours(2))
+            },
+            Episode {
+                available_from: Some(live.into()),
+                ..episode("p0000003", now - Duration::hours(1))
+            },
+        ];
+
+        let ids = |episodes: &[Episode]| episodes.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+        let embargoed = embargo_episodes(episodes.clone(), &FeedOptions::default(), now);
+        assert_eq!(ids(&embargoed), vec!["p0000001", "p0000002"]);
+
+        let options = FeedOptions {
+            upcoming: true,
+            ..Default::default()
+        };
+        let embargoed = embargo_episodes(episodes, &options, now);
+        assert_eq!(ids(&embargoed), vec!["p0000001", "p0000002", "p0000003"]);
+        // dated for when it goes live
+        assert_eq!(embargoed[2].pub_date, Some(live.into()));
+    }
+
+    #[test]
+    fn test_retain_episodes() {
+        let now = Utc::now();
+        let episode = |id: &str, days_ago| episode(id, now - Duration::days(days_ago));
+        let episodes = vec![episode("b", 2), episode("a", 1), episode("c", 10)];
+        let ids = |episodes: Vec<Episode>| episodes.into_iter().map(|e| e.id).collect::<Vec<_>>();
+
+        let options = FeedOptions {
+            max_age_days: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(retain_episodes(episodes.clone(), &options, now)),
+            ["b", "a"]
+        );
+
+        let options = FeedOptions {
+            max_items: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(retain_episodes(episodes.clone(), &options, now)),
+            ["a", "b"]
+        );
+
+        let options = FeedOptions {
+            since: Some(now - Duration::days(5)),
+            until: Some(now - Duration::days(1)),
+            ..Default::default()
+        };
+        assert_eq!(ids(retain_episodes(episodes, &options, now)), ["b"]);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let day = parse_date("2022-03-01").unwrap();
+        assert_eq!(day.to_rfc3339(), "2022-03-01T00:00:00+00:00");
+        assert_eq!(
+            parse_date("2022-03-01T12:30:00+01:00")
+                .unwrap()
+                .to_rfc3339(),
+            "2022-03-01T11:30:00+00:00"
+        );
+        assert_eq!(parse_date("last week"), None);
+    }
+
+    #[test]
+    fn test_page_episodes() {
+        let now = Utc::now();
+        let episodes = (0..5)
+            .map(|i| episode(&i.to_string(), now - Duration::days(i)))
+            .rev()
+            .collect::<Vec<_>>();
+        let ids = |page: Option<(Vec<Episode>, usize)>| {
+            page.map(|(episodes, pages)| {
+                (
+                    episodes.into_iter().map(|e| e.id).collect::<Vec<_>>(),
+                    pages,
+                )
+            })
+        };
+
+        // newest first
+        assert_eq!(
+            ids(page_episodes(episodes.clone(), 1, 2)),
+            Some((vec!["0".to_string(), "1".to_string()], 3))
+        );
+        assert_eq!(
+            ids(page_episodes(episodes.clone(), 3, 2)),
+            Some((vec!["4".to_string()], 3))
+        );
+        assert_eq!(ids(page_episodes(episodes.clone(), 4, 2)), None);
+        assert_eq!(ids(page_episodes(episodes.clone(), 0, 2)), None);
+        assert_eq!(ids(page_episodes(episodes, 1, 5)).unwrap().1, 1);
+        // an empty feed still has its first page
+        assert_eq!(ids(page_episodes(vec![], 1, 5)), Some((vec![], 1)));
+    }
+
+    #[test]
+    fn test_order_episodes() {
+        let now = Utc::now();
+        let mut episodes = vec![
+            episode("b", now - Duration::days(1)),
+            episode("a", now - Duration::days(2)),
+            episode("c", now),
+        ];
+        episodes[1].pub_date = None;
+        let ids = |episodes: Vec<Episode>| episodes.into_iter().map(|e| e.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(order_episodes(episodes.clone(), EpisodeOrder::Desc)),
+            ["c", "b", "a"]
+        );
+        // undated episodes still last
+        assert_eq!(
+            ids(order_episodes(episodes, EpisodeOrder::Asc)),
+            ["b", "c", "a"]
+        );
+    }
+
+    #[test]
+    fn test_feed_links() {
+        let rels = |links: Vec<Extension>| {
+            links
+                .into_iter()
+                .map(|l| format!("{} {}", l.attrs()["rel"], l.attrs()["href"]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rels(feed_links("https://example.com/show/p02pc9pj", 2, 3)),
+            [
+                "self https://example.com/show/p02pc9pj?page=2",
+                "first https://example.com/show/p02pc9pj",
+                "last https://example.com/show/p02pc9pj?page=3",
+                "previous https://example.com/show/p02pc9pj",
+                "next https://example.com/show/p02pc9pj?page=3",
+            ]
+        );
+        assert_eq!(
+            rels(feed_links("https://example.com/show/p02pc9pj", 1, 1)),
+            ["self https://example.com/show/p02pc9pj"]
+        );
+    }
+
+    #[test]
+    fn test_podcast_feed_chunks() {
+        let base_url = "https://example.com";
+        let pid = "b006qykl".parse::<bbc::Pid>().unwrap();
+        let options = FeedOptions::default();
+        let show = Show {
+            title: "In Our Time".to_string(),
+            subtitle: None,
+            author: "BBC Radio 4".to_string(),
+            link: "https://www.bbc.co.uk/programmes/b006qykl".to_string(),
+            image: None,
+            category: None,
+        };
+        let now = Utc::now();
+        let first_page = vec![
+            episode("m001b8ym", now - Duration::days(1)),
+            episode("m001b8yn", now - Duration::days(2)),
+        ];
+        let second_page = vec![episode("m001b8yp", now - Duration::days(3))];
+
+        let (head, foot) = split_channel(&rss_channel(&pid, show.clone(), &options), base_url);
+        let chunked = head
+            + &rss_items(base_url, &pid, &show, first_page.clone(), &options)
+            + &rss_items(base_url, &pid, &show, second_page.clone(), &options)
+            + &rss_tail(base_url, &pid, first_page[0].pub_date)
+            + &foot;
+        let chunked = rss::Channel::read_from(chunked.as_bytes()).unwrap();
+
+        let episodes = first_page.into_iter().chain(second_page).collect();
+        let whole = podcast_feed(base_url, &pid, &options, 1, (show, episodes, 1));
+        let whole = rss::Channel::read_from(whole.as_bytes()).unwrap();
+
+        assert_eq!(chunked.items(), whole.items());
+        assert_eq!(chunked.pub_date(), whole.pub_date());
+        assert_eq!(chunked.title(), whole.title());
+        assert_eq!(chunked.extensions(), whole.extensions());
+    }
+
+    #[test]
+    fn test_json_feed() {
+        let show = Show {
+            title: "In Our Time".to_string(),
+            subtitle: Some("Melvyn Bragg and guests discuss the history of ideas".to_string()),
+            author: "BBC Radio 4".to_string(),
+            link: "https://www.bbc.co.uk/programmes/b006qykl".to_string(),
+            image: None,
+            category: None,
+        };
+        let mut episodes = vec![
+            episode("m001b8ym", Utc::now()),
+            episode("m001b8yn", Utc::now()),
+        ];
+        episodes[0].title = Some("The Siege of Paris".to_string());
+        episodes[0].url = "https://example.com/episode/m001b8ym".to_string();
+        episodes[0].content_type = "audio/aac".to_string();
+        episodes[0].file_size = 1024;
+        episodes[1].part = Some(2);
+
+        let feed = serde_json::to_value(json_feed(
+            show,
+            episodes,
+            "https://example.com/show/b006qykl.json",
+            1,
+            2,
+            &FeedOptions::default(),
+        ))
+        .unwrap();
+        assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+        assert_eq!(feed["authors"][0]["name"], "BBC Radio 4");
+        assert_eq!(
+            feed["next_url"],
+            "https://example.com/show/b006qykl.json?page=2"
+        );
+        assert_eq!(feed["items"][0]["id"], "m001b8ym");
+        assert_eq!(feed["items"][0]["content_text"], "The Siege of Paris");
+        assert_eq!(
+            feed["items"][0]["attachments"][0],
+            serde_json::json!({
+                "url": "https://example.com/episode/m001b8ym",
+                "mime_type": "audio/aac",
+                "size_in_bytes": 1024,
+                "duration_in_seconds": 0,
+            })
+        );
+        assert_eq!(feed["items"][1]["id"], "m001b8yn#part-2");
+    }
+
+    #[test]
+    fn test_atom_feed() {
+        let show = Show {
+            title: "In Our Time".to_string(),
+            subtitle: None,
+            author: "BBC Radio 4".to_string(),
+            link: "https://www.bbc.co.uk/programmes/b006qykl".to_string(),
+            image: None,
+            category: None,
+        };
+        let mut episodes = vec![episode("m001b8ym", Utc::now())];
+        episodes[0].url = "https://example.com/episode/m001b8ym".to_string();
+        episodes[0].content_type = "audio/aac".to_string();
+        episodes[0].file_size = 1024;
+        episodes[0].part = Some(2);
+
+        let feed = atom_feed(
+            show,
+            &"b006qykl".parse().unwrap(),
+            episodes,
+            "https://example.com/show/b006qykl.atom",
+            1,
+            2,
+            &FeedOptions::default(),
+        );
+        assert!(feed.id().starts_with("urn:uuid:"));
+        let rels = feed
+            .links()
+            .iter()
+            .map(|l| format!("{} {}", l.rel(), l.href()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rels,
+            [
+                "alternate https://www.bbc.co.uk/programmes/b006qykl",
+                "self https://example.com/show/b006qykl.atom",
+                "first https://example.com/show/b006qykl.atom",
+                "last https://example.com/show/b006qykl.atom?page=2",
+                "next https://example.com/show/b006qykl.atom?page=2",
+            ]
+        );
+
+        let entry = &feed.entries()[0];
+        // untitled episodes take the show's title, as Atom entries need one
+        assert_eq!(entry.title().as_str(), "In Our Time");
+        assert_eq!(
+            entry.id(),
+            "https://www.bbc.co.uk/programmes/m001b8ym#part-2"
+        );
+        let enclosure = &entry.links()[1];
+        assert_eq!(enclosure.rel(), "enclosure");
+        assert_eq!(enclosure.href(), "https://example.com/episode/m001b8ym");
+        assert_eq!(enclosure.length(), Some("1024"));
+        assert!(feed
+            .to_string()
+            .contains("<feed xmlns=\"http://www.w3.org/2005/Atom\""));
+    }
+
+    #[test]
+    fn test_people() {
+        let person = |name: &str, id: Option<&str>| credits::Person {
+            name: name.to_string(),
+            id: id.map(|id| id.parse().unwrap()),
+        };
+        let credits = Credits {
+            presenters: vec![person("Andy Zaltzman", Some("p00b9f4b"))],
+            guests: vec![person("Ola Labib", None)],
+        };
+        let tags = people(&credits)
+            .into_iter()
+            .map(|t| {
+                format!(
+                    "{} {:?} {}",
+                    t.attrs()["role"],
+                    t.attrs().get("href"),
+                    t.value().unwrap()
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [
+                "host Some(\"https://www.bbc.co.uk/programmes/p00b9f4b\") Andy Zaltzman",
+                "guest None Ola Labib",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chapters() {
+        let segment = |segment_type: &str,
+                       start,
+                       titles: (&str, Option<&str>),
+                       performer: Option<&str>| bbc::SegmentItem {
+            id: format!("p{:07}", start),
+            segment_type: Some(segment_type.to_string()),
+            titles: bbc::Titles {
+                primary: titles.0.to_string(),
+                secondary: titles.1.map(str::to_string),
+            },
+            offset: Some(bbc::SegmentOffset {
+                start,
+                end: Some(start + 300),
+            }),
+            contributions: performer
+                .map(|name| bbc::Contribution {
+                    name: name.to_string(),
+                    role: Some("Performer".to_string()),
+                    id: None,
+                })
+                .into_iter()
+                .collect(),
+        };
+        let segments = [
+            segment(
+                "music",
+                60,
+                ("Johann Sebastian Bach", Some("Cello Suite No.1 in G major")),
+                Some("Yo-Yo Ma"),
+            ),
+            segment("speech", 0, ("Introduction", Some("Tom Service")), None),
+        ];
+        assert_eq!(
+            serde_json::to_string(&chapters(&segments)).unwrap(),
+            "{\"version\":\"1.2.0\",\"chapters\":[\
+             {\"startTime\":0,\"endTime\":300,\"title\":\"Introduction - Tom Service\"},\
+             {\"startTime\":60,\"endTime\":360,\"title\":\
+             \"Johann Sebastian Bach: Cello Suite No.1 in G major (Yo-Yo Ma)\"}]}"
+        );
+    }
+
+    #[test]
+    fn test_merge_episodes() {
+        let now = Utc::now();
+        let show = |ids: &[(&str, i64)]| {
+            ids.iter()
+                .map(|(id, days)| episode(id, now - Duration::days(*days)))
+                .collect::<Vec<_>>()
+        };
+        let merged = merge_episodes(
+            vec![show(&[("a1", 1), ("a3", 3)]), show(&[("b0", 0), ("b2", 2)])],
+            3,
+        );
+        let merged = merged
+            .into_iter()
+            .map(|(i, e)| (i, e.id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            merged,
+            [
+                (1, "b0".to_string()),
+                (0, "a1".to_string()),
+                (1, "b2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_stylesheet() {
+        assert_eq!(
+            with_stylesheet(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?><rss/>",
+                "https://example.com/feed.xsl"
+            ),
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<?xml-stylesheet type=\"text/xsl\" href=\"https://example.com/feed.xsl\"?><rss/>"
+        );
+    }
+
+    #[test]
+    fn test_select_connection() {
+        let media = |kind: &str, service: &str, bitrate: &str| {
+            serde_json::json!({
+                "kind": kind,
+                "service": service,
+                "type": "audio/mp4",
+                "bitrate": bitrate,
+                "encoding": "aac",
+                "connection": [{"protocol": "https", "transferFormat": "hls", "href": format!("{}.m3u8", service)}],
+            })
+        };
+        let media: bbc::MediaList = serde_json::from_value(serde_json::json!({
+            "media": [
+                media("audio", "audio-standard-low", "96"),
+                media("audio", "audio-standard", "128"),
+                media("audio-described", "audio-described", "320"),
+            ]
+        }))
+        .unwrap();
+        let href = |variants: &[MediaVariant]| {
+            select_connections(&media, variants, &[])
+                .first()
+                .map(|c| c.href.clone())
+        };
+
+        assert_eq!(
+            href(DEFAULT_MEDIA_VARIANTS).as_deref(),
+            Some("audio-standard.m3u8")
+        );
+        assert_eq!(
+            href(&[MediaVariant::Described, MediaVariant::Standard]).as_deref(),
+            Some("audio-described.m3u8")
+        );
+        assert_eq!(href(&[MediaVariant::Signed]), None);
+    }
+
+    #[test]
+    fn test_connection_rank() {
+        let connection = |protocol: &str, supplier: &str, priority: &str| {
+            serde_json::json!({
+                "protocol": protocol,
+                "transferFormat": "hls",
+                "href": format!("{}.m3u8", supplier),
+                "supplier": supplier,
+                "priority": priority,
+            })
+        };
+        let media: bbc::MediaList = serde_json::from_value(serde_json::json!({
+            "media": [{
+                "kind": "audio",
+                "service": "audio-standard",
+                "type": "audio/mp4",
+                "bitrate": "128",
+                "encoding": "aac",
+                "connection": [
+                    connection("http", "akamai_hls_open", "10"),
+                    connection("https", "akamai_hls_open_https", "20"),
+                    connection("https", "cloudfront_hls_https", "30"),
+                    connection("https", "af_akamai_nonuk_hls_https", "10"),
+                ],
+            }]
+        }))
+        .unwrap();
+        let suppliers = |preferred: &[&str]| {
+            let preferred = preferred.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            select_connections(&media, DEFAULT_MEDIA_VARIANTS, &preferred)
+                .into_iter()
+                .map(|c| c.supplier.clone().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // https, then by the BBC's priority
+        assert_eq!(
+            suppliers(&[]),
+            vec![
+                "af_akamai_nonuk_hls_https",
+                "akamai_hls_open_https",
+                "cloudfront_hls_https",
+                "akamai_hls_open"
+            ]
+        );
+        assert_eq!(
+            suppliers(&["cloudfront", "open"]),
+            vec![
+                "cloudfront_hls_https",
+                "akamai_hls_open_https",
+                "af_akamai_nonuk_hls_https",
+                "akamai_hls_open"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_uuid_v5() {
+        // the example in the namespace's documentation
+        assert_eq!(
+            uuid_v5("mp3s.nashownotes.com/pc20rss.xml"),
+            "917393e3-1b1e-5cef-ace4-edaa54e1f810"
+        );
+    }
+
+    #[test]
+    fn test_itunes_category() {
+        let category = |ids: &[&str]| {
+            let categories = ids
+                .iter()
+                .map(|id| bbc::Category {
+                    id: id.to_string(),
+                    title: None,
+                })
+                .collect::<Vec<_>>();
+            itunes_category(&categories).map(|c| {
+                (
+                    c.text().to_string(),
+                    c.subcategory().map(|s| s.text().to_string()),
+                )
+            })
+        };
+        let named =
+            |text: &str, sub: Option<&str>| Some((text.to_string(), sub.map(str::to_string)));
+
+        assert_eq!(
+            category(&["comedy/standup"]),
+            named("Comedy", Some("Stand-Up"))
+        );
+        assert_eq!(category(&["comedy/satire"]), named("Comedy", None));
+        assert_eq!(category(&["drama/crime"]), named("Fiction", Some("Drama")));
+        assert_eq!(category(&["factual/history"]), named("History", None));
+        assert_eq!(category(&["history"]), named("History", None));
+        assert_eq!(
+            category(&["podcasts", "sport-football"]),
+            named("Sports", Some("Soccer"))
+        );
+        assert_eq!(category(&["podcasts"]), None);
+        assert_eq!(category(&[]), None);
+    }
+
+    #[test]
+    fn test_retain_listed() {
+        let example = std::fs::read_to_string("./payload_examples/container.json").unwrap();
+        let example: bbc::ContainerResponse = serde_json::from_str(&example).unwrap();
+        let mut data = example
+            .data
+            .into_iter()
+            .find_map(|d| d.into_list())
+            .unwrap()
+            .data;
+        data.reverse();
+        let now = Utc::now();
+        let ids =
+            |data: &[bbc::ContainerListData]| data.iter().map(|d| d.id.clone()).collect::<Vec<_>>();
+
+        assert_eq!(
+            retain_listed(data.clone(), &FeedOptions::default(), now).len(),
+            30
+        );
+
+        // the newest, though listed last
+        let options = FeedOptions {
+            max_items: Some(2),
+            ..Default::default()
+        };
+        let newest = ids(&data[28..]).into_iter().rev().collect::<Vec<_>>();
+        assert_eq!(ids(&retain_listed(data.clone(), &options, now)), newest);
+
+        let options = FeedOptions {
+            since: parse_date("2022-03-25"),
+            until: parse_date("2022-04-08"),
+            ..Default::default()
+        };
+        assert_eq!(retain_listed(data.clone(), &options, now).len(), 2);
+
+        // the newest is still within the show's delay the day after it's released
+        let next_day = parse_date("2022-04-09T12:00:00Z").unwrap();
+        let options = FeedOptions {
+            delay_hours: Some(48),
+            ..Default::default()
+        };
+        assert_eq!(retain_listed(data, &options, next_day).len(), 29);
+    }
+
+    #[test]
+    fn test_ownership() {
+        assert!(ownership(None, None).is_empty());
+
+        let tags = ownership(Some("me@example.com"), Some("abc123"));
+        let locked = &tags["locked"][0];
+        assert_eq!(locked.attrs()["owner"], "me@example.com");
+        assert_eq!(locked.value(), Some("yes"));
+        let txt = &tags["txt"][0];
+        assert_eq!(txt.attrs()["purpose"], "verify");
+        assert_eq!(txt.value(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_dedupe_episodes() {
+        let now = Utc::now();
+        let episodes = vec![
+            episode("repeat", now),
+            episode("other", now - Duration::days(1)),
+            episode("first", now - Duration::days(2)),
+        ];
+        let original = |id: &str| match id {
+            "repeat" => Some("first".to_string()),
+            _ => None,
+        };
+        let ids = dedupe_episodes(episodes, original)
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["other", "first"]);
+
+        // across shows, as in a combined feed
+        let shows = vec![
+            (1, episode("repeat", now)),
+            (0, episode("first", now - Duration::days(2))),
+        ];
+        let kept = dedupe_by(shows, |(_, e)| e, original);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].0, 0);
+
+        // an undated repeat isn't taken as the first
+        let mut undated = episode("repeat", now);
+        undated.pub_date = None;
+        let episodes = vec![undated, episode("first", now - Duration::days(2))];
+        let ids = dedupe_episodes(episodes, original)
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["first"]);
+    }
+
+    #[test]
+    fn test_funding() {
+        let tags = funding(&[Funding {
+            url: "https://example.com/donate".into(),
+            text: "Help pay for hosting".into(),
+        }]);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].name(), "podcast:funding");
+        assert_eq!(tags[0].attrs()["url"], "https://example.com/donate");
+        assert_eq!(tags[0].value(), Some("Help pay for hosting"));
+    }
 }
@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bbc::Pid,
+    config::{Config, ShowConfig},
+    failures::{Failure, FailureTracker},
+    fingerprint,
+};
+
+/// Bumped whenever the bundle changes in a way older proxies can't import
+pub const BUNDLE_VERSION: u32 = 1;
+
+const ENV_PREFIX: &str = "SOUNDS_PROXY_";
+
+/// Everything needed to move the proxy to another host
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub subscriptions: Vec<Pid>,
+    /// Per-show settings, by show id
+    pub shows: BTreeMap<String, ShowConfig>,
+    /// SHA-1 of the admin token, to check the new host has the same one without revealing it
+    pub admin_token_sha1: Option<String>,
+    /// Size of each object in the S3 bucket, by key
+    pub archive: BTreeMap<String, u64>,
+    pub failures: Vec<Failure>,
+    pub fingerprints: HashMap<String, fingerprint::Entry>,
+}
+
+/// What importing a bundle did, and what's left to do by hand
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub failures_restored: usize,
+    pub fingerprints_restored: usize,
+    /// False if this proxy's admin token isn't the one the bundle was exported with
+    pub admin_token_matches: bool,
+    /// Archived objects which aren't in this proxy's bucket, and need copying across
+    pub missing_archive: Vec<String>,
+    /// Environment variables setting the bundle's subscriptions and per-show settings,
+    /// which (as settings come from the environment) have to be set on this host
+    pub settings: Vec<String>,
+}
+
+pub fn token_hash(token: &str) -> String {
+    Sha1::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Bundles up the proxy's state, given the objects in its bucket
+pub fn export(config: &Config, failures: &FailureTracker, archive: HashMap<String, u64>) -> Bundle {
+    Bundle {
+        version: BUNDLE_VERSION,
+        exported_at: Utc::now(),
+        subscriptions: config.subscriptions.clone().unwrap_or_default(),
+        shows: config
+            .shows
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+        admin_token_sha1: config.admin_token.as_deref().map(token_hash),
+        archive: archive.into_iter().collect(),
+        failures: failures.list(),
+        fingerprints: fingerprint::all(),
+    }
+}
+
+/// A setting's value the way it's given in an environment variable, e.g. `[file_url, proxy]`
+fn env_value(value: &Value, nested: bool) -> Option<String> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) if nested => format!("{:?}", s),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .filter_map(|v| env_value(v, true))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Object(fields) => format!(
+            "{{{}}}",
+            fields
+                .iter()
+                .filter_map(|(k, v)| Some(format!("{}={}", k, env_value(v, true)?)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    })
+}
+
+/// Environment variables for the bundle's subscriptions and per-show settings
+pub fn settings(bundle: &Bundle) -> Vec<String> {
+    let mut settings = Vec::new();
+    if !bundle.subscriptions.is_empty() {
+        let subscriptions = serde_json::to_value(&bundle.subscriptions).unwrap();
+        if let Some(value) = env_value(&subscriptions, false) {
+            settings.push(format!("{}SUBSCRIPTIONS={}", ENV_PREFIX, value));
+        }
+    }
+    for (show, show_config) in &bundle.shows {
+        if let Value::Object(fields) = serde_json::to_value(show_config).unwrap() {
+            for (field, value) in fields {
+                if let Some(value) = env_value(&value, false) {
+                    settings.push(format!(
+                        "{}SHOWS__{}__{}={}",
+                        ENV_PREFIX,
+                        show.to_ascii_uppercase(),
+                        field.to_ascii_uppercase(),
+                        value
+                    ));
+                }
+            }
+        }
+    }
+    settings
+}
+
+/// Restores a bundle's state into this proxy, given the objects in its bucket
+pub fn import(
+    bundle: Bundle,
+    config: &Config,
+    failures: &FailureTracker,
+    archive: &HashMap<String, u64>,
+) -> ImportReport {
+    let settings = settings(&bundle);
+    ImportReport {
+        failures_restored: failures.restore(bundle.failures),
+        fingerprints_restored: fingerprint::restore(bundle.fingerprints),
+        admin_token_matches: config.admin_token.as_deref().map(token_hash)
+            == bundle.admin_token_sha1,
+        missing_archive: bundle
+            .archive
+            .into_keys()
+            .filter(|key| !archive.contains_key(key))
+            .collect(),
+        settings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::sounds_proxy::ResolutionStrategy;
+
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let bundle = Bundle {
+            version: BUNDLE_VERSION,
+            exported_at: Utc::now(),
+            subscriptions: vec!["p02pc9pj".parse().unwrap(), "b006qykl".parse().unwrap()],
+            shows: BTreeMap::from([(
+                "p02pc9pj".to_string(),
+                ShowConfig {
+                    resolution: Some(vec![ResolutionStrategy::FileUrl, ResolutionStrategy::Proxy]),
+                    language: Some("en-gb".to_string()),
+                    ..Default::default()
+                },
+            )]),
+            admin_token_sha1: None,
+            archive: BTreeMap::new(),
+            failures: Vec::new(),
+            fingerprints: HashMap::new(),
+        };
+        assert_eq!(
+            settings(&bundle),
+            vec![
+                "SOUNDS_PROXY_SUBSCRIPTIONS=[\"p02pc9pj\", \"b006qykl\"]",
+                "SOUNDS_PROXY_SHOWS__P02PC9PJ__LANGUAGE=en-gb",
+                "SOUNDS_PROXY_SHOWS__P02PC9PJ__RESOLUTION=[\"file_url\", \"proxy\"]",
+            ]
+        );
+
+        assert_eq!(
+            token_hash("secret"),
+            "e5e9fa1ba31ecd1ae84f75caaa474f3a663f05f4"
+        );
+    }
+}
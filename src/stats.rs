@@ -0,0 +1,333 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bbc::{BbcResponseError, Pid},
+    hls::HlsError,
+};
+
+// Stands in for the show when an episode is requested without one
+const UNKNOWN_SHOW: &str = "unknown";
+// Stands in for shows which aren't configured, so clients can't add labels at will
+const OTHER_SHOW: &str = "other";
+
+/// How often counts are saved to the stats file. Counts since the last save are lost if the
+/// proxy is killed, but not when it shuts down cleanly.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why the proxy couldn't serve an episode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// Not available where the proxy is, e.g. outside the UK
+    Geo,
+    /// No longer (or not yet) available
+    Expired,
+    /// Only available encrypted in a way which can't be remuxed
+    Drm,
+    /// Available, but in a form the proxy doesn't understand
+    Format,
+    /// The BBC failed or refused to answer
+    Upstream,
+    Other,
+}
+
+impl FailureReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureReason::Geo => "geo",
+            FailureReason::Expired => "expired",
+            FailureReason::Drm => "drm",
+            FailureReason::Format => "format",
+            FailureReason::Upstream => "upstream",
+            FailureReason::Other => "other",
+        }
+    }
+
+    /// Why an error happened, or None if it isn't the episode failing to resolve
+    /// (e.g. the proxy being too busy, or a bad request)
+    pub fn of(err: &BbcResponseError) -> Option<Self> {
+        Some(match err {
+            BbcResponseError::BadRequest
+            | BbcResponseError::Forbidden
+            | BbcResponseError::Overloaded
+            | BbcResponseError::NotImplemented
+            | BbcResponseError::NotAcceptable => return None,
+            BbcResponseError::Unavailable(result) if result == "geolocation" => FailureReason::Geo,
+            BbcResponseError::Unavailable(_) | BbcResponseError::NotFound => FailureReason::Expired,
+            BbcResponseError::HlsDownloadError(HlsError::Drm) => FailureReason::Drm,
+            BbcResponseError::FormatError
+            | BbcResponseError::UnsupportedMedia(_, _)
+            | BbcResponseError::HlsDownloadError(
+                HlsError::NoAudio | HlsError::UnsupportedCodec | HlsError::PlaylistError,
+            ) => FailureReason::Format,
+            BbcResponseError::FetchError(_)
+            | BbcResponseError::ServerResponseError(_)
+            | BbcResponseError::HlsDownloadError(HlsError::FetchError(_)) => {
+                FailureReason::Upstream
+            }
+            _ => FailureReason::Other,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShowStats {
+    /// Episodes served or redirected to
+    pub resolved: u64,
+    pub failed: BTreeMap<FailureReason, u64>,
+}
+
+impl ShowStats {
+    fn add(&mut self, other: ShowStats) {
+        self.resolved += other.resolved;
+        for (reason, count) in other.failed {
+            *self.failed.entry(reason).or_default() += count;
+        }
+    }
+}
+
+/// The counts as kept in the stats file
+#[derive(Default, Serialize, Deserialize)]
+struct SavedStats {
+    bytes_served: u64,
+    shows: BTreeMap<String, ShowStats>,
+}
+
+/// Counts episode resolutions by show, and failures by reason, so it's clear whether
+/// episodes are failing because e.g. a UK egress has stopped working or they've expired
+#[derive(Default)]
+pub struct ResolutionStats {
+    shows: Mutex<BTreeMap<String, ShowStats>>,
+    /// Bytes of audio streamed through the proxy
+    bytes_served: AtomicU64,
+    /// File the counts are kept in, so they outlast restarts
+    file: Option<PathBuf>,
+    /// Shows counted by name, the rest being counted together
+    labelled: HashSet<String>,
+    /// Held while saving, so saves don't overlap
+    saving: tokio::sync::Mutex<()>,
+    /// Set once another process has taken over saving the counts
+    handed_over: AtomicBool,
+}
+
+impl ResolutionStats {
+    /// Counts which are only kept until the proxy stops, naming the given shows
+    pub fn new(labelled: HashSet<String>) -> Self {
+        ResolutionStats {
+            labelled,
+            ..Default::default()
+        }
+    }
+
+    /// Counts carrying on from those saved to the file, which they're saved to from now on,
+    /// naming the given shows
+    pub fn load(path: &Path, labelled: HashSet<String>) -> Self {
+        let saved = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid stats file: {}", e);
+                SavedStats::default()
+            }),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to read stats file: {}", e);
+                }
+                SavedStats::default()
+            }
+        };
+        let stats = ResolutionStats {
+            bytes_served: AtomicU64::new(saved.bytes_served),
+            file: Some(path.to_path_buf()),
+            labelled,
+            ..Default::default()
+        };
+        // including shows which have stopped being configured since
+        {
+            let mut shows = stats.shows.lock().unwrap();
+            for (show, counts) in saved.shows {
+                shows.entry(stats.label(&show)).or_default().add(counts);
+            }
+        }
+        stats
+    }
+
+    fn label(&self, show: &str) -> String {
+        match show {
+            UNKNOWN_SHOW => UNKNOWN_SHOW,
+            show if self.labelled.contains(show) => show,
+            _ => OTHER_SHOW,
+        }
+        .to_string()
+    }
+
+    fn show_key(&self, show: Option<&Pid>) -> String {
+        self.label(show.map_or(UNKNOWN_SHOW, |s| s.as_str()))
+    }
+
+    /// Saves the counts to the stats file, if there is one and this process is still the
+    /// one saving to it
+    pub async fn save(&self) {
+        let path = match &self.file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let _saving = self.saving.lock().await;
+        if self.handed_over.load(Ordering::Acquire) {
+            return;
+        }
+        let saved = SavedStats {
+            bytes_served: self.bytes_served(),
+            shows: self.shows(),
+        };
+        let bytes = serde_json::to_vec(&saved).unwrap();
+        let result = tokio::task::spawn_blocking(move || {
+            // replaced in one go, so a crash never leaves half a file
+            let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+            std::fs::write(&temp, bytes).and_then(|_| std::fs::rename(&temp, &path))
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+        if let Err(e) = result {
+            log::warn!("Failed to save stats: {}", e);
+        }
+    }
+
+    /// Saves the counts for a successor to carry on from, then leaves saving to it, so
+    /// two processes don't overwrite each other's counts. Anything counted from now on,
+    /// e.g. while streams drain, isn't saved.
+    pub async fn hand_over(&self) {
+        self.save().await;
+        self.handed_over.store(true, Ordering::Release);
+    }
+
+    /// Carries on saving, when a successor failed to start
+    pub fn take_back(&self) {
+        self.handed_over.store(false, Ordering::Release);
+    }
+
+    /// Whether there's a stats file to save to
+    pub fn is_persistent(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Counts an episode served or redirected to
+    pub fn record_resolved(&self, show: Option<&Pid>) {
+        let mut shows = self.shows.lock().unwrap();
+        shows.entry(self.show_key(show)).or_default().resolved += 1;
+    }
+
+    /// Counts an episode which couldn't be, if the error says why
+    pub fn record_failed(&self, show: Option<&Pid>, err: &BbcResponseError) {
+        if let Some(reason) = FailureReason::of(err) {
+            let mut shows = self.shows.lock().unwrap();
+            let stats = shows.entry(self.show_key(show)).or_default();
+            *stats.failed.entry(reason).or_default() += 1;
+        }
+    }
+
+    /// Counts an episode request's outcome
+    pub fn record<T>(&self, show: Option<&Pid>, result: &Result<T, BbcResponseError>) {
+        match result {
+            Ok(_) => self.record_resolved(show),
+            Err(e) => self.record_failed(show, e),
+        }
+    }
+
+    /// Counts for each show
+    pub fn shows(&self) -> BTreeMap<String, ShowStats> {
+        self.shows.lock().unwrap().clone()
+    }
+
+    pub fn bytes_served(&self) -> u64 {
+        self.bytes_served.load(Ordering::Relaxed)
+    }
+
+    /// Counts audio as it's served
+    pub fn record_served(&self, bytes: usize) {
+        self.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let stats = ResolutionStats::new(HashSet::from(["p02pc9pj".to_string()]));
+        let show: Pid = "p02pc9pj".parse().unwrap();
+        let unconfigured: Pid = "b006qykl".parse().unwrap();
+
+        stats.record(Some(&show), &Ok(()));
+        stats.record::<()>(
+            Some(&show),
+            &Err(BbcResponseError::Unavailable("geolocation".into())),
+        );
+        stats.record::<()>(
+            Some(&show),
+            &Err(BbcResponseError::Unavailable("geolocation".into())),
+        );
+        stats.record::<()>(None, &Err(BbcResponseError::NotFound));
+        // the proxy being busy isn't the episode's fault
+        stats.record::<()>(None, &Err(BbcResponseError::Overloaded));
+        stats.record(Some(&unconfigured), &Ok(()));
+
+        let shows = stats.shows();
+        assert_eq!(shows["p02pc9pj"].resolved, 1);
+        assert_eq!(shows["p02pc9pj"].failed[&FailureReason::Geo], 2);
+        assert_eq!(shows["unknown"].resolved, 0);
+        assert_eq!(
+            shows["unknown"].failed,
+            BTreeMap::from([(FailureReason::Expired, 1)])
+        );
+        assert_eq!(shows["other"].resolved, 1);
+        assert!(!shows.contains_key("b006qykl"));
+    }
+
+    #[tokio::test]
+    async fn test_load() {
+        let path =
+            std::env::temp_dir().join(format!("sounds-proxy-stats-{}.json", std::process::id()));
+        let show: Pid = "p02pc9pj".parse().unwrap();
+        let labelled = || HashSet::from(["p02pc9pj".to_string()]);
+
+        let stats = ResolutionStats::load(&path, labelled());
+        assert_eq!(stats.bytes_served(), 0);
+        stats.record(Some(&show), &Ok(()));
+        stats.record::<()>(Some(&show), &Err(BbcResponseError::NotFound));
+        stats.record_served(1024);
+        stats.save().await;
+
+        // a restart carries on counting
+        let restored = ResolutionStats::load(&path, labelled());
+        assert_eq!(restored.bytes_served(), 1024);
+        assert_eq!(restored.shows(), stats.shows());
+        restored.record(Some(&show), &Ok(()));
+        assert_eq!(restored.shows()["p02pc9pj"].resolved, 2);
+
+        // a successor saves from now on
+        restored.hand_over().await;
+        restored.record_served(1024);
+        restored.save().await;
+        assert_eq!(
+            ResolutionStats::load(&path, labelled()).bytes_served(),
+            1024
+        );
+
+        // a show which is no longer configured is counted with the others
+        let unconfigured = ResolutionStats::load(&path, HashSet::new());
+        assert_eq!(unconfigured.shows()["other"].resolved, 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
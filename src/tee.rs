@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::sync::Notify;
+
+use crate::limits::{Reservation, BUFFERS};
+
+/// Bytes kept from the start of an upload, so listeners arriving during it can follow it
+/// from the start. Once an upload outgrows this, no one else can follow it.
+pub const REPLAY_BYTES: usize = 8 * 1024 * 1024;
+
+/// How far the slowest follower may fall behind before the upload waits for it
+pub const MAX_LAG_BYTES: usize = 16 * 1024 * 1024;
+
+// How long an upload waits for followers which have fallen behind before leaving them
+const LAG_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Inner {
+    /// Data not yet read by every follower
+    chunks: VecDeque<Bytes>,
+    /// Position in the whole upload of the first chunk kept
+    first: usize,
+    /// Bytes in `chunks`
+    bytes: usize,
+    /// Position of each follower's next chunk
+    followers: HashMap<u64, usize>,
+    next_follower: u64,
+    /// The upload's own follower, which is waited for however far behind it falls
+    sink: Option<u64>,
+    /// Set once the start has been dropped, so no one else can follow
+    closed: bool,
+    /// Set once the source stream ends, true if it completed successfully
+    finished: Option<bool>,
+    /// Counts the chunks kept towards the buffer limit
+    reservation: Option<Reservation<'static>>,
+}
+
+impl Inner {
+    fn end(&self) -> usize {
+        self.first + self.chunks.len()
+    }
+
+    /// Bytes from a position to the end of what's been received
+    fn bytes_after(&self, position: usize) -> usize {
+        self.chunks
+            .iter()
+            .skip(position - self.first)
+            .map(Bytes::len)
+            .sum()
+    }
+
+    /// Drops chunks every follower has read, once the start is too big to keep
+    fn trim(&mut self) {
+        if !self.closed && self.bytes <= REPLAY_BYTES {
+            return;
+        }
+        self.closed = true;
+        let keep_from = self
+            .followers
+            .values()
+            .copied()
+            .min()
+            .unwrap_or_else(|| self.end());
+        let mut released = 0;
+        while self.first < keep_from {
+            if let Some(chunk) = self.chunks.pop_front() {
+                released += chunk.len();
+            }
+            self.first += 1;
+        }
+        self.bytes -= released;
+        if let Some(reservation) = &mut self.reservation {
+            reservation.shrink(released);
+        }
+    }
+}
+
+/// Keeps a copy of an in-progress upload so that other listeners
+/// can be served the same data instead of starting another upload.
+/// Only what followers haven't read yet is kept, once past the start.
+/// The upload reads it too, as its sink, so listeners can get ahead of a slow upload.
+#[derive(Default)]
+pub struct TeeBuffer {
+    inner: Mutex<Inner>,
+    notify: Notify,
+}
+
+/// A listener following an upload, which stops holding the upload back once dropped
+struct Follower {
+    buffer: Arc<TeeBuffer>,
+    id: u64,
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        let mut inner = self.buffer.inner.lock().unwrap();
+        inner.followers.remove(&self.id);
+        inner.trim();
+        drop(inner);
+        self.buffer.notify.notify_waiters();
+    }
+}
+
+impl TeeBuffer {
+    /// Adds a chunk for followers. Waits while one is too far behind, so the copy stays
+    /// bounded, and leaves behind any still that far behind after a while.
+    pub async fn push(&self, chunk: Bytes) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .reservation
+                .get_or_insert_with(|| BUFFERS.track(0))
+                .grow(chunk.len());
+            inner.bytes += chunk.len();
+            inner.chunks.push_back(chunk);
+            inner.trim();
+        }
+        self.notify.notify_waiters();
+
+        if tokio::time::timeout(LAG_TIMEOUT, self.caught_up())
+            .await
+            .is_err()
+        {
+            {
+                let mut inner = self.inner.lock().unwrap();
+                let behind = inner
+                    .followers
+                    .iter()
+                    .filter(|(id, _)| Some(**id) != inner.sink)
+                    .filter(|(_, position)| inner.bytes_after(**position) > MAX_LAG_BYTES)
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                if !behind.is_empty() {
+                    log::warn!("Leaving {} listeners behind an upload", behind.len());
+                }
+                for id in behind {
+                    inner.followers.remove(&id);
+                }
+                inner.trim();
+            }
+            self.notify.notify_waiters();
+            // the upload itself is never left behind
+            self.caught_up().await;
+        }
+    }
+
+    /// Waits until no follower is too far behind
+    async fn caught_up(&self) {
+        loop {
+            // register before checking so a read in between isn't missed
+            let notified = self.notify.notified();
+            if self.inner.lock().unwrap().bytes <= MAX_LAG_BYTES {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Ends the data. Only the first call counts, so an upload failing after its source
+    /// has ended doesn't cut short listeners who have everything.
+    pub fn finish(&self, success: bool) {
+        self.inner.lock().unwrap().finished.get_or_insert(success);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.inner.lock().unwrap().finished.is_some()
+    }
+
+    /// Replays the data received so far, then follows new data until the source ends.
+    /// None if the start of the upload is no longer kept.
+    pub fn follow(self: Arc<Self>) -> Option<impl Stream<Item = Result<Bytes, std::io::Error>>> {
+        self.follower(false)
+    }
+
+    /// Follows the data as the upload, which the source waits for rather than leaving
+    /// behind. Must be called before any data is pushed.
+    pub fn sink(self: Arc<Self>) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+        let sink = self.follower(true);
+        futures::stream::iter(sink).flatten()
+    }
+
+    fn follower(
+        self: Arc<Self>,
+        sink: bool,
+    ) -> Option<impl Stream<Item = Result<Bytes, std::io::Error>>> {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return None;
+            }
+            let id = inner.next_follower;
+            inner.next_follower += 1;
+            let first = inner.first;
+            inner.followers.insert(id, first);
+            if sink {
+                inner.sink = Some(id);
+            }
+            id
+        };
+        let follower = Follower { buffer: self, id };
+
+        Some(futures::stream::unfold(
+            Some(follower),
+            |state| async move {
+                let follower = state?;
+                loop {
+                    // register before checking so a push in between isn't missed
+                    let notified = follower.buffer.notify.notified();
+                    let next = {
+                        let mut inner = follower.buffer.inner.lock().unwrap();
+                        let position = match inner.followers.get(&follower.id) {
+                            Some(position) => *position,
+                            None => {
+                                return Some((
+                                    Err(std::io::Error::new(
+                                        std::io::ErrorKind::TimedOut,
+                                        "fell too far behind the upload",
+                                    )),
+                                    None,
+                                ))
+                            }
+                        };
+                        let chunk = inner.chunks.get(position - inner.first).cloned();
+                        match (chunk, inner.finished) {
+                            (Some(chunk), _) => {
+                                inner.followers.insert(follower.id, position + 1);
+                                inner.trim();
+                                Some(Ok(chunk))
+                            }
+                            (None, Some(true)) => return None,
+                            (None, Some(false)) => Some(Err(std::io::Error::new(
+                                std::io::ErrorKind::Other,
+                                "upload failed",
+                            ))),
+                            (None, None) => None,
+                        }
+                    };
+                    match next {
+                        Some(Ok(chunk)) => {
+                            drop(notified);
+                            // the upload may be waiting for this follower
+                            follower.buffer.notify.notify_waiters();
+                            return Some((Ok(chunk), Some(follower)));
+                        }
+                        Some(Err(e)) => return Some((Err(e), None)),
+                        None => notified.await,
+                    }
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replays_and_follows() {
+        let buffer = Arc::new(TeeBuffer::default());
+        buffer.push(Bytes::from_static(b"a")).await;
+
+        let reader = buffer.clone().follow().unwrap();
+
+        buffer.push(Bytes::from_static(b"b")).await;
+        buffer.finish(true);
+
+        let chunks = reader.map(|c| c.unwrap()).collect::<Vec<_>>().await;
+        assert_eq!(
+            chunks,
+            vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sink() {
+        let buffer = Arc::new(TeeBuffer::default());
+        let sink = buffer.clone().sink();
+
+        buffer.push(Bytes::from_static(b"a")).await;
+        buffer.finish(true);
+        // the upload failing afterwards doesn't spoil what listeners have
+        buffer.finish(false);
+        let reader = buffer.clone().follow().unwrap();
+
+        let chunks = sink.map(|c| c.unwrap()).collect::<Vec<_>>().await;
+        assert_eq!(chunks, vec![Bytes::from_static(b"a")]);
+        assert_eq!(reader.collect::<Vec<_>>().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_data() {
+        let buffer = Arc::new(TeeBuffer::default());
+        let reader = buffer.clone().follow().unwrap().collect::<Vec<_>>();
+
+        let writer = async {
+            tokio::task::yield_now().await;
+            buffer.push(Bytes::from_static(b"a")).await;
+            tokio::task::yield_now().await;
+            buffer.finish(false);
+        };
+
+        let (chunks, _) = futures::join!(reader, writer);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert!(chunks[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bounded() {
+        let chunk = Bytes::from(vec![0; 1024 * 1024]);
+        let buffer = Arc::new(TeeBuffer::default());
+        let mut reader = Box::pin(buffer.clone().follow().unwrap());
+
+        // the start is kept for anyone else who wants to follow
+        for _ in 0..REPLAY_BYTES / chunk.len() {
+            buffer.push(chunk.clone()).await;
+        }
+        assert!(buffer.clone().follow().is_some());
+
+        // but once it's too big, only what the follower hasn't read yet is kept
+        buffer.push(chunk.clone()).await;
+        assert!(buffer.clone().follow().is_none());
+        reader.next().await.unwrap().unwrap();
+        assert_eq!(buffer.inner.lock().unwrap().bytes, REPLAY_BYTES);
+
+        // and the upload waits for the follower once it's too far behind
+        for _ in 0..(MAX_LAG_BYTES - REPLAY_BYTES) / chunk.len() {
+            buffer.push(chunk.clone()).await;
+        }
+        let mut push = Box::pin(buffer.push(chunk.clone()));
+        assert!(futures::poll!(push.as_mut()).is_pending());
+        reader.next().await.unwrap().unwrap();
+        push.await;
+
+        // until the follower goes away
+        drop(reader);
+        assert_eq!(buffer.inner.lock().unwrap().bytes, 0);
+    }
+}
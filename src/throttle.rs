@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveTime, Timelike};
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+
+/// Time of day queued uploads may run in, e.g. `02:00-06:00`. It may span midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UploadWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TryFrom<String> for UploadWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid upload window {}, expected e.g. 02:00-06:00", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(UploadWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl UploadWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// How long until the window next opens, zero if it's open
+    fn until_open(&self, time: NaiveTime) -> Duration {
+        if self.contains(time) {
+            return Duration::ZERO;
+        }
+        let day = 24 * 60 * 60;
+        let secs =
+            (self.start.num_seconds_from_midnight() + day - time.num_seconds_from_midnight()) % day;
+        Duration::from_secs(secs.into())
+    }
+
+    /// Waits until the window is open, by the server's local time
+    pub async fn wait(&self) {
+        let wait = self.until_open(chrono::Local::now().time());
+        if !wait.is_zero() {
+            log::info!("Waiting {}s for the upload window to open", wait.as_secs());
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Slows a stream of bytes down to an average of `bytes_per_sec`
+pub fn throttle<S, B, E>(stream: S, bytes_per_sec: u64) -> impl Stream<Item = Result<B, E>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+{
+    let start = Instant::now();
+    let mut sent = 0;
+    stream.then(move |item| {
+        if let Ok(bytes) = &item {
+            sent += bytes.as_ref().len() as u64;
+        }
+        // hold each chunk back until the average rate allows it
+        let due = Duration::from_secs_f64(sent as f64 / bytes_per_sec.max(1) as f64);
+        let delay = due.saturating_sub(start.elapsed());
+        async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            item
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_upload_window() {
+        let time = |t| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+
+        let night = UploadWindow::try_from("02:00-06:00".to_string()).unwrap();
+        assert!(night.contains(time("02:00")));
+        assert!(night.contains(time("05:59")));
+        assert!(!night.contains(time("06:00")));
+        assert_eq!(night.until_open(time("03:00")), Duration::ZERO);
+        assert_eq!(night.until_open(time("01:00")), Duration::from_secs(3600));
+        assert_eq!(
+            night.until_open(time("23:00")),
+            Duration::from_secs(3 * 3600)
+        );
+
+        let overnight = UploadWindow::try_from("22:00 - 06:00".to_string()).unwrap();
+        assert!(overnight.contains(time("23:00")));
+        assert!(overnight.contains(time("01:00")));
+        assert!(!overnight.contains(time("12:00")));
+        assert_eq!(
+            overnight.until_open(time("12:00")),
+            Duration::from_secs(10 * 3600)
+        );
+
+        assert!(UploadWindow::try_from("02:00".to_string()).is_err());
+        assert!(UploadWindow::try_from("2am-6am".to_string()).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BytesMut};
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use sha1::{Digest, Sha1};
+use tokio::sync::Semaphore;
+
+const PIECE_LENGTH: usize = 256 * 1024;
+
+// Piece hashes are kept this long, for this many files
+const CACHE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_CACHE_ENTRIES: usize = 1024;
+// Files hashed at once
+const HASH_CONCURRENCY: usize = 2;
+
+struct CacheEntry {
+    pieces: Arc<Pieces>,
+    expires: Instant,
+}
+
+static PIECES: Lazy<Mutex<HashMap<String, CacheEntry>>> = Lazy::new(Mutex::default);
+static HASHING: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(HASH_CONCURRENCY));
+
+enum Bencode {
+    Int(u64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    /// Keys are kept sorted, as the format requires
+    Dict(BTreeMap<&'static str, Bencode>),
+}
+
+impl Bencode {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(i) => out.extend(format!("i{}e", i).as_bytes()),
+            Bencode::Bytes(b) => {
+                out.extend(format!("{}:", b.len()).as_bytes());
+                out.extend(b);
+            }
+            Bencode::List(l) => {
+                out.push(b'l');
+                l.iter().for_each(|v| v.encode(out));
+                out.push(b'e');
+            }
+            Bencode::Dict(d) => {
+                out.push(b'd');
+                for (k, v) in d {
+                    Bencode::Bytes(k.as_bytes().to_vec()).encode(out);
+                    v.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// The length of a file and the SHA-1 hashes of each of its pieces
+pub struct Pieces {
+    length: u64,
+    hashes: Vec<u8>,
+}
+
+pub async fn hash_pieces<S, B, E>(stream: S) -> Result<Pieces, E>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+{
+    let mut stream = Box::pin(stream);
+
+    let mut length = 0;
+    let mut hashes = Vec::new();
+    let mut piece = BytesMut::with_capacity(PIECE_LENGTH);
+    while let Some(data) = stream.next().await {
+        let mut data = data?;
+        length += data.remaining() as u64;
+
+        while data.has_remaining() {
+            let n = data.chunk().len().min(PIECE_LENGTH - piece.len());
+            piece.extend_from_slice(&data.chunk()[..n]);
+            data.advance(n);
+
+            if piece.len() == PIECE_LENGTH {
+                hashes.extend(Sha1::digest(&piece));
+                piece.clear();
+            }
+        }
+    }
+    if !piece.is_empty() {
+        hashes.extend(Sha1::digest(&piece));
+    }
+
+    Ok(Pieces { length, hashes })
+}
+
+fn cached(key: &str) -> Option<Arc<Pieces>> {
+    let cache = PIECES.lock().unwrap();
+    match cache.get(key) {
+        Some(entry) if entry.expires > Instant::now() => Some(entry.pieces.clone()),
+        _ => None,
+    }
+}
+
+fn store(key: &str, pieces: Arc<Pieces>) {
+    let now = Instant::now();
+    let mut cache = PIECES.lock().unwrap();
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        cache.retain(|_, e| e.expires > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            let soonest = cache
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(k, _)| k.clone());
+            if let Some(soonest) = soonest {
+                cache.remove(&soonest);
+            }
+        }
+    }
+    cache.insert(
+        key.to_string(),
+        CacheEntry {
+            pieces,
+            expires: now + CACHE_LIFETIME,
+        },
+    );
+}
+
+/// The pieces hashed for `key` before, or else those `hash` finds. The key should change
+/// with the file, e.g. by including its ETag.
+pub async fn cached_pieces<F, Fut, E>(key: &str, hash: F) -> Result<Arc<Pieces>, E>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Pieces, E>>,
+{
+    if let Some(pieces) = cached(key) {
+        return Ok(pieces);
+    }
+    let _permit = HASHING.acquire().await.unwrap();
+    // the same file may have been hashed while this waited
+    if let Some(pieces) = cached(key) {
+        return Ok(pieces);
+    }
+    let pieces = Arc::new(hash().await?);
+    store(key, pieces.clone());
+    Ok(pieces)
+}
+
+/// Builds a single file torrent, downloadable from the given web seeds (BEP 19)
+pub fn build_torrent(name: &str, pieces: &Pieces, web_seeds: Vec<String>) -> Vec<u8> {
+    let info = BTreeMap::from([
+        ("length", Bencode::Int(pieces.length)),
+        ("name", Bencode::Bytes(name.as_bytes().to_vec())),
+        ("piece length", Bencode::Int(PIECE_LENGTH as u64)),
+        ("pieces", Bencode::Bytes(pieces.hashes.clone())),
+    ]);
+
+    let torrent = Bencode::Dict(BTreeMap::from([
+        ("info", Bencode::Dict(info)),
+        (
+            "url-list",
+            Bencode::List(
+                web_seeds
+                    .into_iter()
+                    .map(|s| Bencode::Bytes(s.into_bytes()))
+                    .collect(),
+            ),
+        ),
+    ]));
+
+    let mut out = Vec::new();
+    torrent.encode(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_build_torrent() {
+        let data = vec![7u8; PIECE_LENGTH + 10];
+        let chunks = data
+            .chunks(1000)
+            .map(|c| Ok::<_, ()>(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+
+        let pieces = hash_pieces(futures::stream::iter(chunks)).await.unwrap();
+        assert_eq!(pieces.length, data.len() as u64);
+        assert_eq!(pieces.hashes.len(), 40);
+        assert_eq!(
+            &pieces.hashes[20..],
+            &Sha1::digest(&data[PIECE_LENGTH..])[..]
+        );
+
+        let torrent = build_torrent("a.aac", &pieces, vec!["http://x/a.aac".to_string()]);
+        let expected_start = format!(
+            "d4:infod6:lengthi{}e4:name5:a.aac12:piece lengthi{}e6:pieces40:",
+            data.len(),
+            PIECE_LENGTH
+        );
+        assert!(torrent.starts_with(expected_start.as_bytes()));
+        assert!(torrent.ends_with(b"e8:url-listl14:http://x/a.aacee"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_pieces() {
+        let hash = |length| async move {
+            Ok::<_, ()>(Pieces {
+                length,
+                hashes: Vec::new(),
+            })
+        };
+        let pieces = cached_pieces("a.aac:1", || hash(1)).await.unwrap();
+        assert_eq!(pieces.length, 1);
+        // not hashed again until the file changes
+        let pieces = cached_pieces("a.aac:1", || hash(2)).await.unwrap();
+        assert_eq!(pieces.length, 1);
+        let pieces = cached_pieces("a.aac:2", || hash(2)).await.unwrap();
+        assert_eq!(pieces.length, 2);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+
+use crate::bbc::{self, BbcResponseError, Pid, SegmentItem};
+
+/// Segments of this type are pieces of music played in the episode
+pub const MUSIC_SEGMENT_TYPE: &str = "music";
+
+// Segments can be added after an episode airs, so they're fetched again after this
+const CACHE_LIFETIME: Duration = Duration::from_secs(6 * 60 * 60);
+const MAX_CACHE_ENTRIES: usize = 2048;
+
+struct CacheEntry {
+    segments: Arc<Vec<SegmentItem>>,
+    expires: Instant,
+}
+
+/// Segments by episode id, for tracklists and credits
+static SEGMENTS: Lazy<Mutex<HashMap<String, CacheEntry>>> = Lazy::new(Mutex::default);
+
+/// A piece of music as a line of text, e.g. `Johann Sebastian Bach: Cello Suite No.1 in G major
+/// (Yo-Yo Ma)`. For classical music the primary title is the composer and the secondary the
+/// work; anyone else credited is a performer.
+pub fn track(segment: &SegmentItem) -> String {
+    let mut line = match &segment.titles.secondary {
+        Some(work) => format!("{}: {}", segment.titles.primary, work),
+        None => segment.titles.primary.clone(),
+    };
+    let performers = segment
+        .contributions
+        .iter()
+        .filter(|c| {
+            c.role
+                .as_deref()
+                .map_or(true, |role| !role.eq_ignore_ascii_case("composer"))
+        })
+        .map(|c| c.name.as_str())
+        .filter(|name| *name != segment.titles.primary)
+        .unique()
+        .join(", ");
+    if !performers.is_empty() {
+        line += &format!(" ({})", performers);
+    }
+    line
+}
+
+/// The music in an episode, in the order it's played, as a numbered list.
+/// None if there's no music.
+pub fn tracklist(segments: &[SegmentItem]) -> Option<String> {
+    let tracks = segments
+        .iter()
+        .filter(|s| s.segment_type.as_deref() == Some(MUSIC_SEGMENT_TYPE))
+        .sorted_by_key(|s| s.offset.as_ref().map(|o| o.start))
+        .map(track)
+        .enumerate()
+        .map(|(i, track)| format!("{}. {}", i + 1, track))
+        .collect::<Vec<_>>();
+    if tracks.is_empty() {
+        None
+    } else {
+        Some(format!("Tracklist:\n{}", tracks.join("\n")))
+    }
+}
+
+fn cached(episode_id: &Pid) -> Option<Arc<Vec<SegmentItem>>> {
+    let cache = SEGMENTS.lock().unwrap();
+    match cache.get(episode_id.as_str()) {
+        Some(entry) if entry.expires > Instant::now() => Some(entry.segments.clone()),
+        _ => None,
+    }
+}
+
+fn store(episode_id: &Pid, segments: Arc<Vec<SegmentItem>>) {
+    let now = Instant::now();
+    let mut cache = SEGMENTS.lock().unwrap();
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        cache.retain(|_, e| e.expires > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            let soonest = cache
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(k, _)| k.clone());
+            if let Some(soonest) = soonest {
+                cache.remove(&soonest);
+            }
+        }
+    }
+    cache.insert(
+        episode_id.to_string(),
+        CacheEntry {
+            segments,
+            expires: now + CACHE_LIFETIME,
+        },
+    );
+}
+
+/// The episode's segments, e.g. each piece of music played
+pub async fn get_segments(episode_id: &Pid) -> Result<Arc<Vec<SegmentItem>>, BbcResponseError> {
+    if let Some(segments) = cached(episode_id) {
+        return Ok(segments);
+    }
+    let segments = Arc::new(bbc::get_segments(episode_id).await?.data);
+    store(episode_id, segments.clone());
+    Ok(segments)
+}
+
+/// The episode's tracklist, from its segments
+pub async fn get_tracklist(episode_id: &Pid) -> Result<Option<String>, BbcResponseError> {
+    Ok(tracklist(&get_segments(episode_id).await?))
+}
+
+/// The summary with the tracklist after it
+pub fn append(summary: Option<String>, tracklist: &str) -> String {
+    match summary {
+        Some(summary) if !summary.is_empty() => format!("{}\n\n{}", summary, tracklist),
+        _ => tracklist.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::bbc::{Contribution, SegmentOffset, Titles};
+
+    use super::*;
+
+    fn segment(
+        segment_type: &str,
+        start: u64,
+        titles: (&str, Option<&str>),
+        contributions: &[(&str, &str)],
+    ) -> SegmentItem {
+        SegmentItem {
+            id: format!("p{:07}", start),
+            segment_type: Some(segment_type.to_string()),
+            titles: Titles {
+                primary: titles.0.to_string(),
+                secondary: titles.1.map(str::to_string),
+            },
+            offset: Some(SegmentOffset { start, end: None }),
+            contributions: contributions
+                .iter()
+                .map(|(name, role)| Contribution {
+                    name: name.to_string(),
+                    role: Some(role.to_string()),
+                    id: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_tracklist() {
+        let segments = vec![
+            segment(
+                "music",
+                600,
+                ("Clara Schumann", Some("Piano Trio in G minor, Op.17")),
+                &[
+                    ("Clara Schumann", "Composer"),
+                    ("Trio Wanderer", "Ensemble"),
+                ],
+            ),
+            segment("speech", 0, ("Introduction", None), &[]),
+            segment(
+                "music",
+                60,
+                ("Johann Sebastian Bach", Some("Cello Suite No.1 in G major")),
+                &[("Yo-Yo Ma", "Performer")],
+            ),
+            segment(
+                "music",
+                1200,
+                ("Arvo Pärt", Some("Spiegel im Spiegel")),
+                &[],
+            ),
+        ];
+        assert_eq!(
+            tracklist(&segments).unwrap(),
+            "Tracklist:\n\
+             1. Johann Sebastian Bach: Cello Suite No.1 in G major (Yo-Yo Ma)\n\
+             2. Clara Schumann: Piano Trio in G minor, Op.17 (Trio Wanderer)\n\
+             3. Arvo Pärt: Spiegel im Spiegel"
+        );
+
+        assert_eq!(tracklist(&segments[1..2]), None);
+        assert_eq!(
+            append(
+                Some("Music for the morning.".to_string()),
+                "Tracklist:\n1. A"
+            ),
+            "Music for the morning.\n\nTracklist:\n1. A"
+        );
+        assert_eq!(append(None, "Tracklist:\n1. A"), "Tracklist:\n1. A");
+    }
+}
@@ -0,0 +1,292 @@
+use ffmpeg_next::{
+    codec, decoder, encoder, filter, format, frame, ChannelLayout, Packet, Rational,
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fingerprint::{Fingerprint, Fingerprinter},
+    hls::HlsError,
+};
+
+type Result<T, E = HlsError> = std::result::Result<T, E>;
+
+const BIT_RATE: usize = 128000;
+
+/// Output parameters for audio which is re-encoded so all episodes match
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Bits per second, 128 kbps if not given
+    #[serde(default)]
+    pub bit_rate: Option<usize>,
+}
+
+impl AudioFormat {
+    /// Bits per second
+    pub fn bit_rate(&self) -> usize {
+        self.bit_rate.unwrap_or(BIT_RATE)
+    }
+}
+
+/// How ffmpeg runs, for every stream
+#[derive(Clone, Debug, Default)]
+pub struct FfmpegOptions {
+    /// Threads each decoder and encoder may use, or None for ffmpeg's choice
+    pub threads: Option<usize>,
+    /// Never decode audio: streams are only remuxed, even if an audio format is configured
+    pub copy_only: bool,
+    /// Niceness of ffmpeg threads, so they yield to the web server
+    pub nice: Option<i32>,
+    /// Best effort IO priority of ffmpeg threads, from 0 (highest) to 7
+    pub io_priority: Option<u8>,
+    /// Fingerprint transcoded episodes, to find the same audio under other pids
+    pub fingerprint: bool,
+}
+
+static FFMPEG_OPTIONS: OnceCell<FfmpegOptions> = OnceCell::new();
+
+/// Sets the options used by streams started from now on. Only the first call has any effect.
+pub fn set_options(options: FfmpegOptions) {
+    let _ = FFMPEG_OPTIONS.set(options);
+}
+
+pub fn options() -> &'static FfmpegOptions {
+    FFMPEG_OPTIONS.get_or_init(FfmpegOptions::default)
+}
+
+/// Applies the configured priority to the calling thread (Linux only)
+pub fn set_thread_priority(options: &FfmpegOptions) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        // on Linux these apply to a single thread when given its id
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if let Some(nice) = options.nice {
+            if libc::setpriority(libc::PRIO_PROCESS, tid, nice) != 0 {
+                log::warn!(
+                    "Failed to set ffmpeg niceness: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        if let Some(level) = options.io_priority {
+            // ioprio_set(IOPRIO_WHO_PROCESS, tid, IOPRIO_PRIO_VALUE(IOPRIO_CLASS_BE, level))
+            let priority = (2 << 13) | i64::from(level.min(7));
+            if libc::syscall(libc::SYS_ioprio_set, 1, tid, priority) != 0 {
+                log::warn!(
+                    "Failed to set ffmpeg IO priority: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if options.nice.is_some() || options.io_priority.is_some() {
+        log::warn!("ffmpeg thread priority can only be set on Linux");
+    }
+}
+
+/// Codec context using the configured number of threads
+fn codec_context(
+    parameters: impl Into<codec::Parameters>,
+    options: &FfmpegOptions,
+) -> Result<codec::context::Context> {
+    let mut context = codec::context::Context::from_parameters(parameters)?;
+    if let Some(count) = options.threads {
+        context.set_threading(codec::threading::Config {
+            kind: codec::threading::Type::Frame,
+            count,
+            ..Default::default()
+        });
+    }
+    Ok(context)
+}
+
+/// Decodes audio and re-encodes it as AAC in the given format
+pub struct Transcoder {
+    decoder: decoder::Audio,
+    graph: filter::Graph,
+    encoder: encoder::Audio,
+    /// Next output timestamp, in samples
+    pts: i64,
+    fingerprinter: Option<Fingerprinter>,
+}
+
+fn find_filter(name: &'static str) -> Result<filter::Filter> {
+    filter::find(name).ok_or(HlsError::MissingFilter(name))
+}
+
+fn graph_filter<'a>(
+    graph: &'a mut filter::Graph,
+    name: &'static str,
+) -> Result<filter::Context<'a>> {
+    graph.get(name).ok_or(HlsError::MissingFilter(name))
+}
+
+fn filter_graph(
+    decoder: &decoder::Audio,
+    encoder: &encoder::Audio,
+    time_base: Rational,
+) -> Result<filter::Graph> {
+    let mut graph = filter::Graph::new();
+
+    let args = format!(
+        "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+        time_base,
+        decoder.rate(),
+        decoder.format().name(),
+        decoder.channel_layout().bits()
+    );
+    graph.add(&find_filter("abuffer")?, "in", &args)?;
+    graph.add(&find_filter("abuffersink")?, "out", "")?;
+
+    {
+        // the graph resamples and remixes to whatever the sink asks for
+        let mut out = graph_filter(&mut graph, "out")?;
+        out.set_sample_format(encoder.format());
+        out.set_channel_layout(encoder.channel_layout());
+        out.set_sample_rate(encoder.rate());
+    }
+
+    graph.output("in", 0)?.input("out", 0)?.parse("anull")?;
+    graph.validate()?;
+
+    // AAC frames are a fixed size
+    graph_filter(&mut graph, "out")?
+        .sink()
+        .set_frame_size(encoder.frame_size());
+
+    Ok(graph)
+}
+
+impl Transcoder {
+    /// Adds an AAC stream for the transcoded audio to `output`
+    pub fn new(
+        input: &format::stream::Stream,
+        output: &mut format::context::Output,
+        audio_format: &AudioFormat,
+    ) -> Result<Self> {
+        let context = codec_context(input.parameters(), options())?;
+        let mut decoder = context.decoder().audio()?;
+        decoder.set_parameters(input.parameters())?;
+
+        let codec = encoder::find(codec::Id::AAC)
+            .ok_or(HlsError::UnsupportedCodec)?
+            .audio()?;
+        let sample_format = codec
+            .formats()
+            .and_then(|mut formats| formats.next())
+            .ok_or(HlsError::UnsupportedCodec)?;
+        let channel_layout = ChannelLayout::default(audio_format.channels.into());
+        let time_base = (1, audio_format.sample_rate as i32);
+
+        let mut output_stream = output.add_stream(codec)?;
+        let context = codec_context(output_stream.parameters(), options())?;
+        let mut encoder = context.encoder().audio()?;
+        encoder.set_rate(audio_format.sample_rate as i32);
+        encoder.set_channel_layout(channel_layout);
+        encoder.set_channels(channel_layout.channels());
+        encoder.set_format(sample_format);
+        encoder.set_bit_rate(audio_format.bit_rate());
+        encoder.set_time_base(time_base);
+        output_stream.set_time_base(time_base);
+
+        let encoder = encoder.open_as(codec)?;
+        output_stream.set_parameters(&encoder);
+
+        let graph = filter_graph(&decoder, &encoder, input.time_base())?;
+
+        Ok(Transcoder {
+            decoder,
+            graph,
+            encoder,
+            pts: 0,
+            fingerprinter: None,
+        })
+    }
+
+    /// Fingerprints the audio as it's transcoded
+    pub fn fingerprint(&mut self) {
+        self.fingerprinter = Some(Fingerprinter::new(self.encoder.rate()));
+    }
+
+    /// The fingerprint of everything transcoded, if fingerprinting
+    pub fn take_fingerprint(&mut self) -> Option<Fingerprint> {
+        self.fingerprinter.take()?.finish()
+    }
+
+    /// Transcodes a packet from the input stream, writing any output to stream 0 of `output`
+    pub fn send_packet(
+        &mut self,
+        packet: &Packet,
+        output: &mut format::context::Output,
+    ) -> Result<()> {
+        self.decoder.send_packet(packet)?;
+        self.receive_decoded(output)
+    }
+
+    /// Flushes everything still buffered through to `output`
+    pub fn finish(&mut self, output: &mut format::context::Output) -> Result<()> {
+        self.decoder.send_eof()?;
+        self.receive_decoded(output)?;
+
+        graph_filter(&mut self.graph, "in")?.source().flush()?;
+        self.receive_filtered(output)?;
+
+        self.encoder.send_eof()?;
+        self.receive_encoded(output)
+    }
+
+    fn receive_decoded(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut decoded = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            decoded.set_pts(timestamp);
+            graph_filter(&mut self.graph, "in")?
+                .source()
+                .add(&decoded)?;
+            self.receive_filtered(output)?;
+        }
+        Ok(())
+    }
+
+    fn receive_filtered(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut filtered = frame::Audio::empty();
+        while graph_filter(&mut self.graph, "out")?
+            .sink()
+            .frame(&mut filtered)
+            .is_ok()
+        {
+            // output is continuous, so count samples rather than converting input timestamps
+            filtered.set_pts(Some(self.pts));
+            self.pts += filtered.samples() as i64;
+
+            // the first channel is enough to recognise the audio by
+            if let Some(fingerprinter) = &mut self.fingerprinter {
+                if let format::Sample::F32(_) = filtered.format() {
+                    fingerprinter.push(&filtered.plane::<f32>(0)[..filtered.samples()]);
+                }
+            }
+
+            self.encoder.send_frame(&filtered)?;
+            self.receive_encoded(output)?;
+        }
+        Ok(())
+    }
+
+    fn receive_encoded(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let output_time_base = output
+            .stream(0)
+            .ok_or(HlsError::MissingStream("output"))?
+            .time_base();
+
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(0);
+            encoded.rescale_ts(self.encoder.time_base(), output_time_base);
+            encoded.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
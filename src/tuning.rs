@@ -0,0 +1,131 @@
+use once_cell::sync::Lazy;
+
+const MB: u64 = 1024 * 1024;
+
+// Rough memory each HTTP worker needs
+const WORKER_MEMORY: u64 = 64 * MB;
+// Rough memory each stream needs: an S3 part buffer, pipes, and ffmpeg itself
+const STREAM_MEMORY: u64 = 24 * MB;
+// Remuxing or transcoding audio is light, so a CPU can keep several streams going
+const STREAMS_PER_CPU: usize = 4;
+// Memory needed for each S3 part uploaded at once, as every part in flight holds a buffer
+const PART_MEMORY: u64 = 128 * MB;
+// Parts uploaded at once, beyond which S3 is rarely any faster
+const MAX_PART_CONCURRENCY: usize = 4;
+
+/// CPUs and memory available to the proxy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resources {
+    pub cpus: usize,
+    /// Bytes, if it could be found
+    pub memory: Option<u64>,
+}
+
+/// Resources of the host, read once at startup
+pub static RESOURCES: Lazy<Resources> = Lazy::new(Resources::detect);
+
+/// Reads a memory size in bytes, e.g. a cgroup limit. `max` means there's no limit.
+fn read_bytes(path: &str) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Memory not in use, from `/proc/meminfo` (Linux only)
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kb = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+impl Resources {
+    /// CPUs this process may run on, and the least of the memory available and any
+    /// container limit
+    pub fn detect() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let memory = [
+            available_memory(),
+            // cgroups v2, then v1
+            read_bytes("/sys/fs/cgroup/memory.max"),
+            read_bytes("/sys/fs/cgroup/memory/memory.limit_in_bytes"),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        Resources { cpus, memory }
+    }
+
+    /// How many of something fit in the memory, or None if the memory isn't known
+    fn fit(&self, each: u64) -> Option<usize> {
+        self.memory.map(|memory| (memory / each) as usize)
+    }
+
+    /// HTTP worker threads: one per CPU, as long as there's memory for them
+    pub fn workers(&self) -> usize {
+        self.cpus
+            .min(self.fit(WORKER_MEMORY).unwrap_or(usize::MAX))
+            .max(1)
+    }
+
+    /// Episodes streamed or uploaded at once, each of which runs its own ffmpeg pipeline
+    pub fn max_streams(&self) -> usize {
+        (self.cpus * STREAMS_PER_CPU)
+            .min(self.fit(STREAM_MEMORY).unwrap_or(usize::MAX))
+            .max(1)
+    }
+
+    /// Parts of each S3 upload sent at once
+    pub fn s3_part_concurrency(&self) -> usize {
+        self.fit(PART_MEMORY)
+            .unwrap_or(1)
+            .min(self.cpus)
+            .clamp(1, MAX_PART_CONCURRENCY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let small = Resources {
+            cpus: 1,
+            memory: Some(256 * MB),
+        };
+        assert_eq!(small.workers(), 1);
+        assert_eq!(small.max_streams(), 4);
+        assert_eq!(small.s3_part_concurrency(), 1);
+
+        // memory runs out before CPUs do
+        let cramped = Resources {
+            cpus: 8,
+            memory: Some(128 * MB),
+        };
+        assert_eq!(cramped.workers(), 2);
+        assert_eq!(cramped.max_streams(), 5);
+        assert_eq!(cramped.s3_part_concurrency(), 1);
+
+        let large = Resources {
+            cpus: 16,
+            memory: Some(32 * 1024 * MB),
+        };
+        assert_eq!(large.workers(), 16);
+        assert_eq!(large.max_streams(), 64);
+        assert_eq!(large.s3_part_concurrency(), 4);
+
+        let unknown = Resources {
+            cpus: 2,
+            memory: None,
+        };
+        assert_eq!(unknown.workers(), 2);
+        assert_eq!(unknown.max_streams(), 8);
+        assert_eq!(unknown.s3_part_concurrency(), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tee::TeeBuffer;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    /// Waiting to be prefetched
+    Queued,
+    InProgress,
+    Complete,
+    Failed,
+}
+
+/// Whether an upload's audio was all there, checked against the episode's playlist
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrity {
+    Verified,
+    /// Some audio was missing, so it wasn't uploaded
+    Truncated,
+}
+
+struct Upload {
+    state: UploadState,
+    integrity: Option<Integrity>,
+    /// Data uploaded so far, while the upload is in progress
+    buffer: Option<Arc<TeeBuffer>>,
+}
+
+/// Tracks background S3 uploads, by object key, so clients can poll for completion.
+#[derive(Default)]
+pub struct UploadStatus {
+    uploads: Mutex<HashMap<String, Upload>>,
+}
+
+impl UploadStatus {
+    pub fn get(&self, s3_path: &str) -> Option<UploadState> {
+        self.uploads.lock().unwrap().get(s3_path).map(|u| u.state)
+    }
+
+    /// Integrity of the last upload of the object, if it finished
+    pub fn integrity(&self, s3_path: &str) -> Option<Integrity> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .get(s3_path)
+            .and_then(|u| u.integrity)
+    }
+
+    pub fn set(&self, s3_path: &str, state: UploadState, integrity: Option<Integrity>) {
+        self.uploads.lock().unwrap().insert(
+            s3_path.to_string(),
+            Upload {
+                state,
+                integrity,
+                buffer: None,
+            },
+        );
+    }
+
+    /// Forgets an upload, e.g. a queued one which isn't needed after all
+    pub fn clear(&self, s3_path: &str) {
+        self.uploads.lock().unwrap().remove(s3_path);
+    }
+
+    /// Forgets an upload if it's still queued, leaving one which has started since
+    pub fn clear_queued(&self, s3_path: &str) {
+        let mut uploads = self.uploads.lock().unwrap();
+        if uploads.get(s3_path).map(|u| u.state) == Some(UploadState::Queued) {
+            uploads.remove(s3_path);
+        }
+    }
+
+    /// Uploads queued or in progress, by object key
+    pub fn active(&self) -> Vec<(String, UploadState)> {
+        let mut active = self
+            .uploads
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, u)| matches!(u.state, UploadState::Queued | UploadState::InProgress))
+            .map(|(key, u)| (key.clone(), u.state))
+            .collect::<Vec<_>>();
+        active.sort_by(|(a, _), (b, _)| a.cmp(b));
+        active
+    }
+
+    /// Returns the buffer of an upload in progress, so its data can be served to another listener
+    pub fn buffer(&self, s3_path: &str) -> Option<Arc<TeeBuffer>> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .get(s3_path)
+            .and_then(|u| u.buffer.clone())
+    }
+
+    /// Marks an upload as queued for a prefetch. Returns false if it's already queued,
+    /// running or complete, so it isn't queued twice.
+    pub fn try_queue(&self, s3_path: &str) -> bool {
+        let mut uploads = self.uploads.lock().unwrap();
+        match uploads.get(s3_path).map(|u| u.state) {
+            None | Some(UploadState::Failed) => {
+                uploads.insert(
+                    s3_path.to_string(),
+                    Upload {
+                        state: UploadState::Queued,
+                        integrity: None,
+                        buffer: None,
+                    },
+                );
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Marks an upload as in progress, returning the buffer the upload should copy its data to.
+    /// Returns None if an upload of this object is already running, or has completed unless
+    /// it's to be replaced.
+    pub fn try_start(&self, s3_path: &str, replace: bool) -> Option<Arc<TeeBuffer>> {
+        let mut uploads = self.uploads.lock().unwrap();
+        match uploads.get(s3_path).map(|u| u.state) {
+            Some(UploadState::InProgress) => return None,
+            Some(UploadState::Complete) if !replace => return None,
+            _ => {}
+        }
+        let buffer = Arc::new(TeeBuffer::default());
+        uploads.insert(
+            s3_path.to_string(),
+            Upload {
+                state: UploadState::InProgress,
+                integrity: None,
+                buffer: Some(buffer.clone()),
+            },
+        );
+        Some(buffer)
+    }
+}
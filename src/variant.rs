@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
+
+use crate::{
+    bbc::{QualityVariant, QualityVariants},
+    fetch,
+};
+
+// How long a file URL is trusted after it's been found, and given up on after it 404s
+const AVAILABLE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+const MISSING_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_CHECKED: usize = 10_000;
+// HEAD requests made at once, across every feed being built
+const CHECK_CONCURRENCY: usize = 16;
+
+/// Whether public file URLs exist, by URL, and until when that's assumed
+static CHECKED: Lazy<Mutex<HashMap<String, (bool, Instant)>>> = Lazy::new(Mutex::default);
+static CHECKS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(CHECK_CONCURRENCY));
+
+/// An episode's download variants, best quality first
+fn by_quality(variants: &QualityVariants) -> impl Iterator<Item = &QualityVariant> {
+    [&variants.high, &variants.medium, &variants.low]
+        .into_iter()
+        .flatten()
+}
+
+/// The best quality variant, as the container advertises it
+pub fn best(variants: &QualityVariants) -> Option<&QualityVariant> {
+    by_quality(variants).next()
+}
+
+/// The best variant whose file URL is available, or the best variant without its file
+/// URL if none are, so the episode is proxied but still has a bitrate to estimate from
+fn choose(variants: &QualityVariants, available: impl Fn(&str) -> bool) -> Option<QualityVariant> {
+    by_quality(variants)
+        .find(|v| v.file_url.as_deref().map_or(false, &available))
+        .cloned()
+        .or_else(|| {
+            best(variants).map(|v| QualityVariant {
+                file_url: None,
+                ..v.clone()
+            })
+        })
+}
+
+fn checked(url: &str) -> Option<bool> {
+    match CHECKED.lock().unwrap().get(url) {
+        Some((available, until)) if *until > Instant::now() => Some(*available),
+        _ => None,
+    }
+}
+
+fn store(url: &str, available: bool, until: Instant) {
+    let mut checked = CHECKED.lock().unwrap();
+    if checked.len() >= MAX_CHECKED {
+        let now = Instant::now();
+        checked.retain(|_, (_, until)| *until > now);
+        if checked.len() >= MAX_CHECKED {
+            let soonest = checked
+                .iter()
+                .min_by_key(|(_, (_, until))| *until)
+                .map(|(url, _)| url.clone());
+            if let Some(soonest) = soonest {
+                checked.remove(&soonest);
+            }
+        }
+    }
+    checked.insert(url.to_string(), (available, until));
+}
+
+async fn is_available(url: &str) -> bool {
+    if let Some(available) = checked(url) {
+        return available;
+    }
+    let _permit = CHECKS.acquire().await.unwrap();
+    // another feed may have checked it while this waited
+    if let Some(available) = checked(url) {
+        return available;
+    }
+
+    let now = Instant::now();
+    match fetch::head(url.to_string()).await {
+        Ok(status) => {
+            let available = status < 400;
+            let ttl = if available {
+                AVAILABLE_TTL
+            } else {
+                MISSING_TTL
+            };
+            store(url, available, now + ttl);
+            available
+        }
+        // a failing host says nothing about whether the file is there
+        Err(e) => {
+            log::warn!("Couldn't check {}: {}", url, e);
+            true
+        }
+    }
+}
+
+/// Like `best`, but checks file URLs exist (with HEAD requests, cached), falling
+/// back through lower qualities when the advertised file is missing
+pub async fn best_available(variants: &QualityVariants) -> Option<QualityVariant> {
+    let urls = by_quality(variants)
+        .filter_map(|v| v.file_url.clone())
+        .collect::<Vec<_>>();
+    let statuses = join_all(urls.iter().map(|url| is_available(url))).await;
+    let available = urls.into_iter().zip(statuses).collect::<HashMap<_, _>>();
+
+    let chosen = choose(variants, |url| available.get(url).copied().unwrap_or(false));
+    if let (Some(best), Some(chosen)) = (best(variants), &chosen) {
+        if best.file_url.is_some() && best.file_url != chosen.file_url {
+            log::warn!(
+                "{} is missing, using {}",
+                best.file_url.as_deref().unwrap_or_default(),
+                chosen.file_url.as_deref().unwrap_or("the proxy")
+            );
+        }
+    }
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn variant(bitrate: u64, file_url: &str) -> Option<QualityVariant> {
+        Some(QualityVariant {
+            bitrate: Some(bitrate),
+            file_url: Some(file_url.to_string()),
+            file_size: None,
+        })
+    }
+
+    #[test]
+    fn test_choose() {
+        let variants = QualityVariants {
+            high: variant(320, "high.mp3"),
+            medium: variant(128, "medium.mp3"),
+            low: variant(64, "low.mp3"),
+        };
+
+        let chosen = choose(&variants, |_| true).unwrap();
+        assert_eq!(chosen.file_url.as_deref(), Some("high.mp3"));
+
+        let chosen = choose(&variants, |url| url != "high.mp3").unwrap();
+        assert_eq!(chosen.file_url.as_deref(), Some("medium.mp3"));
+        assert_eq!(chosen.bitrate, Some(128));
+
+        let chosen = choose(&variants, |url| url == "low.mp3").unwrap();
+        assert_eq!(chosen.file_url.as_deref(), Some("low.mp3"));
+
+        // proxied, estimated from the best bitrate
+        let chosen = choose(&variants, |_| false).unwrap();
+        assert_eq!(chosen.file_url, None);
+        assert_eq!(chosen.bitrate, Some(320));
+
+        let none = QualityVariants {
+            high: None,
+            medium: None,
+            low: None,
+        };
+        assert!(choose(&none, |_| true).is_none());
+    }
+}
@@ -0,0 +1,74 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::{config::Config, limits};
+
+/// What's running, for bug reports
+#[derive(Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_sha: Option<&'static str>,
+    pub build_date: Option<DateTime<Utc>>,
+    pub features: Features,
+    pub runtime: Runtime,
+}
+
+#[derive(Serialize)]
+pub struct Features {
+    pub grpc: bool,
+    /// Remuxing is always done with ffmpeg, raw AAC streams are passed through
+    pub remux: &'static str,
+    /// libavformat version
+    pub ffmpeg: String,
+    /// Where episodes are saved, if anywhere
+    pub storage: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct Runtime {
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub cpus: Option<usize>,
+    pub resident_memory: Option<u64>,
+}
+
+fn ffmpeg_version() -> String {
+    let version = ffmpeg_next::format::version();
+    format!(
+        "{}.{}.{}",
+        version >> 16,
+        (version >> 8) & 0xff,
+        version & 0xff
+    )
+}
+
+pub fn version_info(config: &Config) -> VersionInfo {
+    let git_sha = env!("SOUNDS_PROXY_GIT_SHA");
+    let build_date = env!("SOUNDS_PROXY_BUILD_TIME")
+        .parse()
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single());
+
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        // not built from a git checkout
+        git_sha: if git_sha.is_empty() {
+            None
+        } else {
+            Some(git_sha)
+        },
+        build_date,
+        features: Features {
+            grpc: cfg!(feature = "grpc"),
+            remux: "ffmpeg",
+            ffmpeg: ffmpeg_version(),
+            storage: config.s3_bucket.as_ref().map(|_| "s3"),
+        },
+        runtime: Runtime {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            cpus: std::thread::available_parallelism().ok().map(|n| n.get()),
+            resident_memory: limits::resident_memory(),
+        },
+    }
+}
@@ -1,9 +1,20 @@
-use crate::bbc::BbcResponseError;
+use crate::{bbc::BbcResponseError, fetch::FetchError};
 
 pub fn get_http_response_for_bbc_error(err: &BbcResponseError) -> (u16, Option<String>) {
     match err {
         BbcResponseError::BadRequest => (400, None),
+        BbcResponseError::Forbidden => (403, None),
+        BbcResponseError::NotPermitted => (
+            403,
+            Some("This show isn't available through this proxy".into()),
+        ),
         BbcResponseError::NotFound => (404, None),
+        BbcResponseError::NotImplemented => (501, None),
+        BbcResponseError::NotAcceptable => (
+            406,
+            Some("Available as application/rss+xml or text/html".into()),
+        ),
+        BbcResponseError::Overloaded => (503, Some("Too busy, try again later".into())),
         BbcResponseError::FormatError => (503, Some("Unexpected data from BBC".into())),
         BbcResponseError::ServerResponseError(upstream_status) => {
             if *upstream_status == 400 {
@@ -16,9 +27,29 @@ pub fn get_http_response_for_bbc_error(err: &BbcResponseError) -> (u16, Option<S
                 )
             }
         }
+        BbcResponseError::FetchError(FetchError::CircuitOpen(_, _)) => {
+            (503, Some("BBC is unavailable, try again later".into()))
+        }
+        BbcResponseError::FetchError(FetchError::RateLimited(_, _)) => (
+            503,
+            Some("BBC is limiting requests, try again later".into()),
+        ),
+        BbcResponseError::Unavailable(result) if result == "geolocation" => {
+            (403, Some("Not available where the proxy is located".into()))
+        }
+        BbcResponseError::Unavailable(result) => (404, Some(format!("Not available ({})", result))),
         BbcResponseError::UnsupportedMedia(_, _) => {
             (501, Some("Media format not supported".into()))
         }
         _ => (500, None),
     }
 }
+
+/// Seconds a client should wait before retrying, if the error says
+pub fn retry_after_for_bbc_error(err: &BbcResponseError) -> Option<u64> {
+    match err {
+        BbcResponseError::FetchError(FetchError::CircuitOpen(_, secs))
+        | BbcResponseError::FetchError(FetchError::RateLimited(_, secs)) => Some(*secs),
+        _ => None,
+    }
+}